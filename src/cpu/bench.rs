@@ -0,0 +1,201 @@
+use std::time::{Duration, Instant};
+
+use super::machine::{Engine, Machine, MachineConfig};
+use crate::isa::riscv32::block::BlockCacheStats;
+use crate::isa::riscv32::icache::DecodeCacheStats;
+use crate::isa::riscv32::softmmu::SoftMmuStats;
+use crate::memory::cache::HierarchyConfig;
+
+const BENCH_BASE: u64 = 0x8000_0000;
+/// the 2 KiB array of the memory loop, pointed to by x5
+const BENCH_DATA: u64 = 0x8001_0000;
+
+/// addi x1,x1,1; xor x2,x2,x1; slli x3,x2,3; add x4,x4,x3; j .-16
+const BENCH_LOOP: [u32; 5] = [0x00108093, 0x00114133, 0x00311193, 0x00320233, 0xff1ff06f];
+
+/// addi x1,x1,8; andi x2,x1,0x7f8; add x3,x5,x2; ld x4,0(x3); add x4,x4,x1;
+/// sd x4,0(x3); lw x6,4(x3); add x7,x7,x6; j .-32
+const BENCH_MEMORY_LOOP: [u32; 9] = [
+    0x00808093, 0x7f80f113, 0x002281b3, 0x0001b203, 0x00120233, 0x0041b023, 0x0041a303, 0x006383b3,
+    0xfe1ff06f,
+];
+
+#[derive(Clone, Debug)]
+pub struct Benchmark {
+    pub instructions: usize,
+    /// wall time of each configuration, the first one is the baseline
+    pub times: Vec<(&'static str, Duration)>,
+    pub icache: DecodeCacheStats,
+    pub blocks: BlockCacheStats,
+    /// wall time of a loop of loads and stores on the threaded engine,
+    /// without and then with the softmmu
+    pub memory: Vec<(&'static str, Duration)>,
+    pub softmmu: SoftMmuStats,
+}
+
+fn report_times(instructions: usize, times: &[(&'static str, Duration)]) {
+    for (name, time) in times {
+        println!(
+            "{:<12}\t{:?} ({:.2} MIPS, {:.2}x)",
+            name,
+            time,
+            instructions as f64 / time.as_secs_f64() / 1e6,
+            times[0].1.as_secs_f64() / time.as_secs_f64()
+        );
+    }
+}
+
+impl Benchmark {
+    /// Speedup of the configuration `index` over the baseline.
+    pub fn speedup(&self, index: usize) -> f64 {
+        self.times[0].1.as_secs_f64() / self.times[index].1.as_secs_f64()
+    }
+
+    /// Speedup of the softmmu on the memory loop.
+    pub fn memory_speedup(&self) -> f64 {
+        self.memory[0].1.as_secs_f64() / self.memory[1].1.as_secs_f64()
+    }
+
+    pub fn report(&self) {
+        println!("instructions\t{}", self.instructions);
+        report_times(self.instructions, &self.times);
+        println!(
+            "icache\t\t{:.2}% hits ({} hits, {} misses, {} invalidations)",
+            self.icache.hit_rate() * 100.0,
+            self.icache.hits,
+            self.icache.misses,
+            self.icache.invalidations
+        );
+        println!(
+            "blocks\t\t{} built, {} chained, {} dispatched, {} invalidations",
+            self.blocks.built,
+            self.blocks.chained,
+            self.blocks.dispatched,
+            self.blocks.invalidations
+        );
+        println!("memory loop");
+        report_times(self.instructions, &self.memory);
+        println!(
+            "softmmu\t\t{:.2}% hits ({} hits, {} misses, {} flushes)",
+            self.softmmu.hit_rate() * 100.0,
+            self.softmmu.hits,
+            self.softmmu.misses,
+            self.softmmu.flushes
+        );
+    }
+}
+
+fn bench_machine(config: MachineConfig, code: &[u32]) -> Machine {
+    let mut machine = Machine::new(config);
+    let code: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    machine.bus.ram.load(BENCH_BASE, &code).unwrap();
+    let cpu = &mut machine.harts[0];
+    cpu.set_pc(BENCH_BASE);
+    cpu.set_reg(5, BENCH_DATA);
+    machine
+}
+
+fn time(machine: &mut Machine, instructions: usize) -> Duration {
+    let start = Instant::now();
+    machine.run(instructions);
+    start.elapsed()
+}
+
+/// Run a small loop for `instructions` instructions with the plain
+/// interpreter, the decoded-instruction cache and the threaded engine, and a
+/// loop of loads and stores with and without the softmmu.
+pub fn bench(instructions: usize) -> Benchmark {
+    let configs = [
+        ("uncached", Engine::Interpreter, false),
+        ("icache", Engine::Interpreter, true),
+        ("threaded", Engine::Threaded, false),
+        #[cfg(feature = "jit")]
+        ("jit", Engine::Jit, false),
+    ];
+    let mut times = Vec::new();
+    let mut machines = Vec::new();
+    for (name, engine, icache) in configs {
+        let config = MachineConfig {
+            engine,
+            ..MachineConfig::default()
+        };
+        let mut machine = bench_machine(config, &BENCH_LOOP);
+        machine.harts[0].icache.enabled = icache;
+        times.push((name, time(&mut machine, instructions)));
+        machines.push(machine);
+    }
+
+    let result = machines[0].harts[0].reg(4);
+    assert!(machines
+        .iter()
+        .all(|machine| machine.harts[0].reg(4) == result));
+    let mut memory = Vec::new();
+    let mut memory_machines = Vec::new();
+    for (name, softmmu) in [("slow path", false), ("softmmu", true)] {
+        let config = MachineConfig {
+            engine: Engine::Threaded,
+            ..MachineConfig::default()
+        };
+        let mut machine = bench_machine(config, &BENCH_MEMORY_LOOP);
+        machine.harts[0].softmmu.enabled = softmmu;
+        memory.push((name, time(&mut machine, instructions)));
+        memory_machines.push(machine);
+    }
+    let [slow, fast] = &memory_machines[..] else {
+        unreachable!()
+    };
+    assert_eq!(slow.harts[0].reg(7), fast.harts[0].reg(7));
+    assert_eq!(slow.bus.ram.snapshot(), fast.bus.ram.snapshot());
+
+    Benchmark {
+        instructions,
+        times,
+        icache: machines[1].harts[0].icache.stats,
+        blocks: machines[2].harts[0].blocks.stats,
+        memory,
+        softmmu: fast.harts[0].softmmu.stats,
+    }
+}
+
+/// Run the loop of loads and stores for `instructions` instructions with
+/// the default cache hierarchy modelled, for `Machine::report_caches`.
+pub fn caches(instructions: usize) -> Machine {
+    let config = MachineConfig {
+        caches: Some(HierarchyConfig::default()),
+        ..MachineConfig::default()
+    };
+    let mut machine = bench_machine(config, &BENCH_MEMORY_LOOP);
+    machine.run(instructions);
+    machine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_rates() {
+        let bench = bench(10_000);
+        assert_eq!(bench.icache.misses, BENCH_LOOP.len() as u64);
+        assert!(bench.icache.hit_rate() > 0.99);
+        assert_eq!(bench.blocks.built, 1);
+        // the loop is linked to itself on its first iteration, after that it is
+        // only looked up when a slice starts
+        assert_eq!(bench.blocks.dispatched, 10);
+        // the first load and the first store to the array
+        assert_eq!(bench.softmmu.misses, 2);
+        assert!(bench.softmmu.hit_rate() > 0.99);
+    }
+
+    #[test]
+    fn cache_hit_rates() {
+        let machine = caches(9_000);
+        let caches = machine.harts[0].caches.as_ref().unwrap();
+        // the loop fits in two lines, and the array in the L1 data cache
+        assert_eq!(caches.l1i.stats.reads, 9_000);
+        assert!(caches.l1i.stats.read_misses <= 2);
+        assert_eq!(caches.l1d.stats.reads, 2_000);
+        assert_eq!(caches.l1d.stats.writes, 1_000);
+        assert!(caches.l1d.stats.hit_rate() > 0.9);
+    }
+}
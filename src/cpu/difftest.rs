@@ -0,0 +1,97 @@
+//! Run two machines in lockstep and compare their architectural state, to
+//! check a faster engine against the interpreter.
+
+use thiserror::Error;
+
+use super::machine::Machine;
+use crate::isa::riscv32::reg::{CpuState, REG_NAMES};
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[error("hart {hart} diverged after {instructions} instructions: {what} is {expected:#x} in the reference but {actual:#x}")]
+pub struct Divergence {
+    pub instructions: usize,
+    pub hart: usize,
+    pub what: &'static str,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+/// The state compared after every stride.
+fn state(cpu: &CpuState) -> Vec<(&'static str, u64)> {
+    let mut state = vec![("pc", cpu.pc()), ("mode", cpu.mode as u64)];
+    state.extend((1..32).map(|i| (REG_NAMES[i as usize], cpu.reg(i))));
+    let csr = &cpu.csr;
+    state.extend([
+        ("mstatus", csr.mstatus),
+        ("mip", csr.mip),
+        ("mcause", csr.mcause),
+        ("mepc", csr.mepc),
+        ("mtval", csr.mtval),
+        ("scause", csr.scause),
+        ("sepc", csr.sepc),
+        ("stval", csr.stval),
+        ("satp", csr.satp),
+        ("instret", csr.counters.instret),
+    ]);
+    state
+}
+
+/// Run `instructions` instructions on both machines, comparing every hart
+/// each `stride` instructions. Returns both machines if they never diverged.
+pub fn difftest(
+    mut reference: Machine,
+    mut dut: Machine,
+    instructions: usize,
+    stride: usize,
+) -> Result<(Machine, Machine), Divergence> {
+    let mut done = 0;
+    while done < instructions {
+        let n = stride.min(instructions - done);
+        reference.run(n);
+        dut.run(n);
+        done += n;
+        for (hart, (expected, actual)) in reference.harts.iter().zip(&dut.harts).enumerate() {
+            let mismatch = state(expected)
+                .into_iter()
+                .zip(state(actual))
+                .find(|(expected, actual)| expected.1 != actual.1);
+            if let Some(((what, expected), (_, actual))) = mismatch {
+                return Err(Divergence {
+                    instructions: done,
+                    hart,
+                    what,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+    Ok((reference, dut))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::machine::{Engine, MachineConfig};
+
+    #[test]
+    fn divergence() {
+        let machine = |engine| {
+            let mut machine = Machine::new(MachineConfig {
+                engine,
+                ..MachineConfig::default()
+            });
+            // j .
+            machine.bus.ram.load(0x8000_0000, &[0x6f, 0, 0, 0]).unwrap();
+            machine.harts[0].set_pc(0x8000_0000);
+            machine
+        };
+        let mut dut = machine(Engine::Threaded);
+        dut.harts[0].set_reg(5, 1);
+        let Err(err) = difftest(machine(Engine::Interpreter), dut, 10, 10) else {
+            panic!("the machines should diverge");
+        };
+        assert_eq!(err.what, "t0");
+        assert_eq!((err.expected, err.actual), (0, 1));
+    }
+}
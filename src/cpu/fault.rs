@@ -0,0 +1,557 @@
+//! Fault injection: flip bits in RAM, registers or CSRs, or break device
+//! registers, as a campaign file describes, and find out whether the guest
+//! notices.
+//!
+//! A campaign is a text file with one directive per line, `#` starting a
+//! comment:
+//!
+//! ```text
+//! limit 100000                 # instructions before a run is taken to hang
+//! end 0x80000018               # the guest finished
+//! detect 0x8000001c            # the guest noticed a fault
+//! crash 0x0                    # the guest crashed, e.g. its trap vector
+//! output 0x80001000 8          # RAM compared with the run without faults
+//! fault a at pc 0x80000010 flip t1 bit 0
+//! fault b at icount 100 hart 1 flip csr 0x300 bit 3
+//! fault c at icount 0 flip ram 0x80000400 bit 7
+//! fault d at icount 0 mmio 0x10000005 stuck 0x20
+//! fault e at pc 0x80000008 mmio 0x10000000 error
+//! ```
+//!
+//! The guest runs once without faults, and then once per fault.
+
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+use std::str::SplitWhitespace;
+
+use thiserror::Error;
+
+use super::machine::Machine;
+use crate::isa::riscv32::csr::Privilege;
+use crate::isa::riscv32::reg::REG_NAMES;
+use crate::memory::bus::MmioFault;
+use crate::runtime::State;
+
+/// Instructions a run may execute unless the campaign sets a `limit`.
+pub const CONFIG_FAULT_LIMIT: u64 = 1_000_000;
+
+#[derive(Debug, Error)]
+pub enum CampaignError {
+    #[error("cannot read the campaign: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    Parse { line: usize, message: String },
+    #[error("the campaign has no end pc")]
+    NoEnd,
+    #[error("the run without faults did not finish: {0:?}")]
+    Golden(Outcome),
+    #[error("fault `{name}` flips a bit at {addr:#x}, which is not RAM")]
+    NotRam { name: String, addr: u64 },
+}
+
+/// When a fault is injected. Every fault is injected at most once.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    /// once the machine has executed this many instructions
+    Icount(u64),
+    /// the first time the hart is about to execute the instruction at the pc
+    Pc(u64),
+}
+
+/// What a bit flip hits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Target {
+    /// the byte at a physical address
+    Ram(u64),
+    Gpr(u32),
+    /// written back like an M-mode csr write, so WARL fields stay legal
+    Csr(u32),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Effect {
+    Flip {
+        target: Target,
+        bit: u32,
+    },
+    /// from then on, device accesses at the address fail or are stuck
+    Mmio {
+        addr: u64,
+        fault: MmioFault,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Fault {
+    pub name: String,
+    pub trigger: Trigger,
+    /// the hart whose pc triggers the fault and whose registers it flips
+    pub hart: usize,
+    pub effect: Effect,
+}
+
+impl Fault {
+    fn triggered(&self, machine: &Machine) -> bool {
+        match self.trigger {
+            Trigger::Icount(n) => machine.icount() >= n,
+            Trigger::Pc(pc) => machine
+                .harts
+                .get(self.hart)
+                .is_some_and(|cpu| cpu.pc() == pc),
+        }
+    }
+
+    /// Returns false if there was nothing to inject the fault into, such as
+    /// a read-only csr.
+    fn inject(&self, machine: &mut Machine) -> bool {
+        let Some(cpu) = machine.harts.get_mut(self.hart) else {
+            return false;
+        };
+        match self.effect {
+            Effect::Flip {
+                target: Target::Gpr(reg),
+                bit,
+            } => cpu.set_reg(reg, cpu.reg(reg) ^ 1 << bit),
+            Effect::Flip {
+                target: Target::Csr(csr),
+                bit,
+            } => {
+                return cpu
+                    .csr
+                    .read(csr, Privilege::Machine)
+                    .and_then(|value| cpu.csr.write(csr, value ^ 1 << bit, Privilege::Machine))
+                    .is_ok();
+            }
+            Effect::Flip {
+                target: Target::Ram(paddr),
+                bit,
+            } => {
+                let Some(byte) = machine.bus.ram.read::<u8>(paddr) else {
+                    return false;
+                };
+                if machine.bus.ram.write(paddr, byte ^ 1 << bit).is_none() {
+                    return false;
+                }
+                // the byte may be code the harts have decoded already
+                for cpu in &mut machine.harts {
+                    cpu.invalidate_decoded(paddr);
+                }
+            }
+            Effect::Mmio { addr, fault } => machine.bus.mmio_faults.push((addr, fault)),
+        }
+        true
+    }
+}
+
+/// How a run with a fault ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// finished with the same output as the run without faults
+    Masked,
+    /// reached a detect pc
+    Detected,
+    /// reached a crash pc, stopped, or ran out of instructions
+    Crashed,
+    /// finished with different output
+    SilentCorruption,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunReport {
+    pub name: String,
+    /// whether the fault triggered and could be injected
+    pub injected: bool,
+    pub outcome: Outcome,
+    pub icount: u64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CampaignReport {
+    pub runs: Vec<RunReport>,
+}
+
+impl CampaignReport {
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.runs
+            .iter()
+            .filter(|run| run.outcome == outcome)
+            .count()
+    }
+
+    pub fn report(&self) {
+        for run in &self.runs {
+            println!(
+                "{:<16}\t{:?}{}\t{} instructions",
+                run.name,
+                run.outcome,
+                if run.injected { "" } else { " (not injected)" },
+                run.icount
+            );
+        }
+        for outcome in [
+            Outcome::Masked,
+            Outcome::Detected,
+            Outcome::Crashed,
+            Outcome::SilentCorruption,
+        ] {
+            println!("{:<16}\t{}", format!("{outcome:?}"), self.count(outcome));
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Campaign {
+    /// instructions a run may execute before it is taken to hang
+    pub limit: u64,
+    /// the guest finished once a hart is about to execute one of these
+    pub end: Vec<u64>,
+    /// where the guest goes when it noticed a fault
+    pub detect: Vec<u64>,
+    /// where the guest goes when it crashed
+    pub crash: Vec<u64>,
+    /// RAM holding the guest's output, as address and length
+    pub output: Vec<(u64, usize)>,
+    /// one run each
+    pub faults: Vec<Fault>,
+}
+
+type Words<'a> = SplitWhitespace<'a>;
+
+fn word<'a>(words: &mut Words<'a>, what: &str) -> Result<&'a str, String> {
+    words.next().ok_or_else(|| format!("expected {what}"))
+}
+
+fn expect(words: &mut Words, keyword: &str) -> Result<(), String> {
+    match words.next() {
+        Some(word) if word == keyword => Ok(()),
+        Some(word) => Err(format!("expected `{keyword}`, found `{word}`")),
+        None => Err(format!("expected `{keyword}`")),
+    }
+}
+
+/// A decimal number, or a hexadecimal one starting with `0x`.
+fn number(words: &mut Words, what: &str) -> Result<u64, String> {
+    let word = word(words, what)?;
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("expected {what}, found `{word}`"))
+}
+
+/// `x0` to `x31`, or an ABI name.
+fn register(name: &str) -> Option<u32> {
+    let index = match name.strip_prefix('x') {
+        Some(index) => index.parse().ok()?,
+        None => REG_NAMES.iter().position(|&reg| reg == name)? as u32,
+    };
+    (index < REG_NAMES.len() as u32).then_some(index)
+}
+
+fn fault(words: &mut Words) -> Result<Fault, String> {
+    let name = word(words, "a name")?.to_string();
+    expect(words, "at")?;
+    let trigger = match word(words, "`icount` or `pc`")? {
+        "icount" => Trigger::Icount(number(words, "an instruction count")?),
+        "pc" => Trigger::Pc(number(words, "a pc")?),
+        other => return Err(format!("expected `icount` or `pc`, found `{other}`")),
+    };
+    let mut action = word(words, "`flip` or `mmio`")?;
+    let mut hart = 0;
+    if action == "hart" {
+        hart = number(words, "a hart")? as usize;
+        action = word(words, "`flip` or `mmio`")?;
+    }
+    let effect = match action {
+        "flip" => {
+            let target = match word(words, "`ram`, `csr` or a register")? {
+                "ram" => Target::Ram(number(words, "an address")?),
+                "csr" => Target::Csr(number(words, "a csr")? as u32),
+                reg => Target::Gpr(register(reg).ok_or(format!("unknown register `{reg}`"))?),
+            };
+            expect(words, "bit")?;
+            let bit = number(words, "a bit")?;
+            let bits = match target {
+                Target::Ram(_) => 8,
+                _ => 64,
+            };
+            if bit >= bits {
+                return Err(format!("bit {bit} is out of range"));
+            }
+            Effect::Flip {
+                target,
+                bit: bit as u32,
+            }
+        }
+        "mmio" => {
+            let addr = number(words, "an address")?;
+            let fault = match word(words, "`error` or `stuck`")? {
+                "error" => MmioFault::Error,
+                "stuck" => MmioFault::StuckAt(number(words, "a value")?),
+                other => return Err(format!("expected `error` or `stuck`, found `{other}`")),
+            };
+            Effect::Mmio { addr, fault }
+        }
+        other => return Err(format!("expected `flip` or `mmio`, found `{other}`")),
+    };
+    Ok(Fault {
+        name,
+        trigger,
+        hart,
+        effect,
+    })
+}
+
+impl Campaign {
+    pub fn from_file(path: &Path) -> Result<Self, CampaignError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, CampaignError> {
+        let mut campaign = Self {
+            limit: CONFIG_FAULT_LIMIT,
+            end: Vec::new(),
+            detect: Vec::new(),
+            crash: Vec::new(),
+            output: Vec::new(),
+            faults: Vec::new(),
+        };
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let Some(directive) = words.next() else {
+                continue;
+            };
+            campaign
+                .directive(directive, &mut words)
+                .and_then(|()| match words.next() {
+                    Some(word) => Err(format!("unexpected `{word}`")),
+                    None => Ok(()),
+                })
+                .map_err(|message| CampaignError::Parse {
+                    line: index + 1,
+                    message,
+                })?;
+        }
+        if campaign.end.is_empty() {
+            return Err(CampaignError::NoEnd);
+        }
+        Ok(campaign)
+    }
+
+    fn directive(&mut self, directive: &str, words: &mut Words) -> Result<(), String> {
+        match directive {
+            "limit" => self.limit = number(words, "an instruction count")?,
+            "end" => self.end.push(number(words, "a pc")?),
+            "detect" => self.detect.push(number(words, "a pc")?),
+            "crash" => self.crash.push(number(words, "a pc")?),
+            "output" => {
+                let addr = number(words, "an address")?;
+                let len = number(words, "a length")?;
+                self.output.push((addr, len as usize));
+            }
+            "fault" => self.faults.push(fault(words)?),
+            _ => return Err(format!("unknown directive `{directive}`")),
+        }
+        Ok(())
+    }
+
+    /// Run a machine from `machine` without faults, and then a fresh one for
+    /// every fault, comparing its output with the first run. Faults that
+    /// flip bits outside RAM are rejected before anything runs.
+    pub fn run(&self, machine: impl Fn() -> Machine) -> Result<CampaignReport, CampaignError> {
+        let mut golden = machine();
+        for fault in &self.faults {
+            if let Effect::Flip {
+                target: Target::Ram(addr),
+                ..
+            } = fault.effect
+            {
+                if golden.bus.ram.read::<u8>(addr).is_none() {
+                    return Err(CampaignError::NotRam {
+                        name: fault.name.clone(),
+                        addr,
+                    });
+                }
+            }
+        }
+        match self.execute(&mut golden, None) {
+            (Outcome::Masked, _) => {}
+            (outcome, _) => return Err(CampaignError::Golden(outcome)),
+        }
+        let expected = self.output(&golden);
+        let runs = self
+            .faults
+            .iter()
+            .map(|fault| {
+                let mut machine = machine();
+                let (mut outcome, injected) = self.execute(&mut machine, Some(fault));
+                if outcome == Outcome::Masked && self.output(&machine) != expected {
+                    outcome = Outcome::SilentCorruption;
+                }
+                RunReport {
+                    name: fault.name.clone(),
+                    injected,
+                    outcome,
+                    icount: machine.icount(),
+                }
+            })
+            .collect();
+        Ok(CampaignReport { runs })
+    }
+
+    /// Run one instruction at a time until a hart is about to execute one of
+    /// the campaign's pcs, injecting `fault` when it triggers. A run that
+    /// finished is `Masked` until its output is compared. Also returns
+    /// whether the fault was injected.
+    fn execute(&self, machine: &mut Machine, fault: Option<&Fault>) -> (Outcome, bool) {
+        let mut pending = fault;
+        let mut injected = false;
+        loop {
+            if let Some(fault) = pending.filter(|fault| fault.triggered(machine)) {
+                injected = fault.inject(machine);
+                pending = None;
+            }
+            let pc = machine.harts[machine.current()].pc();
+            if self.end.contains(&pc) {
+                return (Outcome::Masked, injected);
+            }
+            if self.detect.contains(&pc) {
+                return (Outcome::Detected, injected);
+            }
+            if self.crash.contains(&pc)
+                || machine.icount() >= self.limit
+                || !matches!(machine.state, State::RUNNING)
+            {
+                return (Outcome::Crashed, injected);
+            }
+            machine.run(1);
+        }
+    }
+
+    fn output(&self, machine: &Machine) -> Vec<Option<Vec<u8>>> {
+        self.output
+            .iter()
+            .map(|&(addr, len)| machine.bus.ram.bytes(addr, len).map(Cow::into_owned))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::machine::MachineConfig;
+    use crate::device::io::map::IOMap;
+
+    const SENSOR_BASE: usize = 0x9000_0000;
+
+    /// Read the sensor, double the reading twice and store it if both agree.
+    fn machine() -> Machine {
+        // ld t0,0(a0); add t1,t0,t0; add t3,t0,t0; auipc t2,1;
+        // bne t1,t3,detect; sd t1,-12(t2); end: j end; detect: j detect
+        let code: [u32; 8] = [
+            0x00053283, 0x00528333, 0x00528e33, 0x00001397, 0x01c31663, 0xfe63ba23, 0x0000006f,
+            0x0000006f,
+        ];
+        let mut machine = Machine::new(MachineConfig {
+            pmp_entries: 0,
+            ..MachineConfig::default()
+        });
+        let image: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        machine.bus.ram.load(0x8000_0000, &image).unwrap();
+        let mut sensor = IOMap::scratch("sensor", SENSOR_BASE, 8);
+        sensor.space.copy_from_slice(&21u64.to_le_bytes());
+        machine.add_device(sensor);
+        machine.harts[0].set_reg(10, SENSOR_BASE as u64);
+        machine
+    }
+
+    const CAMPAIGN: &str = "
+        # the sensor program, trapping to 0
+        limit 1000
+        end 0x80000018
+        detect 0x8000001c
+        crash 0x0
+        output 0x80001000 8
+
+        fault checked at pc 0x80000010 flip t1 bit 0
+        fault unchecked at pc 0x80000014 flip x6 bit 0
+        fault dead at pc 0x80000014 flip t3 bit 1
+        fault pointer at pc 0x80000014 flip t2 bit 40
+        fault code at icount 0 flip ram 0x80000010 bit 3   # an illegal bne
+        fault scratch at icount 2 hart 0 flip csr 0x340 bit 0
+        fault hartid at icount 0 flip csr 0xf14 bit 0
+        fault never at pc 0x80000100 flip t1 bit 0
+        fault stuck at icount 0 mmio 0x90000000 stuck 22
+        fault broken at icount 0 mmio 0x90000000 error
+    ";
+
+    #[test]
+    fn campaign() {
+        let campaign = Campaign::parse(CAMPAIGN).unwrap();
+        assert_eq!(
+            campaign.faults[5],
+            Fault {
+                name: "scratch".into(),
+                trigger: Trigger::Icount(2),
+                hart: 0,
+                effect: Effect::Flip {
+                    target: Target::Csr(0x340),
+                    bit: 0
+                },
+            }
+        );
+        let report = campaign.run(machine).unwrap();
+        let runs: Vec<_> = report
+            .runs
+            .iter()
+            .map(|run| (run.name.as_str(), run.injected, run.outcome))
+            .collect();
+        assert_eq!(
+            runs,
+            [
+                ("checked", true, Outcome::Detected),
+                ("unchecked", true, Outcome::SilentCorruption),
+                ("dead", true, Outcome::Masked),
+                ("pointer", true, Outcome::Crashed),
+                ("code", true, Outcome::Crashed),
+                ("scratch", true, Outcome::Masked),
+                ("hartid", false, Outcome::Masked),
+                ("never", false, Outcome::Masked),
+                ("stuck", true, Outcome::SilentCorruption),
+                ("broken", true, Outcome::Crashed),
+            ]
+        );
+        assert_eq!(report.count(Outcome::Masked), 4);
+        assert_eq!(report.runs[0].icount, 5);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| Campaign::parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("end 0x10\nfault a at pc 0x10 flip y5 bit 0"),
+            "line 2: unknown register `y5`"
+        );
+        assert_eq!(
+            error("end 0x10\n\nfault a at pc 0x10 flip ram 0x10 bit 8"),
+            "line 3: bit 8 is out of range"
+        );
+        assert_eq!(error("end 0x10 0x20"), "line 1: unexpected `0x20`");
+        assert_eq!(
+            error("fault a at step 3 mmio 0x10 error"),
+            "line 1: expected `icount` or `pc`, found `step`"
+        );
+        assert_eq!(error("limit 10"), "the campaign has no end pc");
+        assert!(matches!(
+            Campaign::parse("end 0x10").unwrap().run(machine),
+            Err(CampaignError::Golden(Outcome::Crashed))
+        ));
+        let campaign = Campaign::parse("end 0x10\nfault a at icount 0 flip ram 0x90000000 bit 0");
+        assert_eq!(
+            campaign.unwrap().run(machine).unwrap_err().to_string(),
+            "fault `a` flips a bit at 0x90000000, which is not RAM"
+        );
+    }
+}
@@ -0,0 +1,768 @@
+use crate::device::clint::{
+    self, new_clint_map, CLINT_BASE, CLINT_MAX_HARTS, CONFIG_TIMEBASE_FREQ,
+};
+use crate::device::io::map::{fetch_mmio_map, fetch_mmio_map_mut, IOMap};
+use crate::device::rtc::{self, new_rtc_map, RTC_BASE};
+use crate::isa::riscv32::cmo::{CmoHook, CONFIG_CACHE_BLOCK};
+use crate::isa::riscv32::csr::{IRQ_MSIP, IRQ_MTIP};
+use crate::isa::riscv32::decode::Extensions;
+use crate::isa::riscv32::exec::RESERVATION_MASK;
+use crate::isa::riscv32::mmu::{Misaligned, PAGE_SIZE};
+use crate::isa::riscv32::pmp::CONFIG_PMP_ENTRIES;
+use crate::isa::riscv32::reg::CpuState;
+use crate::memory::bus::Bus;
+use crate::memory::cache::{CacheHierarchy, HierarchyConfig};
+use crate::memory::ram::{MemoryConfig, Ram};
+use crate::memory::rom::{boot_rom, Boot};
+use crate::memory::CONFIG_MBASE;
+use crate::runtime::State;
+
+pub const CONFIG_NR_HARTS: usize = 1;
+/// Instructions a hart runs before the next one is scheduled.
+pub const CONFIG_QUANTUM: usize = 1000;
+/// Instructions, summed over all harts, per tick of `mtime`.
+pub const CONFIG_INSNS_PER_TICK: u64 = 1;
+
+/// How harts execute instructions.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Engine {
+    /// fetch, decode and execute one instruction at a time
+    Interpreter,
+    /// run basic blocks of pre-decoded handlers
+    #[default]
+    Threaded,
+    /// like `Threaded`, but hot blocks are compiled to native code
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+#[derive(Clone, Debug)]
+pub struct MachineConfig {
+    pub harts: usize,
+    pub quantum: usize,
+    pub pmp_entries: usize,
+    pub engine: Engine,
+    pub insns_per_tick: u64,
+    pub misaligned: Misaligned,
+    /// the optional extensions of every hart
+    pub extensions: Extensions,
+    /// bytes per cache block, for Zicbom and Zicboz
+    pub cache_block: u64,
+    /// called on every cache-block operation
+    pub cmo_hook: Option<CmoHook>,
+    /// where guest RAM starts, its size and what backs it
+    pub memory: MemoryConfig,
+    /// the pc of every hart out of reset
+    pub reset_pc: u64,
+    /// map a boot ROM at `reset_pc` that jumps to the firmware
+    pub boot: Option<Boot>,
+    /// model caches for a performance study. Harts then run the interpreter
+    /// without the decode cache, so that the model sees every fetch.
+    pub caches: Option<HierarchyConfig>,
+    /// stop in `State::STOP` once this many instructions were executed
+    pub icount_limit: Option<u64>,
+    /// record every instruction in `Machine::trace`
+    pub trace: bool,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            harts: CONFIG_NR_HARTS,
+            quantum: CONFIG_QUANTUM,
+            pmp_entries: CONFIG_PMP_ENTRIES,
+            engine: Engine::default(),
+            insns_per_tick: CONFIG_INSNS_PER_TICK,
+            misaligned: Misaligned::default(),
+            extensions: Extensions::default(),
+            cache_block: CONFIG_CACHE_BLOCK,
+            cmo_hook: None,
+            memory: MemoryConfig::default(),
+            reset_pc: CONFIG_MBASE,
+            boot: None,
+            caches: None,
+            icount_limit: None,
+            trace: false,
+        }
+    }
+}
+
+/// An instruction about to be executed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TraceEntry {
+    pub icount: u64,
+    pub hart: usize,
+    pub pc: u64,
+}
+
+/// A set of harts sharing RAM and the devices, scheduled round-robin so that runs are
+/// deterministic. Virtual time only advances with the instruction count.
+pub struct Machine {
+    pub harts: Vec<CpuState>,
+    pub bus: Bus,
+    pub state: State,
+    pub trace: Option<Vec<TraceEntry>>,
+    engine: Engine,
+    quantum: usize,
+    insns_per_tick: u64,
+    icount: u64,
+    icount_limit: Option<u64>,
+    /// the hart currently scheduled
+    current: usize,
+    /// instructions left in the current hart's slice
+    remaining: usize,
+}
+
+impl Machine {
+    pub fn new(config: MachineConfig) -> Self {
+        assert!(config.harts > 0 && config.quantum > 0 && config.insns_per_tick > 0);
+        assert!(
+            config.harts <= CLINT_MAX_HARTS,
+            "the clint has room for {} harts",
+            CLINT_MAX_HARTS
+        );
+        assert!(
+            config.cache_block.is_power_of_two() && (8..=PAGE_SIZE).contains(&config.cache_block)
+        );
+        let engine = match config.caches {
+            Some(_) => Engine::Interpreter,
+            None => config.engine,
+        };
+        let harts = (0..config.harts)
+            .map(|id| {
+                let mut cpu = CpuState::new(config.pmp_entries);
+                cpu.csr.mhartid = id as u64;
+                cpu.set_pc(config.reset_pc);
+                cpu.csr.counters.insns_per_tick = config.insns_per_tick;
+                cpu.misaligned = config.misaligned;
+                cpu.extensions = config.extensions;
+                cpu.cache_block = config.cache_block;
+                cpu.cmo_hook = config.cmo_hook;
+                if let Some(caches) = &config.caches {
+                    cpu.caches = Some(Box::new(CacheHierarchy::new(caches)));
+                    cpu.icache.enabled = false;
+                }
+                #[cfg(feature = "jit")]
+                {
+                    cpu.blocks.jit.enabled = engine == Engine::Jit;
+                }
+                cpu
+            })
+            .collect();
+        let mut bus = Bus::new(
+            Ram::from_config(&config.memory)
+                .unwrap_or_else(|e| panic!("cannot allocate guest RAM: {e}")),
+            vec![new_clint_map(), new_rtc_map()],
+        );
+        if let Some(boot) = config.boot {
+            bus.add_rom(boot_rom(config.reset_pc, boot));
+        }
+        Self {
+            harts,
+            bus,
+            state: State::RUNNING,
+            trace: config.trace.then(Vec::new),
+            engine,
+            quantum: config.quantum,
+            insns_per_tick: config.insns_per_tick,
+            icount: 0,
+            icount_limit: config.icount_limit,
+            current: 0,
+            remaining: config.quantum,
+        }
+    }
+
+    pub fn add_device(&mut self, map: IOMap) {
+        self.bus.add_device(map);
+    }
+
+    /// Print the statistics of every hart's caches, if they are modelled.
+    pub fn report_caches(&self) {
+        for (id, cpu) in self.harts.iter().enumerate() {
+            if let Some(caches) = &cpu.caches {
+                println!("hart {id}");
+                caches.report();
+            }
+        }
+    }
+
+    /// The hart that executes the next instruction.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Leave the debug monitor, harts halted by a trigger carry on from the
+    /// instruction that fired it.
+    pub fn resume(&mut self) {
+        for cpu in &mut self.harts {
+            cpu.halted = false;
+        }
+        self.state = State::RUNNING;
+    }
+
+    /// Instructions executed by all harts so far.
+    pub fn icount(&self) -> u64 {
+        self.icount
+    }
+
+    /// Execute `n` instructions in total, switching harts every quantum. Stops
+    /// early in `State::STOP` when the instruction limit is reached.
+    pub fn run(&mut self, mut n: usize) {
+        while n > 0 && matches!(self.state, State::RUNNING) {
+            if let Some(limit) = self.icount_limit {
+                if self.icount >= limit {
+                    self.state = State::STOP;
+                    break;
+                }
+                n = n.min((limit - self.icount).min(usize::MAX as u64) as usize);
+            }
+            n -= self.run_slice(n);
+        }
+        if self.icount_limit == Some(self.icount) {
+            self.state = State::STOP;
+        }
+    }
+
+    /// Run the current hart for at most `n` instructions and return how many
+    /// were executed. Both engines take interrupts at the same instructions.
+    fn run_slice(&mut self, n: usize) -> usize {
+        let hart = self.current;
+        let mut budget = n.min(self.remaining);
+        if let Some(trace) = &mut self.trace {
+            budget = 1;
+            trace.push(TraceEntry {
+                icount: self.icount,
+                hart,
+                pc: self.harts[hart].pc(),
+            });
+        }
+        let executed = match self.engine {
+            Engine::Interpreter => {
+                self.advance_icount(1);
+                self.sync_clint(hart);
+                self.harts[hart].exec_once(&mut self.bus);
+                1
+            }
+            // the threaded engine, with or without native code
+            _ => {
+                // stop right before the instruction that sees the timer fire
+                let budget = budget.min(self.ticks_to_timer(hart));
+                self.advance_icount(1);
+                self.sync_clint(hart);
+                let executed = self.harts[hart].exec_blocks(&mut self.bus, budget);
+                self.advance_icount(executed as u64 - 1);
+                executed
+            }
+        };
+        self.snoop_stores(hart);
+        if self.harts[hart].halted {
+            self.state = State::STOP;
+        }
+
+        self.remaining -= executed;
+        // a hart stalled in wrs lets the others get on with writing its reservation set
+        if self.remaining == 0 || self.harts[hart].wrs.is_some() {
+            self.current = (self.current + 1) % self.harts.len();
+            self.remaining = self.quantum;
+        }
+        executed
+    }
+
+    /// Instructions `hart` can run before `mtime` reaches its `mtimecmp` or a
+    /// Sstc comparator, unbounded if those timer interrupts are pending already.
+    fn ticks_to_timer(&self, hart: usize) -> usize {
+        let Some(map) = fetch_mmio_map(&self.bus.devices, CLINT_BASE) else {
+            return usize::MAX;
+        };
+        let time = clint::mtime(map);
+        let sstc = self.harts[hart].csr.sstc_deadline();
+        let Some(cmp) = [Some(clint::mtimecmp(map, hart)), sstc]
+            .into_iter()
+            .flatten()
+            .filter(|&cmp| cmp > time)
+            .min()
+        else {
+            return usize::MAX;
+        };
+        // the instruction that completes tick number `cmp - time` from now
+        let rate = self.insns_per_tick;
+        let fire = (self.icount / rate)
+            .saturating_add(cmp - time)
+            .saturating_mul(rate)
+            - self.icount;
+        if fire <= 1 {
+            usize::MAX
+        } else {
+            (fire - 1).min(usize::MAX as u64) as usize
+        }
+    }
+
+    /// Count `instructions` more instructions and advance `mtime` and the RTC
+    /// by the ticks they complete.
+    fn advance_icount(&mut self, instructions: u64) {
+        let rate = self.insns_per_tick;
+        let ticks = (self.icount + instructions) / rate - self.icount / rate;
+        self.icount += instructions;
+        if let Some(map) = fetch_mmio_map_mut(&mut self.bus.devices, CLINT_BASE) {
+            clint::advance_mtime(map, ticks);
+        }
+        if let Some(map) = fetch_mmio_map_mut(&mut self.bus.devices, RTC_BASE) {
+            let us = self.icount / rate / (CONFIG_TIMEBASE_FREQ / 1_000_000);
+            rtc::set_uptime(map, us);
+        }
+    }
+
+    /// Mirror the software and timer interrupts of `hart` into its `mip`.
+    fn sync_clint(&mut self, hart: usize) {
+        let Some(map) = fetch_mmio_map(&self.bus.devices, CLINT_BASE) else {
+            return;
+        };
+        let time = clint::mtime(map);
+        let mut mip = self.harts[hart].csr.mip & !(IRQ_MSIP | IRQ_MTIP);
+        if clint::msip(map, hart) {
+            mip |= IRQ_MSIP;
+        }
+        if time >= clint::mtimecmp(map, hart) {
+            mip |= IRQ_MTIP;
+        }
+        let cpu = &mut self.harts[hart];
+        cpu.csr.mip = mip;
+        cpu.csr.counters.time = time;
+        cpu.csr.counters.tick_phase = self.icount % self.insns_per_tick;
+    }
+
+    /// A store by `hart` invalidates the reservations other harts hold on the
+    /// same doubleword and the instructions they decoded from the same page.
+    fn snoop_stores(&mut self, hart: usize) {
+        let stores = std::mem::take(&mut self.harts[hart].stores);
+        for paddr in &stores {
+            let granule = paddr & RESERVATION_MASK;
+            for (id, other) in self.harts.iter_mut().enumerate() {
+                if id == hart {
+                    continue;
+                }
+                if other.reservation == Some(granule) {
+                    other.reservation = None;
+                }
+                other.invalidate_decoded(*paddr);
+            }
+        }
+        self.harts[hart].stores = stores;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::riscv32::csr::{
+        Privilege, IRQ_STIP, MCAUSE, MENVCFG_STCE, MEPC, MSTATUS_MIE, MTVAL, MTVEC, TDATA1, TDATA2,
+    };
+    use crate::memory::bus::BusError;
+    use crate::memory::rom::CONFIG_BOOT_ROM_BASE;
+
+    const CODE_BASE: u64 = 0x8000_0000;
+    const DATA_BASE: usize = 0x9000_0000;
+
+    fn load(machine: &mut Machine, paddr: u64, code: &[u32]) {
+        let image: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        machine.bus.ram.load(paddr, &image).unwrap();
+    }
+
+    fn machine(harts: usize, quantum: usize, code: &[u32]) -> Machine {
+        machine_with(
+            MachineConfig {
+                harts,
+                quantum,
+                pmp_entries: 0,
+                ..MachineConfig::default()
+            },
+            code,
+        )
+    }
+
+    fn machine_with(config: MachineConfig, code: &[u32]) -> Machine {
+        let mut machine = Machine::new(config);
+        load(&mut machine, CODE_BASE, code);
+        machine.add_device(IOMap::scratch("data", DATA_BASE, 0x1000));
+        for cpu in &mut machine.harts {
+            cpu.set_pc(CODE_BASE);
+            cpu.set_reg(10, DATA_BASE as u64);
+            cpu.set_reg(11, DATA_BASE as u64 + 8);
+        }
+        machine
+    }
+
+    #[test]
+    fn boot() {
+        let boot = Boot {
+            entry: CODE_BASE + 0x100,
+            dtb: CODE_BASE + 0x1_0000,
+        };
+        let mut machine = Machine::new(MachineConfig {
+            harts: 2,
+            quantum: 5,
+            pmp_entries: 0,
+            reset_pc: CONFIG_BOOT_ROM_BASE,
+            boot: Some(boot),
+            ..MachineConfig::default()
+        });
+        machine.run(10);
+        for (id, cpu) in machine.harts.iter().enumerate() {
+            assert_eq!(cpu.pc(), boot.entry);
+            assert_eq!((cpu.reg(10), cpu.reg(11)), (id as u64, boot.dtb));
+        }
+        assert_eq!(
+            machine.bus.write(CONFIG_BOOT_ROM_BASE, 0u32),
+            Err(BusError::ReadOnly(CONFIG_BOOT_ROM_BASE))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "room for 4095 harts")]
+    fn too_many_harts() {
+        Machine::new(MachineConfig {
+            harts: CLINT_MAX_HARTS + 1,
+            ..MachineConfig::default()
+        });
+    }
+
+    #[test]
+    fn caches() {
+        // addi x1,x1,1; sd x1,0(x10); addi x10,x10,64; j .-12
+        let code = [0x00108093, 0x00153023, 0x04050513, 0xff5ff06f];
+        let config = MachineConfig {
+            pmp_entries: 0,
+            caches: Some(HierarchyConfig::default()),
+            ..MachineConfig::default()
+        };
+        let mut machine = machine_with(config, &code);
+        machine.harts[0].set_reg(10, CODE_BASE + 0x1000);
+        machine.run(400);
+        let caches = machine.harts[0].caches.as_ref().unwrap();
+        // one miss for the loop, then every store misses on a new line
+        assert_eq!(
+            (caches.l1i.stats.reads, caches.l1i.stats.read_misses),
+            (400, 1)
+        );
+        assert_eq!(
+            (caches.l1d.stats.writes, caches.l1d.stats.write_misses),
+            (100, 100)
+        );
+        let l2 = caches.l2.as_ref().unwrap().stats;
+        assert_eq!((l2.reads, l2.read_misses), (101, 101));
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut machine = machine(2, 3, &[0x00108093; 8]); // addi x1,x1,1
+        assert_eq!(machine.harts[1].csr.mhartid, 1);
+        machine.run(5);
+        assert_eq!(machine.harts[0].reg(1), 3);
+        assert_eq!(machine.harts[1].reg(1), 2);
+        assert_eq!(machine.current(), 1);
+        machine.run(1);
+        assert_eq!(machine.harts[1].reg(1), 3);
+        assert_eq!(machine.current(), 0);
+        assert_eq!(machine.harts[0].csr.counters.time, 3);
+    }
+
+    #[test]
+    fn ipi() {
+        let mut machine = machine(2, 3, &[0x020002b7, 0x00100313, 0x0062a223]);
+        let target = &mut machine.harts[1];
+        target
+            .csr
+            .write(MTVEC, 0x8000_0100, Privilege::Machine)
+            .unwrap();
+        target.csr.mie = IRQ_MSIP;
+        target.csr.mstatus |= MSTATUS_MIE;
+        machine.run(4);
+        let target = &machine.harts[1];
+        assert_eq!(target.pc(), 0x8000_0100);
+        assert_eq!(target.csr.mcause, 1 << 63 | 3);
+        assert_eq!(target.csr.mip & IRQ_MSIP, IRQ_MSIP);
+        assert_eq!(machine.harts[0].csr.mip & IRQ_MSIP, 0);
+    }
+
+    #[test]
+    fn reservation() {
+        // hart 0: lr.d x1,(x10); sc.d x2,x0,(x10); lr.d x1,(x10); sc.d x2,x0,(x10)
+        // hart 1: sd x0,0(x11); nop; sd x0,0(x10)
+        let mut machine = machine(2, 1, &[0x100530af, 0x1805312f, 0x100530af, 0x1805312f]);
+        machine.harts[1].set_pc(CODE_BASE + 0x10);
+        load(
+            &mut machine,
+            CODE_BASE + 0x10,
+            &[0x0005b023, 0x00000013, 0x00053023],
+        );
+        machine.harts[0].set_reg(2, 7);
+
+        // a store to another doubleword leaves the reservation alone
+        machine.run(3);
+        assert_eq!(machine.harts[0].reg(2), 0);
+        // a store by another hart to the reserved doubleword breaks it
+        machine.run(4);
+        assert_eq!(machine.harts[0].reg(2), 1);
+        assert_eq!(machine.harts[0].reservation, None);
+    }
+
+    #[test]
+    fn wrs_yields() {
+        // hart 0: lr.d x6,(x10); wrs.nto; addi x7,x7,1; j .
+        let mut code = vec![0x1005332f, 0x00d00073, 0x00138393, 0x0000006f];
+        // hart 1: addi x8,x8,1 (20 times); sd x8,0(x10); j .
+        code.extend([0x00140413; 20]);
+        code.extend([0x00853023, 0x0000006f]);
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let config = MachineConfig {
+                harts: 2,
+                quantum: 100,
+                engine,
+                extensions: Extensions {
+                    zawrs: true,
+                    ..Extensions::default()
+                },
+                ..MachineConfig::default()
+            };
+            let mut machine = machine_with(config, &code);
+            machine.harts[1].set_pc(CODE_BASE + 0x10);
+            // hart 0 stalls right away and hands over to hart 1
+            machine.run(2);
+            assert_eq!(machine.current(), 1);
+            assert_eq!(machine.harts[0].wrs, Some(0));
+            // which breaks the reservation, waking hart 0 once it runs again
+            machine.run(100);
+            assert_eq!(machine.current(), 0);
+            machine.run(2);
+            assert_eq!(machine.harts[0].wrs, None);
+            assert_eq!(machine.harts[0].reg(7), 1);
+        }
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            // sw x5,0(x10); nop; j .-8, entered at the nop
+            let config = MachineConfig {
+                harts: 1,
+                quantum: 100,
+                pmp_entries: 0,
+                engine,
+                ..MachineConfig::default()
+            };
+            let mut machine =
+                machine_with(config, &[0x00000013, 0x00552023, 0x00000013, 0xff9ff06f]);
+            let cpu = &mut machine.harts[0];
+            cpu.set_pc(CODE_BASE + 8);
+            cpu.set_reg(10, CODE_BASE + 8);
+            cpu.set_reg(5, 0x00108093); // addi x1,x1,1
+            machine.run(4);
+            let cpu = &machine.harts[0];
+            assert_eq!(cpu.reg(1), 1);
+            assert!(cpu.icache.stats.invalidations + cpu.blocks.stats.invalidations > 0);
+        }
+    }
+
+    #[test]
+    fn engines_agree() {
+        // lui t0,0x2004; addi x1,x1,1; xor x2,x2,x1; rdtime x6; j .-12
+        let mut code = vec![0x020042b7, 0x00108093, 0x00114133, 0xc0102373, 0xff5ff06f];
+        code.resize(0x40, 0);
+        // timer handler: addi x5,x5,1; ld t1,0(t0); addi t1,t1,50; sd t1,0(t0); mret
+        code.extend([0x00128293, 0x0002b303, 0x03230313, 0x0062b023, 0x30200073]);
+        let run = |engine, insns_per_tick, trace| {
+            let config = MachineConfig {
+                harts: 2,
+                quantum: 7,
+                pmp_entries: 0,
+                engine,
+                insns_per_tick,
+                trace,
+                ..MachineConfig::default()
+            };
+            let mut machine = machine_with(config, &code);
+            let cpu = &mut machine.harts[0];
+            cpu.csr
+                .write(MTVEC, 0x8000_0100, Privilege::Machine)
+                .unwrap();
+            cpu.csr.mie = IRQ_MTIP;
+            cpu.csr.mstatus |= MSTATUS_MIE;
+            let clint = fetch_mmio_map_mut(&mut machine.bus.devices, CLINT_BASE).unwrap();
+            clint.space[0x4000..0x4008].copy_from_slice(&30u64.to_le_bytes());
+            machine.run(1000);
+            machine
+        };
+        for rate in [1, 3] {
+            let a = run(Engine::Interpreter, rate, true);
+            let b = run(Engine::Threaded, rate, false);
+            assert!(a.harts[0].reg(5) > 5);
+            // traces are reproducible, and the same whatever the engine
+            let trace = a.trace.as_ref().unwrap();
+            assert_eq!(trace.len(), 1000);
+            assert_eq!(&run(Engine::Interpreter, rate, true).trace.unwrap(), trace);
+            assert_eq!(&run(Engine::Threaded, rate, true).trace.unwrap(), trace);
+            for (x, y) in a.harts.iter().zip(&b.harts) {
+                assert_eq!(x.pc(), y.pc());
+                assert_eq!(
+                    (0..32).map(|i| x.reg(i)).collect::<Vec<_>>(),
+                    (0..32).map(|i| y.reg(i)).collect::<Vec<_>>()
+                );
+                assert_eq!(x.csr.mepc, y.csr.mepc);
+                assert_eq!(x.csr.counters.instret, y.csr.counters.instret);
+                assert_eq!(x.csr.counters.time, y.csr.counters.time);
+            }
+            for (x, y) in a.bus.devices.iter().zip(&b.bus.devices) {
+                assert_eq!(x.space, y.space);
+            }
+        }
+    }
+
+    #[test]
+    fn sstc_engines_agree() {
+        // addi x1,x1,1; xor x2,x2,x1; j .-8
+        let mut code = vec![0x00108093, 0x00114133, 0xff9ff06f];
+        code.resize(0x40, 0);
+        // handler: addi x5,x5,1; rdtime t1; addi t1,t1,50; csrw stimecmp,t1; mret
+        code.extend([0x00128293, 0xc0102373, 0x03230313, 0x14d31073, 0x30200073]);
+        let run = |engine| {
+            let config = MachineConfig {
+                engine,
+                insns_per_tick: 3,
+                ..MachineConfig::default()
+            };
+            let mut machine = machine_with(config, &code);
+            let cpu = &mut machine.harts[0];
+            cpu.csr
+                .write(MTVEC, 0x8000_0100, Privilege::Machine)
+                .unwrap();
+            cpu.csr.menvcfg = MENVCFG_STCE;
+            cpu.csr.stimecmp = 30;
+            cpu.csr.mie = IRQ_STIP;
+            cpu.csr.mstatus |= MSTATUS_MIE;
+            machine.run(1000);
+            machine
+        };
+        let (a, b) = (run(Engine::Interpreter), run(Engine::Threaded));
+        let (x, y) = (&a.harts[0], &b.harts[0]);
+        assert!(x.reg(5) > 5);
+        assert_eq!(x.pc(), y.pc());
+        assert_eq!((x.reg(1), x.reg(5)), (y.reg(1), y.reg(5)));
+        assert_eq!(x.csr.mepc, y.csr.mepc);
+    }
+
+    #[test]
+    #[cfg(feature = "hypervisor")]
+    fn virtual_instruction_tval() {
+        // rdtime x6, which hcounteren keeps from the guest
+        const RDTIME: u32 = 0xc0102373;
+        let mut code = vec![RDTIME];
+        code.resize(0x40, 0);
+        code.push(0x0000006f); // j .
+        let run = |engine, medeleg| {
+            let config = MachineConfig {
+                pmp_entries: 0,
+                engine,
+                ..MachineConfig::default()
+            };
+            let mut machine = machine_with(config, &code);
+            let cpu = &mut machine.harts[0];
+            cpu.csr.mtvec = 0x8000_0100;
+            cpu.csr.stvec = 0x8000_0100;
+            cpu.csr.medeleg = medeleg;
+            cpu.csr.counters.mcounteren = 0b111;
+            cpu.virt = true;
+            cpu.mode = Privilege::Supervisor;
+            machine.run(3);
+            machine
+        };
+        for medeleg in [0, 1 << 22] {
+            let a = run(Engine::Interpreter, medeleg);
+            let b = run(Engine::Threaded, medeleg);
+            let (x, y) = (&a.harts[0].csr, &b.harts[0].csr);
+            let tval = if medeleg == 0 { x.mtval } else { x.stval };
+            assert_eq!(tval, RDTIME as u64);
+            assert_eq!((x.mtval, x.stval), (y.mtval, y.stval));
+            assert_eq!((x.mcause, x.scause), (y.mcause, y.scause));
+        }
+    }
+
+    #[test]
+    fn icount_limit() {
+        let config = MachineConfig {
+            harts: 2,
+            quantum: 3,
+            icount_limit: Some(10),
+            ..MachineConfig::default()
+        };
+        let mut machine = machine_with(config, &[0x00108093; 8]); // addi x1,x1,1
+        machine.run(4);
+        assert!(matches!(machine.state, State::RUNNING));
+        machine.run(usize::MAX);
+        assert!(matches!(machine.state, State::STOP));
+        assert_eq!(machine.icount(), 10);
+        assert_eq!(machine.harts[0].reg(1) + machine.harts[1].reg(1), 10);
+        machine.run(1);
+        assert_eq!(machine.icount(), 10);
+    }
+
+    #[test]
+    fn virtual_time() {
+        let config = MachineConfig {
+            insns_per_tick: 4,
+            ..MachineConfig::default()
+        };
+        let mut machine = machine_with(config, &[0x0000006f]); // j .
+        machine.run(403);
+        assert_eq!(machine.harts[0].csr.counters.time, 100);
+        let clint = fetch_mmio_map(&machine.bus.devices, CLINT_BASE).unwrap();
+        assert_eq!(clint::mtime(clint), 100);
+        let rtc = fetch_mmio_map(&machine.bus.devices, RTC_BASE).unwrap();
+        assert_eq!(rtc::uptime(rtc), 100 * 1_000_000 / CONFIG_TIMEBASE_FREQ);
+    }
+
+    #[test]
+    fn triggers() {
+        // addi x1,x1,1; addi x1,x1,1; sd x1,0(x10); addi x1,x1,1; j .
+        let code = [0x00108093, 0x00108093, 0x00153023, 0x00108093, 0x0000006f];
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let config = MachineConfig {
+                engine,
+                ..MachineConfig::default()
+            };
+            let mut machine = machine_with(config, &code);
+            let cpu = &mut machine.harts[0];
+            let csr = &mut cpu.csr;
+            csr.write(MTVEC, 0x8000_0100, Privilege::Machine).unwrap();
+            csr.mstatus |= MSTATUS_MIE;
+            // mcontrol6: enter the debug monitor on executing the store
+            let execute = 6 << 60 | 1 << 12 | 1 << 6 | 1 << 2;
+            csr.write(TDATA1, execute, Privilege::Machine).unwrap();
+            csr.write(TDATA2, 0x8000_0008, Privilege::Machine).unwrap();
+
+            machine.run(100);
+            assert!(matches!(machine.state, State::STOP));
+            let cpu = &mut machine.harts[0];
+            assert!(cpu.halted);
+            assert_eq!((cpu.pc(), cpu.reg(1)), (0x8000_0008, 2));
+
+            // mcontrol: a breakpoint exception on storing to the data map
+            let store = 2 << 60 | 1 << 6 | 1 << 1;
+            let csr = &mut cpu.csr;
+            csr.write(TDATA1, store, Privilege::Machine).unwrap();
+            csr.write(TDATA2, DATA_BASE as u64, Privilege::Machine)
+                .unwrap();
+            machine.resume();
+            machine.run(1);
+            let cpu = &machine.harts[0];
+            assert_eq!(cpu.csr.read(MCAUSE, Privilege::Machine), Ok(3));
+            assert_eq!(cpu.csr.read(MEPC, Privilege::Machine), Ok(0x8000_0008));
+            assert_eq!(
+                cpu.csr.read(MTVAL, Privilege::Machine),
+                Ok(DATA_BASE as u64)
+            );
+            assert_eq!(cpu.pc(), 0x8000_0100);
+            let data = fetch_mmio_map(&machine.bus.devices, DATA_BASE).unwrap();
+            assert_eq!(data.space[0], 0);
+        }
+    }
+}
@@ -1,4 +1,8 @@
 //! re export some arch related structs/functions
 
+pub mod bench;
 pub mod decode;
-pub mod reg;
\ No newline at end of file
+pub mod difftest;
+pub mod fault;
+pub mod machine;
+pub mod reg;
@@ -0,0 +1,53 @@
+use super::io::map::IOMap;
+
+pub const CLINT_BASE: usize = 0x0200_0000;
+pub const CLINT_SIZE: usize = 0x1_0000;
+/// Frequency of `mtime` in virtual time.
+pub const CONFIG_TIMEBASE_FREQ: u64 = 10_000_000;
+
+const MSIP_OFFSET: usize = 0;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xbff8;
+/// Harts that get an `mtimecmp` before the layout runs into `mtime`.
+pub const CLINT_MAX_HARTS: usize = (MTIME_OFFSET - MTIMECMP_OFFSET) / 8;
+
+/// The registers live in the map space, the machine polls them after every step.
+pub fn clint_io_handler(_offset: u32, _len: i32, _is_write: bool) {}
+
+// [0x0200_0000, 0x0200_ffff] is the core local interruptor
+pub fn new_clint_map() -> IOMap {
+    let mut space = vec![0; CLINT_SIZE];
+    space[MTIMECMP_OFFSET..MTIME_OFFSET].fill(0xff);
+    IOMap::new(
+        "clint".into(),
+        CLINT_BASE,
+        CLINT_BASE + CLINT_SIZE - 1,
+        space,
+        clint_io_handler,
+    )
+}
+
+#[inline]
+fn read_u64(space: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(space[offset..offset + 8].try_into().unwrap())
+}
+
+/// Whether the software interrupt of `hart` is raised.
+pub fn msip(clint: &IOMap, hart: usize) -> bool {
+    clint.space[MSIP_OFFSET + 4 * hart] & 1 != 0
+}
+
+pub fn mtimecmp(clint: &IOMap, hart: usize) -> u64 {
+    read_u64(&clint.space, MTIMECMP_OFFSET + 8 * hart)
+}
+
+pub fn mtime(clint: &IOMap) -> u64 {
+    read_u64(&clint.space, MTIME_OFFSET)
+}
+
+/// Advance `mtime` by `ticks` and return the new value.
+pub fn advance_mtime(clint: &mut IOMap, ticks: u64) -> u64 {
+    let time = mtime(clint).wrapping_add(ticks);
+    clint.space[MTIME_OFFSET..MTIME_OFFSET + 8].copy_from_slice(&time.to_le_bytes());
+    time
+}
@@ -1,4 +1,4 @@
-use crate::{memory::{host_read, host_write}, device::io::port_io::PORT_IO_SPACE_MAX};
+use crate::{memory::{host_read, host_write, Word}, device::io::port_io::PORT_IO_SPACE_MAX};
 
 #[derive(Debug, Clone)]
 pub struct IOMap {
@@ -14,6 +14,12 @@ impl IOMap {
     pub fn inside(&self, addr: usize) -> bool {
         addr >= self.low && addr <= self.high
     }
+
+    /// Whether `len` bytes at `addr` are all inside the map.
+    #[inline]
+    pub fn fits(&self, addr: usize, len: usize) -> bool {
+        self.inside(addr) && addr + len - 1 <= self.high && addr - self.low + len <= self.space.len()
+    }
 }
 
 impl IOMap {
@@ -34,46 +40,51 @@ impl IOMap {
     }
 }
 
+#[cfg(test)]
 impl IOMap {
-    pub fn read(&self, addr: *const u8, len: usize) -> i32 {
-        assert!(len >= 1 && len <= 8);
-        let offset = addr as u32 - self.low as u32;
+    /// A device that is nothing but `len` zeroed bytes at `low`.
+    pub fn scratch(name: &str, low: usize, len: usize) -> Self {
+        fn ignore(_offset: u32, _len: i32, _is_write: bool) {}
+        Self::new(name.into(), low, low + len - 1, vec![0; len], ignore)
+    }
+}
+
+impl IOMap {
+    pub fn read<T: Word>(&self, addr: usize) -> T {
+        let offset = addr - self.low;
         let f = self.callback;
-        f(offset, len as i32, false);
-        let addr = (self.space.as_ptr() as u32 + offset) as *const u8;
-        return host_read(addr, len);
+        f(offset as u32, T::SIZE as i32, false);
+        host_read(&self.space[offset..offset + T::SIZE])
     }
-    pub fn write(&self, addr: *const u8, len: usize, data: i32) {
-        assert!(len >= 1 && len <= 8);
-        let offset = addr as u32 - self.low as u32;
+    pub fn write<T: Word>(&mut self, addr: usize, data: T) {
+        let offset = addr - self.low;
         let f = self.callback;
-        let addr = (self.space.as_ptr() as u32 + offset) as *mut u8;
-        host_write(addr, len, data);
-        f(offset, len as i32, true);
+        host_write(&mut self.space[offset..offset + T::SIZE], data);
+        f(offset as u32, T::SIZE as i32, true);
     }
 
     // bus interface
-    pub fn mmio_read(&self, addr: *const u8, len: usize) -> i32 {
-        self.read(addr, len)
+    pub fn mmio_read<T: Word>(&self, addr: usize) -> T {
+        self.read(addr)
     }
 
-    pub fn mmio_write(&self, addr: *const u8, len: usize, data: i32) {
-        self.write(addr, len, data);
+    pub fn mmio_write<T: Word>(&mut self, addr: usize, data: T) {
+        self.write(addr, data);
     }
 
     // device interface
-    pub fn physical_io_read(&self, addr: *const u8, len: usize) -> i32 {
-        assert!(addr  as usize+ len - 1 < PORT_IO_SPACE_MAX);
-        self.read(addr, len)
+    pub fn physical_io_read<T: Word>(&self, addr: usize) -> T {
+        assert!(addr + T::SIZE - 1 < PORT_IO_SPACE_MAX);
+        self.read(addr)
     }
 
-    pub fn physical_io_write(&self, addr: *const u8, len: usize, data: i32) {
-        assert!(addr  as usize+ len - 1 < PORT_IO_SPACE_MAX);
-        self.write(addr, len, data);
+    pub fn physical_io_write<T: Word>(&mut self, addr: usize, data: T) {
+        assert!(addr + T::SIZE - 1 < PORT_IO_SPACE_MAX);
+        self.write(addr, data);
     }
 }
 
-pub fn find_mapid_by_addr(maps: &Vec<IOMap>, addr: usize) -> Option<usize> {
+pub fn find_mapid_by_addr(maps: &[IOMap], addr: usize) -> Option<usize> {
     for (index, map) in maps.iter().enumerate() {
         if map.inside(addr) {
             return Some(index);
@@ -82,6 +93,10 @@ pub fn find_mapid_by_addr(maps: &Vec<IOMap>, addr: usize) -> Option<usize> {
     None
 }
 
-pub fn fetch_mmio_map(maps: &Vec<IOMap>, addr: usize) -> Option<&IOMap> {
+pub fn fetch_mmio_map(maps: &[IOMap], addr: usize) -> Option<&IOMap> {
     find_mapid_by_addr(maps, addr).map(|index| &maps[index])
 }
+
+pub fn fetch_mmio_map_mut(maps: &mut [IOMap], addr: usize) -> Option<&mut IOMap> {
+    find_mapid_by_addr(maps, addr).map(move |index| &mut maps[index])
+}
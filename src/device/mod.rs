@@ -1,3 +1,5 @@
+pub mod clint;
 pub mod io;
 pub mod keyboard;
-pub mod serial;
\ No newline at end of file
+pub mod rtc;
+pub mod serial;
@@ -0,0 +1,27 @@
+use super::io::map::IOMap;
+
+pub const RTC_BASE: usize = 0xa000_0048;
+pub const RTC_SIZE: usize = 8;
+
+/// The uptime lives in the map space, the machine updates it from the
+/// instruction count so that guests never observe host time.
+pub fn rtc_io_handler(_offset: u32, _len: i32, _is_write: bool) {}
+
+// [0xa000_0048, 0xa000_004f] is the uptime in microseconds
+pub fn new_rtc_map() -> IOMap {
+    IOMap::new(
+        "rtc".into(),
+        RTC_BASE,
+        RTC_BASE + RTC_SIZE - 1,
+        vec![0; RTC_SIZE],
+        rtc_io_handler,
+    )
+}
+
+pub fn uptime(rtc: &IOMap) -> u64 {
+    u64::from_le_bytes(rtc.space[..8].try_into().unwrap())
+}
+
+pub fn set_uptime(rtc: &mut IOMap, us: u64) {
+    rtc.space[..8].copy_from_slice(&us.to_le_bytes());
+}
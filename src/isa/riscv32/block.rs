@@ -0,0 +1,543 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+use super::csr::Privilege;
+use super::decode::decode_ext;
+#[cfg(feature = "jit")]
+use super::icache::Decoded;
+use super::instruction::Instruction;
+#[cfg(feature = "jit")]
+use super::jit::{Jit, NativeBlock};
+use super::mmu::{PAGE_SHIFT, PAGE_SIZE};
+use super::reg::CpuState;
+use super::trap::Exception;
+use crate::memory::bus::Bus;
+use crate::utils::sext;
+
+/// Longest basic block, in instructions.
+pub const CONFIG_MAX_BLOCK_INSTS: usize = 64;
+
+/// One pre-decoded instruction. Handlers leave pc at the next instruction on
+/// success and untouched when they raise, so traps stay precise.
+pub type Handler = Box<dyn Fn(&mut CpuState, &mut Bus) -> Result<(), Exception>>;
+
+/// A chain link: the pc of a successor and its block.
+type Link = Option<(u64, Weak<Block>)>;
+
+pub struct Block {
+    pub pc: u64,
+    /// blocks never cross a page, so a single physical page backs them
+    pub ppn: u64,
+    pub mode: Privilege,
+    /// the decoded instructions, compiled again by the JIT once hot
+    #[cfg(feature = "jit")]
+    pub(super) insts: Vec<Decoded>,
+    ops: Vec<Handler>,
+    valid: Cell<bool>,
+    /// successors seen so far, followed without a lookup in the block cache
+    links: RefCell<[Link; 2]>,
+    /// times the block was entered, it gets compiled once hot
+    #[cfg(feature = "jit")]
+    pub(super) heat: Cell<u32>,
+    #[cfg(feature = "jit")]
+    pub(super) native: RefCell<Option<NativeBlock>>,
+}
+
+impl Block {
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn valid(&self) -> bool {
+        self.valid.get()
+    }
+
+    fn successor(&self, pc: u64) -> Option<Rc<Block>> {
+        self.links
+            .borrow()
+            .iter()
+            .flatten()
+            .find(|(target, _)| *target == pc)
+            .and_then(|(_, block)| block.upgrade())
+            .filter(|block| block.valid())
+    }
+
+    fn chain(&self, pc: u64, block: &Rc<Block>) {
+        let mut links = self.links.borrow_mut();
+        let slot = links
+            .iter()
+            .position(|link| matches!(link, Some((target, _)) if *target == pc))
+            .or_else(|| links.iter().position(Option::is_none))
+            .unwrap_or(1);
+        links[slot] = Some((pc, Rc::downgrade(block)));
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlockCacheStats {
+    pub built: u64,
+    /// blocks entered by following a chain link
+    pub chained: u64,
+    /// blocks entered through a lookup in the cache
+    pub dispatched: u64,
+    pub invalidations: u64,
+}
+
+/// Basic blocks by virtual pc, built on demand by [`CpuState::exec_blocks`].
+pub struct BlockCache {
+    blocks: HashMap<u64, Rc<Block>>,
+    ppns: HashSet<u64>,
+    pub stats: BlockCacheStats,
+    #[cfg(feature = "jit")]
+    pub jit: Jit,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            ppns: HashSet::new(),
+            stats: BlockCacheStats::default(),
+            #[cfg(feature = "jit")]
+            jit: Jit::default(),
+        }
+    }
+
+    fn lookup(&self, pc: u64, mode: Privilege) -> Option<Rc<Block>> {
+        self.blocks
+            .get(&pc)
+            .filter(|block| block.mode == mode)
+            .cloned()
+    }
+
+    fn insert(&mut self, block: Rc<Block>) {
+        self.ppns.insert(block.ppn);
+        if let Some(old) = self.blocks.insert(block.pc, block) {
+            old.valid.set(false);
+        }
+    }
+
+    /// Drop the blocks decoded from the physical page containing `paddr`.
+    #[inline]
+    pub fn invalidate(&mut self, paddr: u64) {
+        let ppn = paddr >> PAGE_SHIFT;
+        if !self.ppns.remove(&ppn) {
+            return;
+        }
+        let stats = &mut self.stats;
+        self.blocks.retain(|_, block| {
+            let keep = block.ppn != ppn;
+            if !keep {
+                block.valid.set(false);
+                stats.invalidations += 1;
+            }
+            keep
+        });
+    }
+
+    pub fn flush(&mut self) {
+        for block in self.blocks.values() {
+            block.valid.set(false);
+        }
+        self.stats.invalidations += self.blocks.len() as u64;
+        self.blocks.clear();
+        self.ppns.clear();
+    }
+}
+
+/// Whether `inst` has to be the last one of its block: it may change the pc,
+/// the privilege level, translation or interrupt state.
+fn ends_block(inst: &Instruction) -> bool {
+    use Instruction::*;
+
+    matches!(
+        inst,
+        Jal(_)
+            | Jalr(_)
+            | Beq(_)
+            | Bne(_)
+            | Blt(_)
+            | Bge(_)
+            | Bltu(_)
+            | Bgeu(_)
+            | FenceI
+            | Ecall
+            | Ebreak
+            | Uret
+            | Sret
+            | Mret
+            | Wfi
+            | WrsNto
+            | WrsSto
+            | SfenceVma(_)
+            | HfenceVvma(_)
+            | HfenceGvma(_)
+            | CmPopret(_)
+            | CmPopretz(_)
+            | Csrrw(_)
+            | Csrrs(_)
+            | Csrrc(_)
+            | Csrrwi(_)
+            | Csrrsi(_)
+            | Csrrci(_)
+            | Illegal
+    )
+}
+
+/// Bind `inst` at `pc` to a handler. Frequent instructions get specialised
+/// closures with their operands extracted up front, the rest go through
+/// [`CpuState::execute`].
+fn compile(inst: Instruction, raw: u32, pc: u64, len: u64) -> Handler {
+    use Instruction::*;
+
+    let npc = pc.wrapping_add(len);
+    macro_rules! op {
+        ($cpu:ident, $body:expr) => {
+            Box::new(move |$cpu: &mut CpuState, _: &mut Bus| {
+                $body;
+                $cpu.set_pc(npc);
+                Ok(())
+            })
+        };
+    }
+    match inst {
+        Lui(u) => {
+            let (rd, value) = (u.rd(), sext((u.imm() << 12) as u64, 32));
+            op!(cpu, cpu.set_reg(rd, value))
+        }
+        Auipc(u) => {
+            let (rd, value) = (u.rd(), pc.wrapping_add(sext((u.imm() << 12) as u64, 32)));
+            op!(cpu, cpu.set_reg(rd, value))
+        }
+        Addi(i) => {
+            let (rd, rs1, imm) = (i.rd(), i.rs1(), sext(i.imm() as u64, 12));
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1).wrapping_add(imm)))
+        }
+        Addiw(i) => {
+            let (rd, rs1, imm) = (i.rd(), i.rs1(), sext(i.imm() as u64, 12));
+            op!(
+                cpu,
+                cpu.set_reg(rd, sext(cpu.reg(rs1).wrapping_add(imm), 32))
+            )
+        }
+        Andi(i) => {
+            let (rd, rs1, imm) = (i.rd(), i.rs1(), sext(i.imm() as u64, 12));
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) & imm))
+        }
+        Ori(i) => {
+            let (rd, rs1, imm) = (i.rd(), i.rs1(), sext(i.imm() as u64, 12));
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) | imm))
+        }
+        Xori(i) => {
+            let (rd, rs1, imm) = (i.rd(), i.rs1(), sext(i.imm() as u64, 12));
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) ^ imm))
+        }
+        Slli(s) => {
+            let (rd, rs1, shamt) = (s.rd(), s.rs1(), s.shamt());
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) << shamt))
+        }
+        Srli(s) => {
+            let (rd, rs1, shamt) = (s.rd(), s.rs1(), s.shamt());
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) >> shamt))
+        }
+        Add(r) => {
+            let (rd, rs1, rs2) = (r.rd(), r.rs1(), r.rs2());
+            op!(
+                cpu,
+                cpu.set_reg(rd, cpu.reg(rs1).wrapping_add(cpu.reg(rs2)))
+            )
+        }
+        Sub(r) => {
+            let (rd, rs1, rs2) = (r.rd(), r.rs1(), r.rs2());
+            op!(
+                cpu,
+                cpu.set_reg(rd, cpu.reg(rs1).wrapping_sub(cpu.reg(rs2)))
+            )
+        }
+        And(r) => {
+            let (rd, rs1, rs2) = (r.rd(), r.rs1(), r.rs2());
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) & cpu.reg(rs2)))
+        }
+        Or(r) => {
+            let (rd, rs1, rs2) = (r.rd(), r.rs1(), r.rs2());
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) | cpu.reg(rs2)))
+        }
+        Xor(r) => {
+            let (rd, rs1, rs2) = (r.rd(), r.rs1(), r.rs2());
+            op!(cpu, cpu.set_reg(rd, cpu.reg(rs1) ^ cpu.reg(rs2)))
+        }
+        Ld(i) => {
+            let (rd, rs1, imm) = (i.rd(), i.rs1(), sext(i.imm() as u64, 12));
+            Box::new(move |cpu: &mut CpuState, bus: &mut Bus| {
+                let data = cpu.load(cpu.reg(rs1).wrapping_add(imm), 8, bus)?;
+                cpu.set_reg(rd, data);
+                cpu.set_pc(npc);
+                Ok(())
+            })
+        }
+        Sd(s) => {
+            let (rs1, rs2, imm) = (s.rs1(), s.rs2(), sext(s.imm() as u64, 12));
+            Box::new(move |cpu: &mut CpuState, bus: &mut Bus| {
+                cpu.store(cpu.reg(rs1).wrapping_add(imm), 8, cpu.reg(rs2), bus)?;
+                cpu.set_pc(npc);
+                Ok(())
+            })
+        }
+        _ => Box::new(move |cpu: &mut CpuState, bus: &mut Bus| {
+            cpu.execute(inst, len, bus).map_err(|e| e.with_inst(raw))
+        }),
+    }
+}
+
+impl CpuState {
+    /// Decode the basic block at pc. Returns `None` if not even its first
+    /// instruction can be fetched and decoded, the interpreter raises the fault.
+    fn build_block(&mut self, bus: &mut Bus) -> Option<Rc<Block>> {
+        let start = self.pc();
+        let mut pc = start;
+        let mut ppn = None;
+        #[cfg(feature = "jit")]
+        let mut insts = Vec::new();
+        let mut ops = Vec::new();
+        while ops.len() < CONFIG_MAX_BLOCK_INSTS {
+            let Ok((raw, paddr)) = self.fetch_at(pc, bus) else {
+                break;
+            };
+            let Ok(inst) = decode_ext(raw, self.extensions) else {
+                break;
+            };
+            let len = if raw & 0b11 == 0b11 { 4 } else { 2 };
+            if (pc & (PAGE_SIZE - 1)) + len > PAGE_SIZE {
+                break;
+            }
+            ppn.get_or_insert(paddr >> PAGE_SHIFT);
+            #[cfg(feature = "jit")]
+            insts.push(Decoded { inst, raw });
+            ops.push(compile(inst, raw, pc, len));
+            pc = pc.wrapping_add(len);
+            if ends_block(&inst) || pc & (PAGE_SIZE - 1) == 0 {
+                break;
+            }
+        }
+        let block = Rc::new(Block {
+            pc: start,
+            ppn: ppn?,
+            mode: self.mode,
+            #[cfg(feature = "jit")]
+            insts,
+            ops,
+            valid: Cell::new(true),
+            links: RefCell::new([None, None]),
+            #[cfg(feature = "jit")]
+            heat: Cell::new(0),
+            #[cfg(feature = "jit")]
+            native: RefCell::new(None),
+        });
+        self.blocks.stats.built += 1;
+        self.blocks.insert(block.clone());
+        Some(block)
+    }
+
+    /// Run up to `budget` instructions a basic block at a time and return how
+    /// many were executed, including interrupts and exceptions taken.
+    ///
+    /// Interrupts are checked whenever a block is entered. Everything that can
+    /// make one pending ends a block, except stores to devices, which make us
+    /// return so that the machine can update `mip` first. `time` advances with
+    /// the instructions executed from its value on entry.
+    pub fn exec_blocks(&mut self, bus: &mut Bus, budget: usize) -> usize {
+        self.stores.clear();
+        let time = self.csr.counters.time;
+        // the budget ends where this Sstc timer fires, a csr write moving it
+        // hands control back to have the budget recomputed
+        let deadline = self.csr.sstc_deadline();
+        let mut executed = 0;
+        let mut prev: Option<Rc<Block>> = None;
+        while executed < budget {
+            self.csr.counters.time = self.csr.counters.time_after(time, executed as u64);
+            if self.take_interrupt() {
+                executed += 1;
+                prev = None;
+                continue;
+            }
+
+            let pc = self.pc();
+            let chained = prev.as_ref().and_then(|prev| prev.successor(pc));
+            let block = match chained.filter(|block| block.mode == self.mode) {
+                // triggers are checked one instruction at a time
+                _ if self.csr.triggers.armed() => None,
+                Some(block) => {
+                    self.blocks.stats.chained += 1;
+                    Some(block)
+                }
+                None => match self.blocks.lookup(pc, self.mode) {
+                    Some(block) => {
+                        self.blocks.stats.dispatched += 1;
+                        Some(block)
+                    }
+                    None => self.build_block(bus),
+                },
+            };
+            let Some(block) = block else {
+                let stores = self.stores.len();
+                self.exec_step(bus);
+                executed += 1;
+                prev = None;
+                let mut device_store = false;
+                for i in stores..self.stores.len() {
+                    let paddr = self.stores[i];
+                    self.invalidate_decoded(paddr);
+                    device_store |= !bus.is_ram(paddr);
+                }
+                if device_store
+                    || self.halted
+                    || self.wrs.is_some()
+                    || self.csr.sstc_deadline() != deadline
+                {
+                    break;
+                }
+                continue;
+            };
+            if let Some(prev) = prev.take() {
+                prev.chain(pc, &block);
+            }
+
+            #[allow(unused_mut)]
+            let mut first = 0;
+            #[cfg(feature = "jit")]
+            if self.blocks.jit.enabled {
+                first = self.run_native(&block, budget - executed);
+                executed += first;
+            }
+
+            let mut completed = true;
+            let mut device_store = false;
+            for op in &block.ops[first..] {
+                if executed == budget {
+                    completed = false;
+                    break;
+                }
+                self.csr.counters.time = self.csr.counters.time_after(time, executed as u64);
+                self.csr.counters.tick();
+                executed += 1;
+                let stores = self.stores.len();
+                if let Err(e) = op(self, bus) {
+                    self.raise(e);
+                    completed = false;
+                    break;
+                }
+                self.csr.counters.retire();
+                if self.stores.len() != stores {
+                    for i in stores..self.stores.len() {
+                        let paddr = self.stores[i];
+                        self.invalidate_decoded(paddr);
+                        device_store |= !bus.is_ram(paddr);
+                    }
+                    if device_store || !block.valid() {
+                        completed = false;
+                        break;
+                    }
+                }
+            }
+            if device_store || self.wrs.is_some() || self.csr.sstc_deadline() != deadline {
+                break;
+            }
+            prev = completed.then_some(block);
+        }
+        executed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(code: &[u32]) -> Bus {
+        let mut bus = Bus::default();
+        let image: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        bus.ram.load(0x8000_0000, &image).unwrap();
+        bus
+    }
+
+    #[test]
+    fn blocks() {
+        // addi x1,x1,1; addi x2,x2,2; bne x1,x3,-8; ebreak
+        let mut bus = rom(&[0x00108093, 0x00210113, 0xfe309ce3, 0x00100073]);
+        let mut cpu = CpuState::new(0);
+        cpu.csr.mtvec = 0x8000_0800;
+        cpu.set_pc(0x8000_0000);
+        cpu.set_reg(3, 10);
+        assert_eq!(cpu.exec_blocks(&mut bus, 7), 7);
+        assert_eq!(cpu.reg(1), 3);
+        assert_eq!(cpu.reg(2), 4);
+        assert_eq!(cpu.pc(), 0x8000_0004);
+        assert_eq!(cpu.csr.counters.instret, 7);
+
+        assert_eq!(cpu.exec_blocks(&mut bus, 24), 24);
+        assert_eq!(cpu.reg(1), 10);
+        assert_eq!(cpu.csr.mcause, 3);
+        assert_eq!(cpu.csr.mepc, 0x8000_000c);
+        assert_eq!(cpu.pc(), 0x8000_0800);
+        assert_eq!(cpu.csr.counters.instret, 30);
+        let stats = cpu.blocks.stats;
+        assert_eq!(stats.built, 3);
+        // B0 -> B0 once before the link exists, then B1 -> B0
+        assert_eq!(stats.dispatched, 2);
+        assert_eq!(stats.chained, 7);
+    }
+
+    #[test]
+    fn top_of_memory() {
+        use super::super::csr::{SATP_MODE_SHIFT, SATP_MODE_SV39};
+        use super::super::mmu::{PTE_A, PTE_PPN_SHIFT, PTE_V, PTE_X};
+
+        // addi x1,x1,1 as the last instruction of the top page, mapped to
+        // 0x8000_1000 through tables at 0x8000_2000 and up
+        let mut bus = rom(&[]);
+        let table = |level: u64| 0x8000_2000 + level * PAGE_SIZE + 0x1ff * 8;
+        for level in 0..2 {
+            let next = (0x8000_3000 + level * PAGE_SIZE) >> PAGE_SHIFT;
+            bus.ram
+                .write(table(level), next << PTE_PPN_SHIFT | PTE_V)
+                .unwrap();
+        }
+        let leaf = 0x8000_1000 >> PAGE_SHIFT << PTE_PPN_SHIFT | PTE_V | PTE_X | PTE_A;
+        bus.ram.write(table(2), leaf).unwrap();
+        bus.ram.write(0x8000_1ffc, 0x00108093u32).unwrap();
+        let mut cpu = CpuState::new(0);
+        cpu.csr.satp = SATP_MODE_SV39 << SATP_MODE_SHIFT | 0x8000_2000 >> PAGE_SHIFT;
+        cpu.mode = Privilege::Supervisor;
+        cpu.set_pc(u64::MAX - 3);
+        assert_eq!(cpu.exec_blocks(&mut bus, 1), 1);
+        assert_eq!(cpu.reg(1), 1);
+        assert_eq!(cpu.pc(), 0);
+    }
+
+    #[test]
+    fn invalidation() {
+        let mut bus = rom(&[0x00108093, 0x00100073]);
+        let mut cpu = CpuState::new(0);
+        cpu.set_pc(0x8000_0000);
+        cpu.exec_blocks(&mut bus, 1);
+        assert_eq!(
+            cpu.blocks
+                .lookup(0x8000_0000, Privilege::Machine)
+                .map(|b| b.len()),
+            Some(2)
+        );
+        cpu.invalidate_decoded(0x8000_0ff0);
+        assert!(cpu.blocks.lookup(0x8000_0000, Privilege::Machine).is_none());
+        assert_eq!(cpu.blocks.stats.invalidations, 1);
+    }
+}
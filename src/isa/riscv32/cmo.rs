@@ -0,0 +1,220 @@
+//! Cache-block operations: management (Zicbom) and zeroing (Zicboz).
+//!
+//! The emulator has no caches, so cbo.clean, cbo.flush and cbo.inval only
+//! check that the block may be accessed and report it to the hart's hook,
+//! for a cache model to follow. cbo.zero stores zeros over the whole block.
+
+use super::csr::{Privilege, ENVCFG_CBCFE, ENVCFG_CBIE, ENVCFG_CBIE_FLUSH, ENVCFG_CBZE};
+use super::reg::CpuState;
+use super::trap::{AccessType, Exception};
+use crate::memory::bus::Bus;
+
+/// Bytes per cache block, unless the machine picks another power of two no
+/// larger than a page.
+pub const CONFIG_CACHE_BLOCK: u64 = 64;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CacheOp {
+    Clean,
+    Flush,
+    Inval,
+    Zero,
+}
+
+/// Observes every cache-block operation a hart performs, with the hart id
+/// and the physical address of the block. cbo.inval shows up as `Flush`
+/// where an envcfg register downgrades it.
+pub type CmoHook = fn(hart: u64, op: CacheOp, paddr: u64);
+
+/// Check `op` against one envcfg register: a clear field raises `exception`,
+/// and CBIE = 01 turns cbo.inval into a flush.
+fn envcfg_permits(envcfg: u64, op: CacheOp, exception: Exception) -> Result<CacheOp, Exception> {
+    let field = match op {
+        CacheOp::Clean | CacheOp::Flush => ENVCFG_CBCFE,
+        CacheOp::Inval => ENVCFG_CBIE,
+        CacheOp::Zero => ENVCFG_CBZE,
+    };
+    match envcfg & field {
+        0 => Err(exception),
+        ENVCFG_CBIE_FLUSH if op == CacheOp::Inval => Ok(CacheOp::Flush),
+        _ => Ok(op),
+    }
+}
+
+/// Management instructions may access a block a load or a store could, but
+/// fault like stores.
+fn store_fault(e: Exception) -> Exception {
+    match e {
+        Exception::LoadPageFault(addr) => Exception::StorePageFault(addr),
+        Exception::LoadAccessFault(addr) => Exception::StoreAccessFault(addr),
+        #[cfg(feature = "hypervisor")]
+        Exception::LoadGuestPageFault(addr, gpa) => Exception::StoreGuestPageFault(addr, gpa),
+        e => e,
+    }
+}
+
+impl CpuState {
+    /// The operation `op` performs in the current mode. Below M-mode it needs
+    /// menvcfg, in a guest henvcfg too, and in (V)U-mode senvcfg as well.
+    fn cbo_permitted(&self, op: CacheOp) -> Result<CacheOp, Exception> {
+        let illegal = Exception::IllegalInstruction(0);
+        #[cfg(feature = "hypervisor")]
+        let guest = Exception::VirtualInstruction(0);
+        #[cfg(not(feature = "hypervisor"))]
+        let guest = illegal;
+        let levels = [
+            (self.mode != Privilege::Machine).then_some((self.csr.menvcfg, illegal)),
+            #[cfg(feature = "hypervisor")]
+            self.virt.then_some((self.csr.h.henvcfg, guest)),
+            (self.mode == Privilege::User)
+                .then_some((self.csr.senvcfg, if self.virt() { guest } else { illegal })),
+        ];
+        let mut effective = op;
+        for (envcfg, exception) in levels.into_iter().flatten() {
+            if envcfg_permits(envcfg, op, exception)? == CacheOp::Flush {
+                effective = CacheOp::Flush;
+            }
+        }
+        Ok(effective)
+    }
+
+    /// Execute `op` on the cache block holding `vaddr`.
+    pub(super) fn cbo(&mut self, op: CacheOp, vaddr: u64, bus: &mut Bus) -> Result<(), Exception> {
+        let op = self.cbo_permitted(op)?;
+        let size = self.cache_block;
+        let vaddr = vaddr & !(size - 1);
+        let mode = self.data_mode();
+        let paddr = if op == CacheOp::Zero {
+            let paddr = self.translate(vaddr, AccessType::Store, bus)?;
+            // nothing is written unless the whole block can be
+            self.csr
+                .pmp
+                .check(paddr, size as usize, AccessType::Store, mode)?;
+            for offset in (0..size).step_by(8) {
+                self.store_paddr(paddr + offset, 8, 0, bus)?;
+            }
+            paddr
+        } else {
+            let paddr = self
+                .translate(vaddr, AccessType::Load, bus)
+                .map_err(store_fault)?;
+            self.csr
+                .pmp
+                .check(paddr, size as usize, AccessType::Load, mode)
+                .map_err(store_fault)?;
+            paddr
+        };
+        if let Some(hook) = self.cmo_hook {
+            hook(self.csr.mhartid, op, paddr);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::csr::{MENVCFG, SATP_MODE_SHIFT, SENVCFG};
+    use super::*;
+    use crate::device::io::map::IOMap;
+    use crate::memory::ram::Ram;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static SEEN: RefCell<Vec<(CacheOp, u64)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(_hart: u64, op: CacheOp, paddr: u64) {
+        SEEN.with(|seen| seen.borrow_mut().push((op, paddr)));
+    }
+
+    fn last_seen() -> Option<(CacheOp, u64)> {
+        SEEN.with(|seen| seen.borrow().last().copied())
+    }
+
+    fn data() -> Bus {
+        let mut data = IOMap::scratch("data", 0x9000_0000, 0x1000);
+        data.space.fill(0xff);
+        Bus::new(Ram::default(), vec![data])
+    }
+
+    #[test]
+    fn envcfg() {
+        let mut cpu = CpuState::new(0);
+        cpu.cmo_hook = Some(record);
+        let mut bus = data();
+        let (m, s) = (Privilege::Machine, Privilege::Supervisor);
+        let illegal = Err(Exception::IllegalInstruction(0));
+        cpu.cbo(CacheOp::Inval, 0x9000_0047, &mut bus).unwrap();
+        assert_eq!(last_seen(), Some((CacheOp::Inval, 0x9000_0040)));
+
+        cpu.mode = s;
+        assert_eq!(cpu.cbo(CacheOp::Clean, 0x9000_0000, &mut bus), illegal);
+        cpu.csr
+            .write(MENVCFG, ENVCFG_CBCFE | ENVCFG_CBIE_FLUSH, m)
+            .unwrap();
+        cpu.cbo(CacheOp::Clean, 0x9000_0080, &mut bus).unwrap();
+        assert_eq!(last_seen(), Some((CacheOp::Clean, 0x9000_0080)));
+        cpu.cbo(CacheOp::Inval, 0x9000_0080, &mut bus).unwrap();
+        assert_eq!(last_seen(), Some((CacheOp::Flush, 0x9000_0080)));
+        assert_eq!(cpu.cbo(CacheOp::Zero, 0x9000_0080, &mut bus), illegal);
+
+        cpu.mode = Privilege::User;
+        assert_eq!(cpu.cbo(CacheOp::Flush, 0x9000_0000, &mut bus), illegal);
+        cpu.csr
+            .write(SENVCFG, ENVCFG_CBCFE | ENVCFG_CBIE, s)
+            .unwrap();
+        cpu.cbo(CacheOp::Inval, 0x9000_00c0, &mut bus).unwrap();
+        assert_eq!(last_seen(), Some((CacheOp::Flush, 0x9000_00c0)));
+        // CBIE = 10 is reserved
+        cpu.csr.write(SENVCFG, 0b10 << 4, s).unwrap();
+        assert_eq!(cpu.csr.senvcfg, 0);
+    }
+
+    #[cfg(feature = "hypervisor")]
+    #[test]
+    fn guest() {
+        let mut cpu = CpuState::new(0);
+        let mut bus = data();
+        let virtual_instruction = Err(Exception::VirtualInstruction(0));
+        cpu.mode = Privilege::Supervisor;
+        cpu.virt = true;
+        assert_eq!(
+            cpu.cbo(CacheOp::Zero, 0x9000_0000, &mut bus),
+            Err(Exception::IllegalInstruction(0))
+        );
+        cpu.csr.menvcfg = ENVCFG_CBZE;
+        assert_eq!(
+            cpu.cbo(CacheOp::Zero, 0x9000_0000, &mut bus),
+            virtual_instruction
+        );
+        cpu.csr.h.henvcfg = ENVCFG_CBZE;
+        cpu.cbo(CacheOp::Zero, 0x9000_0000, &mut bus).unwrap();
+        cpu.mode = Privilege::User;
+        assert_eq!(
+            cpu.cbo(CacheOp::Zero, 0x9000_0000, &mut bus),
+            virtual_instruction
+        );
+    }
+
+    #[test]
+    fn zero() {
+        let mut cpu = CpuState::new(0);
+        cpu.cache_block = 32;
+        let mut bus = data();
+        cpu.cbo(CacheOp::Zero, 0x9000_0047, &mut bus).unwrap();
+        let space = &bus.devices[0].space;
+        assert!(space[0x40..0x60].iter().all(|&b| b == 0));
+        assert_eq!((space[0x3f], space[0x60]), (0xff, 0xff));
+        assert_eq!(cpu.stores.len(), 4);
+
+        // management faults are reported as store faults
+        cpu.mode = Privilege::Supervisor;
+        cpu.csr.menvcfg = ENVCFG_CBCFE;
+        cpu.csr.satp = 8 << SATP_MODE_SHIFT;
+        let vaddr = 0x0000_8000_0000_0000;
+        assert_eq!(
+            cpu.cbo(CacheOp::Clean, vaddr + 5, &mut bus),
+            Err(Exception::StorePageFault(vaddr))
+        );
+    }
+}
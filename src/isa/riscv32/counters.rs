@@ -0,0 +1,177 @@
+use super::csr::Privilege;
+
+/// mhpmcounter3..mhpmcounter31
+pub const HPM_COUNTERS: usize = 29;
+
+/// Events a `mhpmevent` selector can count, encoded by their selector value.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Event {
+    Load = 1,
+    Store = 2,
+    BranchTaken = 3,
+    Trap = 4,
+    TlbMiss = 5,
+}
+
+// mcountinhibit/mcounteren bits
+const CY: u32 = 1 << 0;
+const TM: u32 = 1 << 1;
+const IR: u32 = 1 << 2;
+
+#[derive(Clone, Debug)]
+pub struct Counters {
+    pub cycle: u64,
+    pub time: u64,
+    /// `time` ticks every `insns_per_tick` instructions, `tick_phase` of which
+    /// passed since the last tick
+    pub insns_per_tick: u64,
+    pub tick_phase: u64,
+    pub instret: u64,
+    pub hpm: [u64; HPM_COUNTERS],
+    pub event: [u64; HPM_COUNTERS],
+    pub inhibit: u32,
+    pub mcounteren: u32,
+    pub scounteren: u32,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self {
+            cycle: 0,
+            time: 0,
+            insns_per_tick: 1,
+            tick_phase: 0,
+            instret: 0,
+            hpm: [0; HPM_COUNTERS],
+            event: [0; HPM_COUNTERS],
+            inhibit: 0,
+            mcounteren: 0,
+            scounteren: 0,
+        }
+    }
+
+    /// Advance the clock by one cycle. `time` mirrors the CLINT `mtime` and is set by the machine.
+    pub fn tick(&mut self) {
+        if self.inhibit & CY == 0 {
+            self.cycle = self.cycle.wrapping_add(1);
+        }
+    }
+
+    /// `time` once `n` more instructions ran from the point it was last set.
+    #[inline(always)]
+    pub fn time_after(&self, time: u64, n: u64) -> u64 {
+        time.wrapping_add((self.tick_phase + n) / self.insns_per_tick)
+    }
+
+    /// `tick` and `retire` for `n` instructions at once.
+    pub fn advance(&mut self, n: u64) {
+        if self.inhibit & CY == 0 {
+            self.cycle = self.cycle.wrapping_add(n);
+        }
+        if self.inhibit & IR == 0 {
+            self.instret = self.instret.wrapping_add(n);
+        }
+    }
+
+    pub fn retire(&mut self) {
+        if self.inhibit & IR == 0 {
+            self.instret = self.instret.wrapping_add(1);
+        }
+    }
+
+    pub fn record(&mut self, event: Event) {
+        for i in 0..HPM_COUNTERS {
+            if self.event[i] == event as u64 && self.inhibit & (1 << (i + 3)) == 0 {
+                self.hpm[i] = self.hpm[i].wrapping_add(1);
+            }
+        }
+    }
+
+    /// Whether the user-level counter `index` (0 = cycle, 1 = time, ...) may be read in `mode`.
+    pub fn accessible(&self, index: u32, mode: Privilege) -> bool {
+        let bit = 1 << index;
+        match mode {
+            Privilege::Machine => true,
+            Privilege::Supervisor => self.mcounteren & bit != 0,
+            Privilege::User => self.mcounteren & self.scounteren & bit != 0,
+        }
+    }
+
+    /// Value of the counter `index` as seen through `cycle`/`time`/`instret`/`hpmcounterN`.
+    pub fn read(&self, index: u32) -> u64 {
+        match index {
+            0 => self.cycle,
+            1 => self.time,
+            2 => self.instret,
+            _ => self.hpm[index as usize - 3],
+        }
+    }
+
+    /// Write `mcycle`/`minstret`/`mhpmcounterN`, there is no `mtime` counterpart here.
+    pub fn write(&mut self, index: u32, value: u64) {
+        match index {
+            0 => self.cycle = value,
+            1 => {}
+            2 => self.instret = value,
+            _ => self.hpm[index as usize - 3] = value,
+        }
+    }
+
+    pub fn write_inhibit(&mut self, value: u64) {
+        self.inhibit = value as u32 & !TM;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inhibit() {
+        let mut counters = Counters::new();
+        counters.tick();
+        counters.retire();
+        counters.write_inhibit((CY | TM | IR) as u64);
+        assert_eq!(counters.inhibit, CY | IR);
+        counters.tick();
+        counters.retire();
+        assert_eq!(counters.read(0), 1);
+        assert_eq!(counters.read(2), 1);
+    }
+
+    #[test]
+    fn events() {
+        let mut counters = Counters::new();
+        counters.event[0] = Event::Load as u64;
+        counters.event[1] = Event::Store as u64;
+        counters.event[2] = Event::Load as u64;
+        counters.inhibit = 1 << 5;
+        counters.record(Event::Load);
+        counters.record(Event::Load);
+        counters.record(Event::Store);
+        counters.record(Event::Trap);
+        assert_eq!(counters.read(3), 2);
+        assert_eq!(counters.read(4), 1);
+        assert_eq!(counters.read(5), 0);
+    }
+
+    #[test]
+    fn gating() {
+        let mut counters = Counters::new();
+        assert!(counters.accessible(0, Privilege::Machine));
+        assert!(!counters.accessible(0, Privilege::Supervisor));
+        counters.mcounteren = CY | IR;
+        assert!(counters.accessible(0, Privilege::Supervisor));
+        assert!(!counters.accessible(1, Privilege::Supervisor));
+        assert!(!counters.accessible(0, Privilege::User));
+        counters.scounteren = IR;
+        assert!(counters.accessible(2, Privilege::User));
+        assert!(!counters.accessible(0, Privilege::User));
+    }
+}
@@ -0,0 +1,469 @@
+//! The scalar cryptography extensions for RV64: Zbkb, Zbkc and Zbkx bit
+//! manipulation, the AES rounds of Zknd/Zkne and the SHA-2 functions of Zknh.
+//!
+//! AES instructions see the 128-bit state as two registers holding its
+//! columns 0-1 and 2-3, with the first byte of a column in the low bits.
+
+/// Multiplication by x in GF(2^8) modulo the AES polynomial.
+const fn xtime(a: u8) -> u8 {
+    (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+/// The forward S-box, the affine transform of the multiplicative inverse.
+const fn sbox() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut x = 0;
+    while x < 256 {
+        // x^254 is the inverse of x, and maps 0 to 0
+        let mut inverse = 1;
+        let mut i = 0;
+        while i < 254 {
+            inverse = gf_mul(inverse, x as u8);
+            i += 1;
+        }
+        let b = inverse;
+        table[x] =
+            b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+        x += 1;
+    }
+    table
+}
+
+const fn inverse_sbox() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut x = 0;
+    while x < 256 {
+        table[SBOX[x] as usize] = x as u8;
+        x += 1;
+    }
+    table
+}
+
+const SBOX: [u8; 256] = sbox();
+const INV_SBOX: [u8; 256] = inverse_sbox();
+
+/// Round constants of the key schedule, indexed by `rnum`.
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+fn sub_bytes(x: u64, table: &[u8; 256]) -> u64 {
+    u64::from_le_bytes(x.to_le_bytes().map(|b| table[b as usize]))
+}
+
+/// The two columns of ShiftRows applied to the state in rs1 (columns 0-1)
+/// and rs2 (columns 2-3) that end up in columns 0-1, or their inverse.
+fn shift_rows(rs1: u64, rs2: u64, inverse: bool) -> u64 {
+    let mut state = [0; 16];
+    state[..8].copy_from_slice(&rs1.to_le_bytes());
+    state[8..].copy_from_slice(&rs2.to_le_bytes());
+    let mut out = [0; 8];
+    for (i, byte) in out.iter_mut().enumerate() {
+        let (row, column) = (i % 4, i / 4);
+        let from = if inverse {
+            (column + 4 - row) % 4
+        } else {
+            (column + row) % 4
+        };
+        *byte = state[row + 4 * from];
+    }
+    u64::from_le_bytes(out)
+}
+
+fn mix_column(column: u32, inverse: bool) -> u32 {
+    let b = column.to_le_bytes();
+    let m: [u8; 4] = if inverse {
+        [0x0e, 0x0b, 0x0d, 0x09]
+    } else {
+        [0x02, 0x03, 0x01, 0x01]
+    };
+    let mut out = [0; 4];
+    for (row, byte) in out.iter_mut().enumerate() {
+        for (i, &value) in b.iter().enumerate() {
+            *byte ^= gf_mul(m[(i + 4 - row) % 4], value);
+        }
+    }
+    u32::from_le_bytes(out)
+}
+
+fn mix_columns(x: u64, inverse: bool) -> u64 {
+    mix_column(x as u32, inverse) as u64 | (mix_column((x >> 32) as u32, inverse) as u64) << 32
+}
+
+/// `aes64es`/`aes64esm`: ShiftRows and SubBytes, plus MixColumns for a middle round.
+pub fn aes64es(rs1: u64, rs2: u64, mix: bool) -> u64 {
+    let state = sub_bytes(shift_rows(rs1, rs2, false), &SBOX);
+    if mix {
+        mix_columns(state, false)
+    } else {
+        state
+    }
+}
+
+/// `aes64ds`/`aes64dsm`: the inverse rounds.
+pub fn aes64ds(rs1: u64, rs2: u64, mix: bool) -> u64 {
+    let state = sub_bytes(shift_rows(rs1, rs2, true), &INV_SBOX);
+    if mix {
+        mix_columns(state, true)
+    } else {
+        state
+    }
+}
+
+/// `aes64im`: InvMixColumns, turning encryption round keys into decryption ones.
+pub fn aes64im(rs1: u64) -> u64 {
+    mix_columns(rs1, true)
+}
+
+/// `aes64ks1i`: the substituted and rotated word of a key schedule step, or
+/// just substituted for `rnum` 0xa in AES-256. Larger `rnum` are reserved
+/// and never decoded.
+pub fn aes64ks1i(rs1: u64, rnum: u32) -> u64 {
+    let word = (rs1 >> 32) as u32;
+    let (word, rcon) = match RCON.get(rnum as usize) {
+        Some(&rcon) => (word.rotate_right(8), rcon),
+        None => (word, 0),
+    };
+    let word = sub_bytes(word as u64, &SBOX) as u32 ^ rcon as u32;
+    (word as u64) << 32 | word as u64
+}
+
+/// `aes64ks2`: the xor chain producing the next two round key words.
+pub fn aes64ks2(rs1: u64, rs2: u64) -> u64 {
+    let w0 = (rs1 >> 32) as u32 ^ rs2 as u32;
+    let w1 = w0 ^ (rs2 >> 32) as u32;
+    (w1 as u64) << 32 | w0 as u64
+}
+
+pub fn sha256sig0(x: u32) -> u32 {
+    x.rotate_right(7) ^ x.rotate_right(18) ^ x >> 3
+}
+
+pub fn sha256sig1(x: u32) -> u32 {
+    x.rotate_right(17) ^ x.rotate_right(19) ^ x >> 10
+}
+
+pub fn sha256sum0(x: u32) -> u32 {
+    x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22)
+}
+
+pub fn sha256sum1(x: u32) -> u32 {
+    x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25)
+}
+
+pub fn sha512sig0(x: u64) -> u64 {
+    x.rotate_right(1) ^ x.rotate_right(8) ^ x >> 7
+}
+
+pub fn sha512sig1(x: u64) -> u64 {
+    x.rotate_right(19) ^ x.rotate_right(61) ^ x >> 6
+}
+
+pub fn sha512sum0(x: u64) -> u64 {
+    x.rotate_right(28) ^ x.rotate_right(34) ^ x.rotate_right(39)
+}
+
+pub fn sha512sum1(x: u64) -> u64 {
+    x.rotate_right(14) ^ x.rotate_right(18) ^ x.rotate_right(41)
+}
+
+/// `brev8`: reverse the bits of every byte.
+pub fn brev8(x: u64) -> u64 {
+    u64::from_le_bytes(x.to_le_bytes().map(u8::reverse_bits))
+}
+
+/// Carry-less product of `a` and `b`, as (low, high) halves.
+fn clmul_wide(a: u64, b: u64) -> (u64, u64) {
+    let product = (0..64)
+        .filter(|i| b >> i & 1 != 0)
+        .fold(0u128, |acc, i| acc ^ (a as u128) << i);
+    (product as u64, (product >> 64) as u64)
+}
+
+pub fn clmul(a: u64, b: u64) -> u64 {
+    clmul_wide(a, b).0
+}
+
+pub fn clmulh(a: u64, b: u64) -> u64 {
+    clmul_wide(a, b).1
+}
+
+/// `xperm4`/`xperm8`: look up each nibble or byte of `indices` in `table`,
+/// out-of-range indices give 0.
+pub fn xperm(table: u64, indices: u64, bits: u32) -> u64 {
+    let mask = (1 << bits) - 1;
+    (0..64)
+        .step_by(bits as usize)
+        .map(|i| {
+            let index = (indices >> i & mask) as u32 * bits;
+            let value = if index < 64 { table >> index & mask } else { 0 };
+            value << i
+        })
+        .fold(0, |acc, value| acc | value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const K256: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+
+    const K512: [u64; 80] = [
+        0x428a2f98d728ae22,
+        0x7137449123ef65cd,
+        0xb5c0fbcfec4d3b2f,
+        0xe9b5dba58189dbbc,
+        0x3956c25bf348b538,
+        0x59f111f1b605d019,
+        0x923f82a4af194f9b,
+        0xab1c5ed5da6d8118,
+        0xd807aa98a3030242,
+        0x12835b0145706fbe,
+        0x243185be4ee4b28c,
+        0x550c7dc3d5ffb4e2,
+        0x72be5d74f27b896f,
+        0x80deb1fe3b1696b1,
+        0x9bdc06a725c71235,
+        0xc19bf174cf692694,
+        0xe49b69c19ef14ad2,
+        0xefbe4786384f25e3,
+        0x0fc19dc68b8cd5b5,
+        0x240ca1cc77ac9c65,
+        0x2de92c6f592b0275,
+        0x4a7484aa6ea6e483,
+        0x5cb0a9dcbd41fbd4,
+        0x76f988da831153b5,
+        0x983e5152ee66dfab,
+        0xa831c66d2db43210,
+        0xb00327c898fb213f,
+        0xbf597fc7beef0ee4,
+        0xc6e00bf33da88fc2,
+        0xd5a79147930aa725,
+        0x06ca6351e003826f,
+        0x142929670a0e6e70,
+        0x27b70a8546d22ffc,
+        0x2e1b21385c26c926,
+        0x4d2c6dfc5ac42aed,
+        0x53380d139d95b3df,
+        0x650a73548baf63de,
+        0x766a0abb3c77b2a8,
+        0x81c2c92e47edaee6,
+        0x92722c851482353b,
+        0xa2bfe8a14cf10364,
+        0xa81a664bbc423001,
+        0xc24b8b70d0f89791,
+        0xc76c51a30654be30,
+        0xd192e819d6ef5218,
+        0xd69906245565a910,
+        0xf40e35855771202a,
+        0x106aa07032bbd1b8,
+        0x19a4c116b8d2d0c8,
+        0x1e376c085141ab53,
+        0x2748774cdf8eeb99,
+        0x34b0bcb5e19b48a8,
+        0x391c0cb3c5c95a63,
+        0x4ed8aa4ae3418acb,
+        0x5b9cca4f7763e373,
+        0x682e6ff3d6b2b8a3,
+        0x748f82ee5defb2fc,
+        0x78a5636f43172f60,
+        0x84c87814a1f0ab72,
+        0x8cc702081a6439ec,
+        0x90befffa23631e28,
+        0xa4506cebde82bde9,
+        0xbef9a3f7b2c67915,
+        0xc67178f2e372532b,
+        0xca273eceea26619c,
+        0xd186b8c721c0c207,
+        0xeada7dd6cde0eb1e,
+        0xf57d4f7fee6ed178,
+        0x06f067aa72176fba,
+        0x0a637dc5a2c898a6,
+        0x113f9804bef90dae,
+        0x1b710b35131c471b,
+        0x28db77f523047d84,
+        0x32caab7b40c72493,
+        0x3c9ebe0a15c9bebc,
+        0x431d67c49c100d4c,
+        0x4cc5d4becb3e42b6,
+        0x597f299cfc657e2a,
+        0x5fcb6fab3ad6faec,
+        0x6c44198c4a475817,
+    ];
+
+    fn block(bytes: &[u8]) -> (u64, u64) {
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes[..8]);
+        let low = u64::from_le_bytes(word);
+        word.copy_from_slice(&bytes[8..]);
+        (low, u64::from_le_bytes(word))
+    }
+
+    /// FIPS-197 appendix C.1, AES-128 written with the RV64 instructions
+    /// the way the scalar crypto spec lays out the key schedule and rounds.
+    #[test]
+    fn aes128() {
+        let key: Vec<u8> = (0..16).collect();
+        let plain: Vec<u8> = (0..16).map(|i| i * 0x11).collect();
+        let cipher = [
+            0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+            0xc5, 0x5a,
+        ];
+
+        let mut keys = vec![block(&key)];
+        for rnum in 0..10 {
+            let (k0, k1) = keys[rnum as usize];
+            let k0 = aes64ks2(aes64ks1i(k1, rnum), k0);
+            let k1 = aes64ks2(k0, k1);
+            keys.push((k0, k1));
+        }
+        // round[10].k_sch
+        assert_eq!(
+            keys[10],
+            block(&[
+                0x13, 0x11, 0x1d, 0x7f, 0xe3, 0x94, 0x4a, 0x17, 0xf3, 0x07, 0xa7, 0x8b, 0x4d, 0x2b,
+                0x30, 0xc5,
+            ])
+        );
+
+        let (mut s0, mut s1) = block(&plain);
+        s0 ^= keys[0].0;
+        s1 ^= keys[0].1;
+        for (round, &(k0, k1)) in keys.iter().enumerate().skip(1) {
+            let mix = round != 10;
+            (s0, s1) = (aes64es(s0, s1, mix) ^ k0, aes64es(s1, s0, mix) ^ k1);
+        }
+        assert_eq!((s0, s1), block(&cipher));
+
+        s0 ^= keys[10].0;
+        s1 ^= keys[10].1;
+        for round in (0..10).rev() {
+            let (mut k0, mut k1) = keys[round];
+            let mix = round != 0;
+            if mix {
+                (k0, k1) = (aes64im(k0), aes64im(k1));
+            }
+            (s0, s1) = (aes64ds(s0, s1, mix) ^ k0, aes64ds(s1, s0, mix) ^ k1);
+        }
+        assert_eq!((s0, s1), block(&plain));
+    }
+
+    /// FIPS 180-4 example "abc", one block through the compression function.
+    #[test]
+    fn sha256() {
+        let mut w = [0u32; 64];
+        w[0] = 0x6162_6380;
+        w[15] = 24;
+        for i in 16..64 {
+            w[i] = sha256sig1(w[i - 2])
+                .wrapping_add(w[i - 7])
+                .wrapping_add(sha256sig0(w[i - 15]))
+                .wrapping_add(w[i - 16]);
+        }
+        let init = [
+            0x6a09e667,
+            0xbb67ae85,
+            0x3c6ef372,
+            0xa54ff53a,
+            0x510e527f,
+            0x9b05688c,
+            0x1f83d9ab,
+            0x5be0cd19u32,
+        ];
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = init;
+        for i in 0..64 {
+            let t1 = h
+                .wrapping_add(sha256sum1(e))
+                .wrapping_add((e & f) ^ (!e & g))
+                .wrapping_add(K256[i])
+                .wrapping_add(w[i]);
+            let t2 = sha256sum0(a).wrapping_add((a & b) ^ (a & c) ^ (b & c));
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        let digest: Vec<u32> = [a, b, c, d, e, f, g, h]
+            .iter()
+            .zip(init)
+            .map(|(x, y)| x.wrapping_add(y))
+            .collect();
+        assert_eq!(
+            digest,
+            [
+                0xba7816bf, 0x8f01cfea, 0x414140de, 0x5dae2223, 0xb00361a3, 0x96177a9c, 0xb410ff61,
+                0xf20015ad
+            ]
+        );
+    }
+
+    #[test]
+    fn sha512() {
+        let mut w = [0u64; 80];
+        w[0] = 0x6162_6380_0000_0000;
+        w[15] = 24;
+        for i in 16..80 {
+            w[i] = sha512sig1(w[i - 2])
+                .wrapping_add(w[i - 7])
+                .wrapping_add(sha512sig0(w[i - 15]))
+                .wrapping_add(w[i - 16]);
+        }
+        let init = [
+            0x6a09e667f3bcc908,
+            0xbb67ae8584caa73b,
+            0x3c6ef372fe94f82b,
+            0xa54ff53a5f1d36f1,
+            0x510e527fade682d1,
+            0x9b05688c2b3e6c1f,
+            0x1f83d9abfb41bd6b,
+            0x5be0cd19137e2179u64,
+        ];
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = init;
+        for i in 0..80 {
+            let t1 = h
+                .wrapping_add(sha512sum1(e))
+                .wrapping_add((e & f) ^ (!e & g))
+                .wrapping_add(K512[i])
+                .wrapping_add(w[i]);
+            let t2 = sha512sum0(a).wrapping_add((a & b) ^ (a & c) ^ (b & c));
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        assert_eq!(a.wrapping_add(init[0]), 0xddaf35a193617aba);
+        assert_eq!(h.wrapping_add(init[7]), 0x2a9ac94fa54ca49f);
+    }
+
+    #[test]
+    fn bitmanip() {
+        assert_eq!(SBOX[0x53], 0xed);
+        assert_eq!(INV_SBOX[0xed], 0x53);
+        assert_eq!(brev8(0x0102_0380_0000_00ff), 0x8040_c001_0000_00ff);
+        assert_eq!(clmul(0b1011, 0b0110), 0b111010);
+        assert_eq!(clmulh(1 << 63, 0b110), 0b11);
+        assert_eq!(
+            xperm(0x0706_0504_0302_0100, 0x0009_0001_0203_0407, 8),
+            0x0000_0001_0203_0407
+        );
+        assert_eq!(
+            xperm(0xfedc_ba98_7654_3210, 0x0123_4567_89ab_cdef, 4),
+            0x0123_4567_89ab_cdef
+        );
+    }
+}
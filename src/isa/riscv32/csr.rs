@@ -0,0 +1,673 @@
+use super::counters::Counters;
+use super::decode::Extensions;
+#[cfg(feature = "hypervisor")]
+use super::hypervisor::{self, HypervisorCsrs};
+use super::pmp::Pmp;
+use super::trap::Exception;
+use super::trigger::{Triggers, CONFIG_TRIGGERS};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    pub fn from_bits(bits: u64) -> Self {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+// Supervisor trap setup
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+// Supervisor configuration
+pub const SENVCFG: u32 = 0x10a;
+// Supervisor trap handling
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const STIMECMP: u32 = 0x14d;
+// Supervisor protection and translation
+pub const SATP: u32 = 0x180;
+
+// Machine information registers
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+// Machine trap setup
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+// Machine trap handling
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+// Machine configuration
+pub const MENVCFG: u32 = 0x30a;
+// Machine memory protection
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPCFG15: u32 = 0x3af;
+pub const PMPADDR0: u32 = 0x3b0;
+pub const PMPADDR63: u32 = 0x3ef;
+// Debug/trace registers, shared with debug mode
+pub const TSELECT: u32 = 0x7a0;
+pub const TDATA1: u32 = 0x7a1;
+pub const TDATA2: u32 = 0x7a2;
+pub const TDATA3: u32 = 0x7a3;
+pub const TINFO: u32 = 0x7a4;
+// Machine counter/timers
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MHPMCOUNTER3: u32 = 0xb03;
+pub const MHPMCOUNTER31: u32 = 0xb1f;
+// Machine counter setup
+pub const MCOUNTINHIBIT: u32 = 0x320;
+pub const MHPMEVENT3: u32 = 0x323;
+pub const MHPMEVENT31: u32 = 0x33f;
+// Unprivileged counter/timers
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const HPMCOUNTER3: u32 = 0xc03;
+pub const HPMCOUNTER31: u32 = 0xc1f;
+
+// mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_MPP: u64 = 0b11 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+pub const MSTATUS_GVA: u64 = 1 << 38;
+pub const MSTATUS_MPV: u64 = 1 << 39;
+/// UXL = SXL = 64
+const MSTATUS_XL: u64 = 0b10 << 32 | 0b10 << 34;
+const MSTATUS_H: u64 = if cfg!(feature = "hypervisor") {
+    MSTATUS_GVA | MSTATUS_MPV
+} else {
+    0
+};
+const MSTATUS_WMASK: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR
+    | MSTATUS_H;
+pub const SSTATUS_WMASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+const SSTATUS_RMASK: u64 = SSTATUS_WMASK | 0b11 << 32;
+
+// menvcfg, senvcfg and henvcfg fields
+/// Zicbom: cbo.inval raises an exception (00), flushes (01) or invalidates (11)
+pub const ENVCFG_CBIE: u64 = 0b11 << 4;
+pub const ENVCFG_CBIE_FLUSH: u64 = 0b01 << 4;
+/// Zicbom: cbo.clean and cbo.flush are allowed
+pub const ENVCFG_CBCFE: u64 = 1 << 6;
+/// Zicboz: cbo.zero is allowed
+pub const ENVCFG_CBZE: u64 = 1 << 7;
+pub const ENVCFG_CBO: u64 = ENVCFG_CBIE | ENVCFG_CBCFE | ENVCFG_CBZE;
+/// Svpbmt memory types are allowed in S-mode page tables
+pub const MENVCFG_PBMTE: u64 = 1 << 62;
+/// Sstc: stimecmp is accessible below M-mode and drives STIP
+pub const MENVCFG_STCE: u64 = 1 << 63;
+const MENVCFG_WMASK: u64 = ENVCFG_CBO | MENVCFG_PBMTE | MENVCFG_STCE;
+
+// mip/mie fields
+pub const IRQ_SSIP: u64 = 1 << 1;
+pub const IRQ_VSSIP: u64 = 1 << 2;
+pub const IRQ_MSIP: u64 = 1 << 3;
+pub const IRQ_STIP: u64 = 1 << 5;
+pub const IRQ_VSTIP: u64 = 1 << 6;
+pub const IRQ_MTIP: u64 = 1 << 7;
+pub const IRQ_SEIP: u64 = 1 << 9;
+pub const IRQ_VSEIP: u64 = 1 << 10;
+pub const IRQ_MEIP: u64 = 1 << 11;
+pub const IRQ_S: u64 = IRQ_SSIP | IRQ_STIP | IRQ_SEIP;
+/// The VS-level interrupts only exist with the hypervisor extension, and are
+/// always delegated past M-mode.
+pub const IRQ_VS: u64 = if cfg!(feature = "hypervisor") {
+    IRQ_VSSIP | IRQ_VSTIP | IRQ_VSEIP
+} else {
+    0
+};
+const IRQ_ALL: u64 = IRQ_S | IRQ_VS | IRQ_MSIP | IRQ_MTIP | IRQ_MEIP;
+
+/// Every exception except ecall from M-mode can be delegated, including
+/// those of the hypervisor extension.
+const MEDELEG_WMASK: u64 = 0xb3ff
+    | if cfg!(feature = "hypervisor") {
+        1 << 10 | 0xf << 20
+    } else {
+        0
+    };
+
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+
+const fn misa_ext(c: u8) -> u64 {
+    1 << (c - b'A')
+}
+
+/// The writable bits `wmask` of an envcfg write. CBIE = 10 is reserved and
+/// leaves cbo.inval disabled.
+pub(super) fn legal_envcfg(value: u64, wmask: u64) -> u64 {
+    let value = value & wmask;
+    if value & ENVCFG_CBIE == 0b10 << 4 {
+        value & !ENVCFG_CBIE
+    } else {
+        value
+    }
+}
+
+/// RV64IMACSU, plus H with the hypervisor extension
+const MISA_VALUE: u64 = 0b10 << 62
+    | if cfg!(feature = "hypervisor") {
+        misa_ext(b'H')
+    } else {
+        0
+    }
+    | misa_ext(b'I')
+    | misa_ext(b'M')
+    | misa_ext(b'A')
+    | misa_ext(b'C')
+    | misa_ext(b'S')
+    | misa_ext(b'U');
+
+/// Single-letter extensions in the canonical order of an ISA string.
+const ISA_ORDER: &[u8] = b"IMAFDQLCBKJTPVH";
+
+/// Extensions without a misa bit, in canonical order. This includes every
+/// scalar crypto extension: misa has no bit for them, so software has to
+/// learn about them from the ISA string (or the device tree built from it).
+/// Linux looks for sstc, svnapot and svpbmt there too.
+const ISA_EXTENSIONS: [&str; 17] = [
+    "zicbom", "zicboz", "zicond", "zicsr", "zifencei", "zawrs", "zcb", "zcmp", "zbkb", "zbkc",
+    "zbkx", "zknd", "zkne", "zknh", "sstc", "svnapot", "svpbmt",
+];
+
+#[derive(Clone, Debug)]
+pub struct CsrFile {
+    pub pmp: Pmp,
+    pub counters: Counters,
+    pub triggers: Triggers,
+    #[cfg(feature = "hypervisor")]
+    pub h: HypervisorCsrs,
+    pub mhartid: u64,
+    pub mstatus: u64,
+    pub misa: u64,
+    pub medeleg: u64,
+    pub mideleg: u64,
+    pub mie: u64,
+    pub mip: u64,
+    pub mtvec: u64,
+    pub mscratch: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+    pub stvec: u64,
+    pub sscratch: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub satp: u64,
+    pub menvcfg: u64,
+    pub senvcfg: u64,
+    pub stimecmp: u64,
+}
+
+impl CsrFile {
+    pub fn new(pmp_entries: usize) -> Self {
+        Self {
+            pmp: Pmp::new(pmp_entries),
+            counters: Counters::new(),
+            triggers: Triggers::new(CONFIG_TRIGGERS),
+            #[cfg(feature = "hypervisor")]
+            h: HypervisorCsrs {
+                vstimecmp: u64::MAX,
+                ..HypervisorCsrs::default()
+            },
+            mhartid: 0,
+            mstatus: MSTATUS_XL,
+            misa: MISA_VALUE,
+            medeleg: 0,
+            mideleg: IRQ_VS,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            menvcfg: 0,
+            senvcfg: 0,
+            stimecmp: u64::MAX,
+        }
+    }
+
+    /// mip as software and the interrupt logic see it. With Sstc enabled
+    /// STIP follows stimecmp alone, and a guest's vstimecmp raises VSTIP
+    /// alongside the bit hvip injects.
+    pub fn effective_mip(&self) -> u64 {
+        let mut mip = self.mip;
+        if self.menvcfg & MENVCFG_STCE != 0 {
+            mip &= !IRQ_STIP;
+            if self.counters.time >= self.stimecmp {
+                mip |= IRQ_STIP;
+            }
+            #[cfg(feature = "hypervisor")]
+            if self.vstimecmp_pending() {
+                mip |= IRQ_VSTIP;
+            }
+        }
+        mip
+    }
+
+    /// The `time` at which a Sstc timer interrupt that isn't pending yet fires.
+    pub fn sstc_deadline(&self) -> Option<u64> {
+        if self.menvcfg & MENVCFG_STCE == 0 {
+            return None;
+        }
+        let time = self.counters.time;
+        let deadline = (self.stimecmp > time).then_some(self.stimecmp);
+        #[cfg(feature = "hypervisor")]
+        let deadline = deadline.into_iter().chain(self.vstimecmp_deadline()).min();
+        deadline
+    }
+
+    /// Below M-mode stimecmp and vstimecmp need both menvcfg.STCE and mcounteren.TM.
+    pub(super) fn check_sstc(&self, mode: Privilege) -> Result<(), Exception> {
+        let enabled =
+            self.menvcfg & MENVCFG_STCE != 0 && self.counters.accessible(TIME - CYCLE, mode);
+        if mode == Privilege::Machine || enabled {
+            Ok(())
+        } else {
+            Err(Exception::IllegalInstruction(0))
+        }
+    }
+
+    /// The ISA string of a hart with the optional extensions `ext`, e.g.
+    /// `rv64imac_zicsr_...`, built from the current misa so that a disabled
+    /// letter drops out of it.
+    pub fn isa_string(&self, ext: Extensions) -> String {
+        let mut isa = String::from("rv64");
+        isa.extend(
+            ISA_ORDER
+                .iter()
+                .filter(|&&c| self.misa & misa_ext(c) != 0)
+                .map(|c| c.to_ascii_lowercase() as char),
+        );
+        for name in ISA_EXTENSIONS {
+            let enabled = match name {
+                "zcb" => ext.zcb,
+                "zcmp" => ext.zcmp,
+                "zicbom" => ext.zicbom,
+                "zicboz" => ext.zicboz,
+                "zicond" => ext.zicond,
+                "zawrs" => ext.zawrs,
+                "zbkb" => ext.zbkb,
+                "zbkc" => ext.zbkc,
+                "zbkx" => ext.zbkx,
+                "zknd" => ext.zknd,
+                "zkne" => ext.zkne,
+                "zknh" => ext.zknh,
+                _ => true,
+            };
+            if enabled {
+                isa.push('_');
+                isa.push_str(name);
+            }
+        }
+        isa
+    }
+
+    /// csr[9:8] encodes the lowest privilege level allowed to access a csr,
+    /// where the hypervisor and VS csrs at level 2 belong to HS-mode.
+    fn check_privilege(csr: u32, mode: Privilege) -> Result<(), Exception> {
+        let level = match csr >> 8 & 0b11 {
+            2 => Privilege::Supervisor as u32,
+            level => level,
+        };
+        if level > mode as u32 {
+            Err(Exception::IllegalInstruction(0))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_satp(&self, mode: Privilege) -> Result<(), Exception> {
+        if mode == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0 {
+            Err(Exception::IllegalInstruction(0))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn read(&self, csr: u32, mode: Privilege) -> Result<u64, Exception> {
+        Self::check_privilege(csr, mode)?;
+        let value = match csr {
+            SSTATUS => self.mstatus & SSTATUS_RMASK,
+            SIE => self.mie & self.mideleg & IRQ_S,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.counters.scounteren as u64,
+            SENVCFG => self.senvcfg,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.effective_mip() & self.mideleg & IRQ_S,
+            STIMECMP => {
+                self.check_sstc(mode)?;
+                self.stimecmp
+            }
+            SATP => {
+                self.check_satp(mode)?;
+                self.satp
+            }
+
+            MVENDORID | MARCHID | MIMPID => 0,
+            MHARTID => self.mhartid,
+            MSTATUS => self.mstatus,
+            MISA => self.misa,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.counters.mcounteren as u64,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.effective_mip(),
+            MENVCFG => self.menvcfg,
+            // only the even pmpcfg registers exist on RV64
+            PMPCFG0..=PMPCFG15 if csr & 1 == 0 => self.pmp.read_cfg((csr - PMPCFG0) as usize),
+            PMPADDR0..=PMPADDR63 => self.pmp.read_addr((csr - PMPADDR0) as usize),
+            TSELECT => self.triggers.read_tselect(),
+            TDATA1 => self.triggers.read_tdata1(),
+            TDATA2 => self.triggers.read_tdata2(),
+            TDATA3 => self.triggers.read_tdata3(),
+            TINFO => self.triggers.read_tinfo(),
+            #[cfg(feature = "hypervisor")]
+            hypervisor::VSSTATUS..=hypervisor::VSATP
+            | hypervisor::HSTATUS..=hypervisor::HGATP
+            | hypervisor::HGEIP
+            | hypervisor::MTINST
+            | hypervisor::MTVAL2 => self.read_hypervisor(csr, mode)?,
+
+            MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => self.counters.read(csr - MCYCLE),
+            MCOUNTINHIBIT => self.counters.inhibit as u64,
+            MHPMEVENT3..=MHPMEVENT31 => self.counters.event[(csr - MHPMEVENT3) as usize],
+            CYCLE | TIME | INSTRET | HPMCOUNTER3..=HPMCOUNTER31 => {
+                if !self.counters.accessible(csr - CYCLE, mode) {
+                    return Err(Exception::IllegalInstruction(0));
+                }
+                self.counters.read(csr - CYCLE)
+            }
+            _ => return Err(Exception::IllegalInstruction(0)),
+        };
+        Ok(value)
+    }
+
+    pub fn write(&mut self, csr: u32, value: u64, mode: Privilege) -> Result<(), Exception> {
+        Self::check_privilege(csr, mode)?;
+        // csr[11:10] == 0b11 marks a read-only csr
+        if csr >> 10 & 0b11 == 0b11 {
+            return Err(Exception::IllegalInstruction(0));
+        }
+        match csr {
+            SSTATUS => self.mstatus = (self.mstatus & !SSTATUS_WMASK) | (value & SSTATUS_WMASK),
+            SIE => {
+                let mask = self.mideleg & IRQ_S;
+                self.mie = (self.mie & !mask) | (value & mask);
+            }
+            STVEC => self.stvec = value & !0b10,
+            SCOUNTEREN => self.counters.scounteren = value as u32,
+            SENVCFG => self.senvcfg = legal_envcfg(value, ENVCFG_CBO),
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !1,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            SIP => {
+                let mask = self.mideleg & IRQ_SSIP;
+                self.mip = (self.mip & !mask) | (value & mask);
+            }
+            STIMECMP => {
+                self.check_sstc(mode)?;
+                self.stimecmp = value;
+            }
+            SATP => {
+                self.check_satp(mode)?;
+                // unsupported modes leave satp untouched
+                match value >> SATP_MODE_SHIFT {
+                    SATP_MODE_BARE | SATP_MODE_SV39 => self.satp = value,
+                    _ => {}
+                }
+            }
+
+            MSTATUS => {
+                let mut value = value & MSTATUS_WMASK;
+                // MPP is WARL, the reserved H-mode encoding is not accepted
+                if value & MSTATUS_MPP == 0b10 << MSTATUS_MPP_SHIFT {
+                    value = (value & !MSTATUS_MPP) | (self.mstatus & MSTATUS_MPP);
+                }
+                self.mstatus = (self.mstatus & !MSTATUS_WMASK) | value;
+            }
+            MISA => {}
+            MEDELEG => self.medeleg = value & MEDELEG_WMASK,
+            MIDELEG => self.mideleg = value & IRQ_S | IRQ_VS,
+            MIE => self.mie = value & IRQ_ALL,
+            MTVEC => self.mtvec = value & !0b10,
+            MCOUNTEREN => self.counters.mcounteren = value as u32,
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !1,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            // the machine-level bits are driven by devices only, and STIP
+            // by stimecmp once Sstc is enabled
+            MIP => {
+                let mut mask = IRQ_S | IRQ_VS & IRQ_VSSIP;
+                if self.menvcfg & MENVCFG_STCE != 0 {
+                    mask &= !IRQ_STIP;
+                }
+                self.mip = (self.mip & !mask) | (value & mask);
+            }
+            MENVCFG => self.menvcfg = legal_envcfg(value, MENVCFG_WMASK),
+            PMPCFG0..=PMPCFG15 if csr & 1 == 0 => {
+                self.pmp.write_cfg((csr - PMPCFG0) as usize, value)
+            }
+            PMPADDR0..=PMPADDR63 => self.pmp.write_addr((csr - PMPADDR0) as usize, value),
+            TSELECT => self.triggers.write_tselect(value),
+            TDATA1 => self.triggers.write_tdata1(value),
+            TDATA2 => self.triggers.write_tdata2(value),
+            TDATA3 => self.triggers.write_tdata3(value),
+            TINFO => {}
+            #[cfg(feature = "hypervisor")]
+            hypervisor::VSSTATUS..=hypervisor::VSATP
+            | hypervisor::HSTATUS..=hypervisor::HGATP
+            | hypervisor::MTINST
+            | hypervisor::MTVAL2 => self.write_hypervisor(csr, value, mode)?,
+
+            MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => {
+                self.counters.write(csr - MCYCLE, value)
+            }
+            MCOUNTINHIBIT => self.counters.write_inhibit(value),
+            MHPMEVENT3..=MHPMEVENT31 => self.counters.event[(csr - MHPMEVENT3) as usize] = value,
+            _ => return Err(Exception::IllegalInstruction(0)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privilege() {
+        let mut csr = CsrFile::new(16);
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Supervisor),
+            Err(Exception::IllegalInstruction(0))
+        );
+        assert_eq!(
+            csr.read(SSTATUS, Privilege::User),
+            Err(Exception::IllegalInstruction(0))
+        );
+        assert_eq!(
+            csr.write(MHARTID, 1, Privilege::Machine),
+            Err(Exception::IllegalInstruction(0))
+        );
+        assert_eq!(
+            csr.write(CYCLE, 1, Privilege::Machine),
+            Err(Exception::IllegalInstruction(0))
+        );
+        assert_eq!(
+            csr.read(0x7ff, Privilege::Machine),
+            Err(Exception::IllegalInstruction(0))
+        );
+        // odd pmpcfg registers only exist on RV32
+        assert_eq!(
+            csr.read(PMPCFG0 + 1, Privilege::Machine),
+            Err(Exception::IllegalInstruction(0))
+        );
+    }
+
+    #[test]
+    fn isa_string() {
+        let mut csr = CsrFile::new(16);
+        let h = if cfg!(feature = "hypervisor") {
+            "h"
+        } else {
+            ""
+        };
+        assert_eq!(
+            csr.isa_string(Extensions::default()),
+            format!("rv64imac{h}_zicsr_zifencei_sstc_svnapot_svpbmt")
+        );
+        csr.misa &= !misa_ext(b'C');
+        let ext = Extensions {
+            zcb: true,
+            zicboz: true,
+            zbkb: true,
+            zknh: true,
+            ..Extensions::default()
+        };
+        assert!(csr.isa_string(ext).starts_with(&format!(
+            "rv64ima{h}_zicboz_zicsr_zifencei_zcb_zbkb_zknh_sstc"
+        )));
+    }
+
+    #[test]
+    fn sstatus() {
+        let mut csr = CsrFile::new(16);
+        csr.write(
+            MSTATUS,
+            MSTATUS_MIE | MSTATUS_SIE | 0b11 << MSTATUS_MPP_SHIFT,
+            Privilege::Machine,
+        )
+        .unwrap();
+        assert_eq!(
+            csr.read(SSTATUS, Privilege::Supervisor).unwrap(),
+            MSTATUS_SIE | 0b10 << 32
+        );
+        csr.write(SSTATUS, 0, Privilege::Supervisor).unwrap();
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Machine).unwrap(),
+            MSTATUS_MIE | MSTATUS_MPP | MSTATUS_XL
+        );
+        // MPP=2 is reserved
+        csr.write(MSTATUS, 0b10 << MSTATUS_MPP_SHIFT, Privilege::Machine)
+            .unwrap();
+        assert_eq!(csr.mstatus & MSTATUS_MPP, MSTATUS_MPP);
+    }
+
+    #[test]
+    fn counters() {
+        let mut csr = CsrFile::new(16);
+        csr.counters.tick();
+        csr.counters.retire();
+        csr.counters.time = 1;
+        assert_eq!(csr.read(CYCLE, Privilege::Machine), Ok(1));
+        assert_eq!(
+            csr.read(INSTRET, Privilege::Supervisor),
+            Err(Exception::IllegalInstruction(0))
+        );
+        csr.write(MCOUNTEREN, 0b111, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(INSTRET, Privilege::Supervisor), Ok(1));
+        assert_eq!(
+            csr.read(TIME, Privilege::User),
+            Err(Exception::IllegalInstruction(0))
+        );
+        csr.write(SCOUNTEREN, 0b010, Privilege::Supervisor).unwrap();
+        assert_eq!(csr.read(TIME, Privilege::User), Ok(1));
+        csr.write(MINSTRET, 100, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(INSTRET, Privilege::Machine), Ok(100));
+        csr.write(MHPMEVENT3 + 2, 3, Privilege::Machine).unwrap();
+        assert_eq!(csr.counters.event[2], 3);
+    }
+
+    #[test]
+    fn sstc() {
+        let mut csr = CsrFile::new(16);
+        let (m, s) = (Privilege::Machine, Privilege::Supervisor);
+        let illegal = Exception::IllegalInstruction(0);
+        assert_eq!(csr.read(STIMECMP, s), Err(illegal));
+        csr.write(MENVCFG, MENVCFG_STCE, m).unwrap();
+        assert_eq!(csr.write(STIMECMP, 100, s), Err(illegal));
+        csr.write(MCOUNTEREN, 0b010, m).unwrap();
+        csr.write(STIMECMP, 100, s).unwrap();
+        assert_eq!(csr.sstc_deadline(), Some(100));
+
+        csr.counters.time = 99;
+        assert_eq!(csr.read(MIP, m), Ok(0));
+        csr.counters.time = 100;
+        assert_eq!(csr.read(MIP, m), Ok(IRQ_STIP));
+        // the comparator owns STIP
+        csr.write(MIP, 0, m).unwrap();
+        assert_eq!(csr.effective_mip(), IRQ_STIP);
+        csr.write(STIMECMP, u64::MAX, s).unwrap();
+        assert_eq!(csr.effective_mip(), 0);
+
+        csr.write(MENVCFG, 0, m).unwrap();
+        assert_eq!(csr.sstc_deadline(), None);
+        csr.write(MIP, IRQ_STIP, m).unwrap();
+        assert_eq!(csr.effective_mip(), IRQ_STIP);
+    }
+}
@@ -1,6 +1,6 @@
 use super::types::{
-    BType, CsrIType, CsrType, FenceType, Funct3, IType, JType, RType, SType, ShiftType, UType,
-    MASK3, MASK5,
+    BType, CmType, CsrIType, CsrType, FenceType, Funct3, IType, JType, RType, SType, ShiftType,
+    UType, MASK2, MASK3, MASK5, MASK6,
 };
 
 use super::error::DecodeError;
@@ -9,29 +9,66 @@ use super::instruction::Instruction;
 
 pub type DResult = Result<Instruction, DecodeError>;
 
+/// Optional extensions the decoder recognizes, off unless the machine enables
+/// them: Zcmp takes over the encodings of C.FSDSP, and code built for a hart
+/// without the others expects their encodings to trap.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Extensions {
+    /// c.lbu, c.lh(u), c.sb, c.sh, c.zext.b, c.zext.h, c.not and c.mul
+    pub zcb: bool,
+    /// cm.push, cm.pop, cm.popret(z), cm.mvsa01 and cm.mva01s
+    pub zcmp: bool,
+    /// cbo.clean, cbo.flush and cbo.inval
+    pub zicbom: bool,
+    /// cbo.zero
+    pub zicboz: bool,
+    /// czero.eqz and czero.nez
+    pub zicond: bool,
+    /// wrs.nto and wrs.sto
+    pub zawrs: bool,
+    /// the bit manipulation for cryptography: rotates, andn, orn, xnor, the
+    /// packs, rev8 and brev8
+    pub zbkb: bool,
+    /// clmul and clmulh
+    pub zbkc: bool,
+    /// xperm4 and xperm8
+    pub zbkx: bool,
+    /// the AES decryption rounds and key schedule
+    pub zknd: bool,
+    /// the AES encryption rounds and key schedule
+    pub zkne: bool,
+    /// the SHA-256 and SHA-512 sigma and sum functions
+    pub zknh: bool,
+}
+
+/// Decode the base ISA and the extensions that are always present.
 pub fn decode(i: u32) -> DResult {
+    decode_ext(i, Extensions::default())
+}
+
+pub fn decode_ext(i: u32, ext: Extensions) -> DResult {
     match i & 0b11 {
-        0b00 => decode_compressed_00(i),
-        0b01 => decode_compressed_01(i),
-        0b10 => decode_compressed_10(i),
+        0b00 => decode_compressed_00(i, ext),
+        0b01 => decode_compressed_01(i, ext),
+        0b10 => decode_compressed_10(i, ext),
         0b11 => {
             match (i >> 2) & MASK5 {
                 0b00000 => decode_load(i),
                 0b00001 => Err(DecodeError::Unimplemented), // Load-FP
                 0b00010 => Err(DecodeError::Custom),
-                0b00011 => decode_misc_mem(i),
-                0b00100 => decode_op_imm(i),
+                0b00011 => decode_misc_mem(i, ext),
+                0b00100 => decode_op_imm(i, ext),
                 0b00101 => Ok(Instruction::Auipc(UType(i))),
-                0b00110 => decode_op_imm32(i),
+                0b00110 => decode_op_imm32(i, ext),
                 0b00111 => Err(DecodeError::Reserved), // 48bit instruction
 
                 0b01000 => decode_store(i),
                 0b01001 => Err(DecodeError::Unimplemented), // Store-FP
                 0b01010 => Err(DecodeError::Custom),
-                0b01011 => Err(DecodeError::Unimplemented), // AMO
-                0b01100 => decode_op(i),
+                0b01011 => decode_amo(i),
+                0b01100 => decode_op(i, ext),
                 0b01101 => Ok(Instruction::Lui(UType(i))),
-                0b01110 => decode_op32(i),
+                0b01110 => decode_op32(i, ext),
                 0b01111 => Err(DecodeError::Reserved), // 64bit instruction
 
                 0b10000 => Err(DecodeError::Unimplemented), // MADD
@@ -47,7 +84,7 @@ pub fn decode(i: u32) -> DResult {
                 0b11001 => Ok(Instruction::Jalr(IType(i))),
                 0b11010 => Err(DecodeError::Reserved),
                 0b11011 => Ok(Instruction::Jal(JType(i))),
-                0b11100 => decode_system(i),
+                0b11100 => decode_system(i, ext),
                 0b11101 => Err(DecodeError::Reserved),
                 0b11110 => Err(DecodeError::Custom),
                 0b11111 => Err(DecodeError::Reserved), // >= 80bit instruction
@@ -58,7 +95,8 @@ pub fn decode(i: u32) -> DResult {
     }
 }
 
-pub fn decode_compressed_00(i: u32) -> DResult {
+#[allow(clippy::unusual_byte_groupings)]
+pub fn decode_compressed_00(i: u32, ext: Extensions) -> DResult {
     let real = i as u16;
     match real.funct3() {
         0b000 if i == 0 => Ok(Instruction::Illegal),
@@ -80,12 +118,13 @@ pub fn decode_compressed_00(i: u32) -> DResult {
             | ((i & 0x1c) << 5)       // rd[2:0]
             | 0b_01000_011_01000_0000011,
         ))),
+        0b100 if ext.zcb => decode_zcb_00(real),
         0b100 => Err(DecodeError::Unimplemented), // reserved
         0b101 => Err(DecodeError::Unimplemented), // C.FSD
         0b110 => Ok(Instruction::Sw(SType(
             // C.SW
             ((i & 0x1000) << 13)      // imm[5]
-            | ((i & 0xc00))           // imm[4:3]
+            | (i & 0xc00)             // imm[4:3]
             | ((i & 0x380) << 8)      // rs1[2:0]
             | ((i & 0x40) << 3)       // imm[2]
             | ((i & 0x20) << 21)      // imm[6]
@@ -95,7 +134,7 @@ pub fn decode_compressed_00(i: u32) -> DResult {
         0b111 => Ok(Instruction::Sd(SType(
             // C.SD (C.FSW in RV32)
             ((i & 0x1000) << 13)      // imm[5]
-            | ((i & 0xc00))           // imm[4:3]
+            | (i & 0xc00)             // imm[4:3]
             | ((i & 0x380) << 8)      // rs1[2:0]
             | ((i & 0x60) << 21)      // imm[7:6]
             | ((i & 0x1c) << 18)      // rs2[2:0]
@@ -105,7 +144,7 @@ pub fn decode_compressed_00(i: u32) -> DResult {
     }
 }
 
-pub fn decode_compressed_01(i: u32) -> DResult {
+pub fn decode_compressed_01(i: u32, ext: Extensions) -> DResult {
     let i = i as u16;
     match i.funct3() {
         0b000 if (i >> 7 & 0b11111) == 0 => Err(DecodeError::Unimplemented),
@@ -113,6 +152,10 @@ pub fn decode_compressed_01(i: u32) -> DResult {
         0b001 => Err(DecodeError::Unimplemented),
         0b010 => Err(DecodeError::Unimplemented),
         0b011 => Err(DecodeError::Unimplemented),
+        // funct6 100111 shares C.SUBW and C.ADDW with Zcb, which sets bit 6
+        0b100 if ext.zcb && (i as u32) >> 10 & MASK3 == 0b111 && i >> 6 & 1 != 0 => {
+            decode_zcb_01(i)
+        }
         0b100 => Err(DecodeError::Unimplemented),
         0b101 => Err(DecodeError::Unimplemented),
         0b110 => Err(DecodeError::Unimplemented),
@@ -121,7 +164,7 @@ pub fn decode_compressed_01(i: u32) -> DResult {
     }
 }
 
-pub fn decode_compressed_10(i: u32) -> DResult {
+pub fn decode_compressed_10(i: u32, ext: Extensions) -> DResult {
     let i = i as u16;
     match i.funct3() {
         0b000 => Err(DecodeError::Unimplemented),
@@ -129,13 +172,94 @@ pub fn decode_compressed_10(i: u32) -> DResult {
         0b010 => Err(DecodeError::Unimplemented),
         0b011 => Err(DecodeError::Unimplemented),
         0b100 => Err(DecodeError::Unimplemented),
-        0b101 => Err(DecodeError::Unimplemented),
+        0b101 if ext.zcmp => decode_zcmp(i),
+        0b101 => Err(DecodeError::Unimplemented), // C.FSDSP
         0b110 => Err(DecodeError::Unimplemented),
         0b111 => Err(DecodeError::Unimplemented),
         _ => Err(DecodeError::Unimplemented),
     }
 }
 
+/// The register x8-x15 named by the 3-bit field at `shift`.
+fn creg(i: u16, shift: u32) -> u32 {
+    (i as u32) >> shift & MASK3 | 8
+}
+
+/// Zcb byte and halfword loads and stores, expanded like the base C forms.
+#[allow(clippy::unusual_byte_groupings)]
+fn decode_zcb_00(i: u16) -> DResult {
+    let (rs1, rd) = (creg(i, 7), creg(i, 2));
+    // uimm[0] is bit 6 for bytes, where halfwords have the lh/lhu select
+    let uimm = (i as u32) >> 4 & 0b10;
+    let byte = uimm | (i as u32) >> 6 & 1;
+    let signed = i >> 6 & 1 != 0;
+    match (i as u32) >> 10 & MASK6 {
+        0b100000 => Ok(Instruction::Lbu(IType(
+            byte << 20 | rs1 << 15 | rd << 7 | 0b_100_00000_0000011,
+        ))),
+        0b100001 if signed => Ok(Instruction::Lh(IType(
+            uimm << 20 | rs1 << 15 | rd << 7 | 0b_001_00000_0000011,
+        ))),
+        0b100001 => Ok(Instruction::Lhu(IType(
+            uimm << 20 | rs1 << 15 | rd << 7 | 0b_101_00000_0000011,
+        ))),
+        0b100010 => Ok(Instruction::Sb(SType(
+            rd << 20 | rs1 << 15 | byte << 7 | 0b_000_00000_0100011,
+        ))),
+        0b100011 if !signed => Ok(Instruction::Sh(SType(
+            rd << 20 | rs1 << 15 | uimm << 7 | 0b_001_00000_0100011,
+        ))),
+        _ => Err(DecodeError::Unknown),
+    }
+}
+
+/// Zcb c.mul and the unary operations on rd'.
+#[allow(clippy::unusual_byte_groupings)]
+fn decode_zcb_01(i: u16) -> DResult {
+    let rd = creg(i, 7);
+    match ((i as u32) >> 5 & MASK2, (i as u32) >> 2 & MASK3) {
+        (0b10, _) => Ok(Instruction::Mul(RType(
+            0b0000001 << 25 | creg(i, 2) << 20 | rd << 15 | rd << 7 | 0b_000_00000_0110011,
+        ))),
+        // c.zext.b: andi rd, rd, 0xff
+        (0b11, 0b000) => Ok(Instruction::Andi(IType(
+            0xff << 20 | rd << 15 | rd << 7 | 0b_111_00000_0010011,
+        ))),
+        // c.zext.h: zext.h on RV64 is packw rd, rd, x0
+        (0b11, 0b010) => Ok(Instruction::Packw(RType(
+            0b0000100 << 25 | rd << 15 | rd << 7 | 0b_100_00000_0111011,
+        ))),
+        // c.not: xori rd, rd, -1
+        (0b11, 0b101) => Ok(Instruction::Xori(IType(
+            0xfff << 20 | rd << 15 | rd << 7 | 0b_100_00000_0010011,
+        ))),
+        // c.sext.b, c.sext.h and c.zext.w expand to Zbb and Zba instructions
+        (0b11, 0b001 | 0b011 | 0b100) => Err(DecodeError::Unimplemented),
+        _ => Err(DecodeError::Reserved),
+    }
+}
+
+fn decode_zcmp(i: u16) -> DResult {
+    let c = CmType(i);
+    if (i as u32) >> 10 & MASK3 == 0b011 {
+        return match (i as u32) >> 5 & MASK2 {
+            0b01 if c.r1s() != c.r2s() => Ok(Instruction::CmMvsa01(c)),
+            0b11 => Ok(Instruction::CmMva01s(c)),
+            _ => Err(DecodeError::Reserved),
+        };
+    }
+    if c.rlist() < 4 {
+        return Err(DecodeError::Reserved);
+    }
+    match (i as u32) >> 8 & MASK5 {
+        0b11000 => Ok(Instruction::CmPush(c)),
+        0b11010 => Ok(Instruction::CmPop(c)),
+        0b11100 => Ok(Instruction::CmPopretz(c)),
+        0b11110 => Ok(Instruction::CmPopret(c)),
+        _ => Err(DecodeError::Unknown),
+    }
+}
+
 fn decode_load(i: u32) -> DResult {
     // get the funct
     match (i >> 12) & MASK3 {
@@ -151,29 +275,53 @@ fn decode_load(i: u32) -> DResult {
     }
 }
 
-fn decode_misc_mem(i: u32) -> DResult {
+fn decode_misc_mem(i: u32, ext: Extensions) -> DResult {
     if i == 0b001000000001111 {
         Ok(Instruction::FenceI)
     } else if i & 0xf00fff80 == 0 {
         Ok(Instruction::Fence(FenceType(i)))
+    } else if i & 0x7fff == 0x200f {
+        // cbo.*: rd is zero, imm selects the operation on the block at rs1
+        match (i >> 20, ext.zicbom, ext.zicboz) {
+            (0, true, _) => Ok(Instruction::CboInval(IType(i))),
+            (1, true, _) => Ok(Instruction::CboClean(IType(i))),
+            (2, true, _) => Ok(Instruction::CboFlush(IType(i))),
+            (4, _, true) => Ok(Instruction::CboZero(IType(i))),
+            (0..=2 | 4, _, _) => Err(DecodeError::Unimplemented),
+            _ => Err(DecodeError::Reserved),
+        }
     } else {
         Err(DecodeError::Reserved)
     }
 }
 
-fn decode_op_imm(i: u32) -> DResult {
+fn decode_op_imm(i: u32, ext: Extensions) -> DResult {
     match (i >> 12) & MASK3 {
         0b000 => Ok(Instruction::Addi(IType(i))),
-        0b001 => match i >> 26 {
-            0 => Ok(Instruction::Slli(ShiftType(i))),
+        0b001 => match (i >> 26, i >> 20) {
+            (0, _) => Ok(Instruction::Slli(ShiftType(i))),
+            (_, 0x100) if ext.zknh => Ok(Instruction::Sha256sum0(IType(i))),
+            (_, 0x101) if ext.zknh => Ok(Instruction::Sha256sum1(IType(i))),
+            (_, 0x102) if ext.zknh => Ok(Instruction::Sha256sig0(IType(i))),
+            (_, 0x103) if ext.zknh => Ok(Instruction::Sha256sig1(IType(i))),
+            (_, 0x104) if ext.zknh => Ok(Instruction::Sha512sum0(IType(i))),
+            (_, 0x105) if ext.zknh => Ok(Instruction::Sha512sum1(IType(i))),
+            (_, 0x106) if ext.zknh => Ok(Instruction::Sha512sig0(IType(i))),
+            (_, 0x107) if ext.zknh => Ok(Instruction::Sha512sig1(IType(i))),
+            (_, 0x300) if ext.zknd => Ok(Instruction::Aes64im(IType(i))),
+            // rnum above 0xa is reserved
+            (_, 0x310..=0x31a) if ext.zkne || ext.zknd => Ok(Instruction::Aes64ks1i(IType(i))),
             _ => Err(DecodeError::Unknown),
         },
         0b010 => Ok(Instruction::Slti(IType(i))),
         0b011 => Ok(Instruction::Sltiu(IType(i))),
         0b100 => Ok(Instruction::Xori(IType(i))),
-        0b101 => match i >> 26 {
-            0b000000 => Ok(Instruction::Srli(ShiftType(i))),
-            0b010000 => Ok(Instruction::Srai(ShiftType(i))),
+        0b101 => match (i >> 26, i >> 20) {
+            (_, 0x687) if ext.zbkb => Ok(Instruction::Brev8(IType(i))),
+            (_, 0x6b8) if ext.zbkb => Ok(Instruction::Rev8(IType(i))),
+            (0b000000, _) => Ok(Instruction::Srli(ShiftType(i))),
+            (0b010000, _) => Ok(Instruction::Srai(ShiftType(i))),
+            (0b011000, _) if ext.zbkb => Ok(Instruction::Rori(ShiftType(i))),
             _ => Err(DecodeError::Unknown),
         },
         0b110 => Ok(Instruction::Ori(IType(i))),
@@ -182,12 +330,13 @@ fn decode_op_imm(i: u32) -> DResult {
     }
 }
 
-fn decode_op_imm32(i: u32) -> DResult {
+fn decode_op_imm32(i: u32, ext: Extensions) -> DResult {
     match (i >> 25, (i >> 12) & MASK3) {
         (_, 0b000) => Ok(Instruction::Addiw(IType(i))),
         (0b0000000, 0b001) => Ok(Instruction::Slliw(ShiftType(i))),
         (0b0000000, 0b101) => Ok(Instruction::Srliw(ShiftType(i))),
         (0b0100000, 0b101) => Ok(Instruction::Sraiw(ShiftType(i))),
+        (0b0110000, 0b101) if ext.zbkb => Ok(Instruction::Roriw(ShiftType(i))),
         _ => Err(DecodeError::Unknown),
     }
 }
@@ -202,7 +351,36 @@ fn decode_store(i: u32) -> DResult {
     }
 }
 
-fn decode_op(i: u32) -> DResult {
+fn decode_amo(i: u32) -> DResult {
+    match (i >> 27, (i >> 12) & MASK3) {
+        (0b00010, 0b010) if (i >> 20) & MASK5 == 0 => Ok(Instruction::LrW(RType(i))),
+        (0b00011, 0b010) => Ok(Instruction::ScW(RType(i))),
+        (0b00001, 0b010) => Ok(Instruction::AmoswapW(RType(i))),
+        (0b00000, 0b010) => Ok(Instruction::AmoaddW(RType(i))),
+        (0b00100, 0b010) => Ok(Instruction::AmoxorW(RType(i))),
+        (0b01100, 0b010) => Ok(Instruction::AmoandW(RType(i))),
+        (0b01000, 0b010) => Ok(Instruction::AmoorW(RType(i))),
+        (0b10000, 0b010) => Ok(Instruction::AmominW(RType(i))),
+        (0b10100, 0b010) => Ok(Instruction::AmomaxW(RType(i))),
+        (0b11000, 0b010) => Ok(Instruction::AmominuW(RType(i))),
+        (0b11100, 0b010) => Ok(Instruction::AmomaxuW(RType(i))),
+
+        (0b00010, 0b011) if (i >> 20) & MASK5 == 0 => Ok(Instruction::LrD(RType(i))),
+        (0b00011, 0b011) => Ok(Instruction::ScD(RType(i))),
+        (0b00001, 0b011) => Ok(Instruction::AmoswapD(RType(i))),
+        (0b00000, 0b011) => Ok(Instruction::AmoaddD(RType(i))),
+        (0b00100, 0b011) => Ok(Instruction::AmoxorD(RType(i))),
+        (0b01100, 0b011) => Ok(Instruction::AmoandD(RType(i))),
+        (0b01000, 0b011) => Ok(Instruction::AmoorD(RType(i))),
+        (0b10000, 0b011) => Ok(Instruction::AmominD(RType(i))),
+        (0b10100, 0b011) => Ok(Instruction::AmomaxD(RType(i))),
+        (0b11000, 0b011) => Ok(Instruction::AmominuD(RType(i))),
+        (0b11100, 0b011) => Ok(Instruction::AmomaxuD(RType(i))),
+        _ => Err(DecodeError::Unknown),
+    }
+}
+
+fn decode_op(i: u32, ext: Extensions) -> DResult {
     match (i >> 25, (i >> 12) & MASK3) {
         (0b0000000, 0b000) => Ok(Instruction::Add(RType(i))),
         (0b0100000, 0b000) => Ok(Instruction::Sub(RType(i))),
@@ -223,11 +401,32 @@ fn decode_op(i: u32) -> DResult {
         (0b0000001, 0b101) => Ok(Instruction::Divu(RType(i))),
         (0b0000001, 0b110) => Ok(Instruction::Rem(RType(i))),
         (0b0000001, 0b111) => Ok(Instruction::Remu(RType(i))),
+
+        (0b0100000, 0b111) if ext.zbkb => Ok(Instruction::Andn(RType(i))),
+        (0b0100000, 0b110) if ext.zbkb => Ok(Instruction::Orn(RType(i))),
+        (0b0100000, 0b100) if ext.zbkb => Ok(Instruction::Xnor(RType(i))),
+        (0b0110000, 0b001) if ext.zbkb => Ok(Instruction::Rol(RType(i))),
+        (0b0110000, 0b101) if ext.zbkb => Ok(Instruction::Ror(RType(i))),
+        (0b0000100, 0b100) if ext.zbkb => Ok(Instruction::Pack(RType(i))),
+        (0b0000100, 0b111) if ext.zbkb => Ok(Instruction::Packh(RType(i))),
+        (0b0000101, 0b001) if ext.zbkc => Ok(Instruction::Clmul(RType(i))),
+        (0b0000101, 0b011) if ext.zbkc => Ok(Instruction::Clmulh(RType(i))),
+        (0b0010100, 0b010) if ext.zbkx => Ok(Instruction::Xperm4(RType(i))),
+        (0b0010100, 0b100) if ext.zbkx => Ok(Instruction::Xperm8(RType(i))),
+        (0b0011001, 0b000) if ext.zkne => Ok(Instruction::Aes64es(RType(i))),
+        (0b0011011, 0b000) if ext.zkne => Ok(Instruction::Aes64esm(RType(i))),
+        (0b0011101, 0b000) if ext.zknd => Ok(Instruction::Aes64ds(RType(i))),
+        (0b0011111, 0b000) if ext.zknd => Ok(Instruction::Aes64dsm(RType(i))),
+        (0b0111111, 0b000) if ext.zkne || ext.zknd => Ok(Instruction::Aes64ks2(RType(i))),
+
+        (0b0000111, 0b101) if ext.zicond => Ok(Instruction::CzeroEqz(RType(i))),
+        (0b0000111, 0b111) if ext.zicond => Ok(Instruction::CzeroNez(RType(i))),
+        (0b0000111, 0b101 | 0b111) => Err(DecodeError::Unimplemented),
         _ => Err(DecodeError::Unknown),
     }
 }
 
-fn decode_op32(i: u32) -> DResult {
+fn decode_op32(i: u32, ext: Extensions) -> DResult {
     match (i >> 25, (i >> 12) & MASK3) {
         (0b0000000, 0b000) => Ok(Instruction::Addw(RType(i))),
         (0b0100000, 0b000) => Ok(Instruction::Subw(RType(i))),
@@ -240,6 +439,10 @@ fn decode_op32(i: u32) -> DResult {
         (0b0000001, 0b101) => Ok(Instruction::Divuw(RType(i))),
         (0b0000001, 0b110) => Ok(Instruction::Remw(RType(i))),
         (0b0000001, 0b111) => Ok(Instruction::Remuw(RType(i))),
+
+        (0b0110000, 0b001) if ext.zbkb => Ok(Instruction::Rolw(RType(i))),
+        (0b0110000, 0b101) if ext.zbkb => Ok(Instruction::Rorw(RType(i))),
+        (0b0000100, 0b100) if ext.zbkb => Ok(Instruction::Packw(RType(i))),
         _ => Err(DecodeError::Unknown),
     }
 }
@@ -258,7 +461,8 @@ fn decode_branch(i: u32) -> DResult {
     }
 }
 
-fn decode_system(i: u32) -> DResult {
+#[allow(clippy::unusual_byte_groupings)]
+fn decode_system(i: u32, ext: Extensions) -> DResult {
     match i {
         // Environment Call and Breakpoint
         0b000000000000_00000_000_00000_1110011 => return Ok(Instruction::Ecall),
@@ -269,6 +473,9 @@ fn decode_system(i: u32) -> DResult {
         0b0011000_00010_00000_000_00000_1110011 => return Ok(Instruction::Mret),
         // Interrupt-Management Instructions
         0b0001000_00101_00000_000_00000_1110011 => return Ok(Instruction::Wfi),
+        // Wait-on-Reservation-Set Instructions
+        0b000000001101_00000_000_00000_1110011 if ext.zawrs => return Ok(Instruction::WrsNto),
+        0b000000011101_00000_000_00000_1110011 if ext.zawrs => return Ok(Instruction::WrsSto),
         _ => {}
     }
 
@@ -289,6 +496,37 @@ fn decode_system(i: u32) -> DResult {
         return Ok(Instruction::SfenceVma(RType(i)));
     }
 
+    // Hypervisor Memory-Management Instructions, illegal unless the
+    // hypervisor extension is built in
+    const HFENCE_VVMA_VALUE: u32 = 0b0010001_00000_00000_000_00000_1110011;
+    const HFENCE_GVMA_VALUE: u32 = 0b0110001_00000_00000_000_00000_1110011;
+    match i & SFENCE_VMA_MASK {
+        HFENCE_VVMA_VALUE => return Ok(Instruction::HfenceVvma(RType(i))),
+        HFENCE_GVMA_VALUE => return Ok(Instruction::HfenceGvma(RType(i))),
+        _ => {}
+    }
+
+    // Hypervisor Virtual-Machine Load and Store Instructions
+    if (i >> 12) & MASK3 == 0b100 {
+        let r = RType(i);
+        match (r.simm(), r.rs2()) {
+            (0b0110000, 0b00000) => return Ok(Instruction::HlvB(r)),
+            (0b0110000, 0b00001) => return Ok(Instruction::HlvBu(r)),
+            (0b0110010, 0b00000) => return Ok(Instruction::HlvH(r)),
+            (0b0110010, 0b00001) => return Ok(Instruction::HlvHu(r)),
+            (0b0110010, 0b00011) => return Ok(Instruction::HlvxHu(r)),
+            (0b0110100, 0b00000) => return Ok(Instruction::HlvW(r)),
+            (0b0110100, 0b00001) => return Ok(Instruction::HlvWu(r)),
+            (0b0110100, 0b00011) => return Ok(Instruction::HlvxWu(r)),
+            (0b0110110, 0b00000) => return Ok(Instruction::HlvD(r)),
+            (0b0110001, _) if r.rd() == 0 => return Ok(Instruction::HsvB(r)),
+            (0b0110011, _) if r.rd() == 0 => return Ok(Instruction::HsvH(r)),
+            (0b0110101, _) if r.rd() == 0 => return Ok(Instruction::HsvW(r)),
+            (0b0110111, _) if r.rd() == 0 => return Ok(Instruction::HsvD(r)),
+            _ => {}
+        }
+    }
+
     Err(DecodeError::Unknown)
 }
 
@@ -393,6 +631,57 @@ mod tests {
         assert_eq!(decode(0x4070d09b).unwrap(), Sraiw(ShiftType(0x4070d09b))); // sraiw x1,x1,0x7
     }
 
+    #[test]
+    fn crypto() {
+        let ext = Extensions {
+            zbkb: true,
+            zbkc: true,
+            zbkx: true,
+            zknd: true,
+            zkne: true,
+            zknh: true,
+            ..Extensions::default()
+        };
+        let zkn = |raw| decode_ext(raw, ext);
+        assert_eq!(zkn(0x4020f1b3).unwrap(), Andn(RType(0x4020f1b3))); // andn x3,x1,x2
+        assert_eq!(zkn(0x4020c1b3).unwrap(), Xnor(RType(0x4020c1b3))); // xnor x3,x1,x2
+        assert_eq!(zkn(0x6020d1b3).unwrap(), Ror(RType(0x6020d1b3))); // ror x3,x1,x2
+        assert_eq!(zkn(0x63c0d193).unwrap(), Rori(ShiftType(0x63c0d193))); // rori x3,x1,0x3c
+        assert_eq!(zkn(0x602091bb).unwrap(), Rolw(RType(0x602091bb))); // rolw x3,x1,x2
+        assert_eq!(zkn(0x6040d19b).unwrap(), Roriw(ShiftType(0x6040d19b))); // roriw x3,x1,0x4
+        assert_eq!(zkn(0x0820c1b3).unwrap(), Pack(RType(0x0820c1b3))); // pack x3,x1,x2
+        assert_eq!(zkn(0x0820f1b3).unwrap(), Packh(RType(0x0820f1b3))); // packh x3,x1,x2
+        assert_eq!(zkn(0x0820c1bb).unwrap(), Packw(RType(0x0820c1bb))); // packw x3,x1,x2
+        assert_eq!(zkn(0x6870d193).unwrap(), Brev8(IType(0x6870d193))); // brev8 x3,x1
+        assert_eq!(zkn(0x6b80d193).unwrap(), Rev8(IType(0x6b80d193))); // rev8 x3,x1
+        assert_eq!(zkn(0x0a2091b3).unwrap(), Clmul(RType(0x0a2091b3))); // clmul x3,x1,x2
+        assert_eq!(zkn(0x0a20b1b3).unwrap(), Clmulh(RType(0x0a20b1b3))); // clmulh x3,x1,x2
+        assert_eq!(zkn(0x2820c1b3).unwrap(), Xperm8(RType(0x2820c1b3))); // xperm8 x3,x1,x2
+        assert_eq!(zkn(0x362081b3).unwrap(), Aes64esm(RType(0x362081b3))); // aes64esm x3,x1,x2
+        assert_eq!(zkn(0x3a2081b3).unwrap(), Aes64ds(RType(0x3a2081b3))); // aes64ds x3,x1,x2
+        assert_eq!(zkn(0x30009193).unwrap(), Aes64im(IType(0x30009193))); // aes64im x3,x1
+        assert_eq!(zkn(0x31a09193).unwrap(), Aes64ks1i(IType(0x31a09193))); // aes64ks1i x3,x1,0xa
+        assert_eq!(zkn(0x31b09193), Err(DecodeError::Unknown)); // aes64ks1i x3,x1,0xb
+        assert_eq!(zkn(0x7e2081b3).unwrap(), Aes64ks2(RType(0x7e2081b3))); // aes64ks2 x3,x1,x2
+        assert_eq!(zkn(0x10209193).unwrap(), Sha256sig0(IType(0x10209193))); // sha256sig0 x3,x1
+        assert_eq!(zkn(0x10509193).unwrap(), Sha512sum1(IType(0x10509193))); // sha512sum1 x3,x1
+
+        // each encoding traps unless its own extension is there
+        for raw in [
+            0x4020f1b3, 0x0a2091b3, 0x2820c1b3, 0x362081b3, 0x3a2081b3, 0x10209193,
+        ] {
+            assert!(decode(raw).is_err());
+        }
+        let zkne = Extensions {
+            zkne: true,
+            ..Extensions::default()
+        };
+        assert!(decode_ext(0x362081b3, zkne).is_ok()); // aes64esm x3,x1,x2
+        assert!(decode_ext(0x3a2081b3, zkne).is_err()); // aes64ds x3,x1,x2
+        assert!(decode_ext(0x31a09193, zkne).is_ok()); // aes64ks1i x3,x1,0xa
+        assert!(decode_ext(0x4020f1b3, zkne).is_err()); // andn x3,x1,x2
+    }
+
     #[test]
     fn store() {
         assert_eq!(decode(0x00e78023).unwrap(), Sb(SType(0x00e78023))); // sb x14,0(x15)
@@ -409,6 +698,18 @@ mod tests {
         assert_eq!(decode(0x00f6b423).unwrap(), Sd(SType(0x00f6b423))); // sd x15,8(x13)
     }
 
+    #[test]
+    fn amo() {
+        assert_eq!(decode(0x1005272f).unwrap(), LrW(RType(0x1005272f))); // lr.w x14,(x10)
+        assert_eq!(decode(0x18e5272f).unwrap(), ScW(RType(0x18e5272f))); // sc.w x14,x14,(x10)
+        assert_eq!(decode(0x0ef4b7af).unwrap(), AmoswapD(RType(0x0ef4b7af))); // amoswap.d.aqrl x15,x15,(x9)
+        assert_eq!(decode(0x00f6a02f).unwrap(), AmoaddW(RType(0x00f6a02f))); // amoadd.w x0,x15,(x13)
+        assert_eq!(decode(0x60b6b6af).unwrap(), AmoandD(RType(0x60b6b6af))); // amoand.d x13,x11,(x13)
+        assert_eq!(decode(0xe0b6a6af).unwrap(), AmomaxuW(RType(0xe0b6a6af))); // amomaxu.w x13,x11,(x13)
+        assert_eq!(decode(0x1405372f).unwrap(), LrD(RType(0x1405372f))); // lr.d.aq x14,(x10)
+        assert_eq!(decode(0x10b5272f), Err(DecodeError::Unknown)); // lr.w with rs2 != 0
+    }
+
     #[test]
     fn op() {
         assert_eq!(decode(0x00c58633).unwrap(), Add(RType(0x00c58633))); // add x12,x11,x12
@@ -529,6 +830,10 @@ mod tests {
         assert_eq!(decode(0x10200073).unwrap(), Sret); // sret
         assert_eq!(decode(0x30200073).unwrap(), Mret); // mret
         assert_eq!(decode(0x10500073).unwrap(), Wfi); // wfi
+        assert_eq!(decode(0x62000073).unwrap(), HfenceGvma(RType(0x62000073))); // hfence.gvma
+        assert_eq!(decode(0x6c05c573).unwrap(), HlvD(RType(0x6c05c573))); // hlv.d x10,(x11)
+        assert_eq!(decode(0x6835c573).unwrap(), HlvxWu(RType(0x6835c573))); // hlvx.wu x10,(x11)
+        assert_eq!(decode(0x6aa5c073).unwrap(), HsvW(RType(0x6aa5c073))); // hsv.w x10,(x11)
         assert_eq!(decode(0x10569073).unwrap(), Csrrw(CsrType(0x10569073))); // csrrw x0,stvec,x13
         assert_eq!(decode(0x18079073).unwrap(), Csrrw(CsrType(0x18079073))); // csrrw x0,satp,x15
         assert_eq!(decode(0x10551073).unwrap(), Csrrw(CsrType(0x10551073))); // csrrw x0,stvec,x10
@@ -545,15 +850,106 @@ mod tests {
 
     #[test]
     fn q00() {
-        assert_eq!(decode_compressed_00(0x6188).unwrap(), Ld(IType(0x0005b503))); // ld a0,0(a1)
-        assert_eq!(decode_compressed_00(0x75e0).unwrap(), Ld(IType(0x0e85b403))); // ld s0,232(a1)
-        assert_eq!(decode_compressed_00(0x43b0).unwrap(), Lw(IType(0x0407a603))); // lw a2,64(a5)
-        assert_eq!(decode_compressed_00(0xe188).unwrap(), Sd(SType(0x00a5b023))); // sd a0,0(a1)
-        assert_eq!(decode_compressed_00(0xf5e0).unwrap(), Sd(SType(0x0e85b423))); // sd s0,232(a1)
-        assert_eq!(decode_compressed_00(0xc3b0).unwrap(), Sw(SType(0x04c7a023)));
+        let ext = Extensions::default();
+        assert_eq!(
+            decode_compressed_00(0x6188, ext).unwrap(),
+            Ld(IType(0x0005b503))
+        ); // ld a0,0(a1)
+        assert_eq!(
+            decode_compressed_00(0x75e0, ext).unwrap(),
+            Ld(IType(0x0e85b403))
+        ); // ld s0,232(a1)
+        assert_eq!(
+            decode_compressed_00(0x43b0, ext).unwrap(),
+            Lw(IType(0x0407a603))
+        ); // lw a2,64(a5)
+        assert_eq!(
+            decode_compressed_00(0xe188, ext).unwrap(),
+            Sd(SType(0x00a5b023))
+        ); // sd a0,0(a1)
+        assert_eq!(
+            decode_compressed_00(0xf5e0, ext).unwrap(),
+            Sd(SType(0x0e85b423))
+        ); // sd s0,232(a1)
+        assert_eq!(
+            decode_compressed_00(0xc3b0, ext).unwrap(),
+            Sw(SType(0x04c7a023))
+        );
         // sw a2,64(a5)
     }
 
+    #[test]
+    fn zcb() {
+        let ext = Extensions {
+            zcb: true,
+            ..Extensions::default()
+        };
+        assert_eq!(decode(0x81e8), Err(DecodeError::Unimplemented));
+        assert_eq!(decode_ext(0x81e8, ext).unwrap(), Lbu(IType(0x0035c503))); // c.lbu a0,3(a1)
+        assert_eq!(decode_ext(0x85e8, ext).unwrap(), Lh(IType(0x00259503))); // c.lh a0,2(a1)
+        assert_eq!(decode_ext(0x8588, ext).unwrap(), Lhu(IType(0x0005d503))); // c.lhu a0,0(a1)
+        assert_eq!(decode_ext(0x89c8, ext).unwrap(), Sb(SType(0x00a580a3))); // c.sb a0,1(a1)
+        assert_eq!(decode_ext(0x8da8, ext).unwrap(), Sh(SType(0x00a59123))); // c.sh a0,2(a1)
+        assert_eq!(decode_ext(0x9d61, ext).unwrap(), Andi(IType(0x0ff57513))); // c.zext.b a0
+        assert_eq!(decode_ext(0x9d69, ext).unwrap(), Packw(RType(0x0805453b))); // c.zext.h a0
+        assert_eq!(decode_ext(0x9d75, ext).unwrap(), Xori(IType(0xfff54513))); // c.not a0
+        assert_eq!(decode_ext(0x9d4d, ext).unwrap(), Mul(RType(0x02b50533))); // c.mul a0,a1
+        assert_eq!(decode_ext(0x9d65, ext), Err(DecodeError::Unimplemented)); // c.sext.b a0
+    }
+
+    #[test]
+    fn zcmp() {
+        let ext = Extensions {
+            zcmp: true,
+            ..Extensions::default()
+        };
+        assert_eq!(decode(0xb862), Err(DecodeError::Unimplemented));
+        assert_eq!(decode_ext(0xb862, ext).unwrap(), CmPush(CmType(0xb862))); // cm.push {ra,s0-s1},-32
+        assert_eq!(CmType(0xb862).regs().collect::<Vec<_>>(), [1, 8, 9]);
+        assert_eq!(CmType(0xb862).stack_adj(), 32);
+        assert_eq!(decode_ext(0xbef6, ext).unwrap(), CmPopret(CmType(0xbef6))); // cm.popret {ra,s0-s11},128
+        assert_eq!(CmType(0xbef6).regs().last(), Some(27));
+        assert_eq!(CmType(0xbef6).stack_adj(), 128);
+        assert_eq!(decode_ext(0xbc42, ext).unwrap(), CmPopretz(CmType(0xbc42))); // cm.popretz {ra},16
+        assert_eq!(decode_ext(0xac26, ext).unwrap(), CmMvsa01(CmType(0xac26))); // cm.mvsa01 s0,s1
+        assert_eq!(decode_ext(0xad66, ext).unwrap(), CmMva01s(CmType(0xad66))); // cm.mva01s s2,s1
+        assert_eq!(CmType(0xad66).r1s(), 18);
+        assert_eq!(decode_ext(0xac22, ext), Err(DecodeError::Reserved)); // cm.mvsa01 s0,s0
+        assert_eq!(decode_ext(0xb832, ext), Err(DecodeError::Reserved)); // rlist 3
+    }
+
+    #[test]
+    fn cbo() {
+        let ext = Extensions {
+            zicbom: true,
+            zicboz: true,
+            ..Extensions::default()
+        };
+        assert_eq!(decode(0x15200f), Err(DecodeError::Unimplemented));
+        let cbo = |raw| decode_ext(raw, ext);
+        assert_eq!(cbo(0x05200f), Ok(CboInval(IType(0x05200f)))); // cbo.inval (a0)
+        assert_eq!(cbo(0x15200f), Ok(CboClean(IType(0x15200f)))); // cbo.clean (a0)
+        assert_eq!(cbo(0x25200f), Ok(CboFlush(IType(0x25200f)))); // cbo.flush (a0)
+        assert_eq!(cbo(0x45200f), Ok(CboZero(IType(0x45200f)))); // cbo.zero (a0)
+        assert_eq!(cbo(0x35200f), Err(DecodeError::Reserved));
+        assert_eq!(cbo(0x45208f), Err(DecodeError::Reserved)); // rd = x1
+    }
+
+    #[test]
+    fn zicond_zawrs() {
+        let ext = Extensions {
+            zicond: true,
+            zawrs: true,
+            ..Extensions::default()
+        };
+        assert_eq!(decode(0x0ec5d533), Err(DecodeError::Unimplemented));
+        assert_eq!(decode(0x00d00073), Err(DecodeError::Unknown));
+        assert_eq!(decode_ext(0x0ec5d533, ext), Ok(CzeroEqz(RType(0x0ec5d533)))); // czero.eqz a0,a1,a2
+        assert_eq!(decode_ext(0x0ec5f533, ext), Ok(CzeroNez(RType(0x0ec5f533)))); // czero.nez a0,a1,a2
+        assert_eq!(decode_ext(0x00d00073, ext), Ok(WrsNto));
+        assert_eq!(decode_ext(0x01d00073, ext), Ok(WrsSto));
+    }
+
     #[test]
     fn test_dummy() {
         // dbg!(decode(0x6545));
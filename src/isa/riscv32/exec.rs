@@ -0,0 +1,1211 @@
+use super::cmo::CacheOp;
+use super::counters::Event;
+use super::crypto;
+use super::csr::{
+    Privilege, IRQ_MEIP, IRQ_MSIP, IRQ_MTIP, IRQ_SEIP, IRQ_SSIP, IRQ_STIP, MSTATUS_MIE,
+    MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPP_SHIFT, MSTATUS_MPRV, MSTATUS_SIE, MSTATUS_SPIE,
+    MSTATUS_SPP, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW, PMPADDR63, PMPCFG0, SATP,
+};
+#[cfg(feature = "hypervisor")]
+use super::csr::{IRQ_VSEIP, IRQ_VSSIP, IRQ_VSTIP};
+use super::decode::decode_ext;
+use super::icache::Decoded;
+use super::instruction::Instruction;
+use super::reg::CpuState;
+use super::trap::{AccessType, Exception};
+use super::types::{BType, RType};
+use crate::memory::bus::Bus;
+use crate::utils::sext;
+
+pub(super) const ILLEGAL: Exception = Exception::IllegalInstruction(0);
+
+/// Interrupts in decreasing priority order.
+#[cfg(not(feature = "hypervisor"))]
+const IRQ_PRIORITY: [u64; 6] = [IRQ_MEIP, IRQ_MSIP, IRQ_MTIP, IRQ_SEIP, IRQ_SSIP, IRQ_STIP];
+#[cfg(feature = "hypervisor")]
+const IRQ_PRIORITY: [u64; 9] = [
+    IRQ_MEIP, IRQ_MSIP, IRQ_MTIP, IRQ_SEIP, IRQ_SSIP, IRQ_STIP, IRQ_VSEIP, IRQ_VSSIP, IRQ_VSTIP,
+];
+
+/// LR/SC reservations cover an aligned doubleword.
+pub const RESERVATION_MASK: u64 = !0b111;
+/// Steps a wrs.sto stalls for at most.
+pub const CONFIG_WRS_TIMEOUT: u64 = 100;
+
+#[inline(always)]
+pub(super) fn trap_vector(tvec: u64, code: u64, interrupt: bool) -> u64 {
+    let base = tvec & !0b11;
+    if interrupt && tvec & 0b11 == 1 {
+        base + 4 * code
+    } else {
+        base
+    }
+}
+
+/// The value an AMO writes back, from the old memory value and rs2, both
+/// sign-extended to 64 bits.
+fn amo_op(inst: Instruction, old: u64, src: u64) -> u64 {
+    use Instruction::*;
+
+    match inst {
+        AmoswapW(_) | AmoswapD(_) => src,
+        AmoaddW(_) | AmoaddD(_) => old.wrapping_add(src),
+        AmoxorW(_) | AmoxorD(_) => old ^ src,
+        AmoandW(_) | AmoandD(_) => old & src,
+        AmoorW(_) | AmoorD(_) => old | src,
+        AmominW(_) | AmominD(_) => (old as i64).min(src as i64) as u64,
+        AmomaxW(_) | AmomaxD(_) => (old as i64).max(src as i64) as u64,
+        AmominuW(_) | AmominuD(_) => old.min(src),
+        _ => old.max(src),
+    }
+}
+
+impl CpuState {
+    /// Take a pending interrupt or fetch, decode and execute one instruction,
+    /// taking a trap if it raises an exception.
+    pub fn exec_once(&mut self, bus: &mut Bus) {
+        self.stores.clear();
+        if self.take_interrupt() {
+            return;
+        }
+        self.exec_step(bus);
+        // self-modifying code
+        for i in 0..self.stores.len() {
+            self.invalidate_decoded(self.stores[i]);
+        }
+    }
+
+    /// Enter the handler of the highest priority pending interrupt, if any.
+    pub(super) fn take_interrupt(&mut self) -> bool {
+        let Some(code) = self.pending_interrupt() else {
+            return false;
+        };
+        self.csr.counters.tick();
+        self.csr.counters.record(Event::Trap);
+        self.trap(code, 0, true);
+        true
+    }
+
+    pub(super) fn exec_step(&mut self, bus: &mut Bus) {
+        self.csr.counters.tick();
+        let mode = self.mode;
+        match self.step(bus) {
+            Ok(()) => {
+                self.csr.counters.retire();
+                if self.csr.triggers.armed() {
+                    self.csr.triggers.retire(mode);
+                }
+            }
+            // a trigger handed the hart to the debug monitor before it executed
+            Err(_) if self.halted => {}
+            Err(e) => self.raise(e),
+        }
+    }
+
+    /// Forget the instructions decoded from the page containing `paddr`.
+    pub fn invalidate_decoded(&mut self, paddr: u64) {
+        self.icache.invalidate(paddr);
+        self.blocks.invalidate(paddr);
+    }
+
+    /// Forget every decoded instruction and softmmu page, needed whenever
+    /// translation or the PMP changes.
+    pub fn flush_decoded(&mut self) {
+        self.icache.flush();
+        self.blocks.flush();
+        self.softmmu.flush();
+    }
+
+    /// The highest priority interrupt that is pending, enabled and not masked
+    /// by the current privilege level.
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.csr.effective_mip() & self.csr.mie;
+        if pending == 0 {
+            return None;
+        }
+        let mstatus = self.csr.mstatus;
+        let m_enabled = self.mode < Privilege::Machine || mstatus & MSTATUS_MIE != 0;
+        // HS-level interrupts are always enabled in a guest
+        let s_enabled = self.mode < Privilege::Supervisor
+            || self.virt()
+            || (self.mode == Privilege::Supervisor && mstatus & MSTATUS_SIE != 0);
+        let m_pending = pending & !self.csr.mideleg;
+        let s_pending = pending & self.csr.mideleg;
+        #[cfg(feature = "hypervisor")]
+        let s_pending = s_pending & !self.csr.h.hideleg;
+        #[allow(unused_mut)]
+        let mut enabled =
+            if m_enabled { m_pending } else { 0 } | if s_enabled { s_pending } else { 0 };
+        #[cfg(feature = "hypervisor")]
+        {
+            enabled |= self.vs_interrupts(pending);
+        }
+        IRQ_PRIORITY
+            .iter()
+            .find(|&&irq| enabled & irq != 0)
+            .map(|irq| irq.trailing_zeros() as u64)
+    }
+
+    fn step(&mut self, bus: &mut Bus) -> Result<(), Exception> {
+        self.check_fetch_triggers()?;
+        let pc = self.pc();
+        let decoded = match self.icache.lookup(pc, self.mode) {
+            Some(decoded) => decoded,
+            None => {
+                let (raw, paddr) = self.fetch(bus)?;
+                let inst = decode_ext(raw, self.extensions)
+                    .map_err(|_| Exception::IllegalInstruction(raw as u64))?;
+                let decoded = Decoded { inst, raw };
+                self.icache.insert(pc, paddr, self.mode, decoded);
+                decoded
+            }
+        };
+        self.check_opcode_triggers(decoded.raw)?;
+        self.execute(decoded.inst, decoded.size(), bus)
+            .map_err(|e| e.with_inst(decoded.raw))
+    }
+
+    pub fn raise(&mut self, e: Exception) {
+        self.csr.counters.record(Event::Trap);
+        #[cfg(feature = "hypervisor")]
+        let gva = e.has_address() && (self.virt || self.hlsv.is_some());
+        self.trap(e.code(), e.tval(), false);
+        #[cfg(feature = "hypervisor")]
+        self.trap_guest_address(e.gpa(), gva);
+    }
+
+    /// Enter the trap handler, in S-mode if the cause is delegated and we aren't in M-mode.
+    pub fn trap(&mut self, code: u64, tval: u64, interrupt: bool) {
+        // a trap ends a stalled wrs, which executes again after the handler
+        self.wrs = None;
+        #[cfg(feature = "hypervisor")]
+        if self.trap_to_vs(code, tval, interrupt) {
+            return;
+        }
+        let pc = self.pc();
+        let cause = code | (interrupt as u64) << 63;
+        let deleg = if interrupt {
+            self.csr.mideleg
+        } else {
+            self.csr.medeleg
+        };
+        let mstatus = self.csr.mstatus;
+        if self.mode <= Privilege::Supervisor && deleg >> code & 1 != 0 {
+            self.csr.sepc = pc;
+            self.csr.scause = cause;
+            self.csr.stval = tval;
+            let mut status = mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+            if mstatus & MSTATUS_SIE != 0 {
+                status |= MSTATUS_SPIE;
+            }
+            if self.mode == Privilege::Supervisor {
+                status |= MSTATUS_SPP;
+            }
+            self.csr.mstatus = status;
+            #[cfg(feature = "hypervisor")]
+            self.trap_to_hs();
+            self.set_pc(trap_vector(self.csr.stvec, code, interrupt));
+            self.mode = Privilege::Supervisor;
+        } else {
+            self.csr.mepc = pc;
+            self.csr.mcause = cause;
+            self.csr.mtval = tval;
+            let mut status = mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+            if mstatus & MSTATUS_MIE != 0 {
+                status |= MSTATUS_MPIE;
+            }
+            status |= (self.mode as u64) << MSTATUS_MPP_SHIFT;
+            self.csr.mstatus = status;
+            #[cfg(feature = "hypervisor")]
+            self.trap_to_m();
+            self.set_pc(trap_vector(self.csr.mtvec, code, interrupt));
+            self.mode = Privilege::Machine;
+        }
+    }
+
+    fn csr_read(&self, csr: u32) -> Result<u64, Exception> {
+        #[cfg(feature = "hypervisor")]
+        if self.virt {
+            return self.guest_csr_read(csr);
+        }
+        self.csr.read(csr, self.mode)
+    }
+
+    fn csr_write(&mut self, csr: u32, value: u64) -> Result<(), Exception> {
+        #[cfg(feature = "hypervisor")]
+        let csr = self.guest_csr(csr)?;
+        self.csr.write(csr, value, self.mode)?;
+        #[cfg(feature = "hypervisor")]
+        if csr == super::hypervisor::VSATP || csr == super::hypervisor::HGATP {
+            self.flush_decoded();
+        }
+        if csr == SATP {
+            self.tlb.flush(None, None);
+        }
+        if csr == SATP || (PMPCFG0..=PMPADDR63).contains(&csr) {
+            self.flush_decoded();
+        }
+        Ok(())
+    }
+
+    fn branch(&mut self, b: BType, npc: &mut u64, taken: bool) {
+        if taken {
+            self.csr.counters.record(Event::BranchTaken);
+            *npc = self.pc().wrapping_add(sext(b.imm() as u64, 13));
+        }
+    }
+
+    /// Execute an LR/SC or AMO of `size` bytes, returning the value written to rd.
+    fn amo(
+        &mut self,
+        inst: Instruction,
+        r: RType,
+        size: usize,
+        bus: &mut Bus,
+    ) -> Result<u64, Exception> {
+        use Instruction::*;
+
+        let vaddr = self.reg(r.rs1());
+        let bits = size as u32 * 8;
+        let is_lr = matches!(inst, LrW(_) | LrD(_));
+        let access = if is_lr {
+            AccessType::Load
+        } else {
+            AccessType::Store
+        };
+        let src = self.reg(r.rs2());
+        if !matches!(inst, ScW(_) | ScD(_)) {
+            self.check_access_triggers(AccessType::Load, vaddr, size, None)?;
+        }
+        if !is_lr {
+            let data = src & (u64::MAX >> (64 - bits));
+            self.check_access_triggers(AccessType::Store, vaddr, size, Some(data))?;
+        }
+        if self.split(vaddr, size, access)? {
+            // reservations are on naturally aligned doublewords
+            if let LrW(_) | LrD(_) | ScW(_) | ScD(_) = inst {
+                return Err(access.misaligned(vaddr));
+            }
+            // nothing else runs in between, so a split AMO is still atomic
+            self.csr.counters.record(Event::Load);
+            let old = sext(self.read_split(vaddr, size, access, bus)?, bits);
+            let new = amo_op(inst, old, sext(src, bits));
+            self.csr.counters.record(Event::Store);
+            self.write_split(vaddr, size, new, bus)?;
+            return Ok(old);
+        }
+        if is_lr {
+            let paddr = self.translate(vaddr, AccessType::Load, bus)?;
+            self.csr.counters.record(Event::Load);
+            let data = self.phys_read(paddr, size, AccessType::Load, self.data_mode(), bus)?;
+            self.reservation = Some(paddr & RESERVATION_MASK);
+            return Ok(sext(data, bits));
+        }
+
+        let paddr = self.translate(vaddr, AccessType::Store, bus)?;
+        if let ScW(_) | ScD(_) = inst {
+            let reserved = self.reservation.take() == Some(paddr & RESERVATION_MASK);
+            if !reserved {
+                return Ok(1);
+            }
+            self.store_paddr(paddr, size, src, bus)?;
+            return Ok(0);
+        }
+
+        let mode = self.data_mode();
+        self.csr.counters.record(Event::Load);
+        let old = sext(
+            self.phys_read(paddr, size, AccessType::Store, mode, bus)?,
+            bits,
+        );
+        let new = amo_op(inst, old, sext(src, bits));
+        self.store_paddr(paddr, size, new, bus)?;
+        Ok(old)
+    }
+
+    /// Whether the wrs.nto or wrs.sto `inst` completes. Until then it stalls,
+    /// executing again every step, while the reservation set is valid and no
+    /// locally enabled interrupt is pending.
+    fn wrs(&mut self, inst: Instruction) -> Result<bool, Exception> {
+        if self.reservation.is_none() || self.csr.effective_mip() & self.csr.mie != 0 {
+            self.wrs = None;
+            return Ok(true);
+        }
+        if inst == Instruction::WrsNto {
+            // the time limit mstatus.TW and hstatus.VTW impose is zero, as for wfi
+            if self.mode != Privilege::Machine && self.csr.mstatus & MSTATUS_TW != 0 {
+                return Err(ILLEGAL);
+            }
+            #[cfg(feature = "hypervisor")]
+            self.check_guest(inst)?;
+        }
+        let steps = self.wrs.map_or(0, |steps| steps + 1);
+        if inst == Instruction::WrsSto && steps >= CONFIG_WRS_TIMEOUT {
+            self.wrs = None;
+            return Ok(true);
+        }
+        self.wrs = Some(steps);
+        Ok(false)
+    }
+
+    fn mret(&mut self) -> u64 {
+        let mstatus = self.csr.mstatus;
+        let mpp = Privilege::from_bits(mstatus >> MSTATUS_MPP_SHIFT);
+        let mut status = (mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) | MSTATUS_MPIE;
+        if mstatus & MSTATUS_MPIE != 0 {
+            status |= MSTATUS_MIE;
+        }
+        if mpp != Privilege::Machine {
+            status &= !MSTATUS_MPRV;
+        }
+        self.csr.mstatus = status;
+        self.mode = mpp;
+        #[cfg(feature = "hypervisor")]
+        self.mret_virt(mpp);
+        self.csr.mepc
+    }
+
+    fn sret(&mut self) -> u64 {
+        #[cfg(feature = "hypervisor")]
+        if self.virt {
+            return self.vs_sret();
+        }
+        let mstatus = self.csr.mstatus;
+        let mut status = (mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | MSTATUS_SPIE;
+        if mstatus & MSTATUS_SPIE != 0 {
+            status |= MSTATUS_SIE;
+        }
+        self.csr.mstatus = status;
+        self.mode = if mstatus & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        #[cfg(feature = "hypervisor")]
+        self.set_virt(self.csr.h.hstatus & super::hypervisor::HSTATUS_SPV != 0);
+        self.csr.sepc
+    }
+
+    /// Execute a decoded instruction of `len` bytes at the current pc.
+    pub fn execute(&mut self, inst: Instruction, len: u64, bus: &mut Bus) -> Result<(), Exception> {
+        use Instruction::*;
+
+        let pc = self.pc();
+        let mut npc = pc.wrapping_add(len);
+        match inst {
+            Lui(u) => self.set_reg(u.rd(), sext((u.imm() << 12) as u64, 32)),
+            Auipc(u) => self.set_reg(u.rd(), pc.wrapping_add(sext((u.imm() << 12) as u64, 32))),
+            Jal(j) => {
+                self.set_reg(j.rd(), npc);
+                npc = pc.wrapping_add(sext(j.imm() as u64, 21));
+            }
+            Jalr(i) => {
+                let target = self.reg(i.rs1()).wrapping_add(sext(i.imm() as u64, 12)) & !1;
+                self.set_reg(i.rd(), npc);
+                npc = target;
+            }
+
+            Beq(b) => self.branch(b, &mut npc, self.reg(b.rs1()) == self.reg(b.rs2())),
+            Bne(b) => self.branch(b, &mut npc, self.reg(b.rs1()) != self.reg(b.rs2())),
+            Blt(b) => self.branch(
+                b,
+                &mut npc,
+                (self.reg(b.rs1()) as i64) < self.reg(b.rs2()) as i64,
+            ),
+            Bge(b) => self.branch(
+                b,
+                &mut npc,
+                self.reg(b.rs1()) as i64 >= self.reg(b.rs2()) as i64,
+            ),
+            Bltu(b) => self.branch(b, &mut npc, self.reg(b.rs1()) < self.reg(b.rs2())),
+            Bgeu(b) => self.branch(b, &mut npc, self.reg(b.rs1()) >= self.reg(b.rs2())),
+
+            Lb(i) | Lh(i) | Lw(i) | Lbu(i) | Lhu(i) | Lwu(i) | Ld(i) => {
+                let addr = self.reg(i.rs1()).wrapping_add(sext(i.imm() as u64, 12));
+                let data = match inst {
+                    Lb(_) => sext(self.load(addr, 1, bus)?, 8),
+                    Lh(_) => sext(self.load(addr, 2, bus)?, 16),
+                    Lw(_) => sext(self.load(addr, 4, bus)?, 32),
+                    Lbu(_) => self.load(addr, 1, bus)?,
+                    Lhu(_) => self.load(addr, 2, bus)?,
+                    Lwu(_) => self.load(addr, 4, bus)?,
+                    _ => self.load(addr, 8, bus)?,
+                };
+                self.set_reg(i.rd(), data);
+            }
+            Sb(s) | Sh(s) | Sw(s) | Sd(s) => {
+                let addr = self.reg(s.rs1()).wrapping_add(sext(s.imm() as u64, 12));
+                let size = match inst {
+                    Sb(_) => 1,
+                    Sh(_) => 2,
+                    Sw(_) => 4,
+                    _ => 8,
+                };
+                self.store(addr, size, self.reg(s.rs2()), bus)?;
+            }
+
+            Addi(i) => self.set_reg(
+                i.rd(),
+                self.reg(i.rs1()).wrapping_add(sext(i.imm() as u64, 12)),
+            ),
+            Slti(i) => self.set_reg(
+                i.rd(),
+                ((self.reg(i.rs1()) as i64) < sext(i.imm() as u64, 12) as i64) as u64,
+            ),
+            Sltiu(i) => self.set_reg(
+                i.rd(),
+                (self.reg(i.rs1()) < sext(i.imm() as u64, 12)) as u64,
+            ),
+            Xori(i) => self.set_reg(i.rd(), self.reg(i.rs1()) ^ sext(i.imm() as u64, 12)),
+            Ori(i) => self.set_reg(i.rd(), self.reg(i.rs1()) | sext(i.imm() as u64, 12)),
+            Andi(i) => self.set_reg(i.rd(), self.reg(i.rs1()) & sext(i.imm() as u64, 12)),
+            Slli(s) => self.set_reg(s.rd(), self.reg(s.rs1()) << s.shamt()),
+            Srli(s) => self.set_reg(s.rd(), self.reg(s.rs1()) >> s.shamt()),
+            Srai(s) => self.set_reg(s.rd(), (self.reg(s.rs1()) as i64 >> s.shamt()) as u64),
+
+            Add(r) => self.set_reg(r.rd(), self.reg(r.rs1()).wrapping_add(self.reg(r.rs2()))),
+            Sub(r) => self.set_reg(r.rd(), self.reg(r.rs1()).wrapping_sub(self.reg(r.rs2()))),
+            Sll(r) => self.set_reg(r.rd(), self.reg(r.rs1()) << (self.reg(r.rs2()) & 0x3f)),
+            Slt(r) => self.set_reg(
+                r.rd(),
+                ((self.reg(r.rs1()) as i64) < self.reg(r.rs2()) as i64) as u64,
+            ),
+            Sltu(r) => self.set_reg(r.rd(), (self.reg(r.rs1()) < self.reg(r.rs2())) as u64),
+            Xor(r) => self.set_reg(r.rd(), self.reg(r.rs1()) ^ self.reg(r.rs2())),
+            Srl(r) => self.set_reg(r.rd(), self.reg(r.rs1()) >> (self.reg(r.rs2()) & 0x3f)),
+            Sra(r) => self.set_reg(
+                r.rd(),
+                (self.reg(r.rs1()) as i64 >> (self.reg(r.rs2()) & 0x3f)) as u64,
+            ),
+            Or(r) => self.set_reg(r.rd(), self.reg(r.rs1()) | self.reg(r.rs2())),
+            And(r) => self.set_reg(r.rd(), self.reg(r.rs1()) & self.reg(r.rs2())),
+
+            Mul(r) => self.set_reg(r.rd(), self.reg(r.rs1()).wrapping_mul(self.reg(r.rs2()))),
+            Mulh(r) => {
+                let product = self.reg(r.rs1()) as i64 as i128 * self.reg(r.rs2()) as i64 as i128;
+                self.set_reg(r.rd(), (product >> 64) as u64)
+            }
+            Mulhsu(r) => {
+                let product = (self.reg(r.rs1()) as i64 as i128)
+                    .wrapping_mul(self.reg(r.rs2()) as u128 as i128);
+                self.set_reg(r.rd(), (product >> 64) as u64)
+            }
+            Mulhu(r) => {
+                let product = self.reg(r.rs1()) as u128 * self.reg(r.rs2()) as u128;
+                self.set_reg(r.rd(), (product >> 64) as u64)
+            }
+            Div(r) => {
+                let (a, b) = (self.reg(r.rs1()) as i64, self.reg(r.rs2()) as i64);
+                let q = if b == 0 { -1 } else { a.wrapping_div(b) };
+                self.set_reg(r.rd(), q as u64)
+            }
+            Divu(r) => {
+                let (a, b) = (self.reg(r.rs1()), self.reg(r.rs2()));
+                self.set_reg(r.rd(), a.checked_div(b).unwrap_or(u64::MAX))
+            }
+            Rem(r) => {
+                let (a, b) = (self.reg(r.rs1()) as i64, self.reg(r.rs2()) as i64);
+                let rem = if b == 0 { a } else { a.wrapping_rem(b) };
+                self.set_reg(r.rd(), rem as u64)
+            }
+            Remu(r) => {
+                let (a, b) = (self.reg(r.rs1()), self.reg(r.rs2()));
+                self.set_reg(r.rd(), a.checked_rem(b).unwrap_or(a))
+            }
+
+            LrW(r) | ScW(r) | AmoswapW(r) | AmoaddW(r) | AmoxorW(r) | AmoandW(r) | AmoorW(r)
+            | AmominW(r) | AmomaxW(r) | AmominuW(r) | AmomaxuW(r) => {
+                let data = self.amo(inst, r, 4, bus)?;
+                self.set_reg(r.rd(), data);
+            }
+            LrD(r) | ScD(r) | AmoswapD(r) | AmoaddD(r) | AmoxorD(r) | AmoandD(r) | AmoorD(r)
+            | AmominD(r) | AmomaxD(r) | AmominuD(r) | AmomaxuD(r) => {
+                let data = self.amo(inst, r, 8, bus)?;
+                self.set_reg(r.rd(), data);
+            }
+
+            Fence(_) => {}
+            FenceI => self.flush_decoded(),
+            CboClean(i) => self.cbo(CacheOp::Clean, self.reg(i.rs1()), bus)?,
+            CboFlush(i) => self.cbo(CacheOp::Flush, self.reg(i.rs1()), bus)?,
+            CboInval(i) => self.cbo(CacheOp::Inval, self.reg(i.rs1()), bus)?,
+            CboZero(i) => self.cbo(CacheOp::Zero, self.reg(i.rs1()), bus)?,
+
+            Ecall => {
+                return Err(match self.mode {
+                    #[cfg(feature = "hypervisor")]
+                    Privilege::Supervisor if self.virt => Exception::VirtualSupervisorEnvCall,
+                    Privilege::User => Exception::UserEnvCall,
+                    Privilege::Supervisor => Exception::SupervisorEnvCall,
+                    Privilege::Machine => Exception::MachineEnvCall,
+                })
+            }
+            Ebreak => return Err(Exception::Breakpoint(pc)),
+            Uret => return Err(ILLEGAL),
+            Sret => {
+                #[cfg(feature = "hypervisor")]
+                self.check_guest(inst)?;
+                // TSR and TVM only trap HS-mode, guests have VTSR and VTVM
+                if self.mode == Privilege::User
+                    || (self.mode == Privilege::Supervisor
+                        && !self.virt()
+                        && self.csr.mstatus & MSTATUS_TSR != 0)
+                {
+                    return Err(ILLEGAL);
+                }
+                npc = self.sret();
+            }
+            Mret => {
+                if self.mode != Privilege::Machine {
+                    return Err(ILLEGAL);
+                }
+                npc = self.mret();
+            }
+            Wfi => {
+                #[cfg(feature = "hypervisor")]
+                self.check_guest(inst)?;
+                if self.mode == Privilege::User
+                    || (self.mode == Privilege::Supervisor && self.csr.mstatus & MSTATUS_TW != 0)
+                {
+                    return Err(ILLEGAL);
+                }
+            }
+            WrsNto | WrsSto => {
+                if !self.wrs(inst)? {
+                    npc = pc;
+                }
+            }
+            SfenceVma(r) => {
+                #[cfg(feature = "hypervisor")]
+                self.check_guest(inst)?;
+                if self.mode == Privilege::User
+                    || (self.mode == Privilege::Supervisor
+                        && !self.virt()
+                        && self.csr.mstatus & MSTATUS_TVM != 0)
+                {
+                    return Err(ILLEGAL);
+                }
+                let vaddr = (r.rs1() != 0).then(|| self.reg(r.rs1()));
+                let asid = (r.rs2() != 0).then(|| self.reg(r.rs2()));
+                self.tlb.flush(vaddr, asid);
+                self.flush_decoded();
+            }
+            #[cfg(feature = "hypervisor")]
+            HfenceVvma(_) | HfenceGvma(_) | HlvB(_) | HlvBu(_) | HlvH(_) | HlvHu(_) | HlvxHu(_)
+            | HlvW(_) | HlvWu(_) | HlvxWu(_) | HlvD(_) | HsvB(_) | HsvH(_) | HsvW(_) | HsvD(_) => {
+                self.execute_hypervisor(inst, bus)?
+            }
+            #[cfg(not(feature = "hypervisor"))]
+            HfenceVvma(_) | HfenceGvma(_) | HlvB(_) | HlvBu(_) | HlvH(_) | HlvHu(_) | HlvxHu(_)
+            | HlvW(_) | HlvWu(_) | HlvxWu(_) | HlvD(_) | HsvB(_) | HsvH(_) | HsvW(_) | HsvD(_) => {
+                return Err(ILLEGAL)
+            }
+            Csrrw(c) => {
+                let value = self.reg(c.rs1());
+                let old = if c.rd() != 0 {
+                    self.csr_read(c.csr())?
+                } else {
+                    0
+                };
+                self.csr_write(c.csr(), value)?;
+                self.set_reg(c.rd(), old);
+            }
+            Csrrs(c) => {
+                let old = self.csr_read(c.csr())?;
+                if c.rs1() != 0 {
+                    self.csr_write(c.csr(), old | self.reg(c.rs1()))?;
+                }
+                self.set_reg(c.rd(), old);
+            }
+            Csrrc(c) => {
+                let old = self.csr_read(c.csr())?;
+                if c.rs1() != 0 {
+                    self.csr_write(c.csr(), old & !self.reg(c.rs1()))?;
+                }
+                self.set_reg(c.rd(), old);
+            }
+            Csrrwi(c) => {
+                let old = if c.rd() != 0 {
+                    self.csr_read(c.csr())?
+                } else {
+                    0
+                };
+                self.csr_write(c.csr(), c.imm() as u64)?;
+                self.set_reg(c.rd(), old);
+            }
+            Csrrsi(c) => {
+                let old = self.csr_read(c.csr())?;
+                if c.imm() != 0 {
+                    self.csr_write(c.csr(), old | c.imm() as u64)?;
+                }
+                self.set_reg(c.rd(), old);
+            }
+            Csrrci(c) => {
+                let old = self.csr_read(c.csr())?;
+                if c.imm() != 0 {
+                    self.csr_write(c.csr(), old & !(c.imm() as u64))?;
+                }
+                self.set_reg(c.rd(), old);
+            }
+
+            Addiw(i) => self.set_reg(
+                i.rd(),
+                sext(self.reg(i.rs1()).wrapping_add(sext(i.imm() as u64, 12)), 32),
+            ),
+            Slliw(s) => self.set_reg(s.rd(), sext(self.reg(s.rs1()) << (s.shamt() & 0x1f), 32)),
+            Srliw(s) => self.set_reg(
+                s.rd(),
+                sext((self.reg(s.rs1()) as u32 >> (s.shamt() & 0x1f)) as u64, 32),
+            ),
+            Sraiw(s) => self.set_reg(
+                s.rd(),
+                (self.reg(s.rs1()) as i32 >> (s.shamt() & 0x1f)) as i64 as u64,
+            ),
+
+            Addw(r) => self.set_reg(
+                r.rd(),
+                sext(self.reg(r.rs1()).wrapping_add(self.reg(r.rs2())), 32),
+            ),
+            Subw(r) => self.set_reg(
+                r.rd(),
+                sext(self.reg(r.rs1()).wrapping_sub(self.reg(r.rs2())), 32),
+            ),
+            Sllw(r) => self.set_reg(
+                r.rd(),
+                sext(self.reg(r.rs1()) << (self.reg(r.rs2()) & 0x1f), 32),
+            ),
+            Srlw(r) => self.set_reg(
+                r.rd(),
+                sext(
+                    (self.reg(r.rs1()) as u32 >> (self.reg(r.rs2()) & 0x1f)) as u64,
+                    32,
+                ),
+            ),
+            Sraw(r) => self.set_reg(
+                r.rd(),
+                (self.reg(r.rs1()) as i32 >> (self.reg(r.rs2()) & 0x1f)) as i64 as u64,
+            ),
+            Mulw(r) => self.set_reg(
+                r.rd(),
+                sext(self.reg(r.rs1()).wrapping_mul(self.reg(r.rs2())), 32),
+            ),
+            Divw(r) => {
+                let (a, b) = (self.reg(r.rs1()) as i32, self.reg(r.rs2()) as i32);
+                let q = if b == 0 { -1 } else { a.wrapping_div(b) };
+                self.set_reg(r.rd(), q as i64 as u64)
+            }
+            Divuw(r) => {
+                let (a, b) = (self.reg(r.rs1()) as u32, self.reg(r.rs2()) as u32);
+                self.set_reg(
+                    r.rd(),
+                    a.checked_div(b).unwrap_or(u32::MAX) as i32 as i64 as u64,
+                )
+            }
+            Remw(r) => {
+                let (a, b) = (self.reg(r.rs1()) as i32, self.reg(r.rs2()) as i32);
+                let rem = if b == 0 { a } else { a.wrapping_rem(b) };
+                self.set_reg(r.rd(), rem as i64 as u64)
+            }
+            Remuw(r) => {
+                let (a, b) = (self.reg(r.rs1()) as u32, self.reg(r.rs2()) as u32);
+                self.set_reg(r.rd(), a.checked_rem(b).unwrap_or(a) as i32 as i64 as u64)
+            }
+
+            Andn(r) => self.set_reg(r.rd(), self.reg(r.rs1()) & !self.reg(r.rs2())),
+            Orn(r) => self.set_reg(r.rd(), self.reg(r.rs1()) | !self.reg(r.rs2())),
+            Xnor(r) => self.set_reg(r.rd(), !(self.reg(r.rs1()) ^ self.reg(r.rs2()))),
+            Rol(r) => self.set_reg(
+                r.rd(),
+                self.reg(r.rs1())
+                    .rotate_left(self.reg(r.rs2()) as u32 & 0x3f),
+            ),
+            Ror(r) => self.set_reg(
+                r.rd(),
+                self.reg(r.rs1())
+                    .rotate_right(self.reg(r.rs2()) as u32 & 0x3f),
+            ),
+            Rori(s) => self.set_reg(s.rd(), self.reg(s.rs1()).rotate_right(s.shamt())),
+            Rolw(r) => self.set_reg(
+                r.rd(),
+                (self.reg(r.rs1()) as u32).rotate_left(self.reg(r.rs2()) as u32 & 0x1f) as i32
+                    as i64 as u64,
+            ),
+            Rorw(r) => self.set_reg(
+                r.rd(),
+                (self.reg(r.rs1()) as u32).rotate_right(self.reg(r.rs2()) as u32 & 0x1f) as i32
+                    as i64 as u64,
+            ),
+            Roriw(s) => self.set_reg(
+                s.rd(),
+                (self.reg(s.rs1()) as u32).rotate_right(s.shamt()) as i32 as i64 as u64,
+            ),
+            Pack(r) => self.set_reg(
+                r.rd(),
+                self.reg(r.rs2()) << 32 | self.reg(r.rs1()) & 0xffff_ffff,
+            ),
+            Packh(r) => self.set_reg(
+                r.rd(),
+                (self.reg(r.rs2()) & 0xff) << 8 | self.reg(r.rs1()) & 0xff,
+            ),
+            Packw(r) => self.set_reg(
+                r.rd(),
+                sext(
+                    (self.reg(r.rs2()) & 0xffff) << 16 | self.reg(r.rs1()) & 0xffff,
+                    32,
+                ),
+            ),
+            Rev8(i) => self.set_reg(i.rd(), self.reg(i.rs1()).swap_bytes()),
+            Brev8(i) => self.set_reg(i.rd(), crypto::brev8(self.reg(i.rs1()))),
+            Clmul(r) => self.set_reg(r.rd(), crypto::clmul(self.reg(r.rs1()), self.reg(r.rs2()))),
+            Clmulh(r) => self.set_reg(r.rd(), crypto::clmulh(self.reg(r.rs1()), self.reg(r.rs2()))),
+            Xperm4(r) => self.set_reg(
+                r.rd(),
+                crypto::xperm(self.reg(r.rs1()), self.reg(r.rs2()), 4),
+            ),
+            Xperm8(r) => self.set_reg(
+                r.rd(),
+                crypto::xperm(self.reg(r.rs1()), self.reg(r.rs2()), 8),
+            ),
+            Aes64es(r) | Aes64esm(r) => self.set_reg(
+                r.rd(),
+                crypto::aes64es(
+                    self.reg(r.rs1()),
+                    self.reg(r.rs2()),
+                    matches!(inst, Aes64esm(_)),
+                ),
+            ),
+            Aes64ds(r) | Aes64dsm(r) => self.set_reg(
+                r.rd(),
+                crypto::aes64ds(
+                    self.reg(r.rs1()),
+                    self.reg(r.rs2()),
+                    matches!(inst, Aes64dsm(_)),
+                ),
+            ),
+            Aes64im(i) => self.set_reg(i.rd(), crypto::aes64im(self.reg(i.rs1()))),
+            Aes64ks1i(i) => {
+                self.set_reg(i.rd(), crypto::aes64ks1i(self.reg(i.rs1()), i.imm() & 0xf))
+            }
+            Aes64ks2(r) => self.set_reg(
+                r.rd(),
+                crypto::aes64ks2(self.reg(r.rs1()), self.reg(r.rs2())),
+            ),
+            CzeroEqz(r) => {
+                let value = if self.reg(r.rs2()) == 0 {
+                    0
+                } else {
+                    self.reg(r.rs1())
+                };
+                self.set_reg(r.rd(), value);
+            }
+            CzeroNez(r) => {
+                let value = if self.reg(r.rs2()) != 0 {
+                    0
+                } else {
+                    self.reg(r.rs1())
+                };
+                self.set_reg(r.rd(), value);
+            }
+            Sha256sig0(i) | Sha256sig1(i) | Sha256sum0(i) | Sha256sum1(i) => {
+                let f = match inst {
+                    Sha256sig0(_) => crypto::sha256sig0,
+                    Sha256sig1(_) => crypto::sha256sig1,
+                    Sha256sum0(_) => crypto::sha256sum0,
+                    _ => crypto::sha256sum1,
+                };
+                self.set_reg(i.rd(), f(self.reg(i.rs1()) as u32) as i32 as i64 as u64)
+            }
+            Sha512sig0(i) => self.set_reg(i.rd(), crypto::sha512sig0(self.reg(i.rs1()))),
+            Sha512sig1(i) => self.set_reg(i.rd(), crypto::sha512sig1(self.reg(i.rs1()))),
+            Sha512sum0(i) => self.set_reg(i.rd(), crypto::sha512sum0(self.reg(i.rs1()))),
+            Sha512sum1(i) => self.set_reg(i.rd(), crypto::sha512sum1(self.reg(i.rs1()))),
+
+            // sp only moves once every access succeeded, so a push or pop
+            // that faults halfway is simply restarted
+            CmPush(c) => {
+                let sp = self.reg(2);
+                let mut addr = sp;
+                for reg in c.regs().rev() {
+                    addr = addr.wrapping_sub(8);
+                    self.store(addr, 8, self.reg(reg), bus)?;
+                }
+                self.set_reg(2, sp.wrapping_sub(c.stack_adj()));
+            }
+            CmPop(c) | CmPopret(c) | CmPopretz(c) => {
+                let sp = self.reg(2).wrapping_add(c.stack_adj());
+                let mut addr = sp;
+                for reg in c.regs().rev() {
+                    addr = addr.wrapping_sub(8);
+                    let data = self.load(addr, 8, bus)?;
+                    self.set_reg(reg, data);
+                }
+                self.set_reg(2, sp);
+                if let CmPopretz(_) = inst {
+                    self.set_reg(10, 0);
+                }
+                if let CmPopret(_) | CmPopretz(_) = inst {
+                    npc = self.reg(1) & !1;
+                }
+            }
+            CmMvsa01(c) => {
+                self.set_reg(c.r1s(), self.reg(10));
+                self.set_reg(c.r2s(), self.reg(11));
+            }
+            CmMva01s(c) => {
+                let (a0, a1) = (self.reg(c.r1s()), self.reg(c.r2s()));
+                self.set_reg(10, a0);
+                self.set_reg(11, a1);
+            }
+
+            // the compressed forms are expanded by the decoder
+            CNOP(_) | CADDI(_) | CJAL(_) | CLI(_) | CADDI16(_) | CLUI(_) | CRLI(_) | CRAI(_)
+            | CANDI(_) | CSUB(_) | CXOR(_) | COR(_) | CAND(_) | CJ(_) | CBEQZ(_) | CBNEZ(_)
+            | Illegal => return Err(ILLEGAL),
+        }
+        self.set_pc(npc);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::csr::{MCAUSE, MEDELEG, MEPC, MINSTRET, MSTATUS, MTVEC, STVEC};
+    use super::super::decode::{decode, decode_ext, Extensions};
+    use super::super::mmu::Misaligned;
+    use super::*;
+    use crate::device::io::map::IOMap;
+    use crate::memory::ram::Ram;
+    use crate::memory::CONFIG_MBASE;
+
+    fn run(cpu: &mut CpuState, raw: u32) -> Result<(), Exception> {
+        let len = if raw & 0b11 == 0b11 { 4 } else { 2 };
+        cpu.execute(decode(raw).unwrap(), len, &mut Bus::default())
+    }
+
+    #[test]
+    fn alu() {
+        let mut cpu = CpuState::new(0);
+        run(&mut cpu, 0xfff00093).unwrap(); // addi x1,x0,-1
+        assert_eq!(cpu.reg(1), u64::MAX);
+        run(&mut cpu, 0x0010d113).unwrap(); // srli x2,x1,0x1
+        assert_eq!(cpu.reg(2), u64::MAX >> 1);
+        run(&mut cpu, 0x4010d193).unwrap(); // srai x3,x1,0x1
+        assert_eq!(cpu.reg(3), u64::MAX);
+        run(&mut cpu, 0x0010821b).unwrap(); // addiw x4,x1,1
+        assert_eq!(cpu.reg(4), 0);
+        run(&mut cpu, 0x800002b7).unwrap(); // lui x5,0x80000
+        assert_eq!(cpu.reg(5), 0xffff_ffff_8000_0000);
+        run(&mut cpu, 0x0022b333).unwrap(); // sltu x6,x5,x2
+        assert_eq!(cpu.reg(6), 0);
+        run(&mut cpu, 0x0022a333).unwrap(); // slt x6,x5,x2
+        assert_eq!(cpu.reg(6), 1);
+        run(&mut cpu, 0x00000013).unwrap(); // addi x0,x0,0
+        assert_eq!(cpu.reg(0), 0);
+        assert_eq!(cpu.pc(), CONFIG_MBASE + 8 * 4);
+    }
+
+    #[test]
+    fn muldiv() {
+        let mut cpu = CpuState::new(0);
+        cpu.set_reg(1, (-7i64) as u64);
+        cpu.set_reg(2, 2);
+        run(&mut cpu, 0x0220c1b3).unwrap(); // div x3,x1,x2
+        assert_eq!(cpu.reg(3) as i64, -3);
+        run(&mut cpu, 0x0220e1b3).unwrap(); // rem x3,x1,x2
+        assert_eq!(cpu.reg(3) as i64, -1);
+        run(&mut cpu, 0x0200c1b3).unwrap(); // div x3,x1,x0
+        assert_eq!(cpu.reg(3), u64::MAX);
+        run(&mut cpu, 0x0200f1b3).unwrap(); // remu x3,x1,x0
+        assert_eq!(cpu.reg(3), (-7i64) as u64);
+        run(&mut cpu, 0x022091b3).unwrap(); // mulh x3,x1,x2
+        assert_eq!(cpu.reg(3), u64::MAX);
+        run(&mut cpu, 0x0220b1b3).unwrap(); // mulhu x3,x1,x2
+        assert_eq!(cpu.reg(3), 1);
+        cpu.set_reg(1, i64::MIN as u64);
+        cpu.set_reg(2, u64::MAX);
+        run(&mut cpu, 0x0220c1b3).unwrap(); // div x3,x1,x2
+        assert_eq!(cpu.reg(3), i64::MIN as u64);
+        run(&mut cpu, 0x0220c1bb).unwrap(); // divw x3,x1,x2
+        assert_eq!(cpu.reg(3), 0);
+    }
+
+    #[test]
+    fn crypto() {
+        let zkn = Extensions {
+            zbkb: true,
+            zknd: true,
+            zkne: true,
+            zknh: true,
+            ..Extensions::default()
+        };
+        let run = |cpu: &mut CpuState, raw| {
+            cpu.execute(decode_ext(raw, zkn).unwrap(), 4, &mut Bus::default())
+        };
+        let mut cpu = CpuState::new(0);
+        cpu.set_reg(1, 0x8000_0000_0000_00f1);
+        cpu.set_reg(2, 0x1234_5678_9abc_def0);
+        run(&mut cpu, 0x4020f1b3).unwrap(); // andn x3,x1,x2
+        assert_eq!(cpu.reg(3), 0x8000_0000_0000_0001);
+        run(&mut cpu, 0x63c0d193).unwrap(); // rori x3,x1,0x3c
+        assert_eq!(cpu.reg(3), 0x0000_0000_0000_0f18);
+        run(&mut cpu, 0x602091bb).unwrap(); // rolw x3,x1,x2
+        assert_eq!(cpu.reg(3), 0x00f1_0000);
+        run(&mut cpu, 0x6040d19b).unwrap(); // roriw x3,x1,0x4
+        assert_eq!(cpu.reg(3), 0x1000_000f);
+        run(&mut cpu, 0x0820c1b3).unwrap(); // pack x3,x1,x2
+        assert_eq!(cpu.reg(3), 0x9abc_def0_0000_00f1);
+        run(&mut cpu, 0x0820f1b3).unwrap(); // packh x3,x1,x2
+        assert_eq!(cpu.reg(3), 0xf0f1);
+        run(&mut cpu, 0x0820c1bb).unwrap(); // packw x3,x1,x2
+        assert_eq!(cpu.reg(3), 0xffff_ffff_def0_00f1);
+        run(&mut cpu, 0x6b80d193).unwrap(); // rev8 x3,x1
+        assert_eq!(cpu.reg(3), 0xf100_0000_0000_0080);
+        run(&mut cpu, 0x10209193).unwrap(); // sha256sig0 x3,x1
+        assert_eq!(cpu.reg(3), sext(crypto::sha256sig0(0xf1) as u64, 32));
+        run(&mut cpu, 0x362081b3).unwrap(); // aes64esm x3,x1,x2
+        assert_eq!(cpu.reg(3), crypto::aes64es(cpu.reg(1), cpu.reg(2), true));
+    }
+
+    #[test]
+    fn branch() {
+        let mut cpu = CpuState::new(0);
+        cpu.set_pc(0x8000_0010);
+        cpu.csr.counters.event[0] = Event::BranchTaken as u64;
+        run(&mut cpu, 0x00000463).unwrap(); // beq x0,x0,8
+        assert_eq!(cpu.pc(), 0x8000_0018);
+        run(&mut cpu, 0xfe001ee3).unwrap(); // bne x0,x0,-4
+        assert_eq!(cpu.pc(), 0x8000_001c);
+        run(&mut cpu, 0xfe5ff0ef).unwrap(); // jal x1,-28
+        assert_eq!(cpu.pc(), 0x8000_0000);
+        assert_eq!(cpu.reg(1), 0x8000_0020);
+        run(&mut cpu, 0x00308067).unwrap(); // jalr x0,3(x1)
+        assert_eq!(cpu.pc(), 0x8000_0022);
+        assert_eq!(cpu.csr.counters.hpm[0], 1);
+    }
+
+    #[test]
+    fn csr() {
+        let mut cpu = CpuState::new(0);
+        cpu.set_reg(1, 0x8000_0000);
+        run(&mut cpu, 0x30509173).unwrap(); // csrrw x2,mtvec,x1
+        assert_eq!(cpu.csr.mtvec, 0x8000_0000);
+        assert_eq!(cpu.reg(2), 0);
+        run(&mut cpu, 0x30516173).unwrap(); // csrrsi x2,mtvec,2
+        assert_eq!(cpu.reg(2), 0x8000_0000);
+        assert_eq!(cpu.csr.mtvec, 0x8000_0000);
+        cpu.csr.counters.instret = 41;
+        run(&mut cpu, 0xc0202173).unwrap(); // csrrs x2,instret,x0
+        assert_eq!(cpu.reg(2), 41);
+        assert_eq!(run(&mut cpu, 0xc0209173), Err(ILLEGAL)); // csrrw x2,instret,x1
+        cpu.mode = Privilege::User;
+        assert_eq!(run(&mut cpu, 0xc0202173), Err(ILLEGAL)); // csrrs x2,instret,x0
+        assert_eq!(cpu.csr.read(MINSTRET, Privilege::Machine), Ok(41));
+    }
+
+    #[test]
+    fn traps() {
+        let mut cpu = CpuState::new(0);
+        cpu.csr
+            .write(MTVEC, 0x8000_0100, Privilege::Machine)
+            .unwrap();
+        cpu.csr
+            .write(STVEC, 0x8000_0200, Privilege::Machine)
+            .unwrap();
+        cpu.csr.write(MEDELEG, 1 << 8, Privilege::Machine).unwrap();
+        cpu.set_pc(0x8000_0000);
+        cpu.mode = Privilege::User;
+        let e = run(&mut cpu, 0x00000073).unwrap_err(); // ecall
+        cpu.raise(e);
+        assert_eq!(cpu.mode, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x8000_0200);
+        assert_eq!(cpu.csr.sepc, 0x8000_0000);
+        assert_eq!(cpu.csr.scause, 8);
+
+        let e = run(&mut cpu, 0x00000073).unwrap_err(); // ecall
+        cpu.raise(e);
+        assert_eq!(cpu.mode, Privilege::Machine);
+        assert_eq!(cpu.pc(), 0x8000_0100);
+        assert_eq!(cpu.csr.read(MCAUSE, Privilege::Machine), Ok(9));
+        assert_eq!(cpu.csr.read(MEPC, Privilege::Machine), Ok(0x8000_0200));
+        assert_eq!(
+            cpu.csr.read(MSTATUS, Privilege::Machine).unwrap() & MSTATUS_MPP,
+            1 << MSTATUS_MPP_SHIFT
+        );
+
+        run(&mut cpu, 0x30200073).unwrap(); // mret
+        assert_eq!(cpu.mode, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x8000_0200);
+        run(&mut cpu, 0x10200073).unwrap(); // sret
+        assert_eq!(cpu.mode, Privilege::User);
+        assert_eq!(cpu.pc(), 0x8000_0000);
+        assert_eq!(run(&mut cpu, 0x10200073), Err(ILLEGAL)); // sret
+    }
+
+    #[test]
+    fn amo() {
+        let mut bus = Bus::new(
+            Ram::default(),
+            vec![IOMap::scratch("data", 0x9000_0000, 16)],
+        );
+        let mut cpu = CpuState::new(0);
+        cpu.set_reg(10, 0x9000_0000);
+        cpu.set_reg(11, 0x9000_0002);
+        cpu.set_reg(12, (-5i64) as u64);
+
+        cpu.execute(decode(0x00c521af).unwrap(), 4, &mut bus)
+            .unwrap(); // amoadd.w x3,x12,(x10)
+        assert_eq!(cpu.reg(3), 0);
+        cpu.execute(decode(0x80c521af).unwrap(), 4, &mut bus)
+            .unwrap(); // amomin.w x3,x12,(x10)
+        assert_eq!(cpu.reg(3), (-5i64) as u64);
+        cpu.execute(decode(0xe0c531af).unwrap(), 4, &mut bus)
+            .unwrap(); // amomaxu.d x3,x12,(x10)
+        assert_eq!(cpu.reg(3), 0xffff_fffb);
+        cpu.execute(decode(0x00c521af).unwrap(), 4, &mut bus)
+            .unwrap(); // amoadd.w x3,x12,(x10)
+        assert_eq!(cpu.reg(3), (-5i64) as u64);
+        assert_eq!(
+            cpu.load(0x9000_0000, 8, &mut bus),
+            Ok(0xffff_ffff_ffff_fff6)
+        );
+        assert_eq!(
+            cpu.execute(decode(0x1005a0af).unwrap(), 4, &mut bus), // lr.w x1,(x11)
+            Err(Exception::LoadAddressMisaligned(0x9000_0002))
+        );
+    }
+
+    #[test]
+    fn zcmp() {
+        let mut bus = Bus::new(
+            Ram::default(),
+            vec![IOMap::scratch("stack", 0x9000_0000, 256)],
+        );
+        let ext = Extensions {
+            zcb: true,
+            zcmp: true,
+            ..Extensions::default()
+        };
+        let mut cpu = CpuState::new(0);
+        cpu.set_pc(0x8000_0000);
+        cpu.set_reg(2, 0x9000_0100);
+        cpu.set_reg(1, 0x8000_1234);
+        cpu.set_reg(8, 8);
+        cpu.set_reg(9, 9);
+        let push = decode_ext(0xb862, ext).unwrap(); // cm.push {ra,s0-s1},-32
+        cpu.execute(push, 2, &mut bus).unwrap();
+        assert_eq!(cpu.reg(2), 0x9000_00e0);
+        assert_eq!(cpu.load(0x9000_00f8, 8, &mut bus), Ok(9));
+        assert_eq!(cpu.load(0x9000_00f0, 8, &mut bus), Ok(8));
+        assert_eq!(cpu.load(0x9000_00e8, 8, &mut bus), Ok(0x8000_1234));
+
+        cpu.set_reg(10, 10);
+        cpu.set_reg(11, 11);
+        let mvsa01 = decode_ext(0xac26, ext).unwrap(); // cm.mvsa01 s0,s1
+        cpu.execute(mvsa01, 2, &mut bus).unwrap();
+        assert_eq!((cpu.reg(8), cpu.reg(9)), (10, 11));
+
+        cpu.set_reg(1, 0);
+        let popretz = decode_ext(0xbc62, ext).unwrap(); // cm.popretz {ra,s0-s1},32
+        cpu.execute(popretz, 2, &mut bus).unwrap();
+        assert_eq!((cpu.reg(1), cpu.reg(8), cpu.reg(9)), (0x8000_1234, 8, 9));
+        assert_eq!((cpu.reg(2), cpu.reg(10)), (0x9000_0100, 0));
+        assert_eq!(cpu.pc(), 0x8000_1234);
+
+        // a pop that faults leaves sp alone, so it can be restarted
+        cpu.set_reg(2, 0x9000_00f0);
+        assert!(cpu.execute(popretz, 2, &mut bus).is_err());
+        assert_eq!(cpu.reg(2), 0x9000_00f0);
+    }
+
+    #[test]
+    fn zicond_zawrs() {
+        let ext = Extensions {
+            zicond: true,
+            zawrs: true,
+            ..Extensions::default()
+        };
+        let inst = |raw| decode_ext(raw, ext).unwrap();
+        let (wrs_nto, wrs_sto) = (inst(0x00d00073), inst(0x01d00073));
+        let mut cpu = CpuState::new(0);
+        cpu.set_reg(11, 7);
+        cpu.execute(inst(0x0ec5d533), 4, &mut Bus::default())
+            .unwrap(); // czero.eqz a0,a1,a2
+        assert_eq!(cpu.reg(10), 0);
+        cpu.execute(inst(0x0ec5f533), 4, &mut Bus::default())
+            .unwrap(); // czero.nez a0,a1,a2
+        assert_eq!(cpu.reg(10), 7);
+
+        // without a reservation there is nothing to wait for
+        cpu.set_pc(0x8000_0000);
+        cpu.execute(wrs_nto, 4, &mut Bus::default()).unwrap();
+        assert_eq!(cpu.pc(), 0x8000_0004);
+        cpu.reservation = Some(0x9000_0000);
+        for _ in 0..CONFIG_WRS_TIMEOUT {
+            cpu.execute(wrs_sto, 4, &mut Bus::default()).unwrap();
+            assert_eq!(cpu.pc(), 0x8000_0004);
+        }
+        cpu.execute(wrs_sto, 4, &mut Bus::default()).unwrap();
+        assert_eq!((cpu.pc(), cpu.wrs), (0x8000_0008, None));
+        cpu.execute(wrs_nto, 4, &mut Bus::default()).unwrap();
+        assert_eq!(cpu.wrs, Some(0));
+        // a pending interrupt ends the stall even while interrupts are disabled
+        cpu.csr.mie = IRQ_MSIP;
+        cpu.csr.mip = IRQ_MSIP;
+        cpu.execute(wrs_nto, 4, &mut Bus::default()).unwrap();
+        assert_eq!((cpu.pc(), cpu.wrs), (0x8000_000c, None));
+
+        cpu.csr.mip = 0;
+        cpu.mode = Privilege::Supervisor;
+        cpu.csr.mstatus |= MSTATUS_TW;
+        assert_eq!(cpu.execute(wrs_nto, 4, &mut Bus::default()), Err(ILLEGAL));
+        cpu.execute(wrs_sto, 4, &mut Bus::default()).unwrap();
+        assert_eq!(cpu.wrs, Some(0));
+    }
+
+    #[test]
+    fn misaligned() {
+        // two devices back to back, with nothing mapped after them
+        let mut bus = Bus::new(
+            Ram::default(),
+            vec![
+                IOMap::scratch("a", 0x9000_0000, 16),
+                IOMap::scratch("b", 0x9000_0010, 16),
+            ],
+        );
+        let mut cpu = CpuState::new(0);
+        cpu.store(0x9000_000c, 8, 0x0807_0605_0403_0201, &mut bus)
+            .unwrap();
+        assert_eq!(bus.devices[0].space[12..], [1, 2, 3, 4]);
+        assert_eq!(bus.devices[1].space[..4], [5, 6, 7, 8]);
+        assert_eq!(cpu.load(0x9000_000d, 4, &mut bus), Ok(0x0504_0302));
+        assert_eq!(
+            cpu.load(0x9000_001e, 4, &mut bus),
+            Err(Exception::LoadAccessFault(0x9000_001e))
+        );
+
+        // amoadd.w x3,x12,(x11)
+        let amoadd = decode(0x00c5a1af).unwrap();
+        cpu.set_reg(11, 0x9000_000e);
+        cpu.set_reg(12, 0x0101);
+        cpu.execute(amoadd, 4, &mut bus).unwrap();
+        assert_eq!(cpu.reg(3), 0x0605_0403);
+        assert_eq!(cpu.load(0x9000_000e, 4, &mut bus), Ok(0x0605_0504));
+
+        cpu.misaligned = Misaligned::Trap;
+        assert_eq!(
+            cpu.load(0x9000_0001, 2, &mut bus),
+            Err(Exception::LoadAddressMisaligned(0x9000_0001))
+        );
+        assert_eq!(
+            cpu.execute(amoadd, 4, &mut bus),
+            Err(Exception::StoreAddressMisaligned(0x9000_000e))
+        );
+        cpu.misaligned = Misaligned::AccessFault;
+        assert_eq!(
+            cpu.store(0x9000_0001, 2, 0, &mut bus),
+            Err(Exception::StoreAccessFault(0x9000_0001))
+        );
+        // aligned accesses are unaffected
+        assert_eq!(cpu.load(0x9000_0010, 2, &mut bus), Ok(0x0605));
+    }
+}
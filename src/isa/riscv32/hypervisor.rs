@@ -0,0 +1,955 @@
+//! The H extension: VS/VU-mode guests, the hypervisor and virtual supervisor
+//! csrs, two-stage address translation and the HLV/HSV/HFENCE instructions.
+//!
+//! Guest translations are never cached in the TLB, every guest access walks
+//! the VS-stage and G-stage tables, and the decoded instructions are flushed
+//! whenever V changes.
+
+use super::csr::{
+    legal_envcfg, CsrFile, Privilege, CYCLE, ENVCFG_CBO, HPMCOUNTER31, IRQ_VS, IRQ_VSSIP,
+    MENVCFG_PBMTE, MENVCFG_STCE, MSTATUS_GVA, MSTATUS_MPP_SHIFT, MSTATUS_MPRV, MSTATUS_MPV,
+    MSTATUS_MXR, MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP, MSTATUS_SUM, MSTATUS_TVM, MSTATUS_TW,
+    SATP, SATP_MODE_BARE, SATP_MODE_SHIFT, SATP_MODE_SV39, SCAUSE, SEPC, SIE, SIP, SSCRATCH,
+    SSTATUS, SSTATUS_WMASK, STIMECMP, STVAL, STVEC, TIME,
+};
+use super::exec::{trap_vector, ILLEGAL};
+use super::instruction::Instruction;
+use super::mmu::{
+    parse_pte, pte_permits, Pte, PAGE_SHIFT, PAGE_SIZE, PPN_MASK, PTE_A, PTE_D, PTE_U, SV39_LEVELS,
+    SV39_VA_BITS, SV39_VPN_BITS,
+};
+use super::reg::CpuState;
+use super::trap::{AccessType, Exception};
+use crate::memory::bus::Bus;
+use crate::utils::sext;
+
+// Virtual supervisor registers, substituted for the S csrs when V=1
+pub const VSSTATUS: u32 = 0x200;
+pub const VSIE: u32 = 0x204;
+pub const VSTVEC: u32 = 0x205;
+pub const VSSCRATCH: u32 = 0x240;
+pub const VSEPC: u32 = 0x241;
+pub const VSCAUSE: u32 = 0x242;
+pub const VSTVAL: u32 = 0x243;
+pub const VSIP: u32 = 0x244;
+pub const VSTIMECMP: u32 = 0x24d;
+pub const VSATP: u32 = 0x280;
+// Hypervisor trap setup
+pub const HSTATUS: u32 = 0x600;
+pub const HEDELEG: u32 = 0x602;
+pub const HIDELEG: u32 = 0x603;
+pub const HIE: u32 = 0x604;
+pub const HTIMEDELTA: u32 = 0x605;
+pub const HCOUNTEREN: u32 = 0x606;
+pub const HGEIE: u32 = 0x607;
+pub const HENVCFG: u32 = 0x60a;
+// Hypervisor trap handling
+pub const HTVAL: u32 = 0x643;
+pub const HIP: u32 = 0x644;
+pub const HVIP: u32 = 0x645;
+pub const HTINST: u32 = 0x64a;
+pub const HGEIP: u32 = 0xe12;
+// Hypervisor protection and translation
+pub const HGATP: u32 = 0x680;
+// Machine trap handling, for traps taken out of a guest
+pub const MTINST: u32 = 0x34a;
+pub const MTVAL2: u32 = 0x34b;
+
+// hstatus fields
+pub const HSTATUS_GVA: u64 = 1 << 6;
+pub const HSTATUS_SPV: u64 = 1 << 7;
+pub const HSTATUS_SPVP: u64 = 1 << 8;
+pub const HSTATUS_HU: u64 = 1 << 9;
+pub const HSTATUS_VTVM: u64 = 1 << 20;
+pub const HSTATUS_VTW: u64 = 1 << 21;
+pub const HSTATUS_VTSR: u64 = 1 << 22;
+/// VSXL = 64
+const HSTATUS_VSXL: u64 = 0b10 << 32;
+const HSTATUS_WMASK: u64 = HSTATUS_GVA
+    | HSTATUS_SPV
+    | HSTATUS_SPVP
+    | HSTATUS_HU
+    | HSTATUS_VTVM
+    | HSTATUS_VTW
+    | HSTATUS_VTSR;
+/// henvcfg has the menvcfg bits that apply to VS-mode. PBMTE and STCE are
+/// read-only zero while menvcfg clears them.
+const HENVCFG_WMASK: u64 = ENVCFG_CBO | MENVCFG_PBMTE | MENVCFG_STCE;
+
+/// UXL = 64
+const VSSTATUS_UXL: u64 = 0b10 << 32;
+
+/// Exceptions a hypervisor can hand on to VS-mode: not the ecalls from HS-,
+/// VS- or M-mode, nor the guest page faults and virtual instructions.
+const HEDELEG_WMASK: u64 = 0xb1ff;
+
+pub const HGATP_MODE_SV39X4: u64 = 8;
+/// Sv39x4 widens the root table to 2048 entries, for 41-bit guest physical addresses.
+const SV39X4_ROOT_BITS: u64 = SV39_VPN_BITS + 2;
+const SV39X4_GPA_BITS: u64 = 41;
+
+#[derive(Clone, Debug, Default)]
+pub struct HypervisorCsrs {
+    pub hstatus: u64,
+    pub hedeleg: u64,
+    pub hideleg: u64,
+    pub hcounteren: u32,
+    pub htimedelta: u64,
+    pub henvcfg: u64,
+    pub htval: u64,
+    pub htinst: u64,
+    pub hgatp: u64,
+    pub vsstatus: u64,
+    pub vstvec: u64,
+    pub vsscratch: u64,
+    pub vsepc: u64,
+    pub vscause: u64,
+    pub vstval: u64,
+    pub vsatp: u64,
+    pub vstimecmp: u64,
+    pub mtval2: u64,
+    pub mtinst: u64,
+}
+
+impl CsrFile {
+    /// Read a hypervisor, virtual supervisor or added machine csr. The VS
+    /// interrupt bits live in mip/mie, the VS csrs see them shifted down to
+    /// the S positions.
+    pub(super) fn read_hypervisor(&self, csr: u32, mode: Privilege) -> Result<u64, Exception> {
+        let h = &self.h;
+        let value = match csr {
+            VSSTATUS => h.vsstatus | VSSTATUS_UXL,
+            VSIE => (self.mie & h.hideleg & IRQ_VS) >> 1,
+            VSTVEC => h.vstvec,
+            VSSCRATCH => h.vsscratch,
+            VSEPC => h.vsepc,
+            VSCAUSE => h.vscause,
+            VSTVAL => h.vstval,
+            VSIP => (self.effective_mip() & h.hideleg & IRQ_VS) >> 1,
+            VSTIMECMP => {
+                self.check_sstc(mode)?;
+                h.vstimecmp
+            }
+            VSATP => h.vsatp,
+
+            HSTATUS => h.hstatus | HSTATUS_VSXL,
+            HEDELEG => h.hedeleg,
+            HIDELEG => h.hideleg,
+            HIE => self.mie & IRQ_VS,
+            HTIMEDELTA => h.htimedelta,
+            HCOUNTEREN => h.hcounteren as u64,
+            HENVCFG => h.henvcfg & (self.menvcfg | ENVCFG_CBO),
+            // no guest external interrupt files
+            HGEIE | HGEIP => 0,
+            HTVAL => h.htval,
+            HIP => self.effective_mip() & IRQ_VS,
+            HVIP => self.mip & IRQ_VS,
+            HTINST => h.htinst,
+            HGATP => h.hgatp,
+
+            MTINST => h.mtinst,
+            MTVAL2 => h.mtval2,
+            _ => return Err(ILLEGAL),
+        };
+        Ok(value)
+    }
+
+    pub(super) fn write_hypervisor(
+        &mut self,
+        csr: u32,
+        value: u64,
+        mode: Privilege,
+    ) -> Result<(), Exception> {
+        if csr == VSTIMECMP {
+            self.check_sstc(mode)?;
+        }
+        let h = &mut self.h;
+        match csr {
+            VSSTATUS => h.vsstatus = value & SSTATUS_WMASK,
+            VSIE => {
+                let mask = h.hideleg & IRQ_VS;
+                self.mie = (self.mie & !mask) | (value << 1 & mask);
+            }
+            VSTVEC => h.vstvec = value & !0b10,
+            VSSCRATCH => h.vsscratch = value,
+            VSEPC => h.vsepc = value & !1,
+            VSCAUSE => h.vscause = value,
+            VSTVAL => h.vstval = value,
+            VSIP => {
+                let mask = h.hideleg & IRQ_VSSIP;
+                self.mip = (self.mip & !mask) | (value << 1 & mask);
+            }
+            VSTIMECMP => h.vstimecmp = value,
+            VSATP => match value >> SATP_MODE_SHIFT {
+                SATP_MODE_BARE | SATP_MODE_SV39 => h.vsatp = value,
+                _ => {}
+            },
+
+            HSTATUS => h.hstatus = value & HSTATUS_WMASK,
+            HEDELEG => h.hedeleg = value & HEDELEG_WMASK,
+            HIDELEG => h.hideleg = value & IRQ_VS,
+            HIE => self.mie = (self.mie & !IRQ_VS) | (value & IRQ_VS),
+            HTIMEDELTA => h.htimedelta = value,
+            HCOUNTEREN => h.hcounteren = value as u32,
+            HENVCFG => h.henvcfg = legal_envcfg(value, HENVCFG_WMASK),
+            HGEIE => {}
+            HTVAL => h.htval = value,
+            HIP => self.mip = (self.mip & !IRQ_VSSIP) | (value & IRQ_VSSIP),
+            HVIP => self.mip = (self.mip & !IRQ_VS) | (value & IRQ_VS),
+            HTINST => h.htinst = value,
+            // VMIDs aren't implemented, and the root table is 16KiB aligned
+            HGATP => match value >> SATP_MODE_SHIFT {
+                SATP_MODE_BARE => h.hgatp = 0,
+                HGATP_MODE_SV39X4 => h.hgatp = value & (0xf << SATP_MODE_SHIFT | PPN_MASK & !0b11),
+                _ => {}
+            },
+
+            MTINST => h.mtinst = value,
+            MTVAL2 => h.mtval2 = value,
+            _ => return Err(ILLEGAL),
+        }
+        Ok(())
+    }
+
+    /// The time a guest sees, shifted by htimedelta.
+    fn guest_time(&self) -> u64 {
+        self.counters.time.wrapping_add(self.h.htimedelta)
+    }
+
+    fn guest_sstc(&self) -> bool {
+        self.h.henvcfg & self.menvcfg & MENVCFG_STCE != 0
+    }
+
+    pub(super) fn vstimecmp_pending(&self) -> bool {
+        self.guest_sstc() && self.guest_time() >= self.h.vstimecmp
+    }
+
+    /// The host `time` at which vstimecmp fires, unless it's pending already.
+    pub(super) fn vstimecmp_deadline(&self) -> Option<u64> {
+        let guest_time = self.guest_time();
+        (self.guest_sstc() && guest_time < self.h.vstimecmp).then(|| {
+            self.counters
+                .time
+                .saturating_add(self.h.vstimecmp - guest_time)
+        })
+    }
+}
+
+impl CpuState {
+    /// Enter or leave a guest. Decoded instructions are cached per privilege
+    /// and address, which doesn't tell the guest from its host.
+    pub(super) fn set_virt(&mut self, virt: bool) {
+        if self.virt != virt {
+            self.virt = virt;
+            self.flush_decoded();
+        }
+    }
+
+    /// The csr a guest actually accesses: VS-mode reaches the VS csrs through
+    /// the S csr numbers, everything HS-mode could access but a guest can't
+    /// raises a virtual instruction exception.
+    pub(super) fn guest_csr(&self, csr: u32) -> Result<u32, Exception> {
+        if !self.virt {
+            return Ok(csr);
+        }
+        let virtual_instruction = Err(Exception::VirtualInstruction(0));
+        match csr >> 8 & 0b11 {
+            1 if self.mode == Privilege::User => virtual_instruction,
+            1 => match csr {
+                SSTATUS | SIE | STVEC | SSCRATCH | SEPC | SCAUSE | STVAL | SIP => Ok(csr + 0x100),
+                SATP if self.csr.h.hstatus & HSTATUS_VTVM != 0 => virtual_instruction,
+                SATP => Ok(VSATP),
+                // HS-mode's own access rules come first, then henvcfg and hcounteren
+                STIMECMP => {
+                    self.csr.check_sstc(Privilege::Supervisor)?;
+                    if self.csr.h.henvcfg & MENVCFG_STCE == 0 || self.csr.h.hcounteren & 1 << 1 == 0
+                    {
+                        virtual_instruction
+                    } else {
+                        Ok(VSTIMECMP)
+                    }
+                }
+                _ => Ok(csr),
+            },
+            2 => virtual_instruction,
+            _ => Ok(csr),
+        }
+    }
+
+    /// Guests need hcounteren, and scounteren in VU-mode, to read a counter,
+    /// and see the time shifted by htimedelta.
+    pub(super) fn guest_csr_read(&self, csr: u32) -> Result<u64, Exception> {
+        let csr = self.guest_csr(csr)?;
+        if !(CYCLE..=HPMCOUNTER31).contains(&csr) {
+            return self.csr.read(csr, self.mode);
+        }
+        let counters = &self.csr.counters;
+        let bit = 1 << (csr - CYCLE);
+        if counters.mcounteren & bit == 0 {
+            return Err(ILLEGAL);
+        }
+        if self.csr.h.hcounteren & bit == 0
+            || (self.mode == Privilege::User && counters.scounteren & bit == 0)
+        {
+            return Err(Exception::VirtualInstruction(0));
+        }
+        let value = counters.read(csr - CYCLE);
+        if csr == TIME {
+            Ok(value.wrapping_add(self.csr.h.htimedelta))
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Privileged instructions a guest may not execute although HS-mode could
+    /// raise virtual instruction exceptions.
+    pub(super) fn check_guest(&self, inst: Instruction) -> Result<(), Exception> {
+        use Instruction::*;
+
+        if !self.virt {
+            return Ok(());
+        }
+        let hstatus = self.csr.h.hstatus;
+        let vu = self.mode == Privilege::User;
+        let virtual_instruction = match inst {
+            Sret => vu || hstatus & HSTATUS_VTSR != 0,
+            // mstatus.TW takes precedence and raises an illegal instruction exception
+            Wfi => self.csr.mstatus & MSTATUS_TW == 0 && (vu || hstatus & HSTATUS_VTW != 0),
+            WrsNto => self.csr.mstatus & MSTATUS_TW == 0 && hstatus & HSTATUS_VTW != 0,
+            SfenceVma(_) => vu || hstatus & HSTATUS_VTVM != 0,
+            _ => true,
+        };
+        if virtual_instruction {
+            Err(Exception::VirtualInstruction(0))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(super) fn execute_hypervisor(
+        &mut self,
+        inst: Instruction,
+        bus: &mut Bus,
+    ) -> Result<(), Exception> {
+        use Instruction::*;
+
+        self.check_guest(inst)?;
+        let hstatus = self.csr.h.hstatus;
+        let r = match inst {
+            HfenceVvma(_) | HfenceGvma(_) => {
+                if self.mode == Privilege::User
+                    || (matches!(inst, HfenceGvma(_))
+                        && self.mode == Privilege::Supervisor
+                        && self.csr.mstatus & MSTATUS_TVM != 0)
+                {
+                    return Err(ILLEGAL);
+                }
+                // guest translations are never cached, only decoded instructions
+                self.flush_decoded();
+                return Ok(());
+            }
+            HlvB(r) | HlvBu(r) | HlvH(r) | HlvHu(r) | HlvxHu(r) | HlvW(r) | HlvWu(r)
+            | HlvxWu(r) | HlvD(r) | HsvB(r) | HsvH(r) | HsvW(r) | HsvD(r) => r,
+            _ => unreachable!(),
+        };
+        if self.mode == Privilege::User && hstatus & HSTATUS_HU == 0 {
+            return Err(ILLEGAL);
+        }
+
+        let mode = if hstatus & HSTATUS_SPVP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        self.hlsv = Some((mode, matches!(inst, HlvxHu(_) | HlvxWu(_))));
+        let addr = self.reg(r.rs1());
+        let data = self.reg(r.rs2());
+        let result = match inst {
+            HlvB(_) => self.load(addr, 1, bus).map(|v| Some(sext(v, 8))),
+            HlvBu(_) => self.load(addr, 1, bus).map(Some),
+            HlvH(_) => self.load(addr, 2, bus).map(|v| Some(sext(v, 16))),
+            HlvHu(_) | HlvxHu(_) => self.load(addr, 2, bus).map(Some),
+            HlvW(_) => self.load(addr, 4, bus).map(|v| Some(sext(v, 32))),
+            HlvWu(_) | HlvxWu(_) => self.load(addr, 4, bus).map(Some),
+            HlvD(_) => self.load(addr, 8, bus).map(Some),
+            HsvB(_) => self.store(addr, 1, data, bus).map(|_| None),
+            HsvH(_) => self.store(addr, 2, data, bus).map(|_| None),
+            HsvW(_) => self.store(addr, 4, data, bus).map(|_| None),
+            _ => self.store(addr, 8, data, bus).map(|_| None),
+        };
+        // on a fault the access stays marked until raise() has seen it, so
+        // that the trap reports a guest virtual address
+        if let Some(value) = result? {
+            self.set_reg(r.rd(), value);
+        }
+        self.hlsv = None;
+        Ok(())
+    }
+
+    /// Interrupts pending for and enabled in VS-mode, only while in a guest.
+    pub(super) fn vs_interrupts(&self, pending: u64) -> u64 {
+        if !self.virt {
+            return 0;
+        }
+        let enabled = self.mode == Privilege::User || self.csr.h.vsstatus & MSTATUS_SIE != 0;
+        if enabled {
+            pending & self.csr.h.hideleg & IRQ_VS
+        } else {
+            0
+        }
+    }
+
+    /// Take a trap from a guest in VS-mode if both M-mode and HS-mode delegate
+    /// it. VS interrupts arrive as the matching S interrupts.
+    pub(super) fn trap_to_vs(&mut self, code: u64, tval: u64, interrupt: bool) -> bool {
+        if !self.virt {
+            return false;
+        }
+        let (deleg, hdeleg) = if interrupt {
+            (self.csr.mideleg, self.csr.h.hideleg)
+        } else {
+            (self.csr.medeleg, self.csr.h.hedeleg)
+        };
+        if (deleg & hdeleg) >> code & 1 == 0 {
+            return false;
+        }
+        let code = if interrupt { code - 1 } else { code };
+        let pc = self.pc();
+        let h = &mut self.csr.h;
+        h.vsepc = pc;
+        h.vscause = code | (interrupt as u64) << 63;
+        h.vstval = tval;
+        let vsstatus = h.vsstatus;
+        let mut status = vsstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+        if vsstatus & MSTATUS_SIE != 0 {
+            status |= MSTATUS_SPIE;
+        }
+        if self.mode == Privilege::Supervisor {
+            status |= MSTATUS_SPP;
+        }
+        h.vsstatus = status;
+        let vector = trap_vector(h.vstvec, code, interrupt);
+        self.set_pc(vector);
+        self.mode = Privilege::Supervisor;
+        true
+    }
+
+    /// Record in hstatus where a trap into HS-mode came from, and leave the guest.
+    pub(super) fn trap_to_hs(&mut self) {
+        let h = &mut self.csr.h;
+        let mut hstatus = h.hstatus & !(HSTATUS_SPV | HSTATUS_GVA);
+        if self.virt {
+            hstatus = (hstatus | HSTATUS_SPV) & !HSTATUS_SPVP;
+            if self.mode == Privilege::Supervisor {
+                hstatus |= HSTATUS_SPVP;
+            }
+        }
+        h.hstatus = hstatus;
+        h.htval = 0;
+        h.htinst = 0;
+        self.set_virt(false);
+    }
+
+    /// Record in mstatus whether a trap into M-mode came from a guest, and leave it.
+    pub(super) fn trap_to_m(&mut self) {
+        let mut mstatus = self.csr.mstatus & !(MSTATUS_MPV | MSTATUS_GVA);
+        if self.virt {
+            mstatus |= MSTATUS_MPV;
+        }
+        self.csr.mstatus = mstatus;
+        self.csr.h.mtval2 = 0;
+        self.csr.h.mtinst = 0;
+        self.set_virt(false);
+    }
+
+    /// Complete an exception taken into M- or HS-mode with the guest physical
+    /// address of a guest page fault, and whether xtval is a guest address.
+    pub(super) fn trap_guest_address(&mut self, gpa: u64, gva: bool) {
+        self.hlsv = None;
+        match self.mode {
+            Privilege::Machine => {
+                self.csr.h.mtval2 = gpa >> 2;
+                if gva {
+                    self.csr.mstatus |= MSTATUS_GVA;
+                }
+            }
+            Privilege::Supervisor if !self.virt => {
+                self.csr.h.htval = gpa >> 2;
+                if gva {
+                    self.csr.h.hstatus |= HSTATUS_GVA;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// `sret` in VS-mode returns within the guest, using vsstatus.
+    pub(super) fn vs_sret(&mut self) -> u64 {
+        let h = &mut self.csr.h;
+        let vsstatus = h.vsstatus;
+        let mut status = (vsstatus & !(MSTATUS_SIE | MSTATUS_SPP)) | MSTATUS_SPIE;
+        if vsstatus & MSTATUS_SPIE != 0 {
+            status |= MSTATUS_SIE;
+        }
+        h.vsstatus = status;
+        self.mode = if vsstatus & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        self.csr.h.vsepc
+    }
+
+    /// `mret` re-enters a guest if the trap came from one.
+    pub(super) fn mret_virt(&mut self, mpp: Privilege) {
+        let mpv = self.csr.mstatus & MSTATUS_MPV != 0;
+        self.csr.mstatus &= !MSTATUS_MPV;
+        self.set_virt(mpv && mpp != Privilege::Machine);
+    }
+
+    /// Whether an access is made on behalf of a guest: from VS/VU-mode, by
+    /// HLV/HSV, or by M-mode with MPRV set and MPV selecting a guest.
+    pub(super) fn guest_translation(&self, access: AccessType) -> bool {
+        if self.virt {
+            return true;
+        }
+        if access == AccessType::Fetch {
+            return false;
+        }
+        let mstatus = self.csr.mstatus;
+        self.hlsv.is_some()
+            || (self.mode == Privilege::Machine
+                && mstatus & MSTATUS_MPRV != 0
+                && mstatus & MSTATUS_MPV != 0
+                && Privilege::from_bits(mstatus >> MSTATUS_MPP_SHIFT) != Privilege::Machine)
+    }
+
+    /// Translate a guest virtual address through the VS-stage and then the
+    /// G-stage tables.
+    pub(super) fn translate_guest(
+        &mut self,
+        vaddr: u64,
+        access: AccessType,
+        bus: &mut Bus,
+    ) -> Result<u64, Exception> {
+        let mode = match access {
+            AccessType::Fetch => self.mode,
+            _ => self.data_mode(),
+        };
+        // HLVX needs execute permission in both stages instead of read permission
+        let check = match self.hlsv {
+            Some((_, true)) => AccessType::Fetch,
+            _ => access,
+        };
+        let gpa = if self.csr.h.vsatp >> SATP_MODE_SHIFT == SATP_MODE_BARE {
+            vaddr
+        } else {
+            self.walk_vs(vaddr, access, check, mode, bus)?
+        };
+        self.walk_g(gpa, vaddr, access, check, bus)
+    }
+
+    /// Sv39 walk of the guest's own tables, which live in guest physical
+    /// memory. `check` is the permission the leaf has to grant for `access`.
+    fn walk_vs(
+        &mut self,
+        vaddr: u64,
+        access: AccessType,
+        check: AccessType,
+        mode: Privilege,
+        bus: &mut Bus,
+    ) -> Result<u64, Exception> {
+        let shift = 64 - SV39_VA_BITS;
+        if ((vaddr << shift) as i64 >> shift) as u64 != vaddr {
+            return Err(access.page_fault(vaddr));
+        }
+        let vsstatus = self.csr.h.vsstatus;
+        let sum = vsstatus & MSTATUS_SUM != 0;
+        let mxr = (vsstatus | self.csr.mstatus) & MSTATUS_MXR != 0;
+
+        let vpn = vaddr >> PAGE_SHIFT;
+        let pbmte = self.csr.h.henvcfg & self.csr.menvcfg & MENVCFG_PBMTE != 0;
+        let mut table = (self.csr.h.vsatp & PPN_MASK) << PAGE_SHIFT;
+        for level in (0..SV39_LEVELS).rev() {
+            let index = vpn >> (level * SV39_VPN_BITS) & ((1 << SV39_VPN_BITS) - 1);
+            let pte_gpa = table + index * 8;
+            // the implicit pte accesses are reads, even for a fetch or a store
+            let pte_addr = self.walk_g(pte_gpa, vaddr, access, AccessType::Load, bus)?;
+            let pte = self
+                .phys_read(pte_addr, 8, AccessType::Load, Privilege::Supervisor, bus)
+                .map_err(|_| access.access_fault(vaddr))?;
+
+            let ppn = match parse_pte(pte, level, vpn, pbmte) {
+                Some(Pte::Table(ppn)) => {
+                    table = ppn << PAGE_SHIFT;
+                    continue;
+                }
+                Some(Pte::Leaf(ppn)) if pte_permits(pte, check, mode, sum, mxr) => ppn,
+                _ => return Err(access.page_fault(vaddr)),
+            };
+            let mut updated = pte | PTE_A;
+            if access == AccessType::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                self.walk_g(pte_gpa, vaddr, access, AccessType::Store, bus)?;
+                self.phys_write(pte_addr, 8, updated, Privilege::Supervisor, bus)
+                    .map_err(|_| access.access_fault(vaddr))?;
+            }
+            return Ok(ppn << PAGE_SHIFT | vaddr & (PAGE_SIZE - 1));
+        }
+        Err(access.page_fault(vaddr))
+    }
+
+    /// Sv39x4 walk of the hypervisor's tables for guest physical address
+    /// `gpa`, reached from guest virtual address `vaddr`. Every leaf must be
+    /// a user page, since guest accesses are checked as user accesses.
+    fn walk_g(
+        &mut self,
+        gpa: u64,
+        vaddr: u64,
+        access: AccessType,
+        check: AccessType,
+        bus: &mut Bus,
+    ) -> Result<u64, Exception> {
+        let hgatp = self.csr.h.hgatp;
+        if hgatp >> SATP_MODE_SHIFT == SATP_MODE_BARE {
+            return Ok(gpa);
+        }
+        let fault = access.guest_page_fault(vaddr, gpa);
+        if gpa >> SV39X4_GPA_BITS != 0 {
+            return Err(fault);
+        }
+        let mxr = self.csr.mstatus & MSTATUS_MXR != 0;
+        let pbmte = self.csr.menvcfg & MENVCFG_PBMTE != 0;
+
+        let gpn = gpa >> PAGE_SHIFT;
+        let mut table = (hgatp & PPN_MASK) << PAGE_SHIFT;
+        for level in (0..SV39_LEVELS).rev() {
+            let bits = if level == SV39_LEVELS - 1 {
+                SV39X4_ROOT_BITS
+            } else {
+                SV39_VPN_BITS
+            };
+            let index = gpn >> (level * SV39_VPN_BITS) & ((1 << bits) - 1);
+            let pte_addr = table + index * 8;
+            let pte = self
+                .phys_read(pte_addr, 8, AccessType::Load, Privilege::Supervisor, bus)
+                .map_err(|_| access.access_fault(vaddr))?;
+
+            // G-stage leaves are all user pages
+            let ppn = match parse_pte(pte, level, gpn, pbmte) {
+                Some(Pte::Table(ppn)) => {
+                    table = ppn << PAGE_SHIFT;
+                    continue;
+                }
+                Some(Pte::Leaf(ppn))
+                    if pte & PTE_U != 0 && pte_permits(pte, check, Privilege::User, false, mxr) =>
+                {
+                    ppn
+                }
+                _ => return Err(fault),
+            };
+            let mut updated = pte | PTE_A;
+            if check == AccessType::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                self.phys_write(pte_addr, 8, updated, Privilege::Supervisor, bus)
+                    .map_err(|_| access.access_fault(vaddr))?;
+            }
+            return Ok(ppn << PAGE_SHIFT | gpa & (PAGE_SIZE - 1));
+        }
+        Err(fault)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::csr::{IRQ_VSTIP, MEDELEG, MIDELEG, MISA, MSTATUS};
+    use super::super::decode::decode;
+    use super::super::mmu::{PTE_R, PTE_V, PTE_W, PTE_X};
+    use super::*;
+    use crate::device::io::map::IOMap;
+    use crate::memory::ram::Ram;
+
+    const VALID: u64 = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+
+    fn run(cpu: &mut CpuState, raw: u32, bus: &mut Bus) -> Result<(), Exception> {
+        cpu.execute(decode(raw).unwrap(), 4, bus)
+    }
+
+    fn pte(space: &mut [u8], offset: usize, value: u64) {
+        space[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// The G-stage root at 0x9000_0000 maps guest physical 0x4000_0000 read
+    /// only onto 0x8000_0000, and 0x8000_0000 onto itself. The guest's root
+    /// at 0x9000_4000 maps 0 onto guest physical 0x4000_0000, so that guest
+    /// virtual 0x1000_6000 ends up at 0x9000_6000.
+    fn guest() -> (CpuState, Bus) {
+        let mut data = IOMap::scratch("data", 0x9000_0000, 0x8000);
+        pte(
+            &mut data.space,
+            8,
+            (0x8000_0000 >> 2) | (VALID & !PTE_W) | PTE_U,
+        );
+        pte(&mut data.space, 16, (0x8000_0000 >> 2) | VALID | PTE_U);
+        pte(
+            &mut data.space,
+            0x4000,
+            (0x4000_0000 >> 2) | PTE_V | PTE_R | PTE_W | PTE_X,
+        );
+        data.space[0x6000..0x6008].copy_from_slice(&0x1122_3344_5566_7788u64.to_le_bytes());
+        let bus = Bus::new(Ram::default(), vec![data]);
+        let mut cpu = CpuState::new(0);
+        let m = Privilege::Machine;
+        cpu.csr
+            .write(HGATP, HGATP_MODE_SV39X4 << 60 | 0x9000_0000 >> 12, m)
+            .unwrap();
+        cpu.csr
+            .write(VSATP, SATP_MODE_SV39 << 60 | 0x9000_4000 >> 12, m)
+            .unwrap();
+        cpu.mode = Privilege::Supervisor;
+        (cpu, bus)
+    }
+
+    #[test]
+    fn csrs() {
+        let mut csr = CsrFile::new(0);
+        let m = Privilege::Machine;
+        assert_ne!(csr.read(MISA, m).unwrap() & 1 << 7, 0);
+        assert_eq!(csr.read(MIDELEG, m), Ok(IRQ_VS));
+        csr.write(MIDELEG, 0, m).unwrap();
+        assert_eq!(csr.read(MIDELEG, m), Ok(IRQ_VS));
+        csr.write(MSTATUS, MSTATUS_MPV, m).unwrap();
+        assert_eq!(csr.mstatus & MSTATUS_MPV, MSTATUS_MPV);
+
+        let s = Privilege::Supervisor;
+        csr.write(HSTATUS, u64::MAX, s).unwrap();
+        assert_eq!(csr.read(HSTATUS, s), Ok(HSTATUS_WMASK | HSTATUS_VSXL));
+        assert_eq!(csr.read(HSTATUS, Privilege::User), Err(ILLEGAL));
+        assert_eq!(csr.write(HGEIP, 0, s), Err(ILLEGAL));
+        csr.write(HIDELEG, u64::MAX, s).unwrap();
+        assert_eq!(csr.read(HIDELEG, s), Ok(IRQ_VS));
+        csr.write(HVIP, IRQ_VSTIP, s).unwrap();
+        assert_eq!(csr.read(VSIP, s), Ok(IRQ_VSTIP >> 1));
+        assert_eq!(csr.read(SIP, s), Ok(0));
+        csr.write(VSIE, u64::MAX, s).unwrap();
+        assert_eq!(csr.read(HIE, s), Ok(IRQ_VS));
+        // Sv48x4 isn't supported
+        csr.write(HGATP, 9 << 60, s).unwrap();
+        assert_eq!(csr.read(HGATP, s), Ok(0));
+    }
+
+    #[test]
+    fn guest_csrs() {
+        let mut cpu = CpuState::new(0);
+        cpu.mode = Privilege::Supervisor;
+        cpu.virt = true;
+        cpu.set_reg(1, 0x1234);
+        run(&mut cpu, 0x14009173, &mut Bus::default()).unwrap(); // csrrw x2,sscratch,x1
+        assert_eq!(cpu.csr.h.vsscratch, 0x1234);
+        assert_eq!(cpu.csr.sscratch, 0);
+        run(&mut cpu, 0x10002173, &mut Bus::default()).unwrap(); // csrrs x2,sstatus,x0
+        assert_eq!(cpu.reg(2), VSSTATUS_UXL);
+        assert_eq!(
+            run(&mut cpu, 0x60002173, &mut Bus::default()), // csrrs x2,hstatus,x0
+            Err(Exception::VirtualInstruction(0))
+        );
+        assert_eq!(
+            run(&mut cpu, 0x30002173, &mut Bus::default()), // csrrs x2,mstatus,x0
+            Err(ILLEGAL)
+        );
+
+        cpu.csr.counters.mcounteren = 0b010;
+        cpu.csr.counters.time = 10;
+        assert_eq!(
+            run(&mut cpu, 0xc0102173, &mut Bus::default()), // csrrs x2,time,x0
+            Err(Exception::VirtualInstruction(0))
+        );
+        cpu.csr.h.hcounteren = 0b010;
+        cpu.csr.h.htimedelta = 5;
+        run(&mut cpu, 0xc0102173, &mut Bus::default()).unwrap(); // csrrs x2,time,x0
+        assert_eq!(cpu.reg(2), 15);
+
+        cpu.mode = Privilege::User;
+        assert_eq!(
+            run(&mut cpu, 0x14002173, &mut Bus::default()), // csrrs x2,sscratch,x0
+            Err(Exception::VirtualInstruction(0))
+        );
+        assert_eq!(
+            run(&mut cpu, 0x10500073, &mut Bus::default()), // wfi
+            Err(Exception::VirtualInstruction(0))
+        );
+    }
+
+    #[test]
+    fn vstimecmp() {
+        let mut cpu = CpuState::new(0);
+        cpu.mode = Privilege::Supervisor;
+        cpu.virt = true;
+        cpu.set_reg(1, 100);
+        let csrrw = 0x14d09173; // csrrw x2,stimecmp,x1
+        assert_eq!(run(&mut cpu, csrrw, &mut Bus::default()), Err(ILLEGAL));
+        cpu.csr.menvcfg = MENVCFG_STCE;
+        cpu.csr.counters.mcounteren = 0b010;
+        assert_eq!(
+            run(&mut cpu, csrrw, &mut Bus::default()),
+            Err(Exception::VirtualInstruction(0))
+        );
+        cpu.csr.h.henvcfg = MENVCFG_STCE;
+        cpu.csr.h.hcounteren = 0b010;
+        run(&mut cpu, csrrw, &mut Bus::default()).unwrap();
+        assert_eq!(cpu.csr.h.vstimecmp, 100);
+        assert_eq!(cpu.csr.stimecmp, u64::MAX);
+
+        // the comparator runs on guest time
+        cpu.csr.h.htimedelta = 10;
+        cpu.csr.counters.time = 80;
+        assert_eq!(cpu.csr.effective_mip() & IRQ_VSTIP, 0);
+        assert_eq!(cpu.csr.sstc_deadline(), Some(90));
+        cpu.csr.counters.time = 90;
+        assert_eq!(cpu.csr.effective_mip() & IRQ_VSTIP, IRQ_VSTIP);
+        assert_eq!(cpu.csr.read(HVIP, Privilege::Machine), Ok(0));
+    }
+
+    #[test]
+    fn two_stage() {
+        let (mut cpu, mut bus) = guest();
+        cpu.virt = true;
+        assert_eq!(
+            cpu.load(0x1000_6000, 8, &mut bus),
+            Ok(0x1122_3344_5566_7788)
+        );
+        // the guest's page table walk set A in its pte
+        assert_eq!(
+            cpu.phys_read(0x9000_4000, 8, AccessType::Load, Privilege::Machine, &bus),
+            Ok((0x4000_0000 >> 2) | (VALID & !PTE_D))
+        );
+        let e = cpu.store(0x1000_6000, 4, 0, &mut bus).unwrap_err();
+        assert_eq!(e, Exception::StoreGuestPageFault(0x1000_6000, 0x5000_6000));
+        // unmapped in the G-stage, and beyond 41 bits
+        cpu.csr.h.vsatp = 0;
+        assert_eq!(
+            cpu.load(0x200_0000_0000, 1, &mut bus),
+            Err(Exception::LoadGuestPageFault(
+                0x200_0000_0000,
+                0x200_0000_0000
+            ))
+        );
+        assert_eq!(
+            cpu.load(0xc000_0000, 1, &mut bus),
+            Err(Exception::LoadGuestPageFault(0xc000_0000, 0xc000_0000))
+        );
+
+        // the fault goes to HS-mode with the guest physical address in htval
+        cpu.csr.write(MEDELEG, 1 << 23, Privilege::Machine).unwrap();
+        cpu.csr
+            .write(STVEC, 0x8000_0200, Privilege::Machine)
+            .unwrap();
+        cpu.set_pc(0x1000);
+        cpu.raise(e);
+        assert!(!cpu.virt);
+        assert_eq!(cpu.mode, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x8000_0200);
+        assert_eq!(cpu.csr.stval, 0x1000_6000);
+        assert_eq!(cpu.csr.h.htval, 0x5000_6000 >> 2);
+        let hstatus = cpu.csr.h.hstatus;
+        assert_eq!(
+            hstatus & (HSTATUS_SPV | HSTATUS_SPVP | HSTATUS_GVA),
+            HSTATUS_SPV | HSTATUS_SPVP | HSTATUS_GVA
+        );
+        run(&mut cpu, 0x10200073, &mut bus).unwrap(); // sret
+        assert!(cpu.virt);
+        assert_eq!(cpu.mode, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x1000);
+    }
+
+    #[test]
+    fn hlv_hsv() {
+        let (mut cpu, mut bus) = guest();
+        cpu.csr.h.hstatus = HSTATUS_SPVP;
+        cpu.set_reg(11, 0x1000_6000);
+        run(&mut cpu, 0x6c05c573, &mut bus).unwrap(); // hlv.d x10,(x11)
+        assert_eq!(cpu.reg(10), 0x1122_3344_5566_7788);
+        run(&mut cpu, 0x6835c573, &mut bus).unwrap(); // hlvx.wu x10,(x11)
+        assert_eq!(cpu.reg(10), 0x5566_7788);
+        let e = run(&mut cpu, 0x6aa5c073, &mut bus).unwrap_err(); // hsv.w x10,(x11)
+        assert_eq!(e, Exception::StoreGuestPageFault(0x1000_6000, 0x5000_6000));
+        cpu.raise(e);
+        assert_eq!(cpu.mode, Privilege::Machine);
+        assert!(!cpu.virt);
+        assert_eq!(cpu.csr.h.mtval2, 0x5000_6000 >> 2);
+        assert_ne!(cpu.csr.mstatus & MSTATUS_GVA, 0);
+        assert_eq!(cpu.csr.mstatus & MSTATUS_MPV, 0);
+        // later accesses are the host's own again
+        assert_eq!(
+            cpu.load(0x9000_6000, 8, &mut bus),
+            Ok(0x1122_3344_5566_7788)
+        );
+
+        cpu.mode = Privilege::User;
+        assert_eq!(run(&mut cpu, 0x6c05c573, &mut bus), Err(ILLEGAL));
+        cpu.csr.h.hstatus |= HSTATUS_HU;
+        run(&mut cpu, 0x6015c573, &mut bus).unwrap(); // hlv.bu x10,(x11)
+        assert_eq!(cpu.reg(10), 0x88);
+        cpu.virt = true;
+        assert_eq!(
+            run(&mut cpu, 0x6c05c573, &mut bus),
+            Err(Exception::VirtualInstruction(0))
+        );
+        assert_eq!(
+            run(&mut cpu, 0x62000073, &mut bus), // hfence.gvma
+            Err(Exception::VirtualInstruction(0))
+        );
+    }
+
+    #[test]
+    fn guest_traps() {
+        let mut cpu = CpuState::new(0);
+        let m = Privilege::Machine;
+        cpu.csr.write(MEDELEG, 1 << 8, m).unwrap();
+        cpu.csr.write(HEDELEG, 1 << 8 | 1 << 10, m).unwrap();
+        cpu.csr.write(VSTVEC, 0x8000_0300, m).unwrap();
+        cpu.mode = Privilege::User;
+        cpu.virt = true;
+        cpu.set_pc(0x1000);
+
+        // VU ecall, delegated to VS-mode
+        let e = run(&mut cpu, 0x00000073, &mut Bus::default()).unwrap_err();
+        cpu.raise(e);
+        assert!(cpu.virt);
+        assert_eq!(cpu.mode, Privilege::Supervisor);
+        assert_eq!(cpu.pc(), 0x8000_0300);
+        assert_eq!(cpu.csr.h.vsepc, 0x1000);
+        assert_eq!(cpu.csr.h.vscause, 8);
+
+        // VS ecall isn't delegated by medeleg, so M-mode takes it
+        let e = run(&mut cpu, 0x00000073, &mut Bus::default()).unwrap_err();
+        assert_eq!(e, Exception::VirtualSupervisorEnvCall);
+        cpu.raise(e);
+        assert!(!cpu.virt);
+        assert_eq!(cpu.mode, Privilege::Machine);
+        assert_ne!(cpu.csr.mstatus & MSTATUS_MPV, 0);
+        run(&mut cpu, 0x30200073, &mut Bus::default()).unwrap(); // mret
+        assert!(cpu.virt);
+        assert_eq!(cpu.mode, Privilege::Supervisor);
+        run(&mut cpu, 0x10200073, &mut Bus::default()).unwrap(); // sret
+        assert_eq!(cpu.mode, Privilege::User);
+        assert_eq!(cpu.pc(), 0x1000);
+
+        // a VS timer interrupt is taken in VS-mode as an S timer interrupt
+        cpu.csr.write(HIDELEG, IRQ_VSTIP, m).unwrap();
+        cpu.csr.write(HVIP, IRQ_VSTIP, m).unwrap();
+        cpu.csr.write(HIE, IRQ_VSTIP, m).unwrap();
+        assert_eq!(cpu.pending_interrupt(), Some(6));
+        assert!(cpu.take_interrupt());
+        assert_eq!(cpu.csr.h.vscause, 5 | 1 << 63);
+        assert_eq!(cpu.mode, Privilege::Supervisor);
+        // and masked by vsstatus.SIE in VS-mode
+        assert_eq!(cpu.pending_interrupt(), None);
+        cpu.virt = false;
+        assert_eq!(cpu.pending_interrupt(), None);
+    }
+}
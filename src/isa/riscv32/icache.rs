@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+
+use super::csr::Privilege;
+use super::instruction::Instruction;
+use super::mmu::{PAGE_SHIFT, PAGE_SIZE};
+
+/// Decoded pages kept before the whole cache is dropped.
+pub const CONFIG_ICACHE_PAGES: usize = 256;
+
+/// Instructions are at least 2-byte aligned.
+const SLOTS_PER_PAGE: usize = (PAGE_SIZE / 2) as usize;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Decoded {
+    pub inst: Instruction,
+    pub raw: u32,
+}
+
+impl Decoded {
+    #[inline(always)]
+    pub fn size(&self) -> u64 {
+        if self.raw & 0b11 == 0b11 {
+            4
+        } else {
+            2
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct DecodedPage {
+    /// physical page backing the virtual page when it was decoded
+    ppn: u64,
+    /// privilege level the page was fetched in, translation and PMP depend on it
+    mode: Privilege,
+    slots: Vec<Option<Decoded>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DecodeCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// pages dropped by stores, fence.i or translation changes
+    pub invalidations: u64,
+}
+
+impl DecodeCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Decoded instructions per virtual page, so that a hit skips both the fetch
+/// and `decode`.
+#[derive(Clone, Debug)]
+pub struct DecodeCache {
+    pub enabled: bool,
+    pages: HashMap<u64, DecodedPage>,
+    /// physical pages with decoded instructions, checked on every store
+    ppns: HashSet<u64>,
+    pub stats: DecodeCacheStats,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            pages: HashMap::new(),
+            ppns: HashSet::new(),
+            stats: DecodeCacheStats::default(),
+        }
+    }
+
+    #[inline(always)]
+    fn slot(pc: u64) -> usize {
+        ((pc & (PAGE_SIZE - 1)) >> 1) as usize
+    }
+
+    pub fn lookup(&mut self, pc: u64, mode: Privilege) -> Option<Decoded> {
+        if !self.enabled {
+            return None;
+        }
+        let hit = self
+            .pages
+            .get(&(pc >> PAGE_SHIFT))
+            .filter(|page| page.mode == mode)
+            .and_then(|page| page.slots[Self::slot(pc)]);
+        match hit {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        hit
+    }
+
+    /// Remember the instruction at `pc`, which was fetched from `paddr`.
+    pub fn insert(&mut self, pc: u64, paddr: u64, mode: Privilege, decoded: Decoded) {
+        // an instruction crossing into the next page depends on two translations
+        if !self.enabled || Self::slot(pc) == SLOTS_PER_PAGE - 1 && decoded.size() == 4 {
+            return;
+        }
+        let ppn = paddr >> PAGE_SHIFT;
+        if self.pages.len() >= CONFIG_ICACHE_PAGES && !self.pages.contains_key(&(pc >> PAGE_SHIFT))
+        {
+            self.flush();
+        }
+        let page = self
+            .pages
+            .entry(pc >> PAGE_SHIFT)
+            .or_insert_with(|| DecodedPage {
+                ppn,
+                mode,
+                slots: vec![None; SLOTS_PER_PAGE],
+            });
+        if page.ppn != ppn || page.mode != mode {
+            page.ppn = ppn;
+            page.mode = mode;
+            page.slots.fill(None);
+        }
+        page.slots[Self::slot(pc)] = Some(decoded);
+        self.ppns.insert(ppn);
+    }
+
+    /// Drop the pages decoded from the physical page containing `paddr`.
+    #[inline]
+    pub fn invalidate(&mut self, paddr: u64) {
+        let ppn = paddr >> PAGE_SHIFT;
+        if !self.ppns.remove(&ppn) {
+            return;
+        }
+        let before = self.pages.len();
+        self.pages.retain(|_, page| page.ppn != ppn);
+        self.stats.invalidations += (before - self.pages.len()) as u64;
+    }
+
+    pub fn flush(&mut self) {
+        self.stats.invalidations += self.pages.len() as u64;
+        self.pages.clear();
+        self.ppns.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOP: Decoded = Decoded {
+        inst: Instruction::FenceI,
+        raw: 0x0000100f,
+    };
+
+    #[test]
+    fn lookup() {
+        let mut cache = DecodeCache::new();
+        assert_eq!(cache.lookup(0x1000, Privilege::Machine), None);
+        cache.insert(0x1000, 0x8000_1000, Privilege::Machine, NOP);
+        assert_eq!(cache.lookup(0x1000, Privilege::Machine), Some(NOP));
+        assert_eq!(cache.lookup(0x1000, Privilege::User), None);
+        // crosses the page boundary
+        cache.insert(0x1ffe, 0x8000_1ffe, Privilege::Machine, NOP);
+        assert_eq!(cache.lookup(0x1ffe, Privilege::Machine), None);
+        assert_eq!(cache.stats.hits, 1);
+        assert_eq!(cache.stats.misses, 3);
+    }
+
+    #[test]
+    fn invalidate() {
+        let mut cache = DecodeCache::new();
+        cache.insert(0x1000, 0x8000_1000, Privilege::Machine, NOP);
+        cache.insert(0x5000, 0x8000_1000, Privilege::Machine, NOP);
+        cache.insert(0x2000, 0x8000_2000, Privilege::Machine, NOP);
+        cache.invalidate(0x8000_3000);
+        cache.invalidate(0x8000_1ff8);
+        assert_eq!(cache.stats.invalidations, 2);
+        assert_eq!(cache.lookup(0x1000, Privilege::Machine), None);
+        assert_eq!(cache.lookup(0x5000, Privilege::Machine), None);
+        assert_eq!(cache.lookup(0x2000, Privilege::Machine), Some(NOP));
+        cache.flush();
+        assert_eq!(cache.lookup(0x2000, Privilege::Machine), None);
+    }
+}
@@ -1,4 +1,4 @@
-use super::types::{UType, BType, JType, IType, SType, RType, CsrIType, CsrType, ShiftType, FenceType, CIType, CJType, CRType, CBType};
+use super::types::{UType, BType, JType, IType, SType, RType, CsrIType, CsrType, ShiftType, FenceType, CIType, CJType, CRType, CBType, CmType};
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Instruction {
@@ -68,9 +68,37 @@ pub enum Instruction {
     Rem(RType),
     Remu(RType),
 
+    // AMO
+    LrW(RType),
+    ScW(RType),
+    AmoswapW(RType),
+    AmoaddW(RType),
+    AmoxorW(RType),
+    AmoandW(RType),
+    AmoorW(RType),
+    AmominW(RType),
+    AmomaxW(RType),
+    AmominuW(RType),
+    AmomaxuW(RType),
+    LrD(RType),
+    ScD(RType),
+    AmoswapD(RType),
+    AmoaddD(RType),
+    AmoxorD(RType),
+    AmoandD(RType),
+    AmoorD(RType),
+    AmominD(RType),
+    AmomaxD(RType),
+    AmominuD(RType),
+    AmomaxuD(RType),
+
     // Misc-mem
     Fence(FenceType),
     FenceI,
+    CboClean(IType),
+    CboFlush(IType),
+    CboInval(IType),
+    CboZero(IType),
 
     // System
     Ecall,
@@ -79,6 +107,8 @@ pub enum Instruction {
     Sret,
     Mret,
     Wfi,
+    WrsNto,
+    WrsSto,
     SfenceVma(RType),
     Csrrw(CsrType),
     Csrrs(CsrType),
@@ -87,6 +117,23 @@ pub enum Instruction {
     Csrrsi(CsrIType),
     Csrrci(CsrIType),
 
+    // Hypervisor
+    HfenceVvma(RType),
+    HfenceGvma(RType),
+    HlvB(RType),
+    HlvBu(RType),
+    HlvH(RType),
+    HlvHu(RType),
+    HlvxHu(RType),
+    HlvW(RType),
+    HlvWu(RType),
+    HlvxWu(RType),
+    HlvD(RType),
+    HsvB(RType),
+    HsvH(RType),
+    HsvW(RType),
+    HsvD(RType),
+
     // // OP-imm 32
     Addiw(IType),
     Slliw(ShiftType),
@@ -105,6 +152,45 @@ pub enum Instruction {
     Remw(RType),
     Remuw(RType),
 
+    // Scalar cryptography: Zbkb, Zbkc, Zbkx, Zknd, Zkne, Zknh
+    Andn(RType),
+    Orn(RType),
+    Xnor(RType),
+    Rol(RType),
+    Ror(RType),
+    Rori(ShiftType),
+    Rolw(RType),
+    Rorw(RType),
+    Roriw(ShiftType),
+    Pack(RType),
+    Packh(RType),
+    Packw(RType),
+    Rev8(IType),
+    Brev8(IType),
+    Clmul(RType),
+    Clmulh(RType),
+    Xperm4(RType),
+    Xperm8(RType),
+    Aes64es(RType),
+    Aes64esm(RType),
+    Aes64ds(RType),
+    Aes64dsm(RType),
+    Aes64im(IType),
+    Aes64ks1i(IType),
+    Aes64ks2(RType),
+    Sha256sig0(IType),
+    Sha256sig1(IType),
+    Sha256sum0(IType),
+    Sha256sum1(IType),
+    Sha512sig0(IType),
+    Sha512sig1(IType),
+    Sha512sum0(IType),
+    Sha512sum1(IType),
+
+    // Integer conditional operations: Zicond
+    CzeroEqz(RType),
+    CzeroNez(RType),
+
     // compressed 
     CNOP(CIType),
     CADDI(CIType),
@@ -125,6 +211,14 @@ pub enum Instruction {
     CBEQZ(CBType),
     CBNEZ(CBType),
 
+    // Zcmp
+    CmPush(CmType),
+    CmPop(CmType),
+    CmPopret(CmType),
+    CmPopretz(CmType),
+    CmMvsa01(CmType),
+    CmMva01s(CmType),
+
     // Illegal
     Illegal,
 }
\ No newline at end of file
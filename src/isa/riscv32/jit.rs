@@ -0,0 +1,422 @@
+//! Second tier for hot basic blocks: their leading run of integer ALU
+//! instructions is compiled to x86-64 code working directly on the register
+//! file. Whatever follows, usually the branch ending the block, still runs
+//! through the threaded handlers.
+
+use std::ptr;
+
+use super::block::Block;
+use super::icache::Decoded;
+use super::instruction::Instruction;
+use super::reg::CpuState;
+use crate::utils::sext;
+
+/// Executions of a block before it is compiled.
+pub const CONFIG_JIT_THRESHOLD: u32 = 16;
+
+const HOST_PAGE_SIZE: usize = 4096;
+
+// x86-64 registers used by the generated code, the register file is in rdi
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+
+/// `extern "C" fn(regs: *mut i64)`
+type Entry = unsafe extern "C" fn(*mut i64);
+
+/// Executable memory holding one compiled block, never writable and
+/// executable at the same time.
+struct ExecMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ExecMemory {
+    fn new(code: &[u8]) -> Option<Self> {
+        let len = code.len().div_ceil(HOST_PAGE_SIZE) * HOST_PAGE_SIZE;
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+            let memory = Self {
+                ptr: ptr as *mut u8,
+                len,
+            };
+            ptr::copy_nonoverlapping(code.as_ptr(), memory.ptr, code.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(memory)
+        }
+    }
+}
+
+impl Drop for ExecMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+pub struct NativeBlock {
+    memory: ExecMemory,
+    /// guest instructions covered by the native code
+    pub insts: usize,
+    /// pc of the first instruction left to the handlers
+    pub next_pc: u64,
+}
+
+impl NativeBlock {
+    fn entry(&self) -> Entry {
+        unsafe { std::mem::transmute::<*mut u8, Entry>(self.memory.ptr) }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct JitStats {
+    pub compiled: u64,
+    /// instructions executed by native code
+    pub native: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Jit {
+    pub enabled: bool,
+    pub stats: JitStats,
+}
+
+#[derive(Default)]
+struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// mov reg, [rdi + 8 * index]
+    fn load(&mut self, reg: u8, index: u32) {
+        self.emit(&[0x48, 0x8b, 0x87 | reg << 3]);
+        self.emit(&(index * 8).to_le_bytes());
+    }
+
+    /// mov [rdi + 8 * index], rax
+    fn store(&mut self, index: u32) {
+        if index != 0 {
+            self.emit(&[0x48, 0x89, 0x87]);
+            self.emit(&(index * 8).to_le_bytes());
+        }
+    }
+
+    /// mov reg, imm64
+    fn imm(&mut self, reg: u8, value: u64) {
+        self.emit(&[0x48, 0xb8 | reg]);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// <op> rax, rcx for the two-operand ALU opcodes
+    fn alu(&mut self, opcode: u8) {
+        self.emit(&[0x48, opcode, 0xc8]);
+    }
+
+    /// shl/shr/sar rax by an immediate, `ext` is the ModRM opcode extension
+    fn shift_imm(&mut self, ext: u8, amount: u32, wide: bool) {
+        if wide {
+            self.emit(&[0x48]);
+        }
+        self.emit(&[0xc1, 0xc0 | ext << 3, amount as u8]);
+    }
+
+    /// shl/shr/sar rax by cl
+    fn shift_cl(&mut self, ext: u8, wide: bool) {
+        if wide {
+            self.emit(&[0x48]);
+        }
+        self.emit(&[0xd3, 0xc0 | ext << 3]);
+    }
+
+    /// rax = (rax < rcx) as u64, signed with setl or unsigned with setb
+    fn set_less(&mut self, signed: bool) {
+        self.alu(0x39);
+        self.emit(&[0x0f, if signed { 0x9c } else { 0x92 }, 0xc0]);
+        self.emit(&[0x0f, 0xb6, 0xc0]);
+    }
+
+    /// movsxd rax, eax
+    fn sext_word(&mut self) {
+        self.emit(&[0x48, 0x63, 0xc0]);
+    }
+
+    fn ret(&mut self) {
+        self.emit(&[0xc3]);
+    }
+}
+
+const ADD: u8 = 0x01;
+const OR: u8 = 0x09;
+const AND: u8 = 0x21;
+const SUB: u8 = 0x29;
+const XOR: u8 = 0x31;
+const SHL: u8 = 4;
+const SHR: u8 = 5;
+const SAR: u8 = 7;
+
+/// Emit `inst` at `pc`, returning false if it has to be left to the handlers.
+fn emit(e: &mut Emitter, inst: Instruction, pc: u64) -> bool {
+    use Instruction::*;
+
+    match inst {
+        Lui(u) => {
+            e.imm(RAX, sext((u.imm() << 12) as u64, 32));
+            e.store(u.rd());
+        }
+        Auipc(u) => {
+            e.imm(RAX, pc.wrapping_add(sext((u.imm() << 12) as u64, 32)));
+            e.store(u.rd());
+        }
+        Addi(i) | Slti(i) | Sltiu(i) | Xori(i) | Ori(i) | Andi(i) | Addiw(i) => {
+            e.load(RAX, i.rs1());
+            e.imm(RCX, sext(i.imm() as u64, 12));
+            match inst {
+                Addi(_) => e.alu(ADD),
+                Slti(_) => e.set_less(true),
+                Sltiu(_) => e.set_less(false),
+                Xori(_) => e.alu(XOR),
+                Ori(_) => e.alu(OR),
+                Andi(_) => e.alu(AND),
+                _ => {
+                    e.alu(ADD);
+                    e.sext_word();
+                }
+            }
+            e.store(i.rd());
+        }
+        Slli(s) | Srli(s) | Srai(s) => {
+            e.load(RAX, s.rs1());
+            let ext = match inst {
+                Slli(_) => SHL,
+                Srli(_) => SHR,
+                _ => SAR,
+            };
+            e.shift_imm(ext, s.shamt() & 0x3f, true);
+            e.store(s.rd());
+        }
+        Slliw(s) | Srliw(s) | Sraiw(s) => {
+            e.load(RAX, s.rs1());
+            let ext = match inst {
+                Slliw(_) => SHL,
+                Srliw(_) => SHR,
+                _ => SAR,
+            };
+            e.shift_imm(ext, s.shamt() & 0x1f, false);
+            e.sext_word();
+            e.store(s.rd());
+        }
+        Add(r) | Sub(r) | Sll(r) | Slt(r) | Sltu(r) | Xor(r) | Srl(r) | Sra(r) | Or(r) | And(r)
+        | Mul(r) | Addw(r) | Subw(r) | Sllw(r) | Srlw(r) | Sraw(r) | Mulw(r) => {
+            e.load(RAX, r.rs1());
+            e.load(RCX, r.rs2());
+            match inst {
+                Add(_) => e.alu(ADD),
+                Sub(_) => e.alu(SUB),
+                Sll(_) => e.shift_cl(SHL, true),
+                Slt(_) => e.set_less(true),
+                Sltu(_) => e.set_less(false),
+                Xor(_) => e.alu(XOR),
+                Srl(_) => e.shift_cl(SHR, true),
+                Sra(_) => e.shift_cl(SAR, true),
+                Or(_) => e.alu(OR),
+                And(_) => e.alu(AND),
+                // imul rax, rcx
+                Mul(_) => e.emit(&[0x48, 0x0f, 0xaf, 0xc1]),
+                Addw(_) | Subw(_) | Mulw(_) => {
+                    match inst {
+                        Addw(_) => e.alu(ADD),
+                        Subw(_) => e.alu(SUB),
+                        _ => e.emit(&[0x48, 0x0f, 0xaf, 0xc1]),
+                    }
+                    e.sext_word();
+                }
+                _ => {
+                    let ext = match inst {
+                        Sllw(_) => SHL,
+                        Srlw(_) => SHR,
+                        _ => SAR,
+                    };
+                    e.shift_cl(ext, false);
+                    e.sext_word();
+                }
+            }
+            e.store(r.rd());
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Compile the longest supported prefix of the block at `pc`.
+pub fn compile(insts: &[Decoded], pc: u64) -> Option<NativeBlock> {
+    let mut e = Emitter::default();
+    let mut next_pc = pc;
+    let mut count = 0;
+    for decoded in insts {
+        if !emit(&mut e, decoded.inst, next_pc) {
+            break;
+        }
+        next_pc = next_pc.wrapping_add(decoded.size());
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+    e.ret();
+    Some(NativeBlock {
+        memory: ExecMemory::new(&e.code)?,
+        insts: count,
+        next_pc,
+    })
+}
+
+impl CpuState {
+    /// Run the native code of `block`, compiling it once it is hot. Returns
+    /// the number of leading instructions executed, 0 if the handlers have to
+    /// run the whole block.
+    pub(super) fn run_native(&mut self, block: &Block, budget: usize) -> usize {
+        let heat = block.heat.get() + 1;
+        block.heat.set(heat);
+        if heat == CONFIG_JIT_THRESHOLD {
+            let native = compile(&block.insts, block.pc);
+            self.blocks.jit.stats.compiled += native.is_some() as u64;
+            *block.native.borrow_mut() = native;
+        }
+        let native = block.native.borrow();
+        let Some(native) = native.as_ref().filter(|native| native.insts <= budget) else {
+            return 0;
+        };
+        // SAFETY: the code only accesses the 32 registers behind the pointer
+        unsafe { native.entry()(self.regs_mut_ptr()) };
+        self.csr.counters.advance(native.insts as u64);
+        self.set_pc(native.next_pc);
+        self.blocks.jit.stats.native += native.insts as u64;
+        native.insts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::difftest::difftest;
+    use crate::cpu::machine::{Engine, Machine, MachineConfig};
+    use crate::isa::riscv32::decode::decode;
+
+    fn machine(engine: Engine, code: &[u32]) -> Machine {
+        let mut machine = Machine::new(MachineConfig {
+            harts: 2,
+            quantum: 100,
+            pmp_entries: 0,
+            engine,
+            ..MachineConfig::default()
+        });
+        let image: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        machine.bus.ram.load(0x8000_0000, &image).unwrap();
+        for (id, cpu) in machine.harts.iter_mut().enumerate() {
+            cpu.set_pc(0x8000_0000);
+            cpu.set_reg(1, 0x1234_5678_9abc_def0 * (id as u64 + 1));
+            cpu.set_reg(2, (-3i64) as u64);
+        }
+        machine
+    }
+
+    // one of each supported instruction, then a loop back to the start
+    const ALU: [u32; 33] = [
+        0x87654337, // lui x6,0x87654
+        0x00001397, // auipc x7,0x1
+        0x80008193, // addi x3,x1,-2048
+        0xfff12213, // slti x4,x2,-1
+        0xfff13293, // sltiu x5,x2,-1
+        0x0ff0c413, // xori x8,x1,255
+        0x0f00e493, // ori x9,x1,240
+        0x7ff0f513, // andi x10,x1,2047
+        0x03f09593, // slli x11,x1,63
+        0x0040d613, // srli x12,x1,4
+        0x4210d693, // srai x13,x1,33
+        0x002080b3, // add x1,x1,x2
+        0x40208733, // sub x14,x1,x2
+        0x002097b3, // sll x15,x1,x2
+        0x0020a833, // slt x16,x1,x2
+        0x0020b8b3, // sltu x17,x1,x2
+        0x0020c933, // xor x18,x1,x2
+        0x0020d9b3, // srl x19,x1,x2
+        0x4020da33, // sra x20,x1,x2
+        0x0020eab3, // or x21,x1,x2
+        0x0020fb33, // and x22,x1,x2
+        0x02208bb3, // mul x23,x1,x2
+        0x80008c1b, // addiw x24,x1,-2048
+        0x01f09c9b, // slliw x25,x1,31
+        0x0010dd1b, // srliw x26,x1,1
+        0x4010dd9b, // sraiw x27,x1,1
+        0x00208e3b, // addw x28,x1,x2
+        0x40208ebb, // subw x29,x1,x2
+        0x00209f3b, // sllw x30,x1,x2
+        0x0020dfbb, // srlw x31,x1,x2
+        0x4020d03b, // sraw x0,x1,x2
+        0x0220813b, // mulw x2,x1,x2
+        0xf81ff06f, // j .-128
+    ];
+
+    #[test]
+    fn compile_prefix() {
+        let insts: Vec<_> = [0x00108093, 0x00100073, 0x00108093]
+            .iter()
+            .map(|&raw| Decoded {
+                inst: decode(raw).unwrap(),
+                raw,
+            })
+            .collect();
+        let native = compile(&insts, 0x8000_0000).unwrap();
+        assert_eq!(native.insts, 1);
+        assert_eq!(native.next_pc, 0x8000_0004);
+        assert!(compile(&insts[1..], 0x8000_0004).is_none());
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let reference = machine(Engine::Interpreter, &ALU);
+        let jit = machine(Engine::Jit, &ALU);
+        let Ok((_, jit)) = difftest(reference, jit, 20_000, 500) else {
+            panic!("the jit diverged from the interpreter");
+        };
+        let stats = jit.harts[0].blocks.jit.stats;
+        assert_eq!(stats.compiled, 1);
+        assert!(stats.native > 5_000);
+    }
+
+    #[test]
+    fn invalidation() {
+        // addi x1,x1,1; j .-4
+        let mut machine = machine(Engine::Jit, &[0x00108093, 0xffdff06f]);
+        machine.run(100);
+        let cpu = &machine.harts[0];
+        assert_eq!(cpu.blocks.jit.stats.compiled, 1);
+        assert_eq!(cpu.reg(1), 0x1234_5678_9abc_def0 + 50);
+
+        // what a store to the page does: addi x1,x1,1 becomes addi x1,x1,2
+        machine.bus.ram.write(0x8000_0000, 0x00208093u32).unwrap();
+        machine.harts[0].invalidate_decoded(0x8000_0000);
+        machine.harts[1].invalidate_decoded(0x8000_0000);
+        // a slice of hart 1, then one more of hart 0
+        machine.run(200);
+        let cpu = &machine.harts[0];
+        assert_eq!(cpu.blocks.jit.stats.compiled, 2);
+        assert_eq!(cpu.reg(1), 0x1234_5678_9abc_def0 + 50 + 100);
+    }
+}
@@ -0,0 +1,813 @@
+use super::counters::Event;
+use super::csr::{
+    Privilege, MENVCFG_PBMTE, MSTATUS_MPP_SHIFT, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SUM,
+    SATP_MODE_BARE, SATP_MODE_SHIFT,
+};
+use super::reg::CpuState;
+use super::softmmu::{host_load, host_store};
+use super::trap::{AccessType, Exception};
+use crate::memory::bus::Bus;
+use crate::memory::{physical_addr_read, physical_addr_write, Word};
+
+pub const CONFIG_TLB_ENTRIES: usize = 64;
+
+pub const PAGE_SHIFT: u64 = 12;
+pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+
+pub(super) const PTE_V: u64 = 1 << 0;
+pub(super) const PTE_R: u64 = 1 << 1;
+pub(super) const PTE_W: u64 = 1 << 2;
+pub(super) const PTE_X: u64 = 1 << 3;
+pub(super) const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+pub(super) const PTE_A: u64 = 1 << 6;
+pub(super) const PTE_D: u64 = 1 << 7;
+pub(super) const PTE_PPN_SHIFT: u64 = 10;
+pub(super) const PPN_MASK: u64 = (1 << 44) - 1;
+/// Svpbmt memory type: 0 PMA, 1 NC, 2 IO, 3 reserved
+const PTE_PBMT_SHIFT: u64 = 61;
+const PTE_PBMT: u64 = 0b11 << PTE_PBMT_SHIFT;
+/// Svnapot, a naturally aligned power-of-2 range of 4KiB pages
+const PTE_N: u64 = 1 << 63;
+/// bits 60:54
+const PTE_RESERVED: u64 = 0x7f << 54;
+/// The only NAPOT size defined, 64KiB, is encoded as ppn[3:0] = 1000.
+const NAPOT_64K_MASK: u64 = 0xf;
+const NAPOT_64K: u64 = 0b1000;
+
+pub(super) const SV39_LEVELS: u64 = 3;
+pub(super) const SV39_VPN_BITS: u64 = 9;
+pub(super) const SV39_VA_BITS: u32 = 39;
+
+/// What a hart does with loads, stores and AMOs that are not naturally aligned.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Misaligned {
+    /// split them into byte accesses, which may cross pages and devices
+    #[default]
+    Hardware,
+    /// raise address-misaligned exceptions, for firmware that emulates them
+    Trap,
+    /// raise access faults
+    AccessFault,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TlbEntry {
+    pub valid: bool,
+    /// virtual page number of the cached 4KiB page
+    pub vpn: u64,
+    /// physical page number of the cached 4KiB page, also for superpages
+    pub ppn: u64,
+    pub asid: u64,
+    /// low 8 bits of the leaf pte, plus its Svpbmt memory type. Memory types
+    /// make no difference to the emulator but stay with the translation.
+    pub flags: u64,
+    pub level: u64,
+    /// part of a 64KiB Svnapot page
+    pub napot: bool,
+}
+
+/// Direct-mapped TLB holding 4KiB translations.
+#[derive(Clone, Debug)]
+pub struct Tlb {
+    entries: Vec<TlbEntry>,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![TlbEntry::default(); CONFIG_TLB_ENTRIES],
+        }
+    }
+
+    fn index(vpn: u64) -> usize {
+        vpn as usize % CONFIG_TLB_ENTRIES
+    }
+
+    pub fn lookup(&self, vpn: u64, asid: u64) -> Option<TlbEntry> {
+        let entry = self.entries[Self::index(vpn)];
+        if entry.valid && entry.vpn == vpn && (entry.asid == asid || entry.flags & PTE_G != 0) {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, entry: TlbEntry) {
+        self.entries[Self::index(entry.vpn)] = entry;
+    }
+
+    /// `sfence.vma` semantics: `None` stands for x0, i.e. all addresses or all address spaces.
+    pub fn flush(&mut self, vaddr: Option<u64>, asid: Option<u64>) {
+        for entry in self.entries.iter_mut() {
+            // a superpage may have been cached under any of its 4KiB pages
+            let addr_match = match vaddr {
+                Some(vaddr) if entry.napot => {
+                    entry.vpn & !NAPOT_64K_MASK == vaddr >> PAGE_SHIFT & !NAPOT_64K_MASK
+                }
+                Some(vaddr) => entry.vpn == vaddr >> PAGE_SHIFT || entry.level > 0,
+                None => true,
+            };
+            let asid_match = match asid {
+                Some(asid) => entry.asid == asid && entry.flags & PTE_G == 0,
+                None => true,
+            };
+            if addr_match && asid_match {
+                entry.valid = false;
+            }
+        }
+    }
+}
+
+/// A valid pte met by a Sv39 walk.
+pub(super) enum Pte {
+    /// the physical page number of the next-level table
+    Table(u64),
+    /// the physical page number a leaf maps the virtual page to
+    Leaf(u64),
+}
+
+/// Check the encoding of `pte` at `level` and resolve it for the virtual
+/// page `vpn`, `None` standing for a page fault. Svpbmt memory types are
+/// reserved unless `pbmte`, and Svnapot leaves map 64KiB pages.
+pub(super) fn parse_pte(pte: u64, level: u64, vpn: u64, pbmte: bool) -> Option<Pte> {
+    let pbmt = pte >> PTE_PBMT_SHIFT & 0b11;
+    if pte & PTE_V == 0
+        || (pte & PTE_R == 0 && pte & PTE_W != 0)
+        || pte & PTE_RESERVED != 0
+        || pbmt == 0b11
+        || (pbmt != 0 && !pbmte)
+    {
+        return None;
+    }
+    let ppn = pte >> PTE_PPN_SHIFT & PPN_MASK;
+    if pte & (PTE_R | PTE_X) == 0 {
+        // N and PBMT are reserved in non-leaf ptes
+        return (pte & (PTE_N | PTE_PBMT) == 0).then_some(Pte::Table(ppn));
+    }
+    if pte & PTE_N != 0 {
+        let napot = level == 0 && ppn & NAPOT_64K_MASK == NAPOT_64K;
+        return napot.then_some(Pte::Leaf(ppn & !NAPOT_64K_MASK | vpn & NAPOT_64K_MASK));
+    }
+    let offset_mask = (1 << (level * SV39_VPN_BITS)) - 1;
+    (ppn & offset_mask == 0).then_some(Pte::Leaf(ppn | vpn & offset_mask))
+}
+
+/// Whether a leaf pte with `flags` grants `access` in `mode`, given the
+/// SUM and MXR bits of the status register that governs the translation.
+pub(super) fn pte_permits(
+    flags: u64,
+    access: AccessType,
+    mode: Privilege,
+    sum: bool,
+    mxr: bool,
+) -> bool {
+    let user_page = flags & PTE_U != 0;
+    match mode {
+        Privilege::User if !user_page => return false,
+        Privilege::Supervisor if user_page && (access == AccessType::Fetch || !sum) => {
+            return false
+        }
+        _ => {}
+    }
+    match access {
+        AccessType::Fetch => flags & PTE_X != 0,
+        AccessType::Load => flags & PTE_R != 0 || (mxr && flags & PTE_X != 0),
+        AccessType::Store => flags & PTE_W != 0,
+    }
+}
+
+impl CpuState {
+    /// Privilege used for loads and stores, honouring mstatus.MPRV and HLV/HSV.
+    pub fn data_mode(&self) -> Privilege {
+        #[cfg(feature = "hypervisor")]
+        if let Some((mode, _)) = self.hlsv {
+            return mode;
+        }
+        if self.mode == Privilege::Machine && self.csr.mstatus & MSTATUS_MPRV != 0 {
+            Privilege::from_bits(self.csr.mstatus >> MSTATUS_MPP_SHIFT)
+        } else {
+            self.mode
+        }
+    }
+
+    /// Whether a leaf pte with `flags` grants `access` in `mode`.
+    fn leaf_permits(&self, flags: u64, access: AccessType, mode: Privilege) -> bool {
+        let mstatus = self.csr.mstatus;
+        pte_permits(
+            flags,
+            access,
+            mode,
+            mstatus & MSTATUS_SUM != 0,
+            mstatus & MSTATUS_MXR != 0,
+        )
+    }
+
+    pub fn translate(
+        &mut self,
+        vaddr: u64,
+        access: AccessType,
+        bus: &mut Bus,
+    ) -> Result<u64, Exception> {
+        // guests bypass the TLB, which only holds HS-mode translations
+        #[cfg(feature = "hypervisor")]
+        if self.guest_translation(access) {
+            return self.translate_guest(vaddr, access, bus);
+        }
+        let mode = match access {
+            AccessType::Fetch => self.mode,
+            _ => self.data_mode(),
+        };
+        let satp = self.csr.satp;
+        if mode == Privilege::Machine || satp >> SATP_MODE_SHIFT == SATP_MODE_BARE {
+            return Ok(vaddr);
+        }
+        // bits 63:39 must all equal bit 38
+        let shift = 64 - SV39_VA_BITS;
+        if ((vaddr << shift) as i64 >> shift) as u64 != vaddr {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let vpn = vaddr >> PAGE_SHIFT;
+        let asid = satp >> 44 & 0xffff;
+        if let Some(entry) = self.tlb.lookup(vpn, asid) {
+            // a first store to a clean page has to go through the walker to set D
+            let clean = access == AccessType::Store && entry.flags & PTE_D == 0;
+            if !clean && self.leaf_permits(entry.flags, access, mode) {
+                return Ok(entry.ppn << PAGE_SHIFT | vaddr & (PAGE_SIZE - 1));
+            }
+        }
+
+        self.csr.counters.record(Event::TlbMiss);
+        let entry = self.walk(vaddr, asid, access, mode, bus)?;
+        self.tlb.insert(entry);
+        Ok(entry.ppn << PAGE_SHIFT | vaddr & (PAGE_SIZE - 1))
+    }
+
+    /// Sv39 page-table walk. Pte accesses are checked by the PMP as supervisor accesses.
+    fn walk(
+        &mut self,
+        vaddr: u64,
+        asid: u64,
+        access: AccessType,
+        mode: Privilege,
+        bus: &mut Bus,
+    ) -> Result<TlbEntry, Exception> {
+        let vpn = vaddr >> PAGE_SHIFT;
+        let pbmte = self.csr.menvcfg & MENVCFG_PBMTE != 0;
+        let mut table = (self.csr.satp & PPN_MASK) << PAGE_SHIFT;
+        for level in (0..SV39_LEVELS).rev() {
+            let index = vpn >> (level * SV39_VPN_BITS) & ((1 << SV39_VPN_BITS) - 1);
+            let pte_addr = table + index * 8;
+            let pte = self
+                .phys_read(pte_addr, 8, AccessType::Load, Privilege::Supervisor, bus)
+                .map_err(|_| access.access_fault(vaddr))?;
+
+            let ppn = match parse_pte(pte, level, vpn, pbmte) {
+                Some(Pte::Table(ppn)) => {
+                    table = ppn << PAGE_SHIFT;
+                    continue;
+                }
+                Some(Pte::Leaf(ppn)) if self.leaf_permits(pte, access, mode) => ppn,
+                _ => return Err(access.page_fault(vaddr)),
+            };
+            let mut updated = pte | PTE_A;
+            if access == AccessType::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                self.phys_write(pte_addr, 8, updated, Privilege::Supervisor, bus)
+                    .map_err(|_| access.access_fault(vaddr))?;
+            }
+            return Ok(TlbEntry {
+                valid: true,
+                vpn,
+                ppn,
+                asid,
+                flags: updated & (0xff | PTE_PBMT),
+                level,
+                napot: pte & PTE_N != 0,
+            });
+        }
+        Err(access.page_fault(vaddr))
+    }
+
+    /// Physical read of 1, 2, 4 or 8 bytes, zero-extended.
+    pub fn phys_read(
+        &mut self,
+        paddr: u64,
+        size: usize,
+        access: AccessType,
+        mode: Privilege,
+        bus: &Bus,
+    ) -> Result<u64, Exception> {
+        let pmp = &self.csr.pmp;
+        let data = match size {
+            1 => physical_addr_read(paddr, access, pmp, mode, bus).map(u8::zext),
+            2 => physical_addr_read(paddr, access, pmp, mode, bus).map(u16::zext),
+            4 => physical_addr_read(paddr, access, pmp, mode, bus).map(u32::zext),
+            8 => physical_addr_read(paddr, access, pmp, mode, bus).map(u64::zext),
+            _ => panic!("read size wrong:{}", size),
+        }?;
+        // fetches are fed whole by `fetch_at`, and AMOs read with
+        // `AccessType::Store` but only their write is a store
+        if access != AccessType::Fetch {
+            self.cache_access(paddr, AccessType::Load, bus);
+        }
+        Ok(data)
+    }
+
+    /// Physical write of the low 1, 2, 4 or 8 bytes of `data`.
+    pub fn phys_write(
+        &mut self,
+        paddr: u64,
+        size: usize,
+        data: u64,
+        mode: Privilege,
+        bus: &mut Bus,
+    ) -> Result<(), Exception> {
+        let pmp = &self.csr.pmp;
+        match size {
+            1 => physical_addr_write(paddr, u8::truncate(data), pmp, mode, bus),
+            2 => physical_addr_write(paddr, u16::truncate(data), pmp, mode, bus),
+            4 => physical_addr_write(paddr, u32::truncate(data), pmp, mode, bus),
+            8 => physical_addr_write(paddr, data, pmp, mode, bus),
+            _ => panic!("write size wrong:{}", size),
+        }?;
+        self.cache_access(paddr, AccessType::Store, bus);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn cache_access(&mut self, paddr: u64, access: AccessType, bus: &Bus) {
+        if let Some(caches) = &mut self.caches {
+            if bus.cacheable(paddr) {
+                caches.access(paddr, access);
+            }
+        }
+    }
+
+    /// Fetch the instruction at pc, returning it with the physical address of its first half.
+    pub fn fetch(&mut self, bus: &mut Bus) -> Result<(u32, u64), Exception> {
+        self.fetch_at(self.pc(), bus)
+    }
+
+    pub fn fetch_at(&mut self, pc: u64, bus: &mut Bus) -> Result<(u32, u64), Exception> {
+        let paddr = self.translate(pc, AccessType::Fetch, bus)?;
+        let low = self.phys_read(paddr, 2, AccessType::Fetch, self.mode, bus)? as u32;
+        if low & 0b11 != 0b11 {
+            self.cache_fetch(paddr, None, bus);
+            return Ok((low, paddr));
+        }
+        // the upper half may live on the next page
+        let next = self.translate(pc.wrapping_add(2), AccessType::Fetch, bus)?;
+        let high = self.phys_read(next, 2, AccessType::Fetch, self.mode, bus)? as u32;
+        self.cache_fetch(paddr, Some(next), bus);
+        Ok((low | high << 16, paddr))
+    }
+
+    /// Feed an instruction to the cache model, once for each line it is on.
+    fn cache_fetch(&mut self, paddr: u64, high: Option<u64>, bus: &Bus) {
+        self.cache_access(paddr, AccessType::Fetch, bus);
+        if let (Some(next), Some(caches)) = (high, &self.caches) {
+            let line = caches.l1i.config().line as u64;
+            if next / line != paddr / line {
+                self.cache_access(next, AccessType::Fetch, bus);
+            }
+        }
+    }
+
+    /// The context softmmu pages are looked up in, `None` while every access
+    /// has to take the slow path: with triggers armed, the cache model fed,
+    /// or guest translation in use.
+    #[inline(always)]
+    fn softmmu_context(&self) -> Option<(Privilege, u64)> {
+        if !self.softmmu.enabled
+            || self.csr.triggers.armed()
+            || self.caches.is_some()
+            || self.virt()
+        {
+            return None;
+        }
+        #[cfg(feature = "hypervisor")]
+        if self.hlsv.is_some() {
+            return None;
+        }
+        let status = self.csr.mstatus & (MSTATUS_SUM | MSTATUS_MXR);
+        Some((self.data_mode(), status))
+    }
+
+    /// The host and physical address of an aligned access that can take the
+    /// fast path.
+    #[inline(always)]
+    fn softmmu_lookup(
+        &mut self,
+        vaddr: u64,
+        size: usize,
+        access: AccessType,
+        bus: &Bus,
+    ) -> Option<(*mut u8, u64)> {
+        if vaddr & (size as u64 - 1) != 0 {
+            return None;
+        }
+        let (mode, status) = self.softmmu_context()?;
+        self.softmmu
+            .lookup(vaddr, access, mode, status, bus.epoch())
+    }
+
+    /// Add the page of an access that took the slow path, if all of it is
+    /// RAM and the PMP grants all of it.
+    fn softmmu_insert(&mut self, vaddr: u64, paddr: u64, access: AccessType, bus: &mut Bus) {
+        let Some((mode, status)) = self.softmmu_context() else {
+            return;
+        };
+        let page = paddr & !(PAGE_SIZE - 1);
+        if self
+            .csr
+            .pmp
+            .check(page, PAGE_SIZE as usize, access, mode)
+            .is_err()
+        {
+            return;
+        }
+        if let Some(host) = bus.host_page(page, access == AccessType::Store) {
+            self.softmmu.insert(vaddr, page, access, mode, status, host);
+        }
+    }
+
+    pub fn load(&mut self, vaddr: u64, size: usize, bus: &mut Bus) -> Result<u64, Exception> {
+        if let Some((host, _)) = self.softmmu_lookup(vaddr, size, AccessType::Load, bus) {
+            self.csr.counters.record(Event::Load);
+            // SAFETY: `lookup` checked the pointer against the current bus
+            // epoch, and the access is aligned so it stays in the page
+            return Ok(unsafe { host_load(host, size) });
+        }
+        self.check_access_triggers(AccessType::Load, vaddr, size, None)?;
+        let data = if self.split(vaddr, size, AccessType::Load)? {
+            self.csr.counters.record(Event::Load);
+            self.read_split(vaddr, size, AccessType::Load, bus)?
+        } else {
+            let paddr = self.translate(vaddr, AccessType::Load, bus)?;
+            self.csr.counters.record(Event::Load);
+            let data = self.phys_read(paddr, size, AccessType::Load, self.data_mode(), bus)?;
+            self.softmmu_insert(vaddr, paddr, AccessType::Load, bus);
+            data
+        };
+        self.check_access_triggers(AccessType::Load, vaddr, size, Some(data))?;
+        Ok(data)
+    }
+
+    pub fn store(
+        &mut self,
+        vaddr: u64,
+        size: usize,
+        data: u64,
+        bus: &mut Bus,
+    ) -> Result<(), Exception> {
+        if let Some((host, paddr)) = self.softmmu_lookup(vaddr, size, AccessType::Store, bus) {
+            self.csr.counters.record(Event::Store);
+            // SAFETY: as for loads, and the page is dirty already
+            unsafe { host_store(host, size, data) };
+            self.stores.push(paddr);
+            return Ok(());
+        }
+        let value = data & (u64::MAX >> (64 - size * 8));
+        self.check_access_triggers(AccessType::Store, vaddr, size, Some(value))?;
+        if self.split(vaddr, size, AccessType::Store)? {
+            self.csr.counters.record(Event::Store);
+            return self.write_split(vaddr, size, data, bus);
+        }
+        let paddr = self.translate(vaddr, AccessType::Store, bus)?;
+        self.store_paddr(paddr, size, data, bus)?;
+        self.softmmu_insert(vaddr, paddr, AccessType::Store, bus);
+        Ok(())
+    }
+
+    /// Store to an already translated address, recording it so that other
+    /// harts' reservations on it can be broken.
+    pub fn store_paddr(
+        &mut self,
+        paddr: u64,
+        size: usize,
+        data: u64,
+        bus: &mut Bus,
+    ) -> Result<(), Exception> {
+        self.csr.counters.record(Event::Store);
+        self.phys_write(paddr, size, data, self.data_mode(), bus)?;
+        self.stores.push(paddr);
+        Ok(())
+    }
+
+    /// Whether an access of `size` bytes at `vaddr` is misaligned and has to
+    /// be split, or the exception the misaligned policy raises instead.
+    pub fn split(&self, vaddr: u64, size: usize, access: AccessType) -> Result<bool, Exception> {
+        if vaddr & (size as u64 - 1) == 0 {
+            return Ok(false);
+        }
+        match self.misaligned {
+            Misaligned::Hardware => Ok(true),
+            Misaligned::Trap => Err(access.misaligned(vaddr)),
+            Misaligned::AccessFault => Err(access.access_fault(vaddr)),
+        }
+    }
+
+    /// Translate every page touched by `size` bytes at `vaddr` before any of
+    /// them is accessed, and return the physical address of each byte.
+    fn translate_bytes(
+        &mut self,
+        vaddr: u64,
+        size: usize,
+        access: AccessType,
+        bus: &mut Bus,
+    ) -> Result<Vec<u64>, Exception> {
+        let first = self.translate(vaddr, access, bus)?;
+        let last = vaddr.wrapping_add(size as u64 - 1);
+        let next = if (vaddr ^ last) >> PAGE_SHIFT != 0 {
+            self.translate(last & !(PAGE_SIZE - 1), access, bus)?
+        } else {
+            first
+        };
+        let offset = vaddr & (PAGE_SIZE - 1);
+        Ok((0..size as u64)
+            .map(|i| match offset + i {
+                offset if offset < PAGE_SIZE => first + i,
+                offset => next + offset - PAGE_SIZE,
+            })
+            .collect())
+    }
+
+    /// Misaligned read, a byte at a time. A fault on any byte is reported at
+    /// `vaddr`.
+    pub fn read_split(
+        &mut self,
+        vaddr: u64,
+        size: usize,
+        access: AccessType,
+        bus: &mut Bus,
+    ) -> Result<u64, Exception> {
+        let mode = self.data_mode();
+        let mut data = 0;
+        for (i, paddr) in self
+            .translate_bytes(vaddr, size, access, bus)?
+            .into_iter()
+            .enumerate()
+        {
+            let byte = self
+                .phys_read(paddr, 1, access, mode, bus)
+                .map_err(|_| access.access_fault(vaddr))?;
+            data |= byte << (i * 8);
+        }
+        Ok(data)
+    }
+
+    /// Misaligned write, a byte at a time. Bytes before a faulting one stay
+    /// written, which the ISA allows.
+    pub fn write_split(
+        &mut self,
+        vaddr: u64,
+        size: usize,
+        data: u64,
+        bus: &mut Bus,
+    ) -> Result<(), Exception> {
+        let mode = self.data_mode();
+        for (i, paddr) in self
+            .translate_bytes(vaddr, size, AccessType::Store, bus)?
+            .into_iter()
+            .enumerate()
+        {
+            self.phys_write(paddr, 1, data >> (i * 8), mode, bus)
+                .map_err(|_| AccessType::Store.access_fault(vaddr))?;
+            self.stores.push(paddr);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::io::map::IOMap;
+    use crate::memory::ram::Ram;
+
+    fn entry(vpn: u64, asid: u64, flags: u64, level: u64) -> TlbEntry {
+        TlbEntry {
+            valid: true,
+            vpn,
+            ppn: vpn,
+            asid,
+            flags,
+            level,
+            napot: false,
+        }
+    }
+
+    #[test]
+    fn tlb_flush() {
+        let mut tlb = Tlb::new();
+        tlb.insert(entry(0x10, 1, PTE_V | PTE_R, 0));
+        tlb.insert(entry(0x11, 2, PTE_V | PTE_R, 0));
+        tlb.insert(entry(0x12, 2, PTE_V | PTE_R | PTE_G, 0));
+        tlb.insert(entry(0x200, 1, PTE_V | PTE_R, 1));
+        assert!(tlb.lookup(0x10, 1).is_some());
+        assert!(tlb.lookup(0x10, 2).is_none());
+        assert!(tlb.lookup(0x12, 7).is_some());
+
+        tlb.flush(None, Some(2));
+        assert!(tlb.lookup(0x11, 2).is_none());
+        assert!(tlb.lookup(0x12, 2).is_some());
+        assert!(tlb.lookup(0x10, 1).is_some());
+
+        tlb.flush(Some(0x10 << PAGE_SHIFT), None);
+        assert!(tlb.lookup(0x10, 1).is_none());
+        assert!(tlb.lookup(0x12, 2).is_some());
+        assert!(tlb.lookup(0x200, 1).is_none());
+
+        tlb.flush(None, None);
+        assert!(tlb.lookup(0x12, 2).is_none());
+    }
+
+    #[test]
+    fn leaf_permission() {
+        let mut cpu = CpuState::new(0);
+        let user = PTE_V | PTE_R | PTE_W | PTE_U;
+        assert!(cpu.leaf_permits(user, AccessType::Load, Privilege::User));
+        assert!(!cpu.leaf_permits(user, AccessType::Fetch, Privilege::User));
+        assert!(!cpu.leaf_permits(user, AccessType::Load, Privilege::Supervisor));
+        cpu.csr.mstatus |= MSTATUS_SUM;
+        assert!(cpu.leaf_permits(user, AccessType::Store, Privilege::Supervisor));
+        let exec = PTE_V | PTE_X;
+        assert!(!cpu.leaf_permits(exec, AccessType::Load, Privilege::Supervisor));
+        assert!(!cpu.leaf_permits(exec, AccessType::Fetch, Privilege::User));
+        cpu.csr.mstatus |= MSTATUS_MXR;
+        assert!(cpu.leaf_permits(exec, AccessType::Load, Privilege::Supervisor));
+    }
+
+    #[test]
+    fn bare() {
+        let mut cpu = CpuState::new(0);
+        assert_eq!(
+            cpu.translate(0x8000_1234, AccessType::Load, &mut Bus::default()),
+            Ok(0x8000_1234)
+        );
+        cpu.csr.satp = 8 << SATP_MODE_SHIFT;
+        // machine mode is never translated
+        assert_eq!(
+            cpu.translate(0x8000_1234, AccessType::Store, &mut Bus::default()),
+            Ok(0x8000_1234)
+        );
+        cpu.mode = Privilege::Supervisor;
+        assert_eq!(
+            cpu.translate(0x0000_8000_0000_0000, AccessType::Load, &mut Bus::default()),
+            Err(Exception::LoadPageFault(0x0000_8000_0000_0000))
+        );
+    }
+
+    fn pte(space: &mut [u8], offset: usize, value: u64) {
+        space[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn napot_pbmt() {
+        const LEAF: u64 = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+        // root at 0x9000_0000, 0x4000_0000 through tables at 0x9000_1000 and 0x9000_2000
+        let mut pt = IOMap::scratch("pt", 0x9000_0000, 0x3000);
+        pte(&mut pt.space, 8, (0x9000_1000 >> 2) | PTE_V);
+        pte(&mut pt.space, 0x1000, (0x9000_2000 >> 2) | PTE_V);
+        pte(
+            &mut pt.space,
+            0x2000 + 0x13 * 8,
+            (0x80018 << 10) | PTE_N | LEAF,
+        );
+        pte(
+            &mut pt.space,
+            0x2000 + 0x20 * 8,
+            (0x80024 << 10) | PTE_N | LEAF,
+        );
+        pte(
+            &mut pt.space,
+            0x2000 + 0x30 * 8,
+            (0x80030 << 10) | 2 << 61 | PTE_V | PTE_R | PTE_W | PTE_A,
+        );
+        pte(
+            &mut pt.space,
+            0x2000 + 0x31 * 8,
+            (0x80031 << 10) | 3 << 61 | LEAF,
+        );
+        pte(
+            &mut pt.space,
+            0x2000 + 0x40 * 8,
+            (0x9000_3000 >> 2) | PTE_N | PTE_V,
+        );
+        let mut bus = Bus::new(Ram::default(), vec![pt]);
+        let mut cpu = CpuState::new(0);
+        cpu.csr.satp = 8 << SATP_MODE_SHIFT | 0x90000;
+        cpu.mode = Privilege::Supervisor;
+
+        // the low bits of the ppn come from the virtual address
+        assert_eq!(
+            cpu.translate(0x4001_3abc, AccessType::Load, &mut bus),
+            Ok(0x8001_3abc)
+        );
+        assert!(cpu.tlb.lookup(0x40013, 0).unwrap().napot);
+        cpu.tlb.flush(Some(0x4001_f000), None);
+        assert!(cpu.tlb.lookup(0x40013, 0).is_none());
+        // ppn[3:0] must encode the 64KiB size, and N is reserved in tables
+        for vaddr in [0x4002_0000, 0x4004_0000] {
+            assert_eq!(
+                cpu.translate(vaddr, AccessType::Load, &mut bus),
+                Err(Exception::LoadPageFault(vaddr))
+            );
+        }
+
+        // memory types are reserved until menvcfg.PBMTE is set
+        assert_eq!(
+            cpu.translate(0x4003_0000, AccessType::Store, &mut bus),
+            Err(Exception::StorePageFault(0x4003_0000))
+        );
+        cpu.csr.menvcfg = MENVCFG_PBMTE;
+        assert_eq!(
+            cpu.translate(0x4003_0000, AccessType::Store, &mut bus),
+            Ok(0x8003_0000)
+        );
+        assert_eq!(
+            cpu.tlb.lookup(0x40030, 0).unwrap().flags >> PTE_PBMT_SHIFT,
+            2
+        );
+        let updated = cpu.phys_read(0x9000_2180, 8, AccessType::Load, Privilege::Machine, &bus);
+        assert_eq!(updated, Ok((0x80030 << 10) | 2 << 61 | LEAF));
+        assert_eq!(
+            cpu.translate(0x4003_1000, AccessType::Load, &mut bus),
+            Err(Exception::LoadPageFault(0x4003_1000))
+        );
+    }
+
+    #[test]
+    fn fetch_wrap() {
+        const TABLE: usize = 0x1ff * 8;
+        // the top page of the address space, at 0x9000_3000
+        let mut pt = IOMap::scratch("pt", 0x9000_0000, 0x4000);
+        pte(&mut pt.space, TABLE, (0x9000_1000 >> 2) | PTE_V);
+        pte(&mut pt.space, 0x1000 + TABLE, (0x9000_2000 >> 2) | PTE_V);
+        pte(
+            &mut pt.space,
+            0x2000 + TABLE,
+            (0x9000_3000 >> 2) | PTE_V | PTE_X | PTE_A,
+        );
+        pt.space[0x3ffe] = 0x13;
+        let mut bus = Bus::new(Ram::default(), vec![pt]);
+        let mut cpu = CpuState::new(0);
+        cpu.csr.satp = 8 << SATP_MODE_SHIFT | 0x90000;
+        cpu.mode = Privilege::Supervisor;
+
+        // the upper half of the last instruction is at address 0
+        assert_eq!(
+            cpu.fetch_at(u64::MAX - 1, &mut bus),
+            Err(Exception::InstructionPageFault(0))
+        );
+    }
+
+    #[test]
+    fn softmmu() {
+        let mut bus = Bus::new(
+            Ram::default(),
+            vec![IOMap::scratch("data", 0x9000_0000, 0x1000)],
+        );
+        let mut cpu = CpuState::new(0);
+        let hits = |cpu: &CpuState| cpu.softmmu.stats.hits;
+        cpu.load(0x8000_0100, 8, &mut bus).unwrap();
+        cpu.load(0x8000_0108, 4, &mut bus).unwrap();
+        assert_eq!(hits(&cpu), 1);
+
+        // the first store to the clean page marks it dirty on the slow path
+        cpu.store(0x8000_0100, 8, 5, &mut bus).unwrap();
+        cpu.store(0x8000_0ff8, 8, 6, &mut bus).unwrap();
+        assert_eq!(hits(&cpu), 2);
+        assert_eq!(cpu.stores, [0x8000_0100, 0x8000_0ff8]);
+        assert_eq!(bus.ram.read(0x8000_0ff8), Some(6u64));
+        assert_eq!(cpu.load(0x8000_0100, 8, &mut bus), Ok(5));
+        assert_eq!(hits(&cpu), 3);
+
+        // devices and misaligned accesses always take the slow path
+        for _ in 0..2 {
+            cpu.store(0x9000_0000, 4, 7, &mut bus).unwrap();
+            assert_eq!(cpu.load(0x9000_0000, 4, &mut bus), Ok(7));
+            cpu.load(0x8000_0101, 4, &mut bus).unwrap();
+        }
+        assert_eq!(hits(&cpu), 3);
+
+        // nor do pages survive a translation change or a new mapping
+        cpu.flush_decoded();
+        cpu.load(0x8000_0100, 8, &mut bus).unwrap();
+        bus.add_device(IOMap::scratch("more", 0x9000_1000, 0x1000));
+        cpu.load(0x8000_0100, 8, &mut bus).unwrap();
+        assert_eq!(hits(&cpu), 3);
+        cpu.load(0x8000_0100, 8, &mut bus).unwrap();
+        assert_eq!(hits(&cpu), 4);
+    }
+}
@@ -3,3 +3,25 @@ pub mod types;
 pub mod reg;
 pub mod instruction;
 pub mod error;
+pub mod csr;
+pub mod pmp;
+pub mod trap;
+pub mod counters;
+pub mod crypto;
+pub mod cmo;
+pub mod exec;
+pub mod icache;
+pub mod block;
+#[cfg(feature = "jit")]
+pub mod jit;
+
+#[cfg(all(
+    feature = "jit",
+    not(all(target_arch = "x86_64", target_os = "linux"))
+))]
+compile_error!("the jit feature needs an x86-64 Linux host");
+pub mod mmu;
+pub mod softmmu;
+pub mod trigger;
+#[cfg(feature = "hypervisor")]
+pub mod hypervisor;
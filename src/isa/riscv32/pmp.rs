@@ -0,0 +1,308 @@
+use super::csr::Privilege;
+use super::trap::{AccessType, Exception};
+
+/// Number of PMP entries implemented by default, may be 0, 16 or 64.
+pub const CONFIG_PMP_ENTRIES: usize = 16;
+
+pub const PMP_MAX_ENTRIES: usize = 64;
+
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+/// pmpaddr holds bits [55:2] of the physical address
+const PMP_ADDR_MASK: u64 = (1 << 54) - 1;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AddressMatching {
+    Off,
+    Tor,
+    Na4,
+    Napot,
+}
+
+impl AddressMatching {
+    fn from_cfg(cfg: u8) -> Self {
+        match (cfg & PMP_A) >> 3 {
+            0b00 => AddressMatching::Off,
+            0b01 => AddressMatching::Tor,
+            0b10 => AddressMatching::Na4,
+            0b11 => AddressMatching::Napot,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Pmp {
+    cfg: Vec<u8>,
+    addr: Vec<u64>,
+}
+
+impl Pmp {
+    pub fn new(entries: usize) -> Self {
+        assert!(
+            entries <= PMP_MAX_ENTRIES,
+            "too many pmp entries:{}",
+            entries
+        );
+        Self {
+            cfg: vec![0; entries],
+            addr: vec![0; entries],
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.cfg.len()
+    }
+
+    fn locked(&self, index: usize) -> bool {
+        self.cfg[index] & PMP_L != 0
+    }
+
+    /// Read the `pmpcfg` CSR with the given index, which packs 8 entries on RV64.
+    pub fn read_cfg(&self, csr_index: usize) -> u64 {
+        (0..8)
+            .map(|i| csr_index * 4 + i)
+            .filter(|&entry| entry < self.entries())
+            .fold(0, |acc, entry| {
+                acc | (self.cfg[entry] as u64) << ((entry - csr_index * 4) * 8)
+            })
+    }
+
+    pub fn write_cfg(&mut self, csr_index: usize, value: u64) {
+        for i in 0..8 {
+            let entry = csr_index * 4 + i;
+            if entry >= self.entries() || self.locked(entry) {
+                continue;
+            }
+            let mut cfg = (value >> (i * 8)) as u8 & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+            // R=0,W=1 is reserved
+            if cfg & PMP_R == 0 {
+                cfg &= !PMP_W;
+            }
+            self.cfg[entry] = cfg;
+        }
+    }
+
+    pub fn read_addr(&self, index: usize) -> u64 {
+        self.addr.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn write_addr(&mut self, index: usize, value: u64) {
+        if index >= self.entries() || self.locked(index) {
+            return;
+        }
+        // the top of a locked TOR range can't be moved either
+        if index + 1 < self.entries()
+            && self.locked(index + 1)
+            && AddressMatching::from_cfg(self.cfg[index + 1]) == AddressMatching::Tor
+        {
+            return;
+        }
+        self.addr[index] = value & PMP_ADDR_MASK;
+    }
+
+    /// Byte range `[low, high)` covered by an entry, or `None` if it is off.
+    fn range(&self, index: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[index];
+        match AddressMatching::from_cfg(self.cfg[index]) {
+            AddressMatching::Off => None,
+            AddressMatching::Tor => {
+                let low = if index == 0 {
+                    0
+                } else {
+                    self.addr[index - 1] << 2
+                };
+                Some((low, addr << 2))
+            }
+            AddressMatching::Na4 => Some((addr << 2, (addr << 2) + 4)),
+            AddressMatching::Napot => {
+                let ones = addr.trailing_ones();
+                let base = (addr & !((1u64 << ones) - 1)) << 2;
+                Some((base, base.wrapping_add(1u64 << (ones + 3))))
+            }
+        }
+    }
+
+    /// Check an access of `size` bytes at physical address `addr`.
+    pub fn check(
+        &self,
+        addr: u64,
+        size: usize,
+        access: AccessType,
+        mode: Privilege,
+    ) -> Result<(), Exception> {
+        // an access that wraps around the address space matches no range
+        let last = match addr.checked_add(size as u64 - 1) {
+            Some(last) => last,
+            None => return Err(access.access_fault(addr)),
+        };
+        for index in 0..self.entries() {
+            let (low, high) = match self.range(index) {
+                Some(range) => range,
+                None => continue,
+            };
+            if last < low || addr >= high {
+                continue;
+            }
+            // the lowest-numbered matching entry must cover every byte
+            if addr < low || last >= high {
+                return Err(access.access_fault(addr));
+            }
+            if mode == Privilege::Machine && !self.locked(index) {
+                return Ok(());
+            }
+            let needed = match access {
+                AccessType::Fetch => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return if self.cfg[index] & needed != 0 {
+                Ok(())
+            } else {
+                Err(access.access_fault(addr))
+            };
+        }
+        if mode == Privilege::Machine || self.entries() == 0 {
+            Ok(())
+        } else {
+            Err(access.access_fault(addr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFF: u64 = 0;
+    const TOR: u64 = 0b01 << 3;
+    const NA4: u64 = 0b10 << 3;
+    const NAPOT: u64 = 0b11 << 3;
+    const R: u64 = PMP_R as u64;
+    const W: u64 = PMP_W as u64;
+    const X: u64 = PMP_X as u64;
+    const L: u64 = PMP_L as u64;
+
+    #[test]
+    fn no_match() {
+        let pmp = Pmp::new(16);
+        assert_eq!(
+            pmp.check(0x8000_0000, 4, AccessType::Load, Privilege::Machine),
+            Ok(())
+        );
+        assert_eq!(
+            pmp.check(0x8000_0000, 4, AccessType::Load, Privilege::Supervisor),
+            Err(Exception::LoadAccessFault(0x8000_0000))
+        );
+        let pmp = Pmp::new(0);
+        assert_eq!(
+            pmp.check(0x8000_0000, 4, AccessType::Store, Privilege::User),
+            Ok(())
+        );
+        assert_eq!(
+            pmp.check(u64::MAX - 1, 4, AccessType::Store, Privilege::Machine),
+            Err(Exception::StoreAccessFault(u64::MAX - 1))
+        );
+    }
+
+    #[test]
+    fn tor() {
+        let mut pmp = Pmp::new(16);
+        pmp.write_addr(0, 0x8000_0000 >> 2);
+        pmp.write_addr(1, 0x8000_1000 >> 2);
+        pmp.write_cfg(0, (OFF | R) | (TOR | R | X) << 8);
+        assert_eq!(
+            pmp.check(0x8000_0000, 4, AccessType::Fetch, Privilege::User),
+            Ok(())
+        );
+        assert_eq!(
+            pmp.check(0x8000_0ffc, 4, AccessType::Load, Privilege::User),
+            Ok(())
+        );
+        assert_eq!(
+            pmp.check(0x8000_0ffc, 8, AccessType::Load, Privilege::User),
+            Err(Exception::LoadAccessFault(0x8000_0ffc))
+        );
+        assert_eq!(
+            pmp.check(0x8000_0010, 4, AccessType::Store, Privilege::Supervisor),
+            Err(Exception::StoreAccessFault(0x8000_0010))
+        );
+        assert_eq!(
+            pmp.check(0x7fff_fffc, 4, AccessType::Load, Privilege::User),
+            Err(Exception::LoadAccessFault(0x7fff_fffc))
+        );
+    }
+
+    #[test]
+    fn na4_and_napot() {
+        let mut pmp = Pmp::new(16);
+        // 4 bytes at 0x1000, no permission
+        pmp.write_addr(0, 0x1000 >> 2);
+        // 64KiB at 0x10000
+        pmp.write_addr(1, (0x10000 >> 2) | ((0x10000 >> 3) - 1));
+        pmp.write_cfg(0, NA4 | (NAPOT | R | W) << 8);
+        assert_eq!(
+            pmp.check(0x1000, 4, AccessType::Load, Privilege::User),
+            Err(Exception::LoadAccessFault(0x1000))
+        );
+        assert_eq!(
+            pmp.check(0x10000, 8, AccessType::Store, Privilege::User),
+            Ok(())
+        );
+        assert_eq!(
+            pmp.check(0x1fff8, 8, AccessType::Load, Privilege::User),
+            Ok(())
+        );
+        assert_eq!(
+            pmp.check(0x1fffc, 8, AccessType::Load, Privilege::User),
+            Err(Exception::LoadAccessFault(0x1fffc))
+        );
+        assert_eq!(
+            pmp.check(0x10000, 4, AccessType::Fetch, Privilege::User),
+            Err(Exception::InstructionAccessFault(0x10000))
+        );
+    }
+
+    #[test]
+    fn lock() {
+        let mut pmp = Pmp::new(16);
+        pmp.write_addr(0, 0x2000 >> 2);
+        pmp.write_cfg(0, (TOR | R | L) << 8 | (NA4 | R | W | X));
+        pmp.write_addr(1, 0x3000 >> 2);
+        // entry 1 is locked, so its address and the base of its TOR range are frozen
+        pmp.write_addr(0, 0x1000 >> 2);
+        assert_eq!(pmp.read_addr(0), 0x2000 >> 2);
+        assert_eq!(pmp.read_addr(1), 0);
+        pmp.write_cfg(0, 0);
+        assert_eq!(pmp.read_cfg(0), (TOR | R | L) << 8);
+        // locked entries bind machine mode as well
+        let mut pmp = Pmp::new(16);
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_cfg(0, NA4 | R | L);
+        assert_eq!(
+            pmp.check(0x1000, 4, AccessType::Store, Privilege::Machine),
+            Err(Exception::StoreAccessFault(0x1000))
+        );
+        assert_eq!(
+            pmp.check(0x1000, 4, AccessType::Load, Privilege::Machine),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn warl() {
+        let mut pmp = Pmp::new(8);
+        pmp.write_cfg(0, W | 0x60 | (R | W) << 8);
+        assert_eq!(pmp.read_cfg(0), (R | W) << 8);
+        pmp.write_cfg(2, R);
+        assert_eq!(pmp.read_cfg(2), 0);
+        pmp.write_addr(8, 0x1234);
+        assert_eq!(pmp.read_addr(8), 0);
+        pmp.write_addr(0, u64::MAX);
+        assert_eq!(pmp.read_addr(0), PMP_ADDR_MASK);
+    }
+}
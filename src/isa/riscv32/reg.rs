@@ -0,0 +1,133 @@
+use super::block::BlockCache;
+use super::cmo::{CmoHook, CONFIG_CACHE_BLOCK};
+use super::csr::{CsrFile, Privilege};
+use super::decode::Extensions;
+use super::icache::DecodeCache;
+use super::mmu::{Misaligned, Tlb};
+use super::softmmu::SoftMmu;
+use super::types::NUM_REGISTERS;
+use crate::memory::cache::CacheHierarchy;
+use crate::memory::CONFIG_MBASE;
+
+pub const REG_NAMES: [&str; NUM_REGISTERS] = [
+    "$0", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5",
+    "t6",
+];
+
+#[repr(C)]
+pub struct CpuState {
+    regs: [i64; NUM_REGISTERS],
+    pc: usize,
+    pub mode: Privilege,
+    pub csr: CsrFile,
+    pub tlb: Tlb,
+    pub misaligned: Misaligned,
+    /// optional extensions, fixed before the hart first runs since decoded
+    /// instructions are cached
+    pub extensions: Extensions,
+    /// bytes per cache block, a power of two no larger than a page
+    pub cache_block: u64,
+    pub cmo_hook: Option<CmoHook>,
+    /// the cache model of a performance study, fed by every physical access
+    /// to memory
+    pub caches: Option<Box<CacheHierarchy>>,
+    pub icache: DecodeCache,
+    pub blocks: BlockCache,
+    pub softmmu: SoftMmu,
+    /// LR/SC reservation, the physical address of the reserved doubleword
+    pub reservation: Option<u64>,
+    /// physical addresses written by the last instruction
+    pub stores: Vec<u64>,
+    /// steps the hart has stalled for in a wrs, waiting on its reservation set
+    pub wrs: Option<u64>,
+    /// stopped by a trigger in the debug monitor, until the debugger resumes it
+    pub halted: bool,
+    /// V, set while the hart runs a guest in VS- or VU-mode
+    #[cfg(feature = "hypervisor")]
+    pub virt: bool,
+    /// effective privilege of the HLV/HSV being executed, and whether it is
+    /// an HLVX that needs execute rather than read permission
+    #[cfg(feature = "hypervisor")]
+    pub(super) hlsv: Option<(Privilege, bool)>,
+}
+
+impl CpuState {
+    pub fn new(pmp_entries: usize) -> Self {
+        Self {
+            regs: [0; NUM_REGISTERS],
+            pc: CONFIG_MBASE as usize,
+            mode: Privilege::Machine,
+            csr: CsrFile::new(pmp_entries),
+            tlb: Tlb::new(),
+            misaligned: Misaligned::default(),
+            extensions: Extensions::default(),
+            cache_block: CONFIG_CACHE_BLOCK,
+            cmo_hook: None,
+            caches: None,
+            icache: DecodeCache::new(),
+            blocks: BlockCache::new(),
+            softmmu: SoftMmu::new(),
+            reservation: None,
+            stores: Vec::new(),
+            wrs: None,
+            halted: false,
+            #[cfg(feature = "hypervisor")]
+            virt: false,
+            #[cfg(feature = "hypervisor")]
+            hlsv: None,
+        }
+    }
+
+    /// Whether the hart runs a guest, never without the hypervisor extension.
+    #[inline(always)]
+    pub fn virt(&self) -> bool {
+        #[cfg(feature = "hypervisor")]
+        {
+            self.virt
+        }
+        #[cfg(not(feature = "hypervisor"))]
+        {
+            false
+        }
+    }
+
+    pub fn isa_string(&self) -> String {
+        self.csr.isa_string(self.extensions)
+    }
+
+    #[inline(always)]
+    pub fn reg(&self, index: u32) -> u64 {
+        self.regs[index as usize] as u64
+    }
+
+    #[inline(always)]
+    pub fn set_reg(&mut self, index: u32, value: u64) {
+        if index != 0 {
+            self.regs[index as usize] = value as i64;
+        }
+    }
+
+    /// The register file, for code generated by the JIT.
+    #[cfg(feature = "jit")]
+    pub(super) fn regs_mut_ptr(&mut self) -> *mut i64 {
+        self.regs.as_mut_ptr()
+    }
+
+    #[inline(always)]
+    pub fn pc(&self) -> u64 {
+        self.pc as u64
+    }
+
+    #[inline(always)]
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc as usize;
+    }
+
+    pub fn dump_regs(&self) {
+        println!("pc\t{:#018x}", self.pc);
+        for (name, value) in REG_NAMES.iter().zip(self.regs.iter()) {
+            println!("{}\t{:#018x}", name, value);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::ptr;
+
+use super::csr::Privilege;
+use super::mmu::{PAGE_SHIFT, PAGE_SIZE};
+use super::trap::AccessType;
+use crate::memory::{host_read, host_write, Word};
+
+/// Pages remembered for loads, and as many for stores.
+pub const CONFIG_SOFTMMU_ENTRIES: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    /// virtual page number, `u64::MAX` for an empty entry
+    vpn: u64,
+    /// the privilege and the mstatus.SUM and MXR bits the page was
+    /// translated and checked with
+    mode: Privilege,
+    status: u64,
+    /// the physical page, and where the host keeps it
+    paddr: u64,
+    host: *mut u8,
+}
+
+const EMPTY: Entry = Entry {
+    vpn: u64::MAX,
+    mode: Privilege::Machine,
+    status: 0,
+    paddr: 0,
+    host: ptr::null_mut(),
+};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SoftMmuStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// times every page was dropped, on translation or PMP changes and when
+    /// the bus epoch moved on
+    pub flushes: u64,
+}
+
+impl SoftMmuStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Direct-mapped host pointers to the RAM pages a hart loaded from or stored
+/// to, softmmu style, so that aligned accesses to them skip translation, the
+/// PMP and bus dispatch. Only pages that are RAM from end to end and wholly
+/// granted by the PMP are added, stores only once a page is dirty, so
+/// devices, partial PMP regions and dirty tracking keep the slow path.
+pub struct SoftMmu {
+    pub enabled: bool,
+    loads: Vec<Entry>,
+    stores: Vec<Entry>,
+    /// `Bus::epoch` when the pages were added
+    epoch: u64,
+    pub stats: SoftMmuStats,
+}
+
+// SAFETY: the pointers are only followed while the hart has its bus
+// borrowed, and only if the bus epoch says they are still good
+unsafe impl Send for SoftMmu {}
+
+impl Default for SoftMmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoftMmu {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            loads: vec![EMPTY; CONFIG_SOFTMMU_ENTRIES],
+            stores: vec![EMPTY; CONFIG_SOFTMMU_ENTRIES],
+            epoch: 0,
+            stats: SoftMmuStats::default(),
+        }
+    }
+
+    #[inline(always)]
+    fn index(vpn: u64) -> usize {
+        vpn as usize % CONFIG_SOFTMMU_ENTRIES
+    }
+
+    fn table(&mut self, access: AccessType) -> &mut Vec<Entry> {
+        match access {
+            AccessType::Store => &mut self.stores,
+            _ => &mut self.loads,
+        }
+    }
+
+    /// The host address and the physical address of `vaddr`, if its page was
+    /// added for `access` in the same context and the bus is still at
+    /// `epoch`.
+    #[inline(always)]
+    pub fn lookup(
+        &mut self,
+        vaddr: u64,
+        access: AccessType,
+        mode: Privilege,
+        status: u64,
+        epoch: u64,
+    ) -> Option<(*mut u8, u64)> {
+        if epoch != self.epoch {
+            self.flush();
+            self.epoch = epoch;
+        }
+        let vpn = vaddr >> PAGE_SHIFT;
+        let entry = self.table(access)[Self::index(vpn)];
+        if entry.vpn != vpn || entry.mode != mode || entry.status != status {
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.hits += 1;
+        let offset = vaddr & (PAGE_SIZE - 1);
+        // in bounds, the entry covers a whole page
+        Some((
+            unsafe { entry.host.add(offset as usize) },
+            entry.paddr | offset,
+        ))
+    }
+
+    /// Remember that the page at `vaddr` is the RAM page `paddr`, kept by
+    /// the host at `host`.
+    pub fn insert(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        access: AccessType,
+        mode: Privilege,
+        status: u64,
+        host: *mut u8,
+    ) {
+        let vpn = vaddr >> PAGE_SHIFT;
+        self.table(access)[Self::index(vpn)] = Entry {
+            vpn,
+            mode,
+            status,
+            paddr: paddr & !(PAGE_SIZE - 1),
+            host,
+        };
+    }
+
+    pub fn flush(&mut self) {
+        self.loads.fill(EMPTY);
+        self.stores.fill(EMPTY);
+        self.stats.flushes += 1;
+    }
+}
+
+/// Read an aligned access of `size` bytes from a pointer `lookup` returned.
+///
+/// # Safety
+///
+/// `host` has to come from `SoftMmu::lookup` for an access of `size` bytes,
+/// with the bus still at the epoch given to it.
+#[inline(always)]
+pub unsafe fn host_load(host: *const u8, size: usize) -> u64 {
+    // SAFETY: the page behind `host` is freed, moved or stops being plain RAM
+    // only after the bus epoch changed, and `lookup` returns no pointer added
+    // before that. RAM hands out pointers derived from the base of its host
+    // memory, so reads and writes through `Ram` leave them valid.
+    let bytes = std::slice::from_raw_parts(host, size);
+    match size {
+        1 => host_read::<u8>(bytes).zext(),
+        2 => host_read::<u16>(bytes).zext(),
+        4 => host_read::<u32>(bytes).zext(),
+        _ => host_read::<u64>(bytes),
+    }
+}
+
+/// Write the low `size` bytes of `data` to a pointer `lookup` returned.
+///
+/// # Safety
+///
+/// As for `host_load`.
+#[inline(always)]
+pub unsafe fn host_store(host: *mut u8, size: usize, data: u64) {
+    // SAFETY: as for `host_load`. Store pointers are only added for dirty
+    // pages, and a checkpoint, which cleans them, changes the epoch.
+    let bytes = std::slice::from_raw_parts_mut(host, size);
+    match size {
+        1 => host_write(bytes, u8::truncate(data)),
+        2 => host_write(bytes, u16::truncate(data)),
+        4 => host_write(bytes, u32::truncate(data)),
+        _ => host_write(bytes, data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::bus::Bus;
+    use crate::memory::ram::Ram;
+
+    #[test]
+    fn lookup() {
+        let mut bus = Bus::new(Ram::new(0x8000_0000, 0x2000), Vec::new());
+        let mut mmu = SoftMmu::new();
+        let epoch = bus.epoch();
+        let (load, store) = (AccessType::Load, AccessType::Store);
+        let mode = Privilege::Supervisor;
+        assert_eq!(mmu.lookup(0x1008, load, mode, 0, epoch), None);
+
+        // stores only get clean pages once they are dirty
+        assert_eq!(bus.host_page(0x8000_1000, true), None);
+        let host = bus.host_page(0x8000_1000, false).unwrap();
+        mmu.insert(0x1000, 0x8000_1000, load, mode, 0, host);
+        let (hit, paddr) = mmu.lookup(0x1008, load, mode, 0, epoch).unwrap();
+        assert_eq!(paddr, 0x8000_1008);
+        bus.ram.write(0x8000_1008, 0x1234u16).unwrap();
+        assert_eq!(unsafe { host_load(hit, 2) }, 0x1234);
+        assert_eq!(mmu.lookup(0x1008, store, mode, 0, epoch), None);
+        assert_eq!(mmu.lookup(0x1008, load, Privilege::User, 0, epoch), None);
+
+        let host = bus.host_page(0x8000_1000, true).unwrap();
+        mmu.insert(0x1000, 0x8000_1000, store, mode, 0, host);
+        let (hit, _) = mmu.lookup(0x1ff8, store, mode, 0, epoch).unwrap();
+        unsafe { host_store(hit, 8, u64::MAX) };
+        assert_eq!(bus.ram.read(0x8000_1ffc), Some(u32::MAX));
+
+        // a checkpoint has the next store dirty the page again
+        bus.ram.checkpoint();
+        assert_eq!(mmu.lookup(0x1008, load, mode, 0, bus.epoch()), None);
+        assert_eq!(bus.host_page(0x8000_1000, true), None);
+        assert_eq!(bus.host_page(0x8000_1800, false), None);
+        assert_eq!(
+            mmu.stats,
+            SoftMmuStats {
+                hits: 2,
+                misses: 4,
+                flushes: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,167 @@
+/// Kind of memory access, used to pick the right fault when a check fails.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    pub fn access_fault(&self, addr: u64) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionAccessFault(addr),
+            AccessType::Load => Exception::LoadAccessFault(addr),
+            AccessType::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+
+    pub fn misaligned(&self, addr: u64) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionAddressMisaligned(addr),
+            AccessType::Load => Exception::LoadAddressMisaligned(addr),
+            AccessType::Store => Exception::StoreAddressMisaligned(addr),
+        }
+    }
+
+    pub fn page_fault(&self, addr: u64) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionPageFault(addr),
+            AccessType::Load => Exception::LoadPageFault(addr),
+            AccessType::Store => Exception::StorePageFault(addr),
+        }
+    }
+
+    /// Fault of the G-stage translation of guest physical address `gpa`,
+    /// reached from guest virtual address `addr`.
+    #[cfg(feature = "hypervisor")]
+    pub fn guest_page_fault(&self, addr: u64, gpa: u64) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionGuestPageFault(addr, gpa),
+            AccessType::Load => Exception::LoadGuestPageFault(addr, gpa),
+            AccessType::Store => Exception::StoreGuestPageFault(addr, gpa),
+        }
+    }
+}
+
+/// Synchronous exceptions. The payload, if any, is the value written to `xtval`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    UserEnvCall,
+    SupervisorEnvCall,
+    MachineEnvCall,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+    #[cfg(feature = "hypervisor")]
+    VirtualSupervisorEnvCall,
+    /// Guest page faults carry the guest virtual and the guest physical address.
+    #[cfg(feature = "hypervisor")]
+    InstructionGuestPageFault(u64, u64),
+    #[cfg(feature = "hypervisor")]
+    LoadGuestPageFault(u64, u64),
+    #[cfg(feature = "hypervisor")]
+    VirtualInstruction(u64),
+    #[cfg(feature = "hypervisor")]
+    StoreGuestPageFault(u64, u64),
+}
+
+impl Exception {
+    /// Exception code as reported in `xcause`.
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::UserEnvCall => 8,
+            Exception::SupervisorEnvCall => 9,
+            Exception::MachineEnvCall => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+            #[cfg(feature = "hypervisor")]
+            Exception::VirtualSupervisorEnvCall => 10,
+            #[cfg(feature = "hypervisor")]
+            Exception::InstructionGuestPageFault(..) => 20,
+            #[cfg(feature = "hypervisor")]
+            Exception::LoadGuestPageFault(..) => 21,
+            #[cfg(feature = "hypervisor")]
+            Exception::VirtualInstruction(_) => 22,
+            #[cfg(feature = "hypervisor")]
+            Exception::StoreGuestPageFault(..) => 23,
+        }
+    }
+
+    pub fn tval(&self) -> u64 {
+        match *self {
+            Exception::InstructionAddressMisaligned(v)
+            | Exception::InstructionAccessFault(v)
+            | Exception::IllegalInstruction(v)
+            | Exception::Breakpoint(v)
+            | Exception::LoadAddressMisaligned(v)
+            | Exception::LoadAccessFault(v)
+            | Exception::StoreAddressMisaligned(v)
+            | Exception::StoreAccessFault(v)
+            | Exception::InstructionPageFault(v)
+            | Exception::LoadPageFault(v)
+            | Exception::StorePageFault(v) => v,
+            Exception::UserEnvCall | Exception::SupervisorEnvCall | Exception::MachineEnvCall => 0,
+            #[cfg(feature = "hypervisor")]
+            Exception::InstructionGuestPageFault(v, _)
+            | Exception::LoadGuestPageFault(v, _)
+            | Exception::StoreGuestPageFault(v, _)
+            | Exception::VirtualInstruction(v) => v,
+            #[cfg(feature = "hypervisor")]
+            Exception::VirtualSupervisorEnvCall => 0,
+        }
+    }
+
+    /// The exception with `raw`, the encoding of the instruction that raised
+    /// it, as `xtval` if it is one that reports the instruction.
+    pub fn with_inst(self, raw: u32) -> Self {
+        match self {
+            Exception::IllegalInstruction(_) => Exception::IllegalInstruction(raw as u64),
+            #[cfg(feature = "hypervisor")]
+            Exception::VirtualInstruction(_) => Exception::VirtualInstruction(raw as u64),
+            e => e,
+        }
+    }
+
+    /// Guest physical address of a guest page fault, 0 for anything else.
+    #[cfg(feature = "hypervisor")]
+    pub fn gpa(&self) -> u64 {
+        match *self {
+            Exception::InstructionGuestPageFault(_, gpa)
+            | Exception::LoadGuestPageFault(_, gpa)
+            | Exception::StoreGuestPageFault(_, gpa) => gpa,
+            _ => 0,
+        }
+    }
+
+    /// Whether `xtval` holds a virtual address, for `GVA` when trapping out
+    /// of a guest.
+    #[cfg(feature = "hypervisor")]
+    pub fn has_address(&self) -> bool {
+        !matches!(
+            self,
+            Exception::IllegalInstruction(_)
+                | Exception::VirtualInstruction(_)
+                | Exception::UserEnvCall
+                | Exception::SupervisorEnvCall
+                | Exception::VirtualSupervisorEnvCall
+                | Exception::MachineEnvCall
+        )
+    }
+}
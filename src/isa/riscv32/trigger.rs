@@ -0,0 +1,508 @@
+use super::csr::{Privilege, MSTATUS_MIE};
+use super::reg::CpuState;
+use super::trap::{AccessType, Exception};
+
+/// Number of triggers selectable through `tselect`.
+pub const CONFIG_TRIGGERS: usize = 4;
+
+// tdata1.type
+const TYPE_SHIFT: u64 = 60;
+const TYPE_MCONTROL: u64 = 2;
+const TYPE_ICOUNT: u64 = 3;
+const TYPE_MCONTROL6: u64 = 6;
+const TYPE_DISABLED: u64 = 15;
+
+/// Sdtrig 1.0, with the types above
+const TINFO_VALUE: u64 =
+    1 << 24 | 1 << TYPE_MCONTROL | 1 << TYPE_ICOUNT | 1 << TYPE_MCONTROL6 | 1 << TYPE_DISABLED;
+
+// mcontrol and mcontrol6 fields shared by both
+const MC_LOAD: u64 = 1 << 0;
+const MC_STORE: u64 = 1 << 1;
+const MC_EXECUTE: u64 = 1 << 2;
+const MC_U: u64 = 1 << 3;
+const MC_S: u64 = 1 << 4;
+const MC_M: u64 = 1 << 6;
+const MC_MATCH_SHIFT: u64 = 7;
+const MC_MATCH: u64 = 0b1111 << MC_MATCH_SHIFT;
+const MC_ACTION_SHIFT: u64 = 12;
+const MC_ACTION: u64 = 0b1111 << MC_ACTION_SHIFT;
+// mcontrol only
+const MCONTROL_SELECT: u64 = 1 << 19;
+const MCONTROL_HIT: u64 = 1 << 20;
+/// NAPOT ranges may cover the whole address space
+const MCONTROL_MASKMAX: u64 = 63 << 53;
+// mcontrol6 only
+const MCONTROL6_SELECT: u64 = 1 << 21;
+const MCONTROL6_HIT0: u64 = 1 << 22;
+
+// icount fields
+const ICOUNT_ACTION: u64 = 0b11_1111;
+const ICOUNT_U: u64 = 1 << 6;
+const ICOUNT_S: u64 = 1 << 7;
+const ICOUNT_PENDING: u64 = 1 << 8;
+const ICOUNT_M: u64 = 1 << 9;
+const ICOUNT_COUNT_SHIFT: u64 = 10;
+const ICOUNT_COUNT: u64 = 0x3fff << ICOUNT_COUNT_SHIFT;
+const ICOUNT_HIT: u64 = 1 << 24;
+
+/// What a trigger does when it fires.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// raise a breakpoint exception
+    Breakpoint,
+    /// stop the hart and hand it to the emulator's debug monitor
+    DebugMode,
+}
+
+impl Action {
+    fn from_bits(bits: u64) -> Self {
+        match bits {
+            0 => Action::Breakpoint,
+            _ => Action::DebugMode,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fired {
+    pub action: Action,
+    /// the address or pc that matched, reported in `xtval`
+    pub tval: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Trigger {
+    tdata1: u64,
+    tdata2: u64,
+}
+
+impl Trigger {
+    fn kind(&self) -> u64 {
+        self.tdata1 >> TYPE_SHIFT
+    }
+
+    /// Whether the trigger is enabled in `mode`, the bits differ between types.
+    fn enabled_in(&self, mode: Privilege) -> bool {
+        let (m, s, u) = match self.kind() {
+            TYPE_MCONTROL | TYPE_MCONTROL6 => (MC_M, MC_S, MC_U),
+            TYPE_ICOUNT => (ICOUNT_M, ICOUNT_S, ICOUNT_U),
+            _ => return false,
+        };
+        let bit = match mode {
+            Privilege::Machine => m,
+            Privilege::Supervisor => s,
+            Privilege::User => u,
+        };
+        self.tdata1 & bit != 0
+    }
+
+    fn action(&self) -> Action {
+        match self.kind() {
+            TYPE_ICOUNT => Action::from_bits(self.tdata1 & ICOUNT_ACTION),
+            _ => Action::from_bits((self.tdata1 & MC_ACTION) >> MC_ACTION_SHIFT),
+        }
+    }
+
+    fn select(&self) -> bool {
+        match self.kind() {
+            TYPE_MCONTROL => self.tdata1 & MCONTROL_SELECT != 0,
+            _ => self.tdata1 & MCONTROL6_SELECT != 0,
+        }
+    }
+
+    fn set_hit(&mut self) {
+        self.tdata1 |= match self.kind() {
+            TYPE_MCONTROL => MCONTROL_HIT,
+            TYPE_MCONTROL6 => MCONTROL6_HIT0,
+            _ => ICOUNT_HIT,
+        };
+    }
+
+    /// Compare `len` bytes at `value` against tdata2. Equality and NAPOT
+    /// ranges match if any byte does, the inequalities use the first one.
+    fn compare(&self, value: u64, len: u64) -> bool {
+        let tdata2 = self.tdata2;
+        let kind = (self.tdata1 & MC_MATCH) >> MC_MATCH_SHIFT;
+        let matched = match kind & 0b111 {
+            0 => tdata2.wrapping_sub(value) < len,
+            1 => {
+                let ones = tdata2.trailing_ones() + 1;
+                let mask = if ones >= 64 { 0 } else { u64::MAX << ones };
+                (0..len).any(|i| value.wrapping_add(i) & mask == tdata2 & mask)
+            }
+            2 => value >= tdata2,
+            _ => value < tdata2,
+        };
+        // 8 and 9 are the negations of 0 and 1
+        matched != (kind & 0b1000 != 0)
+    }
+}
+
+/// The Sdtrig trigger module: address/data match triggers of type mcontrol
+/// and mcontrol6, and instruction count triggers.
+#[derive(Clone, Debug)]
+pub struct Triggers {
+    select: usize,
+    triggers: Vec<Trigger>,
+    /// whether any trigger is enabled, checked on every access
+    armed: bool,
+}
+
+impl Triggers {
+    pub fn new(count: usize) -> Self {
+        Self {
+            select: 0,
+            triggers: vec![
+                Trigger {
+                    tdata1: TYPE_DISABLED << TYPE_SHIFT,
+                    tdata2: 0,
+                };
+                count
+            ],
+            armed: false,
+        }
+    }
+
+    #[inline(always)]
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    fn update_armed(&mut self) {
+        self.armed = self
+            .triggers
+            .iter()
+            .any(|t| matches!(t.kind(), TYPE_MCONTROL | TYPE_ICOUNT | TYPE_MCONTROL6));
+    }
+
+    pub fn read_tselect(&self) -> u64 {
+        self.select as u64
+    }
+
+    /// Selecting a trigger that doesn't exist is ignored.
+    pub fn write_tselect(&mut self, value: u64) {
+        if value < self.triggers.len() as u64 {
+            self.select = value as usize;
+        }
+    }
+
+    pub fn read_tdata1(&self) -> u64 {
+        self.triggers.get(self.select).map_or(0, |t| t.tdata1)
+    }
+
+    pub fn read_tdata2(&self) -> u64 {
+        self.triggers.get(self.select).map_or(0, |t| t.tdata2)
+    }
+
+    /// tdata3 holds textra, which isn't implemented.
+    pub fn read_tdata3(&self) -> u64 {
+        0
+    }
+
+    pub fn read_tinfo(&self) -> u64 {
+        if self.triggers.is_empty() {
+            1
+        } else {
+            TINFO_VALUE
+        }
+    }
+
+    /// Unsupported types disable the trigger. timing, size, chain and dmode
+    /// read as zero, as do actions and match kinds that aren't implemented.
+    pub fn write_tdata1(&mut self, value: u64) {
+        let Some(trigger) = self.triggers.get_mut(self.select) else {
+            return;
+        };
+        let kind = value >> TYPE_SHIFT;
+        let legal_match = |value: u64| match (value & MC_MATCH) >> MC_MATCH_SHIFT {
+            0..=3 | 8 | 9 => value & MC_MATCH,
+            _ => 0,
+        };
+        let legal_action = |value: u64, action: u64| {
+            if value & action <= 1 {
+                value & action
+            } else {
+                0
+            }
+        };
+        let common = MC_LOAD | MC_STORE | MC_EXECUTE | MC_U | MC_S | MC_M;
+        trigger.tdata1 = match kind {
+            TYPE_MCONTROL => {
+                let action = legal_action(value >> MC_ACTION_SHIFT, 0b1111) << MC_ACTION_SHIFT;
+                kind << TYPE_SHIFT
+                    | MCONTROL_MASKMAX
+                    | value & (common | MCONTROL_SELECT | MCONTROL_HIT)
+                    | legal_match(value)
+                    | action
+            }
+            TYPE_MCONTROL6 => {
+                let action = legal_action(value >> MC_ACTION_SHIFT, 0b1111) << MC_ACTION_SHIFT;
+                kind << TYPE_SHIFT
+                    | value & (common | MCONTROL6_SELECT | MCONTROL6_HIT0)
+                    | legal_match(value)
+                    | action
+            }
+            TYPE_ICOUNT => {
+                kind << TYPE_SHIFT
+                    | value
+                        & (ICOUNT_U
+                            | ICOUNT_S
+                            | ICOUNT_PENDING
+                            | ICOUNT_M
+                            | ICOUNT_COUNT
+                            | ICOUNT_HIT)
+                    | legal_action(value, ICOUNT_ACTION)
+            }
+            _ => TYPE_DISABLED << TYPE_SHIFT,
+        };
+        self.update_armed();
+    }
+
+    pub fn write_tdata2(&mut self, value: u64) {
+        if let Some(trigger) = self.triggers.get_mut(self.select) {
+            trigger.tdata2 = value;
+        }
+    }
+
+    pub fn write_tdata3(&mut self, _value: u64) {}
+
+    /// Fire the first match trigger enabled in `mode` for which `hit` holds.
+    /// Breakpoints don't fire in M-mode with interrupts disabled, which would
+    /// make the handler trigger itself.
+    fn fire(
+        &mut self,
+        mode: Privilege,
+        mie: bool,
+        tval: u64,
+        hit: impl Fn(&Trigger) -> bool,
+    ) -> Option<Fired> {
+        let trigger = self.triggers.iter_mut().find(|t| {
+            matches!(t.kind(), TYPE_MCONTROL | TYPE_MCONTROL6)
+                && t.enabled_in(mode)
+                && !(t.action() == Action::Breakpoint && mode == Privilege::Machine && !mie)
+                && hit(t)
+        })?;
+        trigger.set_hit();
+        Some(Fired {
+            action: trigger.action(),
+            tval,
+        })
+    }
+
+    /// Execute triggers on the address of the instruction at `pc`, checked
+    /// before it is fetched.
+    pub fn match_pc(&mut self, pc: u64, mode: Privilege, mie: bool) -> Option<Fired> {
+        self.fire(mode, mie, pc, |t| {
+            t.tdata1 & MC_EXECUTE != 0 && !t.select() && t.compare(pc, 1)
+        })
+    }
+
+    /// Execute triggers on the opcode `raw` of the instruction at `pc`.
+    pub fn match_opcode(&mut self, pc: u64, raw: u32, mode: Privilege, mie: bool) -> Option<Fired> {
+        self.fire(mode, mie, pc, |t| {
+            t.tdata1 & MC_EXECUTE != 0 && t.select() && t.compare(raw as u64, 1)
+        })
+    }
+
+    /// Load or store triggers on the address of an access of `size` bytes at
+    /// `vaddr`, and on its `data` if it is known.
+    pub fn match_access(
+        &mut self,
+        access: AccessType,
+        vaddr: u64,
+        size: usize,
+        data: Option<u64>,
+        mode: Privilege,
+        mie: bool,
+    ) -> Option<Fired> {
+        let bit = match access {
+            AccessType::Load => MC_LOAD,
+            AccessType::Store => MC_STORE,
+            AccessType::Fetch => MC_EXECUTE,
+        };
+        self.fire(mode, mie, vaddr, |t| {
+            t.tdata1 & bit != 0
+                && match (t.select(), data) {
+                    (false, _) => t.compare(vaddr, size as u64),
+                    (true, Some(data)) => t.compare(data, 1),
+                    (true, None) => false,
+                }
+        })
+    }
+
+    /// Count an instruction retired in `mode`. An icount trigger reaching
+    /// zero becomes pending.
+    pub fn retire(&mut self, mode: Privilege) {
+        for trigger in &mut self.triggers {
+            if trigger.kind() != TYPE_ICOUNT || !trigger.enabled_in(mode) {
+                continue;
+            }
+            let count = (trigger.tdata1 & ICOUNT_COUNT) >> ICOUNT_COUNT_SHIFT;
+            if count == 0 {
+                continue;
+            }
+            trigger.tdata1 = trigger.tdata1 & !ICOUNT_COUNT | (count - 1) << ICOUNT_COUNT_SHIFT;
+            if count == 1 {
+                trigger.tdata1 |= ICOUNT_PENDING;
+            }
+        }
+    }
+
+    /// A pending icount trigger enabled in `mode` fires before the next
+    /// instruction.
+    pub fn match_icount(&mut self, pc: u64, mode: Privilege, mie: bool) -> Option<Fired> {
+        let trigger = self.triggers.iter_mut().find(|t| {
+            t.kind() == TYPE_ICOUNT
+                && t.tdata1 & ICOUNT_PENDING != 0
+                && t.enabled_in(mode)
+                && !(t.action() == Action::Breakpoint && mode == Privilege::Machine && !mie)
+        })?;
+        trigger.tdata1 = trigger.tdata1 & !ICOUNT_PENDING | ICOUNT_HIT;
+        Some(Fired {
+            action: trigger.action(),
+            tval: pc,
+        })
+    }
+}
+
+impl CpuState {
+    fn mie(&self) -> bool {
+        self.csr.mstatus & MSTATUS_MIE != 0
+    }
+
+    /// The exception a fired trigger raises. Entering debug mode halts the
+    /// hart instead, and the exception only aborts the instruction.
+    fn trigger_fired(&mut self, fired: Option<Fired>) -> Result<(), Exception> {
+        let Some(fired) = fired else {
+            return Ok(());
+        };
+        if fired.action == Action::DebugMode {
+            self.halted = true;
+        }
+        Err(Exception::Breakpoint(fired.tval))
+    }
+
+    /// Check the execute and icount triggers before fetching the instruction at pc.
+    pub(super) fn check_fetch_triggers(&mut self) -> Result<(), Exception> {
+        if !self.csr.triggers.armed() {
+            return Ok(());
+        }
+        let (pc, mode, mie) = (self.pc(), self.mode, self.mie());
+        let fired = self.csr.triggers.match_icount(pc, mode, mie);
+        let fired = fired.or_else(|| self.csr.triggers.match_pc(pc, mode, mie));
+        self.trigger_fired(fired)
+    }
+
+    pub(super) fn check_opcode_triggers(&mut self, raw: u32) -> Result<(), Exception> {
+        if !self.csr.triggers.armed() {
+            return Ok(());
+        }
+        let (pc, mode, mie) = (self.pc(), self.mode, self.mie());
+        let fired = self.csr.triggers.match_opcode(pc, raw, mode, mie);
+        self.trigger_fired(fired)
+    }
+
+    /// Check the load or store triggers of an access, before it is performed
+    /// or, for load data, before the value is written back.
+    pub(super) fn check_access_triggers(
+        &mut self,
+        access: AccessType,
+        vaddr: u64,
+        size: usize,
+        data: Option<u64>,
+    ) -> Result<(), Exception> {
+        if !self.csr.triggers.armed() {
+            return Ok(());
+        }
+        let (mode, mie) = (self.data_mode(), self.mie());
+        let fired = self
+            .csr
+            .triggers
+            .match_access(access, vaddr, size, data, mode, mie);
+        self.trigger_fired(fired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MCONTROL: u64 = TYPE_MCONTROL << TYPE_SHIFT;
+    const MCONTROL6: u64 = TYPE_MCONTROL6 << TYPE_SHIFT;
+    const ICOUNT: u64 = TYPE_ICOUNT << TYPE_SHIFT;
+
+    #[test]
+    fn csrs() {
+        let mut triggers = Triggers::new(2);
+        assert!(!triggers.armed());
+        assert_eq!(triggers.read_tdata1(), TYPE_DISABLED << TYPE_SHIFT);
+        triggers.write_tselect(5);
+        assert_eq!(triggers.read_tselect(), 0);
+        triggers.write_tselect(1);
+        // timing, size, chain and action 3 aren't supported
+        triggers.write_tdata1(MCONTROL | 1 << 18 | 1 << 16 | 1 << 11 | 3 << 12 | MC_M | MC_LOAD);
+        assert_eq!(
+            triggers.read_tdata1(),
+            MCONTROL | MCONTROL_MASKMAX | MC_M | MC_LOAD
+        );
+        assert!(triggers.armed());
+        triggers.write_tdata1(5 << TYPE_SHIFT);
+        assert_eq!(triggers.read_tdata1(), TYPE_DISABLED << TYPE_SHIFT);
+        assert!(!triggers.armed());
+        assert_eq!(triggers.read_tinfo() >> 24, 1);
+    }
+
+    #[test]
+    fn address_match() {
+        let mut triggers = Triggers::new(1);
+        triggers.write_tdata1(MCONTROL6 | MC_S | MC_STORE | 1 << MC_MATCH_SHIFT);
+        // a NAPOT range of 16 bytes at 0x1000
+        triggers.write_tdata2(0x1007);
+        let store = |triggers: &mut Triggers, vaddr, size, mode| {
+            triggers.match_access(AccessType::Store, vaddr, size, Some(0), mode, true)
+        };
+        assert_eq!(store(&mut triggers, 0xff8, 8, Privilege::Supervisor), None);
+        assert_eq!(store(&mut triggers, 0x1010, 4, Privilege::Supervisor), None);
+        assert_eq!(store(&mut triggers, 0x100c, 4, Privilege::User), None);
+        assert_eq!(
+            triggers.match_access(
+                AccessType::Load,
+                0x1000,
+                1,
+                None,
+                Privilege::Supervisor,
+                true
+            ),
+            None
+        );
+        assert_eq!(
+            store(&mut triggers, 0xffc, 8, Privilege::Supervisor),
+            Some(Fired {
+                action: Action::Breakpoint,
+                tval: 0xffc
+            })
+        );
+        assert_ne!(triggers.read_tdata1() & MCONTROL6_HIT0, 0);
+    }
+
+    #[test]
+    fn icount() {
+        let mut triggers = Triggers::new(1);
+        triggers.write_tdata1(ICOUNT | 2 << ICOUNT_COUNT_SHIFT | ICOUNT_U | 1);
+        triggers.retire(Privilege::User);
+        triggers.retire(Privilege::Machine);
+        assert_eq!(triggers.match_icount(0x10, Privilege::User, false), None);
+        triggers.retire(Privilege::User);
+        assert_eq!(triggers.match_icount(0x10, Privilege::Machine, true), None);
+        assert_eq!(
+            triggers.match_icount(0x20, Privilege::User, false),
+            Some(Fired {
+                action: Action::DebugMode,
+                tval: 0x20
+            })
+        );
+        assert_eq!(triggers.read_tdata1() & ICOUNT_PENDING, 0);
+        assert_ne!(triggers.read_tdata1() & ICOUNT_HIT, 0);
+    }
+}
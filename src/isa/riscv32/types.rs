@@ -4,6 +4,7 @@ pub trait Funct3 {
     fn funct3(&self) -> u32;
 }
 
+#[allow(dead_code)]
 trait Funct7 {
     fn funct7(&self) -> u32;
 }
@@ -20,12 +21,6 @@ impl Funct3 for u16 {
     }
 }
 
-#[repr(C)]
-pub struct CpuState {
-    regs: [i64; NUM_REGISTERS],
-    pc: usize,
-}
-
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct RType(pub u32);
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -67,6 +62,10 @@ pub struct CJType(pub u16);
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct CBType(pub u16);
 
+/// Zcmp push/pop and register moves
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct CmType(pub u16);
+
 const fn mask(v: u32) -> u32 {
     (1 << v) - 1
 }
@@ -380,6 +379,47 @@ impl CBType {
     }
 }
 
+/// The n-th s register, which for the 3-bit Zcmp r1s'/r2s' fields is s0-s7.
+const fn sreg(field: u32) -> u32 {
+    if field < 2 {
+        8 + field
+    } else {
+        16 + field
+    }
+}
+
+impl CmType {
+    /// 4 saves or restores ra, every value above adds the next s register,
+    /// and 15 takes both s10 and s11. Values below 4 are reserved.
+    pub fn rlist(&self) -> u32 {
+        (self.0 as u32) >> 4 & MASK4
+    }
+
+    /// The registers in rlist, from ra up to the highest s register.
+    pub fn regs(&self) -> impl DoubleEndedIterator<Item = u32> {
+        let sregs = match self.rlist() {
+            15 => 12,
+            rlist => rlist - 4,
+        };
+        std::iter::once(1).chain((0..sregs).map(sreg))
+    }
+
+    /// Bytes a push allocates or a pop frees: the saved registers rounded
+    /// up to the 16-byte stack alignment, plus spimm more 16-byte units.
+    pub fn stack_adj(&self) -> u64 {
+        let saved = self.regs().count() as u64 * 8;
+        saved.next_multiple_of(16) + ((self.0 as u64) >> 2 & 0b11) * 16
+    }
+
+    pub fn r1s(&self) -> u32 {
+        sreg((self.0 as u32) >> 7 & MASK3)
+    }
+
+    pub fn r2s(&self) -> u32 {
+        sreg((self.0 as u32) >> 2 & MASK3)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
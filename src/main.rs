@@ -10,5 +10,22 @@ pub mod runtime;
 pub mod utils;
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--bench") {
+        cpu::bench::bench(10_000_000).report();
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--caches") {
+        cpu::bench::caches(10_000_000).report_caches();
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--isa") {
+        println!("{}", isa::riscv32::reg::CpuState::new(0).isa_string());
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--memmap") {
+        let machine = cpu::machine::Machine::new(cpu::machine::MachineConfig::default());
+        machine.bus.dump_map();
+        return;
+    }
     println!("Hello, world!");
 }
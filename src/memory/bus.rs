@@ -0,0 +1,372 @@
+//! The system bus: routes every physical address to RAM, a ROM or a device.
+
+use thiserror::Error;
+
+use super::ram::Ram;
+use super::rom::{Rom, RomWrites};
+use super::{next_epoch, Word};
+use crate::device::io::map::{find_mapid_by_addr, IOMap};
+use crate::isa::riscv32::mmu::PAGE_SIZE;
+
+/// Why the bus could not complete an access, an access fault for the hart.
+#[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
+pub enum BusError {
+    #[error("nothing is mapped at {0:#x}")]
+    Unmapped(u64),
+    #[error("the access at {0:#x} runs past the end of its region")]
+    Boundary(u64),
+    #[error("{0:#x} is read only")]
+    ReadOnly(u64),
+    #[error("an injected fault at {0:#x}")]
+    Injected(u64),
+}
+
+impl BusError {
+    /// The address of the access that failed.
+    pub fn addr(self) -> u64 {
+        match self {
+            Self::Unmapped(addr)
+            | Self::Boundary(addr)
+            | Self::ReadOnly(addr)
+            | Self::Injected(addr) => addr,
+        }
+    }
+}
+
+/// A region in the address map.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegionKind {
+    Ram,
+    Rom,
+    Device,
+}
+
+/// The inclusive range of guest physical addresses a region decodes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Region {
+    pub low: u64,
+    pub high: u64,
+    pub kind: RegionKind,
+    pub name: String,
+}
+
+/// A broken device register, injected to see how the guest copes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MmioFault {
+    /// accesses fail with an access fault
+    Error,
+    /// reads return the value and writes are dropped
+    StuckAt(u64),
+}
+
+/// Everything the physical accesses of a hart can reach: the machine's RAM,
+/// and the ROMs and devices mapped around it. Regions never overlap, except
+/// that ROMs and devices may sit inside a sparse RAM.
+#[derive(Default)]
+pub struct Bus {
+    pub ram: Ram,
+    pub roms: Vec<Rom>,
+    pub devices: Vec<IOMap>,
+    /// faults on the device accesses starting at an address
+    pub mmio_faults: Vec<(u64, MmioFault)>,
+    /// renewed whenever a region is mapped
+    epoch: u64,
+}
+
+impl Bus {
+    pub fn new(ram: Ram, devices: Vec<IOMap>) -> Self {
+        let mut bus = Self {
+            ram,
+            roms: Vec::new(),
+            devices: Vec::new(),
+            mmio_faults: Vec::new(),
+            epoch: next_epoch(),
+        };
+        for map in devices {
+            bus.add_device(map);
+        }
+        bus
+    }
+
+    /// Panics if the region overlaps one already mapped.
+    fn check_free(&self, low: u64, high: u64, name: &str) {
+        if let Some(region) = self
+            .address_map()
+            .into_iter()
+            .filter(|region| !(region.kind == RegionKind::Ram && self.ram.is_sparse()))
+            .find(|region| low <= region.high && region.low <= high)
+        {
+            panic!(
+                "{name} at {low:#x}-{high:#x} overlaps {} at {:#x}-{:#x}",
+                region.name, region.low, region.high
+            );
+        }
+    }
+
+    pub fn add_rom(&mut self, rom: Rom) {
+        let (low, size) = (rom.base(), rom.size() as u64);
+        self.check_free(low, low + size - 1, &rom.name);
+        self.roms.push(rom);
+        self.epoch = next_epoch();
+    }
+
+    pub fn add_device(&mut self, map: IOMap) {
+        self.check_free(map.low as u64, map.high as u64, &map.name);
+        self.devices.push(map);
+        self.epoch = next_epoch();
+    }
+
+    /// Every region, sorted by address.
+    pub fn address_map(&self) -> Vec<Region> {
+        let ram = Region {
+            low: self.ram.base(),
+            high: self.ram.base() + self.ram.size() as u64 - 1,
+            kind: RegionKind::Ram,
+            name: "ram".into(),
+        };
+        let roms = self.roms.iter().map(|rom| Region {
+            low: rom.base(),
+            high: rom.base() + rom.size() as u64 - 1,
+            kind: RegionKind::Rom,
+            name: rom.name.clone(),
+        });
+        let devices = self.devices.iter().map(|map| Region {
+            low: map.low as u64,
+            high: map.high as u64,
+            kind: RegionKind::Device,
+            name: map.name.clone(),
+        });
+        let mut regions: Vec<_> = [ram].into_iter().chain(roms).chain(devices).collect();
+        regions.sort_by_key(|region| region.low);
+        regions
+    }
+
+    pub fn dump_map(&self) {
+        for region in self.address_map() {
+            println!(
+                "{:#018x}-{:#018x}\t{:?}\t{}",
+                region.low, region.high, region.kind, region.name
+            );
+        }
+    }
+
+    /// Whether `paddr` is memory rather than a device.
+    pub fn cacheable(&self, paddr: u64) -> bool {
+        self.is_ram(paddr) || self.roms.iter().any(|rom| rom.contains(paddr))
+    }
+
+    /// Whether `paddr` is in RAM, rather than in a ROM or device mapped over a
+    /// sparse RAM.
+    pub fn is_ram(&self, paddr: u64) -> bool {
+        self.ram.contains(paddr)
+            && !(self.ram.is_sparse()
+                && (self.roms.iter().any(|rom| rom.contains(paddr))
+                    || find_mapid_by_addr(&self.devices, paddr as usize).is_some()))
+    }
+
+    /// Changes whenever the host pointers of `host_page` may have gone
+    /// stale.
+    #[inline(always)]
+    pub fn epoch(&self) -> u64 {
+        // both come from the same counter, so the larger is always the newer
+        self.epoch.max(self.ram.epoch())
+    }
+
+    /// The host address of the page at `paddr` if all of it is RAM, see
+    /// `Ram::host_page`.
+    pub fn host_page(&mut self, paddr: u64, store: bool) -> Option<*mut u8> {
+        if self.ram.is_sparse() {
+            let high = paddr + PAGE_SIZE - 1;
+            let overlaps = |low: u64, last: u64| paddr <= last && low <= high;
+            if self
+                .roms
+                .iter()
+                .any(|rom| overlaps(rom.base(), rom.base() + rom.size() as u64 - 1))
+                || self
+                    .devices
+                    .iter()
+                    .any(|map| overlaps(map.low as u64, map.high as u64))
+            {
+                return None;
+            }
+        }
+        self.ram.host_page(paddr, store)
+    }
+
+    pub fn read<T: Word>(&self, paddr: u64) -> Result<T, BusError> {
+        if self.is_ram(paddr) {
+            return self.ram.read(paddr).ok_or(BusError::Boundary(paddr));
+        }
+        if let Some(rom) = self.roms.iter().find(|rom| rom.contains(paddr)) {
+            return rom.read(paddr).ok_or(BusError::Boundary(paddr));
+        }
+        let index = self.device(paddr, T::SIZE)?;
+        match self.mmio_fault(paddr) {
+            Some(MmioFault::Error) => Err(BusError::Injected(paddr)),
+            Some(MmioFault::StuckAt(value)) => Ok(T::truncate(value)),
+            None => Ok(self.devices[index].mmio_read(paddr as usize)),
+        }
+    }
+
+    pub fn write<T: Word>(&mut self, paddr: u64, data: T) -> Result<(), BusError> {
+        if self.is_ram(paddr) {
+            return self.ram.write(paddr, data).ok_or(BusError::Boundary(paddr));
+        }
+        if let Some(rom) = self.roms.iter().find(|rom| rom.contains(paddr)) {
+            return match rom.writes {
+                RomWrites::Fault => Err(BusError::ReadOnly(paddr)),
+                RomWrites::Ignore => Ok(()),
+            };
+        }
+        let index = self.device(paddr, T::SIZE)?;
+        match self.mmio_fault(paddr) {
+            Some(MmioFault::Error) => return Err(BusError::Injected(paddr)),
+            Some(MmioFault::StuckAt(_)) => {}
+            None => self.devices[index].mmio_write(paddr as usize, data),
+        }
+        Ok(())
+    }
+
+    fn mmio_fault(&self, paddr: u64) -> Option<MmioFault> {
+        self.mmio_faults
+            .iter()
+            .find(|(addr, _)| *addr == paddr)
+            .map(|&(_, fault)| fault)
+    }
+
+    /// The device that `size` bytes at `paddr` all fall in.
+    fn device(&self, paddr: u64, size: usize) -> Result<usize, BusError> {
+        let index =
+            find_mapid_by_addr(&self.devices, paddr as usize).ok_or(BusError::Unmapped(paddr))?;
+        if self.devices[index].fits(paddr as usize, size) {
+            Ok(index)
+        } else {
+            Err(BusError::Boundary(paddr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::ram::{MemoryConfig, RamBackend};
+    use crate::memory::sparse::ChunkSize;
+
+    fn bus() -> Bus {
+        let mut bus = Bus::new(
+            Ram::new(0x8000_0000, 0x1000),
+            vec![IOMap::scratch("uart", 0x1000_0000, 8)],
+        );
+        bus.add_rom(Rom::new(
+            "boot".into(),
+            0x1000,
+            vec![0x13, 0, 0, 0],
+            RomWrites::Fault,
+        ));
+        bus.add_rom(Rom::new(
+            "flash".into(),
+            0x2000,
+            vec![0xff; 8],
+            RomWrites::Ignore,
+        ));
+        bus
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut bus = bus();
+        bus.write(0x8000_0ff8, 0x1122_3344_5566_7788u64).unwrap();
+        assert_eq!(bus.read(0x8000_0ffc), Ok(0x1122_3344u32));
+        assert_eq!(bus.read(0x1000), Ok(0x13u32));
+        bus.write(0x1000_0004, 0xabu8).unwrap();
+        assert_eq!(bus.devices[0].space[4], 0xab);
+        assert_eq!(bus.read(0x1000_0004), Ok(0xabu16));
+
+        assert_eq!(bus.write(0x1002, 0u16), Err(BusError::ReadOnly(0x1002)));
+        assert_eq!(bus.write(0x2000, 0u64), Ok(()));
+        assert_eq!(bus.read(0x2000), Ok(u64::MAX));
+        assert_eq!(bus.read::<u64>(0x1000), Err(BusError::Boundary(0x1000)));
+        assert_eq!(
+            bus.read::<u64>(0x1000_0004),
+            Err(BusError::Boundary(0x1000_0004))
+        );
+        assert_eq!(
+            bus.write(0x8000_0ffc, 0u64),
+            Err(BusError::Boundary(0x8000_0ffc))
+        );
+        assert_eq!(bus.read::<u8>(0x3000), Err(BusError::Unmapped(0x3000)));
+    }
+
+    #[test]
+    fn mmio_faults() {
+        let mut bus = bus();
+        bus.mmio_faults = vec![
+            (0x1000_0000, MmioFault::Error),
+            (0x1000_0004, MmioFault::StuckAt(0x1ff)),
+        ];
+        assert_eq!(
+            bus.read::<u32>(0x1000_0000),
+            Err(BusError::Injected(0x1000_0000))
+        );
+        assert_eq!(
+            bus.write(0x1000_0000, 0u8),
+            Err(BusError::Injected(0x1000_0000))
+        );
+        bus.write(0x1000_0004, 0xabu8).unwrap();
+        assert_eq!(bus.devices[0].space[4], 0);
+        assert_eq!(bus.read(0x1000_0004), Ok(0xffu8));
+        // only accesses starting at the address are affected
+        assert_eq!(bus.read(0x1000_0002), Ok(0u16));
+    }
+
+    #[test]
+    fn address_map() {
+        let regions: Vec<_> = bus()
+            .address_map()
+            .into_iter()
+            .map(|region| (region.low, region.high, region.kind))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (0x1000, 0x1003, RegionKind::Rom),
+                (0x2000, 0x2007, RegionKind::Rom),
+                (0x1000_0000, 0x1000_0007, RegionKind::Device),
+                (0x8000_0000, 0x8000_0fff, RegionKind::Ram),
+            ]
+        );
+    }
+
+    #[test]
+    fn sparse() {
+        let mut bus = Bus::new(
+            Ram::from_config(&MemoryConfig {
+                base: 0,
+                size: 1 << 56,
+                backend: RamBackend::Sparse {
+                    chunk: ChunkSize::Page,
+                    fill: 0,
+                },
+            })
+            .unwrap(),
+            vec![IOMap::scratch("uart", 0x1000_0000, 8)],
+        );
+        bus.write(0x1000_0000, 0xabu8).unwrap();
+        assert_eq!(bus.devices[0].space[0], 0xab);
+        assert!(!bus.is_ram(0x1000_0000) && bus.is_ram(0x1000_0008));
+        bus.write(0xff_0000_0000_0000, 0xcdu8).unwrap();
+        assert_eq!(bus.read(0xff_0000_0000_0000), Ok(0xcdu8));
+        assert_eq!(bus.ram.sparse_stats().unwrap().chunks, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps ram")]
+    fn overlap() {
+        bus().add_rom(Rom::new(
+            "flash".into(),
+            0x8000_0ffc,
+            vec![0; 8],
+            RomWrites::Fault,
+        ));
+    }
+}
@@ -0,0 +1,431 @@
+//! A model of a hart's caches for performance studies. It only keeps tags
+//! and counts hits, misses and writebacks: the data always comes from the
+//! bus, so the model never changes what the guest sees.
+
+use crate::isa::riscv32::trap::AccessType;
+
+/// Which way of a full set a miss evicts.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Replacement {
+    /// the least recently used
+    #[default]
+    Lru,
+    /// a pseudo-random one, from a fixed seed so that runs repeat
+    Random,
+    /// the one a binary tree of recency bits points at, which needs a power
+    /// of two ways
+    Plru,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WritePolicy {
+    /// stores only dirty the line, which is written back once evicted
+    #[default]
+    WriteBack,
+    /// stores go to the next level as well
+    WriteThrough,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CacheConfig {
+    /// total bytes, a power of two
+    pub size: usize,
+    pub ways: usize,
+    /// bytes per line, a power of two
+    pub line: usize,
+    pub replacement: Replacement,
+    pub write: WritePolicy,
+    /// whether a store that misses fills the line
+    pub write_allocate: bool,
+}
+
+impl CacheConfig {
+    /// A write-back, write-allocate cache with LRU replacement.
+    pub fn new(size: usize, ways: usize, line: usize) -> Self {
+        Self {
+            size,
+            ways,
+            line,
+            replacement: Replacement::Lru,
+            write: WritePolicy::WriteBack,
+            write_allocate: true,
+        }
+    }
+
+    fn sets(&self) -> usize {
+        self.size / (self.ways * self.line)
+    }
+}
+
+/// The caches of every hart: split first-level caches, backed by an
+/// optional unified second level. Each hart has its own L2.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HierarchyConfig {
+    pub l1i: CacheConfig,
+    pub l1d: CacheConfig,
+    pub l2: Option<CacheConfig>,
+}
+
+impl Default for HierarchyConfig {
+    fn default() -> Self {
+        Self {
+            l1i: CacheConfig::new(32 << 10, 8, 64),
+            l1d: CacheConfig::new(32 << 10, 8, 64),
+            l2: Some(CacheConfig::new(512 << 10, 16, 64)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub read_misses: u64,
+    pub writes: u64,
+    pub write_misses: u64,
+    /// dirty lines evicted to the next level
+    pub writebacks: u64,
+}
+
+impl CacheStats {
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.reads + self.writes;
+        if total == 0 {
+            0.0
+        } else {
+            (total - self.misses()) as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Way {
+    valid: bool,
+    dirty: bool,
+    tag: u64,
+    /// the access count when the way was last used, for LRU
+    used: u64,
+}
+
+/// What one access did, for the next level to see.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Outcome {
+    hit: bool,
+    /// the line was brought in from the next level
+    filled: bool,
+    /// the address of a dirty line that was evicted
+    writeback: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    /// `ways` entries per set
+    ways: Vec<Way>,
+    /// the PLRU tree of each set, node `n` at bit `n`, set when the older
+    /// half is the upper one
+    plru: Vec<u128>,
+    accesses: u64,
+    seed: u64,
+    pub stats: CacheStats,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        assert!(config.size.is_power_of_two() && config.line.is_power_of_two());
+        assert!(config.ways > 0 && config.sets() > 0);
+        assert!(config.sets() * config.ways * config.line == config.size);
+        if config.replacement == Replacement::Plru {
+            assert!(config.ways.is_power_of_two() && config.ways <= 64);
+        }
+        Self {
+            config,
+            ways: vec![Way::default(); config.sets() * config.ways],
+            plru: vec![0; config.sets()],
+            accesses: 0,
+            seed: 0x2545_f491_4f6c_dd1d,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    fn touch(&mut self, set: usize, way: usize) {
+        self.ways[set * self.config.ways + way].used = self.accesses;
+        // point every node on the path away from `way`
+        let (mut node, mut low, mut high) = (1, 0, self.config.ways);
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if way < mid {
+                self.plru[set] |= 1 << node;
+                (node, high) = (2 * node, mid);
+            } else {
+                self.plru[set] &= !(1 << node);
+                (node, low) = (2 * node + 1, mid);
+            }
+        }
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        let ways = &self.ways[set * self.config.ways..(set + 1) * self.config.ways];
+        if let Some(way) = ways.iter().position(|way| !way.valid) {
+            return way;
+        }
+        match self.config.replacement {
+            Replacement::Lru => (0..ways.len()).min_by_key(|&way| ways[way].used).unwrap(),
+            Replacement::Random => {
+                // xorshift64
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                (self.seed % self.config.ways as u64) as usize
+            }
+            Replacement::Plru => {
+                let (mut node, mut low, mut high) = (1, 0, self.config.ways);
+                while high - low > 1 {
+                    let mid = (low + high) / 2;
+                    if self.plru[set] & 1 << node != 0 {
+                        (node, low) = (2 * node + 1, mid);
+                    } else {
+                        (node, high) = (2 * node, mid);
+                    }
+                }
+                low
+            }
+        }
+    }
+
+    fn access(&mut self, paddr: u64, write: bool) -> Outcome {
+        self.accesses += 1;
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+        let line = paddr / self.config.line as u64;
+        let sets = self.config.sets() as u64;
+        let (set, tag) = ((line % sets) as usize, line / sets);
+        let first = set * self.config.ways;
+        let write_back = self.config.write == WritePolicy::WriteBack;
+
+        let hit = self.ways[first..first + self.config.ways]
+            .iter()
+            .position(|way| way.valid && way.tag == tag);
+        if let Some(way) = hit {
+            self.ways[first + way].dirty |= write && write_back;
+            self.touch(set, way);
+            return Outcome {
+                hit: true,
+                ..Outcome::default()
+            };
+        }
+
+        if write {
+            self.stats.write_misses += 1;
+            if !self.config.write_allocate {
+                return Outcome::default();
+            }
+        } else {
+            self.stats.read_misses += 1;
+        }
+        let way = self.victim(set);
+        let evicted = self.ways[first + way];
+        let writeback = (evicted.valid && evicted.dirty).then(|| {
+            self.stats.writebacks += 1;
+            (evicted.tag * sets + set as u64) * self.config.line as u64
+        });
+        self.ways[first + way] = Way {
+            valid: true,
+            dirty: write && write_back,
+            tag,
+            used: self.accesses,
+        };
+        self.touch(set, way);
+        Outcome {
+            hit: false,
+            filled: true,
+            writeback,
+        }
+    }
+}
+
+/// Feed an access that `l1` handled with `outcome` to the second level.
+fn next_level(l2: &mut Option<Cache>, l1: &CacheConfig, paddr: u64, write: bool, outcome: Outcome) {
+    let Some(l2) = l2 else {
+        return;
+    };
+    if let Some(victim) = outcome.writeback {
+        l2.access(victim, true);
+    }
+    if outcome.filled {
+        l2.access(paddr, false);
+    }
+    if write && (l1.write == WritePolicy::WriteThrough || !outcome.hit && !outcome.filled) {
+        l2.access(paddr, true);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheHierarchy {
+    pub l1i: Cache,
+    pub l1d: Cache,
+    pub l2: Option<Cache>,
+}
+
+impl CacheHierarchy {
+    pub fn new(config: &HierarchyConfig) -> Self {
+        Self {
+            l1i: Cache::new(config.l1i),
+            l1d: Cache::new(config.l1d),
+            l2: config.l2.map(Cache::new),
+        }
+    }
+
+    /// Feed a physical access to memory; device accesses are not cached.
+    pub fn access(&mut self, paddr: u64, access: AccessType) {
+        let (l1, write) = match access {
+            AccessType::Fetch => (&mut self.l1i, false),
+            AccessType::Load => (&mut self.l1d, false),
+            AccessType::Store => (&mut self.l1d, true),
+        };
+        let outcome = l1.access(paddr, write);
+        let config = l1.config;
+        next_level(&mut self.l2, &config, paddr, write, outcome);
+    }
+
+    pub fn levels(&self) -> Vec<(&'static str, &Cache)> {
+        let mut levels = vec![("l1i", &self.l1i), ("l1d", &self.l1d)];
+        levels.extend(self.l2.as_ref().map(|l2| ("l2", l2)));
+        levels
+    }
+
+    pub fn report(&self) {
+        for (name, cache) in self.levels() {
+            let stats = cache.stats;
+            println!(
+                "{}\t\t{:.2}% hits ({} reads, {} read misses, {} writes, {} write misses, {} writebacks)",
+                name,
+                stats.hit_rate() * 100.0,
+                stats.reads,
+                stats.read_misses,
+                stats.writes,
+                stats.write_misses,
+                stats.writebacks
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One set of four 16-byte lines.
+    fn cache(replacement: Replacement) -> Cache {
+        Cache::new(CacheConfig {
+            replacement,
+            ..CacheConfig::new(64, 4, 16)
+        })
+    }
+
+    /// Which of the lines 0..=5 are cached.
+    fn resident(cache: &Cache) -> Vec<u64> {
+        (0..6)
+            .filter(|&line| cache.ways.iter().any(|way| way.valid && way.tag == line))
+            .collect()
+    }
+
+    #[test]
+    fn replacement() {
+        // fill the set, use line 0 again, then miss on line 4
+        let fill = |cache: &mut Cache| {
+            for line in [0, 1, 2, 3, 0, 4] {
+                cache.access(line * 16, false);
+            }
+        };
+        let mut lru = cache(Replacement::Lru);
+        fill(&mut lru);
+        assert_eq!(resident(&lru), [0, 2, 3, 4]);
+        assert_eq!((lru.stats.reads, lru.stats.read_misses), (6, 5));
+
+        // the tree only approximates LRU: it points away from line 0 at the
+        // root, then away from line 3, the newer of lines 2 and 3
+        let mut plru = cache(Replacement::Plru);
+        fill(&mut plru);
+        assert_eq!(resident(&plru), [0, 1, 3, 4]);
+        plru.access(5 * 16, false);
+        assert_eq!(resident(&plru), [0, 3, 4, 5]);
+
+        let mut random = cache(Replacement::Random);
+        fill(&mut random);
+        let evicted = resident(&random);
+        assert_eq!(evicted.len(), 4);
+        assert!(evicted.contains(&4));
+        let mut again = cache(Replacement::Random);
+        fill(&mut again);
+        assert_eq!(resident(&again), evicted);
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut write_back = cache(Replacement::Lru);
+        write_back.access(0x00, true);
+        write_back.access(0x04, true);
+        for line in 1..4 {
+            write_back.access(line * 16, false);
+        }
+        assert_eq!(write_back.access(0x40, false).writeback, Some(0));
+        assert_eq!(write_back.stats.writes, 2);
+        assert_eq!(write_back.stats.write_misses, 1);
+        assert_eq!(write_back.stats.writebacks, 1);
+
+        let mut write_through = Cache::new(CacheConfig {
+            write: WritePolicy::WriteThrough,
+            write_allocate: false,
+            ..CacheConfig::new(64, 4, 16)
+        });
+        let miss = write_through.access(0x00, true);
+        assert_eq!(miss, Outcome::default());
+        write_through.access(0x00, false);
+        assert!(write_through.access(0x08, true).hit);
+        for line in 1..5 {
+            write_through.access(line * 16, false);
+        }
+        assert_eq!(write_through.stats.writebacks, 0);
+    }
+
+    #[test]
+    fn hierarchy() {
+        let mut caches = CacheHierarchy::new(&HierarchyConfig {
+            l1i: CacheConfig::new(64, 4, 16),
+            l1d: CacheConfig::new(64, 4, 16),
+            l2: Some(CacheConfig::new(256, 4, 16)),
+        });
+        for _ in 0..2 {
+            for pc in (0..0x40).step_by(4) {
+                caches.access(0x8000_0000 + pc, AccessType::Fetch);
+            }
+        }
+        assert_eq!(
+            (caches.l1i.stats.reads, caches.l1i.stats.read_misses),
+            (32, 4)
+        );
+        // only the misses reach the second level
+        let l2 = caches.l2.as_ref().unwrap().stats;
+        assert_eq!((l2.reads, l2.read_misses), (4, 4));
+
+        // five dirty lines through one set: the first one is written back
+        for line in 0..5 {
+            caches.access(0x9000_0000 + line * 16, AccessType::Store);
+        }
+        let l2 = caches.l2.as_ref().unwrap().stats;
+        assert_eq!((l2.reads, l2.writes), (9, 1));
+        assert_eq!(caches.l1d.stats.writebacks, 1);
+        assert_eq!(caches.levels().len(), 3);
+    }
+}
@@ -0,0 +1,221 @@
+//! Compare two snapshots of RAM, to find what a stretch of guest execution
+//! changed, and name the changes after the guest's symbols.
+
+use std::fmt;
+
+use super::ram::Snapshot;
+use crate::isa::riscv32::mmu::PAGE_SIZE;
+
+/// Guest symbols, each covering the addresses up to the next one.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    /// sorted by address
+    symbols: Vec<(u64, String)>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<(u64, String)>) -> Self {
+        symbols.sort();
+        Self { symbols }
+    }
+
+    /// Read the output of `nm`: an address, a type and a name per line.
+    /// Undefined symbols, without an address, are skipped.
+    pub fn from_nm(text: &str) -> Self {
+        let symbols = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let name = fields.nth(1)?;
+                Some((addr, name.to_string()))
+            })
+            .collect();
+        Self::new(symbols)
+    }
+
+    /// The symbol at or below `addr`, and the offset of `addr` into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|(start, _)| *start <= addr);
+        let (start, name) = self.symbols.get(index.checked_sub(1)?)?;
+        Some((name, addr - start))
+    }
+}
+
+/// The bytes from `low` up to, but not including, `high` differ.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Change {
+    pub low: u64,
+    pub high: u64,
+}
+
+/// A change, named after the symbol it starts in.
+pub struct Annotated<'a> {
+    pub change: Change,
+    pub symbol: Option<(&'a str, u64)>,
+}
+
+impl fmt::Display for Annotated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Change { low, high } = self.change;
+        write!(f, "{:#x}-{:#x}", low, high - 1)?;
+        match self.symbol {
+            Some((name, 0)) => write!(f, " {name}"),
+            Some((name, offset)) => write!(f, " {name}+{offset:#x}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Every run of bytes that differs between `old` and `new`, two snapshots
+/// of the same RAM. Only the chunks copied into either are compared.
+pub fn diff(old: &Snapshot, new: &Snapshot) -> Vec<Change> {
+    let mut pages: Vec<u64> = old
+        .pages()
+        .chain(new.pages())
+        .map(|offset| old.base + offset as u64)
+        .collect();
+    pages.sort_unstable();
+    pages.dedup();
+    diff_pages(old, new, pages)
+}
+
+/// The runs of bytes that differ within `pages`, such as the dirty pages of
+/// RAM since `old` was taken. Runs crossing from one page into the next are
+/// merged.
+pub fn diff_pages(
+    old: &Snapshot,
+    new: &Snapshot,
+    pages: impl IntoIterator<Item = u64>,
+) -> Vec<Change> {
+    assert!(old.base == new.base && old.size == new.size);
+    let mut changes: Vec<Change> = Vec::new();
+    for page in pages {
+        let offset = (page - old.base) as usize;
+        let len = (PAGE_SIZE as usize).min(old.size - offset);
+        let (before, after) = (old.bytes(offset, len), new.bytes(offset, len));
+        if before == after {
+            continue;
+        }
+        for i in (0..before.len()).filter(|&i| before[i] != after[i]) {
+            let addr = page + i as u64;
+            match changes.last_mut() {
+                Some(last) if last.high == addr => last.high += 1,
+                _ => changes.push(Change {
+                    low: addr,
+                    high: addr + 1,
+                }),
+            }
+        }
+    }
+    changes
+}
+
+pub fn annotate<'a>(changes: &[Change], symbols: &'a SymbolTable) -> Vec<Annotated<'a>> {
+    changes
+        .iter()
+        .map(|&change| Annotated {
+            change,
+            symbol: symbols.lookup(change.low),
+        })
+        .collect()
+}
+
+/// Print the changes from `old` to `new`, one range per line.
+pub fn dump_diff(old: &Snapshot, new: &Snapshot, symbols: &SymbolTable) {
+    for change in annotate(&diff(old, new), symbols) {
+        println!("{change}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::ram::{MemoryConfig, Ram, RamBackend};
+    use crate::memory::sparse::ChunkSize;
+
+    #[test]
+    fn changes() {
+        let mut ram = Ram::new(0x8000_0000, 0x4000);
+        ram.write(0x8000_0ff8, u64::MAX).unwrap();
+        let old = ram.snapshot();
+        ram.checkpoint();
+        // a run across a page boundary, a store of the value already there,
+        // and a single byte
+        ram.write(0x8000_0ffc, 0x1234_5678_0000_0000u64).unwrap();
+        ram.write(0x8000_2000, 0u32).unwrap();
+        ram.write(0x8000_3123, 1u8).unwrap();
+        let new = ram.snapshot();
+
+        let expected = [
+            Change {
+                low: 0x8000_0ffc,
+                high: 0x8000_1004,
+            },
+            Change {
+                low: 0x8000_3123,
+                high: 0x8000_3124,
+            },
+        ];
+        assert_eq!(diff(&old, &new), expected);
+        assert_eq!(ram.dirty_pages().len(), 4);
+        assert_eq!(diff_pages(&old, &new, ram.dirty_pages()), expected);
+
+        let symbols = SymbolTable::from_nm(
+            "0000000080000ff8 D flag\n\
+             0000000080001000 D counter\n\
+             0000000080003000 B buffer\n\
+                              U printf\n",
+        );
+        let lines: Vec<_> = annotate(&expected, &symbols)
+            .iter()
+            .map(|change| change.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "0x80000ffc-0x80001003 flag+0x4",
+                "0x80003123-0x80003123 buffer+0x123",
+            ]
+        );
+        assert_eq!(symbols.lookup(0x8000_1000), Some(("counter", 0)));
+        assert_eq!(symbols.lookup(0x8000_0000), None);
+    }
+
+    #[test]
+    fn sparse() {
+        // from 4 KiB up to the top of the address space, far too much to copy
+        let mut ram = Ram::from_config(&MemoryConfig {
+            base: 0x1000,
+            size: usize::MAX - 0xfff,
+            backend: RamBackend::Sparse {
+                chunk: ChunkSize::Page,
+                fill: 0xa5a5_a5a5_a5a5_a5a5,
+            },
+        })
+        .unwrap();
+        ram.write(0x8000_0000, 1u64).unwrap();
+        let old = ram.snapshot();
+        ram.checkpoint();
+        // a change in a chunk both snapshots have, a store of the fill
+        // pattern to a new chunk, and a change in a new chunk at the top
+        ram.write(0x8000_0004, 2u8).unwrap();
+        ram.write(0x10_0000_0000_0000, 0xa5a5u16).unwrap();
+        ram.write(u64::MAX - 1, 0xa500u16).unwrap();
+        let new = ram.snapshot();
+
+        let expected = [
+            Change {
+                low: 0x8000_0004,
+                high: 0x8000_0005,
+            },
+            Change {
+                low: u64::MAX - 1,
+                high: u64::MAX,
+            },
+        ];
+        assert_eq!(diff(&old, &new), expected);
+        assert_eq!(diff_pages(&old, &new, ram.dirty_pages()), expected);
+        assert_eq!(new.pages().count(), 3);
+    }
+}
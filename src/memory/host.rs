@@ -0,0 +1,156 @@
+//! Host memory that guest RAM lives in.
+//!
+//! The bytes are only ever reached through the one pointer the allocation
+//! returned, never through a reference to the whole of it, so the host
+//! pointers the softmmu keeps stay valid while RAM is read and written
+//! through `Ram`.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use super::{host_read, host_write, Word};
+
+enum Allocation {
+    /// a leaked `Box<[u8]>`
+    Heap,
+    /// a mapping made with `mmap`
+    Mapped,
+}
+
+/// `len` bytes of host memory, freed or unmapped on drop.
+pub(super) struct HostMemory {
+    ptr: NonNull<u8>,
+    len: usize,
+    allocation: Allocation,
+}
+
+// the memory is owned by exactly one `HostMemory`
+unsafe impl Send for HostMemory {}
+
+impl HostMemory {
+    /// Memory holding `data`.
+    pub fn heap(data: Vec<u8>) -> Self {
+        let len = data.len();
+        let ptr = Box::into_raw(data.into_boxed_slice()) as *mut u8;
+        Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            len,
+            allocation: Allocation::Heap,
+        }
+    }
+
+    fn mmap(len: usize, flags: i32, fd: i32) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            len,
+            allocation: Allocation::Mapped,
+        })
+    }
+
+    /// An anonymous mapping without swap reserved for it.
+    pub fn anonymous(len: usize) -> io::Result<Self> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+        Self::mmap(len, flags, -1)
+    }
+
+    /// A shared mapping of the file at `path`, created or resized to `len`.
+    pub fn file(path: &Path, len: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        // the mapping stays valid once the file is closed
+        Self::mmap(len, libc::MAP_SHARED, file.as_raw_fd())
+    }
+
+    /// The host address of the byte at `offset`, panics if `len` bytes from
+    /// there are not all inside.
+    fn at(&self, offset: usize, len: usize) -> *mut u8 {
+        assert!(offset <= self.len && len <= self.len - offset);
+        // in bounds, checked above
+        unsafe { self.ptr.as_ptr().add(offset) }
+    }
+
+    /// The `len` bytes at `offset`.
+    pub fn bytes(&self, offset: usize, len: usize) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.at(offset, len), len) }
+    }
+
+    fn bytes_mut(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.at(offset, len), len) }
+    }
+
+    pub fn read<T: Word>(&self, offset: usize) -> T {
+        host_read(self.bytes(offset, T::SIZE))
+    }
+
+    pub fn write<T: Word>(&mut self, offset: usize, data: T) {
+        host_write(self.bytes_mut(offset, T::SIZE), data)
+    }
+
+    pub fn read_bytes(&self, offset: usize, bytes: &mut [u8]) {
+        bytes.copy_from_slice(self.bytes(offset, bytes.len()));
+    }
+
+    pub fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        self.bytes_mut(offset, bytes.len()).copy_from_slice(bytes);
+    }
+
+    /// The host address of the page at `offset`, derived from the same
+    /// pointer every other access is.
+    pub fn page(&mut self, offset: usize, size: usize) -> *mut u8 {
+        self.at(offset, size)
+    }
+}
+
+impl Drop for HostMemory {
+    fn drop(&mut self) {
+        let ptr = self.ptr.as_ptr();
+        match self.allocation {
+            Allocation::Heap => unsafe {
+                drop(Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, self.len)));
+            },
+            Allocation::Mapped => unsafe {
+                libc::munmap(ptr as *mut libc::c_void, self.len);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages() {
+        let mut memory = HostMemory::heap(vec![0; 0x2000]);
+        let page = memory.page(0x1000, 0x1000);
+        memory.write(0x1008, 0x1234u16);
+        // the page pointer survives the write through `memory`, and the
+        // other way round
+        assert_eq!(unsafe { page.add(8).read() }, 0x34);
+        unsafe { page.add(9).write(0x56) };
+        assert_eq!(memory.read::<u16>(0x1008), 0x5634);
+        assert_eq!(memory.bytes(0x1fff, 1), [0]);
+    }
+}
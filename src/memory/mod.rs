@@ -1,76 +1,163 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::isa::riscv32::{
+    csr::Privilege,
+    pmp::Pmp,
+    trap::{AccessType, Exception},
+};
+
+pub mod bus;
+pub mod cache;
+pub mod diff;
 pub mod dram;
+pub mod host;
+pub mod ram;
+pub mod rom;
+pub mod sparse;
 
+use bus::Bus;
+
+/// Bytes of guest RAM, and the guest physical address it starts at, unless
+/// the memory configuration of the machine picks others.
 pub const CONFIG_MSIZE: usize = 0x2000000;
-pub const CONFIG_MBASE: usize = 0x0;
-
-pub const PHYSICAL_MEM: [u8; CONFIG_MSIZE] = [0; CONFIG_MSIZE];
-
-pub fn host_read(addr: *const u8, size: usize) -> i32 {
-    return unsafe {
-        match size {
-            1 => *addr as i32,
-            2 => *(addr as *const i16) as i32,
-            4 => *(addr as *const i32),
-            _ => {
-                panic!("read size wrong:{}", size)
-            }
-        }
-    };
+pub const CONFIG_MBASE: u64 = 0x8000_0000;
+
+/// The width of a memory access. Guest memory is little-endian whatever the
+/// host is, and reads are zero-extended unless the caller sign-extends them.
+pub trait Word: Copy + Into<u64> {
+    const SIZE: usize;
+
+    fn from_le(bytes: &[u8]) -> Self;
+    fn to_le(self, bytes: &mut [u8]);
+    /// The low `SIZE` bytes of `data`.
+    fn truncate(data: u64) -> Self;
+    fn sext(self) -> u64;
+
+    fn zext(self) -> u64 {
+        self.into()
+    }
 }
 
-pub fn host_write(addr: *mut u8, size: usize, data: i32) {
-    unsafe {
-        match size {
-            1 => *addr = data as u8,
-            2 => *(addr as *mut u16) = data as u16,
-            4 => *(addr as *mut u32) = data as u32,
-            _ => {
-                panic!("read size wrong:{}", size)
+macro_rules! word {
+    ($($unsigned:ty, $signed:ty);*) => {
+        $(
+            impl Word for $unsigned {
+                const SIZE: usize = std::mem::size_of::<$unsigned>();
+
+                fn from_le(bytes: &[u8]) -> Self {
+                    Self::from_le_bytes(bytes.try_into().unwrap())
+                }
+
+                fn to_le(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn truncate(data: u64) -> Self {
+                    data as $unsigned
+                }
+
+                fn sext(self) -> u64 {
+                    self as $signed as i64 as u64
+                }
             }
-        }
+        )*
     };
 }
 
-pub fn in_physical_mem(addr: *const u8) -> bool {
-    let a = addr as usize;
-    return a >= CONFIG_MBASE && a <= (CONFIG_MSIZE + CONFIG_MBASE);
+word!(u8, i8; u16, i16; u32, i32; u64, i64);
+
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// A number larger than any returned before, marking a change to RAM or the
+/// address map that host pointers into RAM have to be dropped for.
+pub fn next_epoch() -> u64 {
+    EPOCH.fetch_add(1, Ordering::Relaxed) + 1
 }
 
-#[allow(unused_variables)]
-pub fn physical_addr_read(addr: *const u8, size: usize) -> i32 {
-    let a = addr as usize;
-    if in_physical_mem(addr) {
-        host_read(addr, size)
-    } else {
-        panic!(
-            "addr out of memory bound,addr:{:X},bound:{:X}-{:X}",
-            addr as usize,
-            CONFIG_MBASE,
-            CONFIG_MBASE + CONFIG_MSIZE
-        );
-    }
+/// Read a `T` from the start of `bytes`.
+pub fn host_read<T: Word>(bytes: &[u8]) -> T {
+    T::from_le(&bytes[..T::SIZE])
 }
-#[allow(unused_variables)]
-pub fn physical_addr_write(addr: *mut u8, size: usize, data: i32) {
-    let a = addr as usize;
-    if in_physical_mem(addr) {
-        host_write(addr, size, data)
-    } else {
-        panic!(
-            "addr out of memory bound,addr:{:X},bound:{:X}-{:X}",
-            addr as usize,
-            CONFIG_MBASE,
-            CONFIG_MBASE + CONFIG_MSIZE
-        );
-    }
+
+/// Write `data` to the start of `bytes`.
+pub fn host_write<T: Word>(bytes: &mut [u8], data: T) {
+    data.to_le(&mut bytes[..T::SIZE]);
+}
+
+/// Every physical access goes through the PMP of the accessing hart first,
+/// including instruction fetches and page-table walks, and then raises an
+/// access fault if the bus cannot complete it.
+pub fn physical_addr_read<T: Word>(
+    addr: u64,
+    access: AccessType,
+    pmp: &Pmp,
+    mode: Privilege,
+    bus: &Bus,
+) -> Result<T, Exception> {
+    pmp.check(addr, T::SIZE, access, mode)?;
+    bus.read(addr).map_err(|e| access.access_fault(e.addr()))
+}
+
+pub fn physical_addr_write<T: Word>(
+    addr: u64,
+    data: T,
+    pmp: &Pmp,
+    mode: Privilege,
+    bus: &mut Bus,
+) -> Result<(), Exception> {
+    pmp.check(addr, T::SIZE, AccessType::Store, mode)?;
+    bus.write(addr, data)
+        .map_err(|e| AccessType::Store.access_fault(e.addr()))
 }
 
 #[inline(always)]
-pub fn vaddr_read(addr: *const u8, size: usize) -> i32 {
-    return physical_addr_read(addr, size);
+pub fn vaddr_ifetch<T: Word>(
+    addr: u64,
+    pmp: &Pmp,
+    mode: Privilege,
+    bus: &Bus,
+) -> Result<T, Exception> {
+    physical_addr_read(addr, AccessType::Fetch, pmp, mode, bus)
 }
 
 #[inline(always)]
-pub fn vaddr_write(addr: *mut u8, size: usize, data: i32) {
-    return physical_addr_write(addr, size, data);
+pub fn vaddr_read<T: Word>(
+    addr: u64,
+    pmp: &Pmp,
+    mode: Privilege,
+    bus: &Bus,
+) -> Result<T, Exception> {
+    physical_addr_read(addr, AccessType::Load, pmp, mode, bus)
+}
+
+#[inline(always)]
+pub fn vaddr_write<T: Word>(
+    addr: u64,
+    data: T,
+    pmp: &Pmp,
+    mode: Privilege,
+    bus: &mut Bus,
+) -> Result<(), Exception> {
+    physical_addr_write(addr, data, pmp, mode, bus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words() {
+        let mut bytes = [0; 8];
+        host_write(&mut bytes, 0x80u8);
+        assert_eq!(host_read::<u8>(&bytes).zext(), 0x80);
+        assert_eq!(host_read::<u8>(&bytes).sext(), 0xffff_ffff_ffff_ff80);
+        host_write(&mut bytes, 0x8001u16);
+        assert_eq!(bytes[..2], [0x01, 0x80]);
+        assert_eq!(host_read::<u16>(&bytes).zext(), 0x8001);
+        assert_eq!(host_read::<u16>(&bytes).sext(), 0xffff_ffff_ffff_8001);
+        host_write(&mut bytes, 0x0807_0605_0403_0201u64);
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(host_read::<u32>(&bytes[4..]), 0x0807_0605);
+        assert_eq!(u16::truncate(0x1234_5678), 0x5678);
+    }
 }
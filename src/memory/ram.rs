@@ -0,0 +1,421 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+
+use super::host::HostMemory;
+use super::sparse::{ChunkSize, Sparse, SparseStats};
+use super::{next_epoch, Word, CONFIG_MBASE, CONFIG_MSIZE};
+use crate::isa::riscv32::mmu::{PAGE_SHIFT, PAGE_SIZE};
+
+/// Where the bytes of guest RAM live.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum RamBackend {
+    /// zeroed memory on the heap
+    #[default]
+    Heap,
+    /// an anonymous mapping without swap reserved for it, so that host pages
+    /// are only allocated once the guest touches them
+    Mmap,
+    /// a shared mapping of a file, created or resized to the size of RAM,
+    /// that keeps the contents after the machine is gone
+    File(PathBuf),
+    /// chunks allocated as the guest writes them, for RAM spanning a huge or
+    /// mostly empty address range. ROMs and devices may be mapped inside it.
+    Sparse { chunk: ChunkSize, fill: u64 },
+}
+
+/// The guest RAM of a machine.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemoryConfig {
+    pub base: u64,
+    pub size: usize,
+    pub backend: RamBackend,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            base: CONFIG_MBASE,
+            size: CONFIG_MSIZE,
+            backend: RamBackend::default(),
+        }
+    }
+}
+
+enum Storage {
+    Host(HostMemory),
+    Sparse(Sparse),
+}
+
+impl Storage {
+    fn read<T: Word>(&self, offset: usize) -> T {
+        match self {
+            Self::Host(memory) => memory.read(offset),
+            Self::Sparse(sparse) => sparse.read(offset),
+        }
+    }
+
+    fn write<T: Word>(&mut self, offset: usize, data: T) {
+        match self {
+            Self::Host(memory) => memory.write(offset, data),
+            Self::Sparse(sparse) => sparse.write(offset, data),
+        }
+    }
+
+    fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        match self {
+            Self::Host(memory) => memory.write_bytes(offset, bytes),
+            Self::Sparse(sparse) => sparse.write_bytes(offset, bytes),
+        }
+    }
+}
+
+/// Guest RAM: `size` bytes starting at the guest physical address `base`.
+/// Accesses are bounds checked and little-endian.
+pub struct Ram {
+    base: u64,
+    size: usize,
+    data: Storage,
+    /// a bit for every page, counted from `base`, written since the last
+    /// checkpoint. Sparse chunks keep their own.
+    dirty: Vec<u64>,
+    /// renewed at every checkpoint, which host pointers are dropped at
+    epoch: u64,
+}
+
+/// A copy of RAM. Of sparse RAM only the allocated chunks are copied, the
+/// others read as its fill pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Snapshot {
+    pub base: u64,
+    pub size: usize,
+    /// bytes in a chunk, all of RAM is one chunk unless it is sparse
+    chunk: usize,
+    /// the copied chunks by offset / chunk, none reaching past `size`
+    chunks: BTreeMap<usize, Vec<u8>>,
+    fill: u64,
+}
+
+impl Snapshot {
+    /// The offsets of the pages in the copied chunks, in order.
+    pub fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.chunks.iter().flat_map(|(&index, data)| {
+            let start = index * self.chunk;
+            (start..start + data.len()).step_by(PAGE_SIZE as usize)
+        })
+    }
+
+    /// The `len` bytes at `offset`, which must not cross a page.
+    pub fn bytes(&self, offset: usize, len: usize) -> Cow<'_, [u8]> {
+        match self.chunks.get(&(offset / self.chunk)) {
+            Some(data) => {
+                let within = offset % self.chunk;
+                Cow::Borrowed(&data[within..within + len])
+            }
+            None => {
+                let fill = self.fill.to_le_bytes();
+                Cow::Owned((offset..offset + len).map(|i| fill[i % 8]).collect())
+            }
+        }
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new(CONFIG_MBASE, CONFIG_MSIZE)
+    }
+}
+
+impl Ram {
+    pub fn new(base: u64, size: usize) -> Self {
+        Self::from_image(base, vec![0; size])
+    }
+
+    /// Memory holding `data`, starting at `base`.
+    pub fn from_image(base: u64, data: Vec<u8>) -> Self {
+        Self::with_storage(base, data.len(), Storage::Host(HostMemory::heap(data)))
+    }
+
+    pub fn from_config(config: &MemoryConfig) -> io::Result<Self> {
+        let storage = match &config.backend {
+            RamBackend::Heap => return Ok(Self::new(config.base, config.size)),
+            RamBackend::Mmap => Storage::Host(HostMemory::anonymous(config.size)?),
+            RamBackend::File(path) => Storage::Host(HostMemory::file(path, config.size)?),
+            &RamBackend::Sparse { chunk, fill } => Storage::Sparse(Sparse::new(chunk, fill)),
+        };
+        Ok(Self::with_storage(config.base, config.size, storage))
+    }
+
+    fn with_storage(base: u64, size: usize, data: Storage) -> Self {
+        assert!(size > 0 && base.checked_add(size as u64 - 1).is_some());
+        let pages = match data {
+            Storage::Sparse(_) => 0,
+            _ => size.div_ceil(PAGE_SIZE as usize),
+        };
+        Self {
+            base,
+            size,
+            data,
+            dirty: vec![0; pages.div_ceil(64)],
+            epoch: next_epoch(),
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether ROMs and devices may be mapped over parts of RAM.
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.data, Storage::Sparse(_))
+    }
+
+    /// The chunks a sparse RAM allocated.
+    pub fn sparse_stats(&self) -> Option<SparseStats> {
+        match &self.data {
+            Storage::Sparse(sparse) => Some(sparse.stats()),
+            _ => None,
+        }
+    }
+
+    /// The offset of `len` bytes at `paddr`, if all of them are in RAM.
+    fn offset(&self, paddr: u64, len: usize) -> Option<usize> {
+        let offset = usize::try_from(paddr.checked_sub(self.base)?).ok()?;
+        (offset.checked_add(len)? <= self.size()).then_some(offset)
+    }
+
+    pub fn contains(&self, paddr: u64) -> bool {
+        self.offset(paddr, 1).is_some()
+    }
+
+    pub fn read<T: Word>(&self, paddr: u64) -> Option<T> {
+        let offset = self.offset(paddr, T::SIZE)?;
+        Some(self.data.read(offset))
+    }
+
+    pub fn write<T: Word>(&mut self, paddr: u64, data: T) -> Option<()> {
+        let offset = self.offset(paddr, T::SIZE)?;
+        self.data.write(offset, data);
+        self.mark_dirty(offset, T::SIZE);
+        Some(())
+    }
+
+    /// The `len` bytes at `paddr`, copied only if RAM is sparse.
+    pub fn bytes(&self, paddr: u64, len: usize) -> Option<Cow<'_, [u8]>> {
+        let offset = self.offset(paddr, len)?;
+        Some(match &self.data {
+            Storage::Host(memory) => Cow::Borrowed(memory.bytes(offset, len)),
+            Storage::Sparse(sparse) => {
+                let mut bytes = vec![0; len];
+                sparse.read_bytes(offset, &mut bytes);
+                Cow::Owned(bytes)
+            }
+        })
+    }
+
+    /// Copy an image, a kernel or a test program, to `paddr`.
+    pub fn load(&mut self, paddr: u64, image: &[u8]) -> Option<()> {
+        let offset = self.offset(paddr, image.len())?;
+        self.data.write_bytes(offset, image);
+        self.mark_dirty(offset, image.len());
+        Some(())
+    }
+
+    fn mark_dirty(&mut self, offset: usize, len: usize) {
+        if len == 0 || self.is_sparse() {
+            return;
+        }
+        let first = offset >> PAGE_SHIFT;
+        let last = (offset + len - 1) >> PAGE_SHIFT;
+        for page in first..=last {
+            self.dirty[page / 64] |= 1 << (page % 64);
+        }
+    }
+
+    /// The addresses of the pages written since the last checkpoint, in order.
+    pub fn dirty_pages(&self) -> Vec<u64> {
+        if let Storage::Sparse(sparse) = &self.data {
+            return sparse
+                .dirty_pages()
+                .into_iter()
+                .map(|offset| self.base + offset as u64)
+                .collect();
+        }
+        let mut pages = Vec::new();
+        for (index, &word) in self.dirty.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let page = index * 64 + word.trailing_zeros() as usize;
+                pages.push(self.base + ((page as u64) << PAGE_SHIFT));
+                word &= word - 1;
+            }
+        }
+        pages
+    }
+
+    /// The host address of the page at `paddr`, which has to be page
+    /// aligned within RAM, so that the accesses to it can bypass `read` and
+    /// `write`. Stores only get it once the page is dirty, and none of the
+    /// untouched chunks of a sparse RAM are handed out. The pointer is good
+    /// until the epoch changes.
+    pub fn host_page(&mut self, paddr: u64, store: bool) -> Option<*mut u8> {
+        let offset = self.offset(paddr, PAGE_SIZE as usize)?;
+        if offset % PAGE_SIZE as usize != 0 {
+            return None;
+        }
+        let page = offset >> PAGE_SHIFT;
+        let memory = match &mut self.data {
+            Storage::Host(memory) => memory,
+            Storage::Sparse(sparse) => return sparse.host_page(offset, store),
+        };
+        if store && self.dirty[page / 64] & 1 << (page % 64) == 0 {
+            return None;
+        }
+        Some(memory.page(offset, PAGE_SIZE as usize))
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Start tracking writes afresh.
+    pub fn checkpoint(&mut self) {
+        self.epoch = next_epoch();
+        self.dirty.fill(0);
+        if let Storage::Sparse(sparse) = &mut self.data {
+            sparse.checkpoint();
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let (chunk, chunks, fill) = match &self.data {
+            Storage::Host(memory) => {
+                let data = memory.bytes(0, self.size).to_vec();
+                (self.size, BTreeMap::from([(0, data)]), 0)
+            }
+            Storage::Sparse(sparse) => (
+                sparse.chunk().bytes(),
+                sparse.copy_chunks(self.size),
+                sparse.fill(),
+            ),
+        };
+        Snapshot {
+            base: self.base,
+            size: self.size,
+            chunk,
+            chunks,
+            fill,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let mut ram = Ram::new(0x8000_0000, 0x1000);
+        assert!(ram.contains(0x8000_0fff));
+        assert!(!ram.contains(0x7fff_ffff) && !ram.contains(0x8000_1000));
+        ram.write(0x8000_0ff8, 0x0807_0605_0403_0201u64).unwrap();
+        assert_eq!(ram.read(0x8000_0ffc), Some(0x0807_0605u32));
+        assert_eq!(ram.read(0x8000_0ff9), Some(0x0302u16));
+        assert_eq!(ram.bytes(0x8000_0ffe, 2).as_deref(), Some(&[7, 8][..]));
+        // accesses that run off either end fail instead of wrapping
+        assert_eq!(ram.read::<u64>(0x8000_0ffc), None);
+        assert_eq!(ram.write(0x7fff_fffc, 0u64), None);
+        assert_eq!(ram.read::<u8>(u64::MAX), None);
+        assert_eq!(ram.load(0x8000_0fff, &[1, 2]), None);
+        assert_eq!(ram.read(0x8000_0ff8), Some(1u8));
+    }
+
+    #[test]
+    fn dirty_pages() {
+        let mut ram = Ram::new(0x8000_0000, 0x10_0000);
+        ram.load(0x8000_0ffc, &[0; 8]).unwrap();
+        ram.write(0x8008_0010, 1u8).unwrap();
+        ram.read::<u64>(0x8004_0000).unwrap();
+        assert_eq!(ram.dirty_pages(), [0x8000_0000, 0x8000_1000, 0x8008_0000]);
+        ram.checkpoint();
+        assert!(ram.dirty_pages().is_empty());
+        ram.write(0x800f_fff8, 0u64).unwrap();
+        assert_eq!(ram.dirty_pages(), [0x800f_f000]);
+    }
+
+    #[test]
+    fn sparse() {
+        // from 4 KiB up to the top of the address space
+        let mut ram = Ram::from_config(&MemoryConfig {
+            base: 0x1000,
+            size: usize::MAX - 0xfff,
+            backend: RamBackend::Sparse {
+                chunk: ChunkSize::HugePage,
+                fill: 0xa5a5_a5a5_a5a5_a5a5,
+            },
+        })
+        .unwrap();
+        assert!(ram.contains(u64::MAX) && !ram.contains(0xfff));
+        assert_eq!(ram.read(u64::MAX), Some(0xa5u8));
+        ram.load(0x8000_0000, &[1, 2, 3, 4]).unwrap();
+        ram.write(0x10_0000_0000_0000, 5u64).unwrap();
+        ram.write(u64::MAX - 7, 6u64).unwrap();
+        assert_eq!(ram.read(0x8000_0002), Some(0xa5a5_0403u32));
+        assert_eq!(ram.bytes(0x8000_0000, 2).as_deref(), Some(&[1, 2][..]));
+        assert_eq!(ram.read(u64::MAX - 7), Some(6u64));
+        assert_eq!(
+            ram.dirty_pages(),
+            [0x8000_0000, 0x10_0000_0000_0000, 0xffff_ffff_ffff_f000]
+        );
+        assert_eq!(
+            ram.sparse_stats(),
+            Some(SparseStats {
+                chunks: 3,
+                footprint: 6 << 20
+            })
+        );
+        assert_eq!(Ram::default().sparse_stats(), None);
+    }
+
+    #[test]
+    fn mmap() {
+        // far more than the host has to spare, if it were committed
+        let mut ram = Ram::from_config(&MemoryConfig {
+            size: 16 << 30,
+            backend: RamBackend::Mmap,
+            ..MemoryConfig::default()
+        })
+        .unwrap();
+        let top = CONFIG_MBASE + (16 << 30) - 8;
+        assert_eq!(ram.read(top), Some(0u64));
+        ram.write(top, 0x1234_5678u64).unwrap();
+        assert_eq!(ram.read(top + 4), Some(0u32));
+        assert_eq!(ram.read(top), Some(0x1234_5678u64));
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("nemu-ram-{}", std::process::id()));
+        let config = MemoryConfig {
+            size: 0x10000,
+            backend: RamBackend::File(path.clone()),
+            ..MemoryConfig::default()
+        };
+        let mut ram = Ram::from_config(&config).unwrap();
+        ram.write(CONFIG_MBASE + 0x100, 0xaa55u16).unwrap();
+        drop(ram);
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(contents.len(), 0x10000);
+        assert_eq!(contents[0x100..0x102], [0x55, 0xaa]);
+        // the contents persist into the next machine
+        let ram = Ram::from_config(&config).unwrap();
+        assert_eq!(ram.read(CONFIG_MBASE + 0x100), Some(0xaa55u16));
+        drop(ram);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
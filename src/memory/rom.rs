@@ -0,0 +1,78 @@
+//! Read-only memory, and the boot ROM harts start from.
+
+use super::ram::Ram;
+use super::Word;
+
+/// Where the boot ROM is mapped, unless the machine resets elsewhere.
+pub const CONFIG_BOOT_ROM_BASE: u64 = 0x1000;
+
+/// What a store to a ROM does.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RomWrites {
+    /// raise a store access fault
+    #[default]
+    Fault,
+    /// complete without changing anything
+    Ignore,
+}
+
+/// Read-only memory, such as a boot ROM or a flash image.
+pub struct Rom {
+    pub name: String,
+    pub writes: RomWrites,
+    mem: Ram,
+}
+
+impl Rom {
+    pub fn new(name: String, base: u64, image: Vec<u8>, writes: RomWrites) -> Self {
+        Self {
+            name,
+            writes,
+            mem: Ram::from_image(base, image),
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.mem.base()
+    }
+
+    pub fn size(&self) -> usize {
+        self.mem.size()
+    }
+
+    pub fn contains(&self, paddr: u64) -> bool {
+        self.mem.contains(paddr)
+    }
+
+    pub fn read<T: Word>(&self, paddr: u64) -> Option<T> {
+        self.mem.read(paddr)
+    }
+}
+
+/// What the boot ROM hands over to: the firmware entry point, and the
+/// address of the device tree, for `a1`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Boot {
+    pub entry: u64,
+    pub dtb: u64,
+}
+
+/// A boot ROM at `base` that sets `a0` to the hart id and `a1` to the device
+/// tree, then jumps to the entry point.
+pub fn boot_rom(base: u64, boot: Boot) -> Rom {
+    let code: [u32; 6] = [
+        0x00000297, // auipc t0,0
+        0xf1402573, // csrr a0,mhartid
+        0x0182b583, // ld a1,24(t0)
+        0x0202b283, // ld t0,32(t0)
+        0x00028067, // jr t0
+        0x00000013, // nop, to align the data
+    ];
+    let image = code
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .chain(boot.dtb.to_le_bytes())
+        .chain(boot.entry.to_le_bytes())
+        .collect();
+    Rom::new("boot".into(), base, image, RomWrites::Fault)
+}
@@ -0,0 +1,252 @@
+//! Memory that only allocates the chunks the guest writes, for physical
+//! address maps too large or too fragmented to back in one piece.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::host::HostMemory;
+use super::{host_read, host_write, Word};
+use crate::isa::riscv32::mmu::{PAGE_SHIFT, PAGE_SIZE};
+
+/// The unit in which sparse memory is allocated.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ChunkSize {
+    /// 4 KiB
+    #[default]
+    Page,
+    /// 2 MiB, fewer and larger allocations for guests that touch a lot
+    HugePage,
+}
+
+impl ChunkSize {
+    pub fn shift(self) -> u32 {
+        match self {
+            Self::Page => PAGE_SHIFT as u32,
+            Self::HugePage => 21,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        1 << self.shift()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SparseStats {
+    /// chunks allocated so far
+    pub chunks: usize,
+    /// bytes of host memory they take
+    pub footprint: usize,
+}
+
+struct Chunk {
+    data: HostMemory,
+    /// a bit for every page written since the last checkpoint
+    dirty: Vec<u64>,
+}
+
+/// Memory addressed by offset, of any size up to the whole 64-bit space. A
+/// chunk is allocated on the first write to it. Reads of the others return
+/// `fill`, repeated every 8 bytes in little-endian order.
+pub struct Sparse {
+    chunk: ChunkSize,
+    fill: u64,
+    /// by offset >> chunk.shift()
+    chunks: HashMap<usize, Chunk>,
+}
+
+impl Sparse {
+    pub fn new(chunk: ChunkSize, fill: u64) -> Self {
+        Self {
+            chunk,
+            fill,
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn chunk(&self) -> ChunkSize {
+        self.chunk
+    }
+
+    pub fn fill(&self) -> u64 {
+        self.fill
+    }
+
+    pub fn stats(&self) -> SparseStats {
+        SparseStats {
+            chunks: self.chunks.len(),
+            footprint: self.chunks.len() * self.chunk.bytes(),
+        }
+    }
+
+    fn fill_byte(&self, offset: usize) -> u8 {
+        self.fill.to_le_bytes()[offset % 8]
+    }
+
+    /// The chunk containing `offset`, and the offset into it.
+    fn split(&self, offset: usize) -> (usize, usize) {
+        (
+            offset >> self.chunk.shift(),
+            offset & (self.chunk.bytes() - 1),
+        )
+    }
+
+    pub fn read<T: Word>(&self, offset: usize) -> T {
+        let (index, within) = self.split(offset);
+        match self.chunks.get(&index) {
+            Some(chunk) if within + T::SIZE <= self.chunk.bytes() => chunk.data.read(within),
+            _ => {
+                let mut bytes = [0; 8];
+                self.read_bytes(offset, &mut bytes[..T::SIZE]);
+                host_read(&bytes)
+            }
+        }
+    }
+
+    pub fn write<T: Word>(&mut self, offset: usize, data: T) {
+        let mut bytes = [0; 8];
+        host_write(&mut bytes, data);
+        self.write_bytes(offset, &bytes[..T::SIZE]);
+    }
+
+    /// Visit the pieces of `len` bytes at `offset` that fall in one chunk
+    /// each, as chunk, offset into it, and range of the bytes.
+    fn pieces(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> impl Iterator<Item = (usize, usize, usize, usize)> {
+        let shift = self.chunk.shift();
+        let size = self.chunk.bytes();
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            if done == len {
+                return None;
+            }
+            let at = offset + done;
+            let within = at & (size - 1);
+            let n = (size - within).min(len - done);
+            let piece = (at >> shift, within, done, done + n);
+            done += n;
+            Some(piece)
+        })
+    }
+
+    pub fn read_bytes(&self, offset: usize, bytes: &mut [u8]) {
+        for (index, within, low, high) in self.pieces(offset, bytes.len()) {
+            let piece = &mut bytes[low..high];
+            match self.chunks.get(&index) {
+                Some(chunk) => chunk.data.read_bytes(within, piece),
+                None => {
+                    for (i, byte) in piece.iter_mut().enumerate() {
+                        *byte = self.fill_byte(offset + low + i);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        let size = self.chunk.bytes();
+        let pages = size >> PAGE_SHIFT;
+        let fill = self.fill.to_le_bytes();
+        for (index, within, low, high) in self.pieces(offset, bytes.len()) {
+            let chunk = self.chunks.entry(index).or_insert_with(|| Chunk {
+                data: HostMemory::heap((0..size).map(|i| fill[i % 8]).collect()),
+                dirty: vec![0; pages.div_ceil(64)],
+            });
+            chunk.data.write_bytes(within, &bytes[low..high]);
+            let first = within >> PAGE_SHIFT;
+            let last = (within + high - low - 1) >> PAGE_SHIFT;
+            for page in first..=last {
+                chunk.dirty[page / 64] |= 1 << (page % 64);
+            }
+        }
+    }
+
+    /// The page at `offset`, if its chunk is allocated and, for stores, it
+    /// was written since the last checkpoint.
+    pub fn host_page(&mut self, offset: usize, store: bool) -> Option<*mut u8> {
+        let (index, within) = self.split(offset);
+        let chunk = self.chunks.get_mut(&index)?;
+        let page = within >> PAGE_SHIFT;
+        if store && chunk.dirty[page / 64] & 1 << (page % 64) == 0 {
+            return None;
+        }
+        Some(chunk.data.page(within, PAGE_SIZE as usize))
+    }
+
+    /// The offsets of the pages written since the last checkpoint, in order.
+    pub fn dirty_pages(&self) -> Vec<usize> {
+        let mut pages = Vec::new();
+        for (&index, chunk) in &self.chunks {
+            for (word_index, &word) in chunk.dirty.iter().enumerate() {
+                let mut word = word;
+                while word != 0 {
+                    let page = word_index * 64 + word.trailing_zeros() as usize;
+                    pages.push((index << self.chunk.shift()) + (page << PAGE_SHIFT));
+                    word &= word - 1;
+                }
+            }
+        }
+        pages.sort_unstable();
+        pages
+    }
+
+    /// A copy of every allocated chunk by index, cut off at `size` bytes
+    /// into the memory.
+    pub fn copy_chunks(&self, size: usize) -> BTreeMap<usize, Vec<u8>> {
+        self.chunks
+            .iter()
+            .map(|(&index, chunk)| {
+                let len = self.chunk.bytes().min(size - (index << self.chunk.shift()));
+                (index, chunk.data.bytes(0, len).to_vec())
+            })
+            .collect()
+    }
+
+    pub fn checkpoint(&mut self) {
+        for chunk in self.chunks.values_mut() {
+            chunk.dirty.fill(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks() {
+        let mut sparse = Sparse::new(ChunkSize::Page, 0xdead_beef_0bad_f00d);
+        assert_eq!(sparse.read::<u64>(0x1000), 0xdead_beef_0bad_f00d);
+        assert_eq!(sparse.read::<u32>(usize::MAX - 3), 0xdead_beef);
+        assert_eq!(sparse.stats(), SparseStats::default());
+
+        // across two chunks, which are allocated with the fill pattern
+        sparse.write(0x1ffe, 0x1122_3344u32);
+        assert_eq!(sparse.read::<u32>(0x1ffc), 0x3344_beef);
+        assert_eq!(sparse.read::<u32>(0x2000), 0x0bad_1122);
+        assert_eq!(sparse.read::<u32>(0x1ffe), 0x1122_3344);
+        sparse.write(usize::MAX - 7, u64::MAX);
+        assert_eq!(sparse.read::<u64>(usize::MAX - 7), u64::MAX);
+        assert_eq!(
+            sparse.stats(),
+            SparseStats {
+                chunks: 3,
+                footprint: 0x3000
+            }
+        );
+        assert_eq!(
+            sparse.dirty_pages(),
+            [0x1000, 0x2000, 0xffff_ffff_ffff_f000]
+        );
+        sparse.checkpoint();
+        assert!(sparse.dirty_pages().is_empty());
+
+        let mut huge = Sparse::new(ChunkSize::HugePage, 0);
+        huge.write(0x20_0000, 1u8);
+        huge.write(0x3f_f000, 1u8);
+        assert_eq!(huge.dirty_pages(), [0x20_0000, 0x3f_f000]);
+        assert_eq!(huge.stats().footprint, 2 << 20);
+    }
+}
@@ -25,6 +25,7 @@ pub struct MonitorState {
 impl Monitor {
     pub fn start(self) {}
 
+    #[allow(clippy::result_unit_err)]
     pub fn exec(self) -> Result<i32, ()> {
         match self.state.inner {
             State::END | State::ABORT => {
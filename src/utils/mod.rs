@@ -0,0 +1,6 @@
+/// Sign-extend the low `bits` bits of `value` to 64 bits.
+#[inline(always)]
+pub fn sext(value: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64 >> shift) as u64
+}